@@ -0,0 +1,101 @@
+//! 出力をまとめた tar.gz（`--output-archive`）。
+//!
+//! 配布するときに別の手順でまとめ直さなくて済むよう、出力を出力先と同じディレクトリ構成で1つのファイルにする。
+//! 出力のファイルもそのまま残す。エントリの更新日時は、出力を書き出した日時ではなく元のファイルの更新日時にする。
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::error::CompressError;
+use crate::file::ensure_parent_dir;
+
+/// まとめる1つの出力
+pub struct Entry {
+    /// 書き出した出力。--extract-frames の出力はディレクトリ
+    pub output: PathBuf,
+    /// tar の中での相対パス
+    pub name: PathBuf,
+    /// エントリに付ける更新日時（元のファイルの更新日時）。`None` なら出力の更新日時
+    pub modified: Option<SystemTime>,
+}
+
+/// `entries` を gzip で圧縮した tar として `archive_path` に書き出す。まとめたファイルの数を返す。
+///
+/// 出力は1つずつ読みながら書き込むため、大きな動画もメモリに読み込まない。
+pub fn write(archive_path: &Path, entries: &[Entry]) -> Result<usize, CompressError> {
+    ensure_parent_dir(archive_path)?;
+    let encoder = GzEncoder::new(BufWriter::new(File::create(archive_path)?), Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut count = 0;
+    for entry in entries {
+        if entry.output.is_dir() {
+            // --extract-frames の出力は、連番の画像を直下に並べたディレクトリ
+            let mut frames: Vec<PathBuf> = fs::read_dir(&entry.output)?
+                .filter_map(Result::ok)
+                .map(|frame| frame.path())
+                .filter(|frame| frame.is_file())
+                .collect();
+            frames.sort();
+            for frame in &frames {
+                append(&mut builder, frame, &entry.name.join(frame.file_name().unwrap_or_default()), entry.modified)?;
+            }
+            count += frames.len();
+        } else {
+            append(&mut builder, &entry.output, &entry.name, entry.modified)?;
+            count += 1;
+        }
+    }
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(count)
+}
+
+fn append(builder: &mut tar::Builder<impl Write>, path: &Path, name: &Path, modified: Option<SystemTime>) -> Result<(), CompressError> {
+    let file = File::open(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&file.metadata()?);
+    if let Some(since_epoch) = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        header.set_mtime(since_epoch.as_secs());
+    }
+    builder.append_data(&mut header, name, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Duration;
+    use flate2::read::GzDecoder;
+
+    /// 出力を相対パスのまま元の更新日時でまとめ、ディレクトリの出力は中のファイルをまとめること
+    #[test]
+    fn archives_outputs_with_source_mtimes() {
+        let dir = std::env::temp_dir().join("compressor_archive_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("out/clip")).unwrap();
+        fs::write(dir.join("out/photo.jpg"), b"jpeg").unwrap();
+        fs::write(dir.join("out/clip/clip-000001.jpg"), b"frame").unwrap();
+        let source_time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let entries = [
+            Entry { output: dir.join("out/photo.jpg"), name: PathBuf::from("sub/photo.jpg"), modified: Some(source_time) },
+            Entry { output: dir.join("out/clip"), name: PathBuf::from("clip"), modified: None },
+        ];
+        assert_eq!(write(&dir.join("bundle.tar.gz"), &entries).unwrap(), 2);
+
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(dir.join("bundle.tar.gz")).unwrap()));
+        let mut found = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            found.push((entry.path().unwrap().into_owned(), content, entry.header().mtime().unwrap()));
+        }
+        assert_eq!(found[0], (PathBuf::from("sub/photo.jpg"), "jpeg".to_string(), 1_600_000_000));
+        assert_eq!((found[1].0.as_path(), found[1].1.as_str()), (Path::new("clip/clip-000001.jpg"), "frame"));
+        assert_eq!(found.len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
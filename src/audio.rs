@@ -1,7 +1,8 @@
 use std::path::Path;
 use std::process::Command;
 use crate::error::CompressError;
-use crate::utilities::is_ffmpeg_available;
+use crate::file::ensure_parent_dir;
+use crate::utilities::{is_ffmpeg_available, run_tool, FfmpegLogLevel};
 
 /// 可逆音源の拡張子。既定でFLACに可逆圧縮する。
 const LOSSLESS_EXTENSIONS: [&str; 4] = ["wav", "aiff", "aif", "flac"];
@@ -31,34 +32,39 @@ impl AudioCodec {
 }
 
 /// 拡張子を小文字で取り出す
-fn normalized_extension(input_path: &str) -> Option<String> {
-    Path::new(input_path)
+fn normalized_extension(input_path: &Path) -> Option<String> {
+    input_path
         .extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| ext.to_lowercase())
 }
 
 /// 対応する音声拡張子かどうかを判定する
-pub fn is_match_extension(input_path: &str) -> bool {
+#[allow(dead_code)]
+pub fn is_match_extension(input_path: &Path) -> bool {
     // 入力ファイルの存在チェック
-    if !Path::new(input_path).exists() {
+    if !input_path.exists() {
         return false;
     }
 
-    matches!(
-        normalized_extension(input_path),
-        Some(ext) if LOSSLESS_EXTENSIONS.contains(&ext.as_str())
-            || LOSSY_EXTENSIONS.contains(&ext.as_str())
-    )
+    matches!(normalized_extension(input_path), Some(ext) if is_audio_extension(&ext))
+}
+
+/// 対応する音声の拡張子（小文字）か
+pub fn is_audio_extension(ext: &str) -> bool {
+    LOSSLESS_EXTENSIONS.contains(&ext) || LOSSY_EXTENSIONS.contains(&ext)
 }
 
 /// 拡張子から、入力が可逆音源（WAV/AIFF/FLAC）かどうかを判定する。
 /// 可逆音源は既定でFLACに圧縮し、非可逆音源（MP3/AAC等）は既定で非可逆再エンコードする。
-pub fn is_lossless_source(input_path: &str) -> bool {
-    matches!(
-        normalized_extension(input_path),
-        Some(ext) if LOSSLESS_EXTENSIONS.contains(&ext.as_str())
-    )
+#[allow(dead_code)]
+pub fn is_lossless_source(input_path: &Path) -> bool {
+    matches!(normalized_extension(input_path), Some(ext) if is_lossless_extension(&ext))
+}
+
+/// 可逆音源の拡張子（小文字）か
+pub fn is_lossless_extension(ext: &str) -> bool {
+    LOSSLESS_EXTENSIONS.contains(&ext)
 }
 
 /// 音声ファイルを圧縮する関数
@@ -69,27 +75,24 @@ pub fn is_lossless_source(input_path: &str) -> bool {
 /// * `output_path` - 圧縮後の出力先ファイルパス
 /// * `codec` - 出力コーデック（FLAC/AAC/Opus）
 /// * `bitrate` - 非可逆圧縮時のビットレート（例: "128k"）。FLACでは無視される
+/// * `loglevel` - ffmpeg 自身のログの詳しさ
 pub fn path2compress(
     input_path: &Path,
     output_path: &Path,
     codec: AudioCodec,
     bitrate: &str,
+    loglevel: FfmpegLogLevel,
 ) -> Result<(), CompressError> {
     // FFmpegの存在チェック
     if !is_ffmpeg_available() {
-        return Err(CompressError::Ffmpeg(
-            "FFmpegがインストールされていないか、PATHに含まれていません".to_string(),
-        ));
+        return Err(CompressError::ToolMissing("ffmpeg"));
     }
 
     // 出力ディレクトリの存在チェックと作成
-    if let Some(parent) = output_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            std::fs::create_dir_all(parent)?;
-        }
-    }
+    ensure_parent_dir(output_path)?;
 
     let mut command = Command::new("ffmpeg");
+    command.args(["-loglevel", loglevel.name()]);
     command.arg("-i").arg(input_path);
 
     match codec {
@@ -117,17 +120,10 @@ pub fn path2compress(
         }
     }
 
-    let status = command
+    command
         .arg("-y") // 確認なしで上書き
-        .arg(output_path)
-        .status()
-        .map_err(|e| CompressError::Ffmpeg(format!("FFmpegの実行に失敗: {e}")))?;
-
-    if !status.success() {
-        return Err(CompressError::Ffmpeg(format!("FFmpegがエラーコードで終了: {status}")));
-    }
-
-    Ok(())
+        .arg(output_path);
+    run_tool("ffmpeg", &mut command, loglevel.is_quiet())
 }
 
 #[cfg(test)]
@@ -138,25 +134,25 @@ mod tests {
     #[test]
     fn detects_lossless_sources() {
         for ext in LOSSLESS_EXTENSIONS {
-            assert!(is_lossless_source(&format!("song.{ext}")), "{ext} が可逆と判定されない");
+            assert!(is_lossless_source(Path::new(&format!("song.{ext}"))), "{ext} が可逆と判定されない");
         }
         for ext in LOSSY_EXTENSIONS {
-            assert!(!is_lossless_source(&format!("song.{ext}")), "{ext} が可逆と判定された");
+            assert!(!is_lossless_source(Path::new(&format!("song.{ext}"))), "{ext} が可逆と判定された");
         }
     }
 
     /// 大文字の拡張子でも判定できること
     #[test]
     fn extension_check_is_case_insensitive() {
-        assert!(is_lossless_source("song.WAV"));
-        assert!(!is_lossless_source("song.MP3"));
+        assert!(is_lossless_source(Path::new("song.WAV")));
+        assert!(!is_lossless_source(Path::new("song.MP3")));
     }
 
     /// 拡張子がない・対象外の場合は可逆扱いしないこと
     #[test]
     fn non_audio_is_not_lossless() {
-        assert!(!is_lossless_source("song"));
-        assert!(!is_lossless_source("clip.mp4"));
+        assert!(!is_lossless_source(Path::new("song")));
+        assert!(!is_lossless_source(Path::new("clip.mp4")));
     }
 
     /// コーデックごとの出力拡張子
@@ -0,0 +1,157 @@
+//! 複数の画像をまとめて圧縮し、ファイルごとの進み具合を知らせる（ライブラリ向け）。
+//!
+//! GUI などに組み込んで進捗を表示するためのもの。CLI の `--progress` も同じ [`ProgressEvent`] を受け取って表示する。
+
+use std::path::{Path, PathBuf};
+use crate::error::CompressError;
+use crate::file::{extension_of, file_size, ExtensionMap};
+use crate::utilities::{write_output, Encoded, ImageOptions};
+use crate::{rgb_image, rgba_image, webp_image};
+
+/// 1ファイル分の進み具合
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ProgressEvent<'a> {
+    /// 圧縮を始めた
+    Started {
+        /// 何番目のファイルか（0 から）
+        index: usize,
+        /// ファイルの総数
+        total: usize,
+        input: &'a Path,
+        /// 圧縮前のサイズ（バイト）
+        original_bytes: u64,
+    },
+    /// 圧縮を終えた
+    Finished {
+        /// 何番目のファイルか（0 から）
+        index: usize,
+        /// ファイルの総数
+        total: usize,
+        input: &'a Path,
+        output: &'a Path,
+        /// 圧縮前のサイズ（バイト）
+        original_bytes: u64,
+        /// 圧縮後のサイズ（バイト）。失敗した場合は 0
+        compressed_bytes: u64,
+        /// 失敗した場合のエラー
+        error: Option<&'a CompressError>,
+    },
+}
+
+/// `(入力, 出力先)` の組を順に圧縮し、ファイルごとに開始と終了を `on_progress` で知らせる。結果は `files` と同じ順に返す。
+///
+/// 入力は jpg/png/webp（拡張子で判断し、出力も同じ形式）。それ以外とアニメーション WebP は [`CompressError::InvalidInput`]。
+/// 1ファイルが失敗しても残りのファイルは続けて処理する。
+#[allow(dead_code)]
+pub fn compress_images(
+    files: &[(PathBuf, PathBuf)],
+    options: &ImageOptions,
+    on_progress: impl Fn(ProgressEvent),
+) -> Vec<Result<(), CompressError>> {
+    let total = files.len();
+    files
+        .iter()
+        .enumerate()
+        .map(|(index, (input, output))| {
+            let original_bytes = file_size(input);
+            on_progress(ProgressEvent::Started { index, total, input, original_bytes });
+            let result = compress_image(input, output, options);
+            on_progress(ProgressEvent::Finished {
+                index,
+                total,
+                input,
+                output,
+                original_bytes,
+                compressed_bytes: if result.is_ok() { file_size(output) } else { 0 },
+                error: result.as_ref().err(),
+            });
+            result
+        })
+        .collect()
+}
+
+/// `input` を [`compress_images`] と同じように圧縮した場合に、書き出すバイト数を返す（ファイルには書かない）。
+///
+/// 元のほうが小さい場合は元の大きさになる。圧縮したものを残すか、容量の上限に収まるかを先に決めるためのもの。
+#[allow(dead_code)]
+pub fn estimate_size(input: &Path, options: &ImageOptions) -> Result<u64, CompressError> {
+    Ok(encode_image(input, &ExtensionMap::default(), options)?.into_output().len() as u64)
+}
+
+#[allow(dead_code)]
+fn compress_image(input: &Path, output: &Path, options: &ImageOptions) -> Result<(), CompressError> {
+    write_output(output, &encode_image(input, &ExtensionMap::default(), options)?.into_output())
+}
+
+/// 拡張子（`ext_map` で読み替えたもの）に応じたエンコーダで、入力と同じ形式にメモリ上で圧縮する。
+/// 入力は jpg/png/静止画の webp で、それ以外は [`CompressError::InvalidInput`]
+pub fn encode_image(input: &Path, ext_map: &ExtensionMap, options: &ImageOptions) -> Result<Encoded, CompressError> {
+    match ext_map.resolve(&extension_of(input)).as_str() {
+        "jpg" | "jpeg" => rgb_image::path2jpeg(input, options),
+        "png" => rgba_image::path2png(input, options),
+        "webp" => match webp_image::inspect(&std::fs::read(input)?) {
+            Some(kind) if !kind.animated => webp_image::path2webp(input, kind, options),
+            _ => Err(CompressError::InvalidInput(format!("静止画の WebP ではありません: {:?}", input))),
+        },
+        _ => Err(CompressError::InvalidInput(format!("対応していない形式です: {:?}", input))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// ファイルごとに開始と終了を順に知らせ、失敗しても残りを処理すること
+    #[test]
+    fn reports_progress_per_file() {
+        let dir = std::env::temp_dir().join("compressor_batch_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbaImage::from_pixel(8, 8, image::Rgba([10, 20, 30, 255])).save(dir.join("a.png")).unwrap();
+        std::fs::write(dir.join("b.txt"), b"text").unwrap();
+
+        let files = [(dir.join("a.png"), dir.join("out/a.png")), (dir.join("b.txt"), dir.join("out/b.txt"))];
+        let events = Mutex::new(Vec::new());
+        let results = compress_images(&files, &ImageOptions::default(), |event| {
+            events.lock().unwrap().push(match event {
+                ProgressEvent::Started { index, total, .. } => format!("start {index}/{total}"),
+                ProgressEvent::Finished { index, compressed_bytes, error, .. } => {
+                    format!("finish {index} {} {}", compressed_bytes > 0, error.is_some())
+                }
+            })
+        });
+
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(CompressError::InvalidInput(_))));
+        assert_eq!(
+            events.into_inner().unwrap(),
+            ["start 0/2", "finish 0 true false", "start 1/2", "finish 1 false true"]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// 見積もりは書き出した場合の大きさと一致し、ファイルを作らないこと
+    #[test]
+    fn estimates_size_without_writing() {
+        let dir = std::env::temp_dir().join("compressor_estimate_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let img = image::RgbImage::from_fn(32, 24, |x, y| image::Rgb([(x * 8) as u8, (y * 10) as u8, 128]));
+        img.save(dir.join("a.png")).unwrap();
+        img.save(dir.join("b.jpg")).unwrap();
+        let options = ImageOptions { quality: 70.0, ..Default::default() };
+
+        for name in ["a.png", "b.jpg"] {
+            let estimated = estimate_size(&dir.join(name), &options).unwrap();
+            assert!(!dir.join("out").exists());
+            compress_image(&dir.join(name), &dir.join("out").join(name), &options).unwrap();
+            assert_eq!(estimated, file_size(&dir.join("out").join(name)), "{name}");
+            std::fs::remove_dir_all(dir.join("out")).unwrap();
+        }
+        std::fs::write(dir.join("c.txt"), b"text").unwrap();
+        assert!(matches!(estimate_size(&dir.join("c.txt"), &options), Err(CompressError::InvalidInput(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,223 @@
+//! ディレクトリごとの設定ファイル（`.compressorrc`）。
+//!
+//! ディレクトリに置いた設定は、その配下のファイルすべてに効く。入れ子のディレクトリは親の設定を引き継ぎ、
+//! 同じ項目を書けば上書きする。コマンドラインで指定したオプションは常に設定ファイルより優先する。
+//!
+//! 書式は TOML のうち `key = value` の行だけ（値は数値・真偽値・文字列）。`#` 以降はコメント。
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::utilities::parse_quality;
+use crate::AppArgs;
+
+/// 設定ファイルの名前
+pub const FILE_NAME: &str = ".compressorrc";
+
+/// 1つの設定ファイルの内容。書かれていない項目は `None`。
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct DirConfig {
+    pub quality: Option<f32>,
+    pub min_reduction: Option<f64>,
+    pub webp: Option<bool>,
+    pub hevc: Option<bool>,
+    pub crf: Option<u8>,
+    pub opus: Option<bool>,
+    pub audio_bitrate: Option<String>,
+}
+
+impl DirConfig {
+    /// 設定ファイルの内容を読む。エラーには行番号を含める。
+    pub fn parse(text: &str) -> Result<DirConfig, String> {
+        let mut config = DirConfig::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("{line_number} 行目: `key = value` の形式ではありません"));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || format!("{line_number} 行目: {key} の値が不正です: {value}");
+
+            match key {
+                "quality" => {
+                    // 名前付きの段階は文字列で書く（quality = "high"）
+                    let quality = parse_string(value).map_or_else(|| value.parse().ok(), |level| parse_quality(&level).ok());
+                    config.quality = Some(quality.ok_or_else(invalid)?);
+                }
+                "min_reduction" => config.min_reduction = Some(value.parse().map_err(|_| invalid())?),
+                "webp" => config.webp = Some(parse_bool(value).ok_or_else(invalid)?),
+                "hevc" => config.hevc = Some(parse_bool(value).ok_or_else(invalid)?),
+                "crf" => config.crf = Some(value.parse().map_err(|_| invalid())?),
+                "opus" => config.opus = Some(parse_bool(value).ok_or_else(invalid)?),
+                "audio_bitrate" => config.audio_bitrate = Some(parse_string(value).ok_or_else(invalid)?),
+                _ => return Err(format!("{line_number} 行目: 不明な項目です: {key}")),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// `child`（より深いディレクトリの設定）で書かれている項目を上書きする
+    pub fn merge(&mut self, child: &DirConfig) {
+        fn pick<T: Clone>(parent: &mut Option<T>, child: &Option<T>) {
+            if child.is_some() {
+                parent.clone_from(child);
+            }
+        }
+        pick(&mut self.quality, &child.quality);
+        pick(&mut self.min_reduction, &child.min_reduction);
+        pick(&mut self.webp, &child.webp);
+        pick(&mut self.hevc, &child.hevc);
+        pick(&mut self.crf, &child.crf);
+        pick(&mut self.opus, &child.opus);
+        pick(&mut self.audio_bitrate, &child.audio_bitrate);
+    }
+
+    /// コマンドラインで指定されていない項目に設定を反映する。`explicit` は引数名が指定済みかを返す。
+    pub fn apply(&self, args: &mut AppArgs, explicit: impl Fn(&str) -> bool) {
+        if let (Some(quality), false) = (self.quality, explicit("quality")) {
+            args.quality = quality;
+        }
+        if let (Some(min), false) = (self.min_reduction, explicit("min_reduction")) {
+            args.min_reduction = Some(min);
+        }
+        if let (Some(webp), false) = (self.webp, explicit("webp")) {
+            args.webp = webp;
+        }
+        if let (Some(hevc), false) = (self.hevc, explicit("hevc")) {
+            args.hevc = hevc;
+        }
+        if let (Some(crf), false) = (self.crf, explicit("crf")) {
+            args.crf = Some(crf);
+        }
+        if let (Some(opus), false) = (self.opus, explicit("opus")) {
+            args.opus = opus;
+        }
+        if let (Some(bitrate), false) = (&self.audio_bitrate, explicit("audio_bitrate")) {
+            args.audio_bitrate = bitrate.clone();
+        }
+    }
+}
+
+/// 読み込んだ設定ファイルをディレクトリごとに覚えておく（ファイルごとに読み直さないため）
+#[derive(Default)]
+pub struct ConfigCache {
+    dirs: HashMap<PathBuf, Option<DirConfig>>,
+}
+
+impl ConfigCache {
+    /// `file` に効く設定を求める。`root` から `file` のディレクトリまでの設定ファイルを、浅い順に重ねる。
+    /// `root` の外にあるファイルには設定を適用しない。
+    pub fn resolve(&mut self, file: &Path, root: &Path) -> Result<DirConfig, String> {
+        let Some(parent) = file.parent() else {
+            return Ok(DirConfig::default());
+        };
+        if !parent.starts_with(root) {
+            return Ok(DirConfig::default());
+        }
+
+        let mut dirs: Vec<&Path> = parent.ancestors().take_while(|dir| dir.starts_with(root)).collect();
+        dirs.reverse();
+
+        let mut resolved = DirConfig::default();
+        for dir in dirs {
+            if let Some(config) = self.load(dir)? {
+                resolved.merge(&config);
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn load(&mut self, dir: &Path) -> Result<Option<DirConfig>, String> {
+        if let Some(config) = self.dirs.get(dir) {
+            return Ok(config.clone());
+        }
+
+        let path = dir.join(FILE_NAME);
+        let config = match std::fs::read_to_string(&path) {
+            Ok(text) => Some(DirConfig::parse(&text).map_err(|e| format!("{:?}: {e}", path))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("{:?}: {e}", path)),
+        };
+        self.dirs.insert(dir.to_path_buf(), config.clone());
+        Ok(config)
+    }
+}
+
+/// 文字列の外にある `#` 以降を取り除く
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some(inner.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 数値・真偽値・文字列とコメントを読めること
+    #[test]
+    fn parses_settings() {
+        let config = DirConfig::parse("# 写真用\nquality = 85.5\nwebp = true # 変換する\n\naudio_bitrate = \"96k\"\n").unwrap();
+        assert_eq!(config.quality, Some(85.5));
+        assert_eq!(DirConfig::parse("quality = \"high\"\n").unwrap().quality, Some(85.0));
+        assert!(DirConfig::parse("quality = \"best\"\n").is_err());
+        assert_eq!(config.webp, Some(true));
+        assert_eq!(config.audio_bitrate, Some("96k".to_string()));
+        assert_eq!(config.crf, None);
+    }
+
+    /// 不明な項目や不正な値は行番号付きでエラーにすること
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(DirConfig::parse("qualty = 80").unwrap_err().contains("1 行目"));
+        assert!(DirConfig::parse("\nwebp = yes").unwrap_err().contains("2 行目"));
+        assert!(DirConfig::parse("crf").is_err());
+    }
+
+    /// 深いディレクトリの設定が親を上書きし、書かれていない項目は親を引き継ぐこと
+    #[test]
+    fn nested_configs_override_parents() {
+        let root = std::env::temp_dir().join("compressor_config_test");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("photos/raw")).unwrap();
+        std::fs::write(root.join(FILE_NAME), "quality = 60\nwebp = true\n").unwrap();
+        std::fs::write(root.join("photos/raw").join(FILE_NAME), "quality = 90\n").unwrap();
+
+        let mut cache = ConfigCache::default();
+        let nested = cache.resolve(&root.join("photos/raw/a.jpg"), &root).unwrap();
+        assert_eq!(nested.quality, Some(90.0));
+        assert_eq!(nested.webp, Some(true));
+
+        let middle = cache.resolve(&root.join("photos/b.jpg"), &root).unwrap();
+        assert_eq!(middle.quality, Some(60.0));
+
+        let outside = cache.resolve(Path::new("/elsewhere/c.jpg"), &root).unwrap();
+        assert_eq!(outside, DirConfig::default());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,95 @@
+//! 処理した画像のサムネイルを格子状に並べた一覧画像（`--contact-sheet`）。
+//!
+//! 大量の画像をまとめて圧縮したときに、結果を1枚の画像で見て確かめるためのもの。
+
+use std::path::Path;
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use crate::error::CompressError;
+use crate::rgb_image;
+use crate::utilities::write_output;
+
+/// サムネイルどうしとサムネイルと縁の間の余白（ピクセル）
+const GAP: u32 = 4;
+/// 背景の色。透過した画像の透明な部分もこの色になる
+const BACKGROUND: Rgba<u8> = Rgba([40, 40, 40, 255]);
+
+/// 一覧画像の並べ方
+#[derive(Clone, Copy, Debug)]
+pub struct ContactSheet {
+    /// 列数
+    pub columns: u32,
+    /// サムネイルの大きさ（縦横ともこの大きさに収める）
+    pub thumb_size: u32,
+}
+
+impl ContactSheet {
+    /// 画像を読み込み、縦横とも `thumb_size` に収まるよう縦横比を保って縮小する
+    pub fn thumbnail(&self, path: &Path) -> Result<RgbaImage, CompressError> {
+        let img = image::open(path)?;
+        Ok(img.thumbnail(self.thumb_size, self.thumb_size).to_rgba8())
+    }
+
+    /// サムネイルを左上から行ごとに並べる。各サムネイルは `thumb_size` 四方の枠の中央に置く。
+    pub fn compose(&self, thumbs: &[RgbaImage]) -> RgbaImage {
+        let (width, height) = self.sheet_size(thumbs.len());
+        let mut sheet = RgbaImage::from_pixel(width, height, BACKGROUND);
+        let sizes: Vec<(u32, u32)> = thumbs.iter().map(RgbaImage::dimensions).collect();
+        for (thumb, (x, y)) in thumbs.iter().zip(self.positions(&sizes)) {
+            imageops::overlay(&mut sheet, thumb, x as i64, y as i64);
+        }
+        sheet
+    }
+
+    /// `count` 枚を並べた一覧画像の幅と高さ
+    fn sheet_size(&self, count: usize) -> (u32, u32) {
+        let cell = self.thumb_size + GAP;
+        let columns = self.columns.min(count as u32).max(1);
+        let rows = (count as u32).div_ceil(columns).max(1);
+        (columns * cell + GAP, rows * cell + GAP)
+    }
+
+    /// 幅と高さが `sizes` のサムネイルを [`compose`](Self::compose) で並べたときの、それぞれの左上の位置
+    pub fn positions(&self, sizes: &[(u32, u32)]) -> Vec<(u32, u32)> {
+        let cell = self.thumb_size + GAP;
+        let columns = self.columns.min(sizes.len() as u32).max(1);
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(index, &(width, height))| {
+                let (column, row) = (index as u32 % columns, index as u32 / columns);
+                let x = GAP + column * cell + (self.thumb_size - width.min(self.thumb_size)) / 2;
+                let y = GAP + row * cell + (self.thumb_size - height.min(self.thumb_size)) / 2;
+                (x, y)
+            })
+            .collect()
+    }
+
+    /// 一覧画像を JPEG で書き出す
+    pub fn write(&self, output_path: &Path, thumbs: &[RgbaImage], quality: f32) -> Result<(), CompressError> {
+        let sheet = DynamicImage::ImageRgba8(self.compose(thumbs));
+        write_output(output_path, &rgb_image::image2jpeg(&sheet, quality, None)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 列数で折り返し、サムネイルを枠の中央に置くこと
+    #[test]
+    fn composes_grid() {
+        let sheet = ContactSheet { columns: 2, thumb_size: 10 };
+        let red = RgbaImage::from_pixel(10, 4, Rgba([255, 0, 0, 255]));
+        let sheet_image = sheet.compose(&[red.clone(), red.clone(), red]);
+
+        // 2列 x 2行: 幅・高さとも 余白 + (10 + 余白) * 2
+        assert_eq!(sheet_image.dimensions(), (32, 32));
+        // 横長のサムネイルは枠の上下中央に置く
+        assert_eq!(*sheet_image.get_pixel(GAP, GAP), BACKGROUND);
+        assert_eq!(*sheet_image.get_pixel(GAP, GAP + 3), Rgba([255, 0, 0, 255]));
+        // 3枚目は2行目の左、2行目の右は空き
+        assert_eq!(*sheet_image.get_pixel(GAP, GAP * 2 + 10 + 3), Rgba([255, 0, 0, 255]));
+        assert_eq!(*sheet_image.get_pixel(GAP * 2 + 10, GAP * 2 + 10 + 3), BACKGROUND);
+        assert_eq!(sheet.positions(&[(10, 4), (6, 10), (10, 4)]), [(GAP, GAP + 3), (GAP * 2 + 12, GAP), (GAP, GAP * 2 + 13)]);
+    }
+}
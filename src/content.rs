@@ -0,0 +1,157 @@
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use crate::error::CompressError;
+use crate::utilities;
+
+/// 画像の内容の傾向（`--format auto` で出力形式を選ぶために使う）
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Content {
+    /// 透過している画素がある。可逆で残す
+    Transparent,
+    /// 色数が少ない、または同じ色の連続が多い（スクリーンショット・図・ロゴなど）。可逆で残す
+    Graphic,
+    /// 写真のように色が連続的に変化する。非可逆で圧縮する
+    Photo,
+}
+
+impl Content {
+    /// ログ表示用の名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Content::Transparent => "alpha",
+            Content::Graphic => "graphic",
+            Content::Photo => "photo",
+        }
+    }
+
+    /// 可逆圧縮で残すべきか
+    pub fn is_lossless(self) -> bool {
+        !matches!(self, Content::Photo)
+    }
+}
+
+/// 色数がこれ以下なら図とみなす（パレット PNG に収まる）
+const MAX_GRAPHIC_COLORS: usize = 256;
+/// 左隣と同じ色の画素がこの割合以上なら図とみなす（アンチエイリアスで色数の増えたスクリーンショット向け）
+const MIN_GRAPHIC_FLAT_RATIO: f64 = 0.6;
+
+/// 画像の内容を判定する。透過、色数、隣接画素のうち同じ色の割合の順に見る。
+pub fn classify(img: &DynamicImage) -> Content {
+    if img.color().has_alpha() && img.to_rgba8().pixels().any(|p| p.0[3] < 255) {
+        return Content::Transparent;
+    }
+
+    let rgb = img.to_rgb8();
+    let mut colors = std::collections::HashSet::new();
+    for pixel in rgb.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > MAX_GRAPHIC_COLORS {
+            break;
+        }
+    }
+    if colors.len() <= MAX_GRAPHIC_COLORS {
+        return Content::Graphic;
+    }
+
+    let (width, height) = rgb.dimensions();
+    let pairs = (width.saturating_sub(1) as u64) * height as u64;
+    let flat = rgb
+        .rows()
+        .map(|row| {
+            let row: Vec<_> = row.collect();
+            row.windows(2).filter(|pair| pair[0] == pair[1]).count() as u64
+        })
+        .sum::<u64>();
+    if pairs > 0 && flat as f64 / pairs as f64 >= MIN_GRAPHIC_FLAT_RATIO {
+        return Content::Graphic;
+    }
+
+    Content::Photo
+}
+
+/// 画像ファイルをデコードして内容を判定する。拡張子を読み替えた入力もあるため、形式は中身から判定する
+pub fn classify_file(path: &Path) -> Result<Content, CompressError> {
+    Ok(classify(&image::ImageReader::open(path)?.with_guessed_format()?.decode()?))
+}
+
+/// `paths` の画像の内容を `jobs` 件ずつ並列に判定する。
+/// 判定にはデコードが要るため、処理を決めながら1枚ずつ判定すると始まるまでが長くなる（`video::probe_streams_all` と同じ理由）。
+/// ヘッダの寸法が `max_megapixels` を超える画像と読めない画像は含めない（処理を決めるときに改めて調べ、理由を報告する）。
+pub fn classify_all(paths: &[PathBuf], jobs: usize, max_megapixels: f64) -> HashMap<PathBuf, Content> {
+    let next = AtomicUsize::new(0);
+    let found = Mutex::new(HashMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, paths.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if utilities::ensure_within_megapixels(path, max_megapixels).is_err() {
+                        continue;
+                    }
+                    if let Ok(content) = classify_file(path) {
+                        found.lock().unwrap().insert(path.clone(), content);
+                    }
+                }
+            });
+        }
+    });
+    found.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    /// 写真のように色が連続的に変化する画素
+    fn gradient(x: u32, y: u32) -> Rgb<u8> {
+        Rgb([(x * 7 % 256) as u8, (y * 5 % 256) as u8, ((x * y) % 256) as u8])
+    }
+
+    /// 透過・少色・写真をそれぞれ判定すること
+    #[test]
+    fn classifies_contents() {
+        let photo = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, gradient));
+        assert_eq!(classify(&photo), Content::Photo);
+
+        let two_colors = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, _| Rgb([if x < 32 { 0 } else { 255 }; 3])));
+        assert_eq!(classify(&two_colors), Content::Graphic);
+
+        let mut transparent = RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255]));
+        transparent.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        assert_eq!(classify(&DynamicImage::ImageRgba8(transparent)), Content::Transparent);
+
+        // アルファを持っていても全画素が不透明なら内容で判定する
+        let opaque = DynamicImage::ImageRgba8(DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, gradient)).to_rgba8());
+        assert_eq!(classify(&opaque), Content::Photo);
+    }
+
+    /// 色数が多くても同じ色の連続が多ければ図と判定すること
+    #[test]
+    fn flat_areas_count_as_graphic() {
+        // 背景は単色で、一部だけ写真のように色が変化する
+        let img = RgbImage::from_fn(100, 100, |x, y| if x < 20 { gradient(x, y) } else { Rgb([250, 250, 250]) });
+        assert_eq!(classify(&DynamicImage::ImageRgb8(img)), Content::Graphic);
+    }
+
+    /// 複数のファイルを並列に判定し、読めないファイルは結果に含めないこと
+    #[test]
+    fn classifies_files_in_parallel() {
+        let dir = std::env::temp_dir().join("compressor_classify_all_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = ["photo.png", "graphic.png", "broken.png"].iter().map(|name| dir.join(name)).collect();
+        RgbImage::from_fn(64, 64, gradient).save(&paths[0]).unwrap();
+        RgbImage::from_pixel(64, 64, Rgb([0, 0, 0])).save(&paths[1]).unwrap();
+        std::fs::write(&paths[2], b"not a png").unwrap();
+
+        let found = classify_all(&paths, 2, 0.0);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[&paths[0]], Content::Photo);
+        assert_eq!(found[&paths[1]], Content::Graphic);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
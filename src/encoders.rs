@@ -0,0 +1,78 @@
+//! JPEG エンコーダの比較（開発用の `--compare-encoders`）。
+//!
+//! 同じ画像を mozjpeg と image クレート組み込みのエンコーダで同じ品質にエンコードし、大きさと時間を比べる。
+//! mozjpeg に依存する理由の確認と、既定の品質の調整に使うためのもの。
+
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use crate::error::CompressError;
+use crate::rgb_image;
+use crate::utilities::{Background, Tonemap};
+
+/// 比べるエンコーダ
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoder {
+    Mozjpeg,
+    /// image クレート組み込みの JPEG エンコーダ
+    Image,
+}
+
+impl Encoder {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoder::Mozjpeg => "mozjpeg",
+            Encoder::Image => "image",
+        }
+    }
+}
+
+/// 1つのエンコーダでエンコードした結果
+pub struct Trial {
+    pub encoder: Encoder,
+    pub jpeg: Vec<u8>,
+    /// エンコードにかかった時間（デコードは含まない）
+    pub elapsed: Duration,
+}
+
+/// `img` を両方のエンコーダで `quality`（0-100）の JPEG にする。透過した部分は `background` に合成する。結果は小さい順。
+pub fn compare(img: &DynamicImage, quality: f32, background: Background) -> Result<Vec<Trial>, CompressError> {
+    // RGB への変換は比べる時間に含めず、両方に同じ画素を渡す
+    let rgb = rgb_image::to_rgb8(img, Tonemap::default(), background)?;
+    let flattened = DynamicImage::ImageRgb8(rgb.clone());
+    let start = Instant::now();
+    let jpeg = rgb_image::image2jpeg(&flattened, quality, None)?;
+    let mozjpeg = Trial { encoder: Encoder::Mozjpeg, jpeg, elapsed: start.elapsed() };
+
+    // image クレートの品質は 1-100 の整数
+    let start = Instant::now();
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut Cursor::new(&mut jpeg), quality.round().clamp(1.0, 100.0) as u8).encode_image(&rgb)?;
+    let builtin = Trial { encoder: Encoder::Image, jpeg, elapsed: start.elapsed() };
+
+    let mut trials = vec![mozjpeg, builtin];
+    trials.sort_by_key(|trial| trial.jpeg.len());
+    Ok(trials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 両方のエンコーダで同じ寸法の JPEG になり、小さい順に並ぶこと
+    #[test]
+    fn compares_both_encoders() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 24, |x, y| image::Rgb([(x * 8) as u8, (y * 10) as u8, 128])));
+        let trials = compare(&img, 75.0, Background::default()).unwrap();
+
+        let mut encoders: Vec<Encoder> = trials.iter().map(|trial| trial.encoder).collect();
+        encoders.sort_by_key(|encoder| encoder.name());
+        assert_eq!(encoders, [Encoder::Image, Encoder::Mozjpeg]);
+        assert!(trials[0].jpeg.len() <= trials[1].jpeg.len());
+        for trial in &trials {
+            let decoded = image::load_from_memory(&trial.jpeg).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (32, 24), "{}", trial.encoder.name());
+        }
+    }
+}
@@ -9,8 +9,16 @@ pub enum CompressError {
     Io(std::io::Error),
     /// oxipngによるPNG最適化に失敗した。
     Png(oxipng::PngError),
-    /// ffmpegの実行に失敗した（未インストール、エンコードエラーなど）。
-    Ffmpeg(String),
+    /// 入力の中身を解釈できなかった（image クレートを通さない CMYK JPEG のデコードなど）。
+    Decode(String),
+    /// 入力とオプションの組み合わせを処理できない（切り抜き範囲が画像の外、空の透かしなど）。同じ指定で再実行しても失敗する。
+    InvalidInput(String),
+    /// 外部コマンド（ffmpeg, ffprobe, pdftoppm）が見つからない。以降のファイルも同じ理由で失敗する。
+    ToolMissing(&'static str),
+    /// 外部コマンドが失敗した。`stderr` はコマンドの標準エラー出力の末尾（端末に流した場合は空）。
+    ToolFailed { tool: &'static str, status: String, stderr: String },
+    /// 画像の画素数が上限を超えている（デコードするとメモリを使い果たす恐れがある）。
+    TooLarge { width: u32, height: u32, max_megapixels: f64 },
 }
 
 impl fmt::Display for CompressError {
@@ -19,7 +27,15 @@ impl fmt::Display for CompressError {
             CompressError::Image(e) => write!(f, "image decode error: {e}"),
             CompressError::Io(e) => write!(f, "io error: {e}"),
             CompressError::Png(e) => write!(f, "png optimize error: {e}"),
-            CompressError::Ffmpeg(e) => write!(f, "ffmpeg error: {e}"),
+            CompressError::Decode(e) => write!(f, "decode error: {e}"),
+            CompressError::InvalidInput(e) => write!(f, "invalid input: {e}"),
+            CompressError::ToolMissing(tool) => write!(f, "{tool} not found in PATH"),
+            CompressError::ToolFailed { tool, status, stderr } if stderr.is_empty() => write!(f, "{tool} failed: {status}"),
+            CompressError::ToolFailed { tool, status, stderr } => write!(f, "{tool} failed: {status}\n{stderr}"),
+            CompressError::TooLarge { width, height, max_megapixels } => write!(
+                f,
+                "image too large: {width}x{height} exceeds {max_megapixels} megapixels"
+            ),
         }
     }
 }
@@ -30,11 +46,22 @@ impl std::error::Error for CompressError {
             CompressError::Image(e) => Some(e),
             CompressError::Io(e) => Some(e),
             CompressError::Png(e) => Some(e),
-            CompressError::Ffmpeg(_) => None,
+            CompressError::Decode(_)
+            | CompressError::InvalidInput(_)
+            | CompressError::ToolMissing(_)
+            | CompressError::ToolFailed { .. }
+            | CompressError::TooLarge { .. } => None,
         }
     }
 }
 
+impl CompressError {
+    /// 以降のファイルも同じ理由で失敗するため、処理を打ち切るべきエラーか
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CompressError::ToolMissing(_))
+    }
+}
+
 impl From<image::ImageError> for CompressError {
     fn from(e: image::ImageError) -> Self {
         CompressError::Image(e)
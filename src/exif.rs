@@ -0,0 +1,368 @@
+//! Exif の最小限の読み取りと書き換え。
+//!
+//! 必要なのは数個のタグだけなので、外部クレートを使わず TIFF 構造（IFD）を直接たどる。
+//! 入力は APP1 のペイロード（`Exif\0\0` から始まるバイト列）。
+
+/// Exif IFD へのポインタ
+const TAG_EXIF_IFD: u16 = 0x8769;
+/// GPS IFD へのポインタ
+const TAG_GPS_IFD: u16 = 0x8825;
+/// 撮影日時
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+/// ファイル変更日時（撮影日時がない場合の代わり）
+const TAG_DATE_TIME: u16 = 0x0132;
+/// 画像の向き
+const TAG_ORIENTATION: u16 = 0x0112;
+/// ASCII 型
+const TYPE_ASCII: u16 = 2;
+/// SHORT 型（符号なし16bit）
+const TYPE_SHORT: u16 = 3;
+
+/// IFD のエントリ
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// 値（4バイト以内）またはオフセットが入っているフィールドの位置
+    value_pos: usize,
+}
+
+/// TIFF 構造を読むためのビュー
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// `Exif\0\0` で始まる APP1 ペイロードから TIFF 部分を取り出す
+    fn from_app1(payload: &'a [u8]) -> Option<Self> {
+        Tiff::new(payload.strip_prefix(b"Exif\0\0")?)
+    }
+
+    /// バイトオーダーの印（`II` / `MM`）から始まる TIFF 構造
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(0..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        Some(Tiff { data, little_endian })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    /// 先頭の IFD（IFD0）の位置
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    /// IFD のエントリを列挙する。範囲外を指す壊れたデータは読める分だけ返す。
+    fn entries(&self, ifd: usize) -> Vec<Entry> {
+        let Some(count) = self.u16_at(ifd) else {
+            return Vec::new();
+        };
+
+        (0..count as usize)
+            .map(|i| ifd + 2 + i * 12)
+            .map_while(|pos| {
+                Some(Entry {
+                    tag: self.u16_at(pos)?,
+                    kind: self.u16_at(pos + 2)?,
+                    count: self.u32_at(pos + 4)?,
+                    value_pos: pos + 8,
+                })
+            })
+            .collect()
+    }
+
+    fn find(&self, ifd: usize, tag: u16) -> Option<Entry> {
+        self.entries(ifd).into_iter().find(|entry| entry.tag == tag)
+    }
+
+    /// ASCII 型の値を取り出す（終端の NUL は除く）
+    fn ascii(&self, entry: &Entry) -> Option<&'a str> {
+        if entry.kind != TYPE_ASCII {
+            return None;
+        }
+
+        let len = entry.count as usize;
+        let start = if len <= 4 {
+            entry.value_pos
+        } else {
+            self.u32_at(entry.value_pos)? as usize
+        };
+        let bytes = self.data.get(start..start.checked_add(len)?)?;
+        let bytes = bytes.split(|&b| b == 0).next().unwrap_or_default();
+        std::str::from_utf8(bytes).ok()
+    }
+}
+
+/// IFD0 の Orientation の値（1〜8）と、APP1 ペイロード内での値の位置
+fn orientation_entry(app1: &[u8]) -> Option<(u16, usize)> {
+    let tiff = Tiff::from_app1(app1)?;
+    let entry = tiff.find(tiff.first_ifd()?, TAG_ORIENTATION)?;
+    if entry.kind != TYPE_SHORT || entry.count != 1 {
+        return None;
+    }
+    let value = tiff.u16_at(entry.value_pos)?;
+    // TIFF 部分は `Exif\0\0` の6バイトの後ろから始まる
+    (1..=8).contains(&value).then_some((value, entry.value_pos + 6))
+}
+
+/// Exif の Orientation（1〜8）。ない場合や不正な値の場合は `None`。
+pub fn orientation(app1: &[u8]) -> Option<u8> {
+    orientation_entry(app1).map(|(value, _)| value as u8)
+}
+
+/// Orientation を 1（変換なし）に書き換える。向きをピクセルに焼き込んだ後、二重に回転されないようにする。
+pub fn reset_orientation(app1: &mut [u8]) {
+    let Some((_, pos)) = orientation_entry(app1) else {
+        return;
+    };
+    let little_endian = app1.get(6..8) == Some(b"II");
+    let value = if little_endian { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() };
+    app1[pos..pos + 2].copy_from_slice(&value);
+}
+
+/// 型ごとの値1個のバイト数。知らない型は `None`
+fn type_size(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+/// APP1 ペイロードの Exif から、位置情報（GPS IFD）だけを消す。消した場合は true
+pub fn strip_gps(app1: &mut [u8]) -> bool {
+    app1.starts_with(b"Exif\0\0") && strip_gps_tiff(&mut app1[6..])
+}
+
+/// TIFF 構造（PNG の eXIf チャンクの中身など）から、位置情報（GPS IFD）だけを消す。消した場合は true。
+///
+/// 長さを変えずに書き換えるため、他のタグの値を指すオフセットはそのまま使える。
+/// IFD0 から GPS IFD へのポインタを除いて後ろのエントリを詰め、GPS IFD とその値のバイトは 0 で埋める（読み出せないよう）。
+pub fn strip_gps_tiff(data: &mut [u8]) -> bool {
+    let Some(tiff) = Tiff::new(data) else {
+        return false;
+    };
+    let little_endian = tiff.little_endian;
+    let Some(ifd0) = tiff.first_ifd() else {
+        return false;
+    };
+    let Some(count) = tiff.u16_at(ifd0).map(usize::from) else {
+        return false;
+    };
+    // 次の IFD へのオフセットまでを詰めるため、IFD0 が途中で切れていれば書き換えない
+    let ifd0_end = ifd0 + 2 + count * 12 + 4;
+    let entries = tiff.entries(ifd0);
+    if ifd0_end > data.len() || entries.len() != count {
+        return false;
+    }
+    let Some(index) = entries.iter().position(|entry| entry.tag == TAG_GPS_IFD) else {
+        return false;
+    };
+
+    let mut wipe = Vec::new();
+    if let Some(gps) = tiff.u32_at(entries[index].value_pos).map(|offset| offset as usize) {
+        let gps_entries = tiff.entries(gps);
+        for entry in &gps_entries {
+            let Some(size) = type_size(entry.kind).and_then(|size| size.checked_mul(entry.count as usize)) else {
+                continue;
+            };
+            if size > 4 {
+                if let Some(offset) = tiff.u32_at(entry.value_pos) {
+                    wipe.push(offset as usize..(offset as usize).saturating_add(size));
+                }
+            }
+        }
+        wipe.push(gps..gps + 2 + gps_entries.len() * 12 + 4);
+    }
+
+    let start = ifd0 + 2 + index * 12;
+    data.copy_within(start + 12..ifd0_end, start);
+    data[ifd0_end - 12..ifd0_end].fill(0);
+    let count = (count as u16 - 1).to_le_bytes();
+    data[ifd0..ifd0 + 2].copy_from_slice(&if little_endian { count } else { [count[1], count[0]] });
+    for range in wipe {
+        // 壊れたオフセットが IFD0 を指していても、詰めたエントリは消さない
+        let (start, end) = (range.start.min(data.len()), range.end.min(data.len()));
+        if end <= ifd0 || start >= ifd0_end {
+            data[start..end].fill(0);
+        }
+    }
+    true
+}
+
+/// XMP のパケットが位置情報（`exif:GPSLatitude` など）を含むか。XMP は書き換えず、含むものは丸ごと落とす
+pub fn xmp_has_gps(xmp: &[u8]) -> bool {
+    xmp.windows(3).any(|window| window == b"GPS")
+}
+
+/// 撮影日時を `YYYYMMDD-HHMMSS` 形式で返す。撮影日時がなければファイル変更日時を使う。
+#[allow(dead_code)]
+pub fn capture_date(app1: &[u8]) -> Option<String> {
+    let tiff = Tiff::from_app1(app1)?;
+    let ifd0 = tiff.first_ifd()?;
+
+    let original = tiff
+        .find(ifd0, TAG_EXIF_IFD)
+        .and_then(|pointer| tiff.u32_at(pointer.value_pos))
+        .and_then(|exif_ifd| tiff.find(exif_ifd as usize, TAG_DATE_TIME_ORIGINAL))
+        .and_then(|entry| tiff.ascii(&entry));
+    let date = original.or_else(|| tiff.find(ifd0, TAG_DATE_TIME).and_then(|entry| tiff.ascii(&entry)))?;
+
+    format_exif_date(date)
+}
+
+/// Exif の日時 `YYYY:MM:DD HH:MM:SS` をファイル名向けの `YYYYMMDD-HHMMSS` にする
+fn format_exif_date(date: &str) -> Option<String> {
+    let (day, time) = date.trim().split_once(' ')?;
+    let day: String = day.split(':').collect();
+    let time: String = time.split(':').collect();
+    if day.len() != 8 || time.len() != 6 || !day.chars().chain(time.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // 日時が不明な場合は 0000:00:00 00:00:00 が入っていることがある
+    if day == "00000000" {
+        return None;
+    }
+
+    Some(format!("{day}-{time}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IFD を1つ持つリトルエンディアンの Exif ペイロードを組み立てる。
+    /// `entries` は (タグ, 型, 個数, 4バイトの値) 。値が4バイトを超えるものは `extra` に置き、オフセットで指す。
+    fn build_app1(entries: &[(u16, u16, u32, [u8; 4])], extra: &[u8]) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(value);
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes()); // 次の IFD はない
+        tiff.extend_from_slice(extra);
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+        app1
+    }
+
+    /// IFD0 のエントリ直後（extra の先頭）のオフセット
+    fn extra_offset(entry_count: usize) -> u32 {
+        (8 + 2 + entry_count * 12 + 4) as u32
+    }
+
+    /// IFD0 の DateTime を読めること
+    #[test]
+    fn reads_date_time() {
+        let offset = extra_offset(1);
+        let app1 = build_app1(&[(TAG_DATE_TIME, TYPE_ASCII, 20, offset.to_le_bytes())], b"2024:01:02 15:30:45\0");
+        assert_eq!(capture_date(&app1), Some("20240102-153045".to_string()));
+    }
+
+    /// 撮影日時（Exif IFD）を変更日時より優先すること
+    #[test]
+    fn prefers_date_time_original() {
+        let offset = extra_offset(2);
+        let exif_ifd = offset + 20;
+        let mut extra = b"2024:01:02 15:30:45\0".to_vec();
+        extra.extend_from_slice(&1u16.to_le_bytes());
+        extra.extend_from_slice(&TAG_DATE_TIME_ORIGINAL.to_le_bytes());
+        extra.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        extra.extend_from_slice(&20u32.to_le_bytes());
+        extra.extend_from_slice(&(exif_ifd + 2 + 12 + 4).to_le_bytes());
+        extra.extend_from_slice(&0u32.to_le_bytes());
+        extra.extend_from_slice(b"2023:12:31 23:59:58\0");
+
+        let app1 = build_app1(
+            &[
+                (TAG_DATE_TIME, TYPE_ASCII, 20, offset.to_le_bytes()),
+                (TAG_EXIF_IFD, 4, 1, exif_ifd.to_le_bytes()),
+            ],
+            &extra,
+        );
+        assert_eq!(capture_date(&app1), Some("20231231-235958".to_string()));
+    }
+
+    /// 壊れたデータ・日時のないデータではパニックせず `None` を返すこと
+    #[test]
+    fn broken_exif_yields_none() {
+        assert_eq!(capture_date(b""), None);
+        assert_eq!(capture_date(b"Exif\0\0II*\0\xFF\xFF\xFF\xFF"), None);
+        let app1 = build_app1(&[(TAG_DATE_TIME, TYPE_ASCII, 20, 0xFFFFu32.to_le_bytes())], b"");
+        assert_eq!(capture_date(&app1), None);
+    }
+
+    /// Orientation を読み、1 に書き換えられること
+    #[test]
+    fn resets_orientation() {
+        let mut app1 = build_app1(&[(TAG_ORIENTATION, TYPE_SHORT, 1, [6, 0, 0, 0])], b"");
+        assert_eq!(orientation(&app1), Some(6));
+        reset_orientation(&mut app1);
+        assert_eq!(orientation(&app1), Some(1));
+
+        let mut broken = build_app1(&[(TAG_ORIENTATION, TYPE_SHORT, 1, [9, 0, 0, 0])], b"");
+        assert_eq!(orientation(&broken), None);
+        reset_orientation(&mut broken);
+    }
+
+    /// 位置情報だけを消し、長さと他のタグ（向き・日時）はそのまま残すこと
+    #[test]
+    fn strips_only_gps() {
+        let offset = extra_offset(3);
+        let gps_ifd = offset + 20;
+        let mut extra = b"2024:01:02 15:30:45\0".to_vec();
+        extra.extend_from_slice(&1u16.to_le_bytes());
+        extra.extend_from_slice(&2u16.to_le_bytes()); // GPSLatitude
+        extra.extend_from_slice(&5u16.to_le_bytes()); // RATIONAL
+        extra.extend_from_slice(&3u32.to_le_bytes());
+        extra.extend_from_slice(&(gps_ifd + 2 + 12 + 4).to_le_bytes());
+        extra.extend_from_slice(&0u32.to_le_bytes());
+        extra.extend_from_slice(&[0x35; 24]);
+
+        let mut app1 = build_app1(
+            &[
+                (TAG_ORIENTATION, TYPE_SHORT, 1, [6, 0, 0, 0]),
+                (TAG_GPS_IFD, 4, 1, gps_ifd.to_le_bytes()),
+                (TAG_DATE_TIME, TYPE_ASCII, 20, offset.to_le_bytes()),
+            ],
+            &extra,
+        );
+        let length = app1.len();
+        assert!(strip_gps(&mut app1));
+        assert_eq!(app1.len(), length);
+        assert_eq!(orientation(&app1), Some(6));
+        assert_eq!(capture_date(&app1), Some("20240102-153045".to_string()));
+        let tiff = Tiff::from_app1(&app1).unwrap();
+        assert!(tiff.find(8, TAG_GPS_IFD).is_none());
+        assert!(!app1.windows(24).any(|window| window == [0x35; 24]));
+
+        assert!(!strip_gps(&mut app1));
+        assert!(!strip_gps(&mut b"Exif\0\0II*\0\xFF\xFF\xFF\xFF".to_vec()));
+    }
+
+    /// 不明な日時（すべて0）は使わないこと
+    #[test]
+    fn rejects_unknown_date() {
+        assert_eq!(format_exif_date("0000:00:00 00:00:00"), None);
+        assert_eq!(format_exif_date("2024:01:02"), None);
+    }
+}
@@ -1,25 +1,171 @@
-use std::{collections::HashSet, fs, path::{Component, PathBuf}};
+use std::{collections::{HashMap, HashSet}, fs, path::{Component, Path, PathBuf}};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use crate::ignore::{is_ignored, IgnoreFile};
 
-/// 指定されたディレクトリ内のファイルを再帰的に取得する。
+/// jpg として扱う拡張子の別名
+const JPEG_ALIASES: [&str; 4] = ["jpe", "jfif", "jif", "jfi"];
+/// png として扱う拡張子の別名
+const PNG_ALIASES: [&str; 1] = ["apng"];
+
+/// 指定されたディレクトリ内のファイルを再帰的に取得する。`.compressorignore` に当てはまるパスと、
+/// 中断した実行が残した一時ファイル（[`temp_path`]）は除く。
 /// 出力先が衝突した際にどちらが元の名前を取るかを実行ごとに変えないため、パス順にソートして返す。
-pub fn get_files(dir: &str) -> Vec<PathBuf> {
+pub fn get_files(dir: &Path) -> Vec<PathBuf> {
     let mut files: Vec<PathBuf> = Vec::new();
+    walk(dir, &mut Vec::new(), &mut |path, is_dir| {
+        if !is_dir {
+            files.push(path.to_path_buf());
+        }
+    });
+    files.sort();
+    files
+}
+
+/// 指定されたディレクトリ配下のサブディレクトリを再帰的に取得する（`dir` 自身は含まない）。
+/// `.compressorignore` に当てはまるディレクトリとその中は除く。パス順にソートして返す。
+pub fn get_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    walk(dir, &mut Vec::new(), &mut |path, is_dir| {
+        if is_dir {
+            dirs.push(path.to_path_buf());
+        }
+    });
+    dirs.sort();
+    dirs
+}
+
+/// `dir` の中を再帰的にたどり、除外されないファイルとディレクトリを `visit` に渡す。
+/// `ignores` は外側のディレクトリから積んだ `.compressorignore` で、除外したディレクトリの中には入らない。
+fn walk(dir: &Path, ignores: &mut Vec<(PathBuf, IgnoreFile)>, visit: &mut impl FnMut(&Path, bool)) {
+    let loaded = IgnoreFile::load(dir).map(|file| ignores.push((dir.to_path_buf(), file))).is_some();
     if let Ok(entries) = fs::read_dir(dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let path: std::path::PathBuf = entry.path();
-                if path.is_file() {
-                    files.push(path);
-                } else if path.is_dir() {
-                    for filepath in get_files(path.to_str().unwrap()) {
-                        files.push(filepath);
-                    }
-                }
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_dir = path.is_dir();
+            if !is_dir && (!path.is_file() || is_temp_path(&path)) {
+                continue;
+            }
+            let stack: Vec<(&Path, &IgnoreFile)> = ignores.iter().map(|(base, file)| (base.as_path(), file)).collect();
+            if is_ignored(&stack, &path, is_dir) {
+                continue;
+            }
+            visit(&path, is_dir);
+            if is_dir {
+                walk(&path, ignores, visit);
             }
         }
     }
-    files.sort();
-    files
+    if loaded {
+        ignores.pop();
+    }
+}
+
+/// ファイルをサイズ順に並べ替える。同じサイズ同士は元の順序を保つ。
+pub fn sort_by_size(files: &mut [PathBuf], descending: bool) {
+    files.sort_by_cached_key(|path| {
+        let size = file_size(path);
+        // 降順でも同じサイズ同士の順序を保つため、結果を逆順にせずキー自体を反転する
+        if descending { u64::MAX - size } else { size }
+    });
+}
+
+/// ファイルを更新日時の新しい順（`newest` が false なら古い順）に並べ、先頭の `count` 件だけを残す。
+/// 同じ日時同士は元の順序を保つ。更新日時を読めないファイルは最も古いものとして扱う。
+pub fn keep_by_modified(files: &mut Vec<PathBuf>, count: usize, newest: bool) {
+    files.sort_by_cached_key(|path| {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let since_epoch = modified.and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok()).unwrap_or_default();
+        // sort_by_size と同じく、同じ日時同士の順序を保つため結果ではなくキーを反転する
+        if newest { Duration::MAX - since_epoch } else { since_epoch }
+    });
+    files.truncate(count);
+}
+
+/// 同じディレクトリにある同名（拡張子違い）の JPEG を探す。Exif の参照元として使う。
+pub fn find_jpeg_sibling(path: &Path) -> Option<PathBuf> {
+    ["jpg", "jpeg", "JPG", "JPEG"]
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|candidate| candidate != path && candidate.is_file())
+}
+
+/// ディレクトリを親も含めて作成する。
+/// 複数のワーカーが同じ出力先を同時に作成しても失敗しないよう、既に存在する場合は成功とみなす。
+pub fn ensure_dir(dir: &Path) -> std::io::Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    match fs::create_dir_all(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => Ok(()),
+        result => result,
+    }
+}
+
+/// `path` の親ディレクトリを [`ensure_dir`] で作成する
+pub fn ensure_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => ensure_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// `path` を `backup_dir` の下へ、`root` からの相対パスを保ってコピーする。コピー先を返す。
+/// 前の実行で退避したファイルを上書きしないよう、既にあれば番号を付けた名前にする。
+/// 例: photos/a.jpg → backup/photos/a.jpg、2回目は backup/photos/a.jpg.1
+pub fn backup(path: &Path, root: &Path, backup_dir: &Path) -> std::io::Result<PathBuf> {
+    let first = backup_dir.join(get_relative_path(root, path));
+    ensure_parent_dir(&first)?;
+    let mut destination = first.clone();
+    for index in 1.. {
+        if fs::symlink_metadata(&destination).is_err() {
+            break;
+        }
+        let mut name = first.clone().into_os_string();
+        name.push(format!(".{index}"));
+        destination = PathBuf::from(name);
+    }
+    fs::copy(path, &destination)?;
+    Ok(destination)
+}
+
+/// 一時ファイルを置く作業ディレクトリのパス（作成はしない）。並列に実行しても同じ名前にならないよう番号を付ける。
+/// `temp_dir` の下に作り、`None` ならシステムの一時ディレクトリの下に作る。
+/// 例: work_dir(None, "pdf") → /tmp/compressor-pdf-1234-0
+pub fn work_dir(temp_dir: Option<&Path>, label: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let index = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let base = temp_dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    base.join(format!("compressor-{label}-{}-{index}", std::process::id()))
+}
+
+/// ディレクトリを作成し、実際にファイルを書けることを確かめる（--temp-dir）
+pub fn ensure_writable_dir(dir: &Path) -> std::io::Result<()> {
+    ensure_dir(dir)?;
+    let probe = dir.join(format!(".compressor-write-test-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+/// [`temp_path`] が一時ファイルの名前に入れる目印
+const TEMP_INFIX: &str = ".compressor-tmp";
+
+/// `path` と同じディレクトリに置く一時ファイルのパス。ffmpeg が拡張子で形式を判断するため拡張子は残す。
+/// 例: photo.jpg → .photo.compressor-tmp.jpg
+pub fn temp_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!(".{stem}{TEMP_INFIX}.{}", ext.to_string_lossy()),
+        None => format!(".{stem}{TEMP_INFIX}"),
+    };
+    path.with_file_name(name)
+}
+
+/// [`temp_path`] で作った一時ファイルの名前か。強制終了などで残ったものを入力として拾わないために使う
+fn is_temp_path(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') && (name.ends_with(TEMP_INFIX) || name.contains(&format!("{TEMP_INFIX}.")))
 }
 
 /// 絶対パスを取得する。
@@ -28,10 +174,91 @@ pub fn get_absolute_path(dir: &PathBuf) -> std::io::Result<PathBuf> {
     fs::canonicalize(dir)
 }
 
+/// 元の大文字小文字を保ったまま拡張子を取り出す。拡張子がない場合は空文字列。
+pub fn extension_of(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// 出力を番号付きのサブディレクトリ（`000`, `001`, ...）に分ける割り当てを返す（--max-files-per-dir）。
+///
+/// 同じディレクトリに `max` 件を超えるファイルがある場合だけ、そのディレクトリのファイルを並び順に `max` 件ずつ割り当てる。
+/// それ以外のファイルは含めない（分けずにそのまま出力する）。
+pub fn shard_names(files: &[PathBuf], max: usize) -> HashMap<PathBuf, String> {
+    let mut counts: HashMap<&Path, usize> = HashMap::new();
+    for file in files {
+        *counts.entry(file.parent().unwrap_or(Path::new(""))).or_default() += 1;
+    }
+
+    let mut positions: HashMap<&Path, usize> = HashMap::new();
+    files
+        .iter()
+        .filter_map(|file| {
+            let dir = file.parent().unwrap_or(Path::new(""));
+            if counts[dir] <= max {
+                return None;
+            }
+            let position = positions.entry(dir).or_default();
+            let shard = format!("{:03}", *position / max.max(1));
+            *position += 1;
+            Some((file.clone(), shard))
+        })
+        .collect()
+}
+
+/// 拡張子の直前に `suffix` を挟んだパスを返す。例: photo.jpg, ".min" → photo.min.jpg
+pub fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}{suffix}"),
+    };
+    path.with_file_name(name)
+}
+
+/// [`suffixed_path`] で作られた出力かどうか（再実行時に出力を再び圧縮しないため）
+pub fn has_suffix(path: &Path, suffix: &str) -> bool {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().ends_with(suffix))
+        .unwrap_or(false)
+}
+
+/// ファイルサイズ（バイト）を取得する。取得できない場合は 0 とみなす。
+pub fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// 出力の大きさ（バイト）。ディレクトリ（--extract-frames の出力）は直下のファイルの合計。
+pub fn output_size(path: &Path) -> u64 {
+    if !path.is_dir() {
+        return file_size(path);
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.filter_map(Result::ok).map(|entry| file_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// 出力先のテンプレートを展開する。`{type}` は種類（images, videos, audio, others）、
+/// `{ext}` は出力の拡張子（jpg, webp, mp4 など）に置き換える。
+/// 例: `compressed/{type}` → `compressed/images`
+pub fn expand_output_dir(template: &str, kind: &str, ext: &str) -> PathBuf {
+    PathBuf::from(template.replace("{type}", kind).replace("{ext}", ext))
+}
+
+/// 出力先テンプレートのうち、展開を含まない先頭部分（すべての出力の共通の親）。
+/// 例: `compressed/{type}` → `compressed`。テンプレートを含まない場合はそのまま返す。
+pub fn output_dir_root(template: &str) -> PathBuf {
+    Path::new(template)
+        .components()
+        .take_while(|component| !component.as_os_str().to_string_lossy().contains('{'))
+        .collect()
+}
+
 /// `to` を `from` 起点の相対パスにする。
 /// 接頭辞が一致しない場合（`-i` で `./` なしや絶対パスを渡した場合）でも
 /// panic せず、ルート・`.`・`..` を取り除いて output_dir 配下に収まる相対パスを返す。
-pub fn get_relative_path(from: &PathBuf, to: &PathBuf) -> PathBuf {
+pub fn get_relative_path(from: &Path, to: &Path) -> PathBuf {
     if let Ok(stripped) = to.strip_prefix(from) {
         return stripped.to_path_buf();
     }
@@ -50,26 +277,133 @@ pub fn get_relative_path(from: &PathBuf, to: &PathBuf) -> PathBuf {
 /// 衝突（無言スキップ・上書き）を防ぐ。
 /// 例: photo.jpg と photo.png → photo.webp, photo.png.webp
 /// 例: song.m4a と song.mp3 → song.m4a, song.mp3.m4a
-pub fn unique_target(base: &PathBuf, ext: &str, used: &mut HashSet<PathBuf>) -> PathBuf {
-    let mut clean = base.clone();
-    clean.set_extension(ext);
+pub fn unique_target(base: &Path, ext: &str, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let clean = base.with_extension(ext);
     if used.insert(clean.clone()) {
         return clean;
     }
 
     // 元の拡張子を残した候補。それも埋まっている場合は連番を付ける。
-    let name = base.file_name().unwrap().to_string_lossy().into_owned();
-    let mut candidate = base.with_file_name(format!("{name}.{ext}"));
+    let name = base.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let suffix = if ext.is_empty() { String::new() } else { format!(".{ext}") };
+    let mut candidate = base.with_file_name(format!("{name}{suffix}"));
+    let mut counter = 1;
+    while !used.insert(candidate.clone()) {
+        counter += 1;
+        candidate = base.with_file_name(format!("{name}-{counter}{suffix}"));
+    }
+    candidate
+}
+
+/// ファイル名を `stem` に付け替えた出力先のパスを決める（--rename-scheme 用）。
+/// 付け替えた名前同士は元の拡張子で区別できないため、衝突したら連番を付ける。
+/// 例: 0001.jpg, 0001-2.jpg
+pub fn unique_named_target(dir: &Path, stem: &str, ext: &str, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let mut candidate = dir.join(format!("{stem}.{ext}"));
     let mut counter = 1;
     while !used.insert(candidate.clone()) {
         counter += 1;
-        candidate = base.with_file_name(format!("{name}-{counter}.{ext}"));
+        candidate = dir.join(format!("{stem}-{counter}.{ext}"));
     }
     candidate
 }
 
+/// 2つの拡張子が同じ形式を指すか（jpeg と jpg は同じとみなす）
+pub fn is_same_format(a: &str, b: &str) -> bool {
+    let normalize = |ext: &str| {
+        let ext = ext.to_lowercase();
+        if ext == "jpeg" { "jpg".to_string() } else { ext }
+    };
+    normalize(a) == normalize(b)
+}
+
+/// 拡張子の読み替え（--ext-map）。`(元の拡張子, 扱う拡張子)` の組で、どちらも小文字・先頭の `.` なし
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionMap(Vec<(String, String)>);
+
+impl ExtensionMap {
+    /// 処理を選ぶための拡張子を返す。指定した読み替えを先に、次に jpg/png の別名を見る。
+    pub fn resolve(&self, ext: &str) -> String {
+        let ext = ext.to_lowercase();
+        if let Some((_, to)) = self.0.iter().find(|(from, _)| *from == ext) {
+            return to.clone();
+        }
+        if JPEG_ALIASES.contains(&ext.as_str()) {
+            "jpg".to_string()
+        } else if PNG_ALIASES.contains(&ext.as_str()) {
+            "png".to_string()
+        } else {
+            ext
+        }
+    }
+}
+
+/// --ext-map の値を読む（例: `jpe=jpg,qt=mov`）。扱う拡張子は対応している形式に限る。
+pub fn parse_extension_map(value: &str) -> Result<ExtensionMap, String> {
+    let normalize = |ext: &str| ext.trim().trim_start_matches('.').to_lowercase();
+    value
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (from, to) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("元の拡張子=扱う拡張子 の形式で指定してください: {pair}"))?;
+            let (from, to) = (normalize(from), normalize(to));
+            if from.is_empty() || to.is_empty() {
+                return Err(format!("元の拡張子=扱う拡張子 の形式で指定してください: {pair}"));
+            }
+            if !is_supported_extension(&to) {
+                return Err(format!("対応していない形式の拡張子です: {to}"));
+            }
+            Ok((from, to))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(ExtensionMap)
+}
+
+/// 処理を選べる拡張子か（画像・動画・音声と、`pdf` フィーチャーが有効なら PDF、`avif` フィーチャーが有効なら AVIF）
+fn is_supported_extension(ext: &str) -> bool {
+    matches!(ext, "png" | "jpg" | "jpeg" | "gif" | "webp" | "tif" | "tiff")
+        || (cfg!(feature = "pdf") && ext == "pdf")
+        || (cfg!(feature = "avif") && ext == "avif")
+        || crate::video::is_video_extension(ext)
+        || crate::audio::is_audio_extension(ext)
+}
+
+/// 圧縮結果 `target` を捨て、元のファイル `source` をそのまま出力する。書き出したパスを返す。
+/// 形式が変わる出力（png → webp など）では、元の拡張子のまま近い名前に置く。
+pub fn replace_with_original(source: &Path, target: &Path, used: &mut HashSet<PathBuf>) -> std::io::Result<PathBuf> {
+    let source_ext = extension_of(source);
+    if is_same_format(&source_ext, &extension_of(target)) {
+        fs::copy(source, target)?;
+        return Ok(target.to_path_buf());
+    }
+
+    fs::remove_file(target)?;
+    // 衝突回避で元の拡張子を残した出力（photo.png.webp）は、その名前（photo.png）に戻す
+    let without_ext = target.with_extension("");
+    let base = if is_same_format(&extension_of(&without_ext), &source_ext) { &without_ext } else { target };
+    let kept = unique_target(base, &source_ext, used);
+    fs::copy(source, &kept)?;
+    Ok(kept)
+}
+
+/// `source` のパーミッション（Unix のモード）を `target` に写す。Unix 以外では何もしない。
+pub fn copy_permissions(source: &Path, target: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(source)?.permissions().mode();
+        fs::set_permissions(target, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (source, target);
+    Ok(())
+}
+
 /// `--webp` 出力先のパスを決める。[`unique_target`] の webp 固定版。
-pub fn webp_target(base: &PathBuf, used: &mut HashSet<PathBuf>) -> PathBuf {
+#[allow(dead_code)]
+pub fn webp_target(base: &Path, used: &mut HashSet<PathBuf>) -> PathBuf {
     unique_target(base, "webp", used)
 }
 
@@ -77,6 +411,23 @@ pub fn webp_target(base: &PathBuf, used: &mut HashSet<PathBuf>) -> PathBuf {
 mod tests {
     use super::*;
 
+    /// 種類と拡張子を展開し、テンプレートでなければそのまま使うこと
+    #[test]
+    fn expands_output_dir_template() {
+        assert_eq!(expand_output_dir("compressed/{type}", "images", "jpg"), PathBuf::from("compressed/images"));
+        assert_eq!(expand_output_dir("out/{type}/{ext}", "videos", "mp4"), PathBuf::from("out/videos/mp4"));
+        assert_eq!(expand_output_dir("compress", "audio", "flac"), PathBuf::from("compress"));
+    }
+
+    /// 展開を含む要素より前だけを共通の親にすること
+    #[test]
+    fn finds_output_dir_root() {
+        assert_eq!(output_dir_root("compressed/{type}"), PathBuf::from("compressed"));
+        assert_eq!(output_dir_root("a/b/{ext}/c"), PathBuf::from("a/b"));
+        assert_eq!(output_dir_root("compress"), PathBuf::from("compress"));
+        assert_eq!(output_dir_root("{type}"), PathBuf::new());
+    }
+
     /// 走査結果が順序不定だと、衝突時にどちらが元の名前を取るか実行ごとに変わってしまう
     #[test]
     fn get_files_returns_sorted_paths() {
@@ -88,7 +439,7 @@ mod tests {
         }
         fs::write(dir.join("sub").join("b.png"), b"x").unwrap();
 
-        let files = get_files(dir.to_str().unwrap());
+        let files = get_files(&dir);
         let mut sorted = files.clone();
         sorted.sort();
         assert_eq!(files, sorted, "get_files がソートされていない");
@@ -97,6 +448,258 @@ mod tests {
         let _ = fs::remove_dir_all(&dir);
     }
 
+    /// .compressorignore に当てはまるパスを除き、サブディレクトリの一覧はその中にだけ効くこと
+    #[test]
+    fn get_files_skips_ignored_paths() {
+        let dir = std::env::temp_dir().join("compressor_ignore_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("raw")).unwrap();
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join(".compressorignore"), "raw/\n*.psd\n").unwrap();
+        fs::write(dir.join("assets/.compressorignore"), "*.png\n!logo.png\n").unwrap();
+        for name in ["a.jpg", "b.psd", "raw/c.jpg", "assets/d.png", "assets/logo.png", "assets/e.psd"] {
+            fs::write(dir.join(name), b"x").unwrap();
+        }
+
+        assert_eq!(
+            get_files(&dir),
+            [dir.join(".compressorignore"), dir.join("a.jpg"), dir.join("assets/.compressorignore"), dir.join("assets/logo.png")]
+        );
+        assert_eq!(get_dirs(&dir), [dir.join("assets")]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 空のものも含めてサブディレクトリだけを列挙すること
+    #[test]
+    fn get_dirs_lists_nested_directories() {
+        let dir = std::env::temp_dir().join("compressor_get_dirs_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a/empty")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a/photo.jpg"), b"x").unwrap();
+
+        assert_eq!(get_dirs(&dir), [dir.join("a"), dir.join("a/empty"), dir.join("b")]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 上限を超えるディレクトリのファイルだけを、並び順に上限の件数ずつ番号付きのサブディレクトリに割り当てること
+    #[test]
+    fn shards_only_crowded_dirs() {
+        let files: Vec<PathBuf> = ["a/1.jpg", "a/2.jpg", "a/3.jpg", "a/4.jpg", "a/5.jpg", "b/1.jpg", "b/2.jpg"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let shards = shard_names(&files, 2);
+        assert_eq!(shards.len(), 5);
+        assert_eq!(shards[Path::new("a/1.jpg")], "000");
+        assert_eq!(shards[Path::new("a/2.jpg")], "000");
+        assert_eq!(shards[Path::new("a/3.jpg")], "001");
+        assert_eq!(shards[Path::new("a/5.jpg")], "002");
+        assert!(!shards.contains_key(Path::new("b/1.jpg")));
+    }
+
+    /// 一時ファイルは同じディレクトリに置き、拡張子を残すこと
+    #[test]
+    fn temp_path_keeps_extension() {
+        assert_eq!(work_dir(Some(Path::new("scratch")), "pdf").parent(), Some(Path::new("scratch")));
+        assert_eq!(temp_path(Path::new("dir/clip.mp4")), PathBuf::from("dir/.clip.compressor-tmp.mp4"));
+        assert_eq!(temp_path(Path::new("README")), PathBuf::from(".README.compressor-tmp"));
+    }
+
+    /// 前の実行が残した一時ファイルは入力として拾わないこと
+    #[test]
+    fn get_files_skips_leftover_temp_files() {
+        let dir = std::env::temp_dir().join("compressor_leftover_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["clip.mp4", "README"] {
+            fs::write(dir.join(name), b"x").unwrap();
+            fs::write(temp_path(&dir.join(name)), b"x").unwrap();
+        }
+        fs::write(dir.join("notes.compressor-tmp.txt"), b"x").unwrap();
+
+        assert_eq!(get_files(&dir), [dir.join("README"), dir.join("clip.mp4"), dir.join("notes.compressor-tmp.txt")]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 相対パスの構成を保ってバックアップすること
+    #[test]
+    fn backs_up_with_relative_structure() {
+        let dir = std::env::temp_dir().join("compressor_backup_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("photos")).unwrap();
+        fs::write(dir.join("photos/a.jpg"), b"original").unwrap();
+
+        let copied = backup(&dir.join("photos/a.jpg"), &dir, &dir.join("backup")).unwrap();
+        assert_eq!(copied, dir.join("backup/photos/a.jpg"));
+        assert_eq!(fs::read(&copied).unwrap(), b"original");
+
+        // 既に退避したファイルは残し、番号を付けて退避する
+        fs::write(dir.join("photos/a.jpg"), b"second").unwrap();
+        let copied = backup(&dir.join("photos/a.jpg"), &dir, &dir.join("backup")).unwrap();
+        assert_eq!(copied, dir.join("backup/photos/a.jpg.1"));
+        assert_eq!(fs::read(&copied).unwrap(), b"second");
+        assert_eq!(fs::read(dir.join("backup/photos/a.jpg")).unwrap(), b"original");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 入力のモードをそのまま出力に写すこと
+    #[cfg(unix)]
+    #[test]
+    fn copies_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("compressor_permissions_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source, target) = (dir.join("source.sh"), dir.join("target.sh"));
+        fs::write(&source, b"x").unwrap();
+        fs::write(&target, b"y").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o750)).unwrap();
+
+        copy_permissions(&source, &target).unwrap();
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o7777, 0o750);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// UTF-8 として読めない名前のディレクトリも panic せずに走査すること
+    #[cfg(unix)]
+    #[test]
+    fn get_files_handles_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::env::temp_dir().join("compressor_non_utf8_test");
+        let _ = fs::remove_dir_all(&dir);
+        let sub = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(sub.join(std::ffi::OsStr::from_bytes(b"\xff.png")), b"x").unwrap();
+
+        let files = get_files(&dir);
+        assert_eq!(files.len(), 1);
+        assert_eq!(extension_of(&files[0]), "png");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// サイズ順に並び、同じサイズはパス順のままであること
+    #[test]
+    fn sorts_by_size() {
+        let dir = std::env::temp_dir().join("compressor_sort_by_size_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, size) in [("a.png", 3), ("b.png", 1), ("c.png", 3), ("d.png", 2)] {
+            fs::write(dir.join(name), vec![0u8; size]).unwrap();
+        }
+
+        let names = |files: &[PathBuf]| -> Vec<String> {
+            files.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+
+        let mut files = get_files(&dir);
+        sort_by_size(&mut files, true);
+        assert_eq!(names(&files), ["a.png", "c.png", "d.png", "b.png"]);
+
+        let mut files = get_files(&dir);
+        sort_by_size(&mut files, false);
+        assert_eq!(names(&files), ["b.png", "d.png", "a.png", "c.png"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 更新日時の新しい順・古い順に並べて指定した件数だけ残し、同じ日時はパス順のままであること
+    #[test]
+    fn keeps_newest_and_oldest() {
+        let dir = std::env::temp_dir().join("compressor_keep_by_modified_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, seconds) in [("a.png", 300), ("b.png", 100), ("c.png", 300), ("d.png", 200)] {
+            let path = dir.join(name);
+            fs::write(&path, b"x").unwrap();
+            fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+        }
+
+        let names = |files: &[PathBuf]| -> Vec<String> {
+            files.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+
+        let mut files = get_files(&dir);
+        keep_by_modified(&mut files, 3, true);
+        assert_eq!(names(&files), ["a.png", "c.png", "d.png"]);
+
+        let mut files = get_files(&dir);
+        keep_by_modified(&mut files, 2, false);
+        assert_eq!(names(&files), ["b.png", "d.png"]);
+
+        let mut files = get_files(&dir);
+        keep_by_modified(&mut files, 10, true);
+        assert_eq!(files.len(), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 自分自身は兄弟ファイルとみなさないこと
+    #[test]
+    fn finds_jpeg_sibling() {
+        let dir = std::env::temp_dir().join("compressor_jpeg_sibling_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("photo.png"), b"x").unwrap();
+        fs::write(dir.join("photo.jpeg"), b"x").unwrap();
+        fs::write(dir.join("alone.jpg"), b"x").unwrap();
+
+        assert_eq!(find_jpeg_sibling(&dir.join("photo.png")), Some(dir.join("photo.jpeg")));
+        assert_eq!(find_jpeg_sibling(&dir.join("alone.jpg")), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 拡張子の直前に接尾辞を挟むこと
+    #[test]
+    fn inserts_suffix_before_extension() {
+        assert_eq!(suffixed_path(Path::new("./a/photo.jpg"), ".min"), PathBuf::from("./a/photo.min.jpg"));
+        assert_eq!(suffixed_path(Path::new("README"), ".min"), PathBuf::from("README.min"));
+    }
+
+    /// 出力（接尾辞付き）だけを圧縮済みとみなすこと
+    #[test]
+    fn detects_suffixed_outputs() {
+        assert!(has_suffix(Path::new("photo.min.jpg"), ".min"));
+        assert!(!has_suffix(Path::new("photo.jpg"), ".min"));
+        assert!(!has_suffix(Path::new("photo.min.jpg.bak"), ".min"));
+    }
+
+    /// 同じ入れ子のディレクトリを同時に作成しても、すべて成功すること
+    #[test]
+    fn ensure_dir_is_safe_concurrently() {
+        let dir = std::env::temp_dir().join("compressor_ensure_dir_test");
+        let _ = fs::remove_dir_all(&dir);
+        let nested = dir.join("a").join("b").join("c");
+
+        let results: Vec<std::io::Result<()>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| ensure_dir(&nested))).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(results.iter().all(|r| r.is_ok()), "{results:?}");
+        assert!(nested.is_dir());
+        assert!(ensure_parent_dir(Path::new("relative.png")).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 既存のファイルと同名のディレクトリは作成できずにエラーになること
+    #[test]
+    fn ensure_dir_fails_on_file() {
+        let path = std::env::temp_dir().join("compressor_ensure_dir_file_test");
+        fs::write(&path, b"x").unwrap();
+        assert!(ensure_dir(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
     /// 衝突がなければ拡張子を置き換えるだけ
     #[test]
     fn replaces_extension() {
@@ -138,6 +741,75 @@ mod tests {
         assert_eq!(third, PathBuf::from("compress/song.mp3-2.m4a"));
     }
 
+    /// 拡張子のないファイル（--on-unsupported copy）でも末尾に `.` を付けず連番で回避する
+    #[test]
+    fn empty_extension_keeps_name() {
+        let mut used = HashSet::new();
+        let first = unique_target(&PathBuf::from("compress/LICENSE"), "", &mut used);
+        let second = unique_target(&PathBuf::from("compress/LICENSE"), "", &mut used);
+        assert_eq!(first, PathBuf::from("compress/LICENSE"));
+        assert_eq!(second, PathBuf::from("compress/LICENSE-2"));
+    }
+
+    /// 名前を付け替えた出力同士の衝突は連番で回避し、名前中の `.` は拡張子とみなさないこと
+    #[test]
+    fn named_target_uses_counter() {
+        let mut used = HashSet::new();
+        let dir = PathBuf::from("compress");
+        assert_eq!(unique_named_target(&dir, "20240102-153045", "jpg", &mut used), dir.join("20240102-153045.jpg"));
+        assert_eq!(unique_named_target(&dir, "20240102-153045", "jpg", &mut used), dir.join("20240102-153045-2.jpg"));
+        assert_eq!(unique_named_target(&dir, "photo.v2", "jpg", &mut used), dir.join("photo.v2.jpg"));
+    }
+
+    /// jpeg と jpg は同じ形式として扱うこと
+    #[test]
+    fn same_format_treats_jpeg_as_jpg() {
+        assert!(is_same_format("JPEG", "jpg"));
+        assert!(is_same_format("png", "PNG"));
+        assert!(!is_same_format("png", "webp"));
+    }
+
+    /// 指定した読み替えを優先し、jpg/png の別名は指定がなくても読み替えること
+    #[test]
+    fn resolves_extension_aliases() {
+        let map = parse_extension_map(" .JPE=png, qt=MOV ").unwrap();
+        assert_eq!(map.resolve("jpe"), "png");
+        assert_eq!(map.resolve("QT"), "mov");
+        assert_eq!(map.resolve("JFIF"), "jpg");
+        assert_eq!(map.resolve("apng"), "png");
+        assert_eq!(map.resolve("txt"), "txt");
+        assert_eq!(parse_extension_map("").unwrap(), ExtensionMap::default());
+
+        assert!(parse_extension_map("qt").is_err());
+        assert!(parse_extension_map("=mov").is_err());
+        assert!(parse_extension_map("foo=txt").is_err());
+    }
+
+    /// 形式が変わる出力は削除し、元の拡張子で元データを置くこと
+    #[test]
+    fn replaces_converted_output_with_original() {
+        let dir = std::env::temp_dir().join("compressor_replace_original_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("photo.png");
+        let target = dir.join("out").with_extension("webp");
+        fs::write(&source, b"original").unwrap();
+        fs::write(&target, b"converted").unwrap();
+
+        let mut used = HashSet::from([target.clone()]);
+        let kept = replace_with_original(&source, &target, &mut used).unwrap();
+        assert_eq!(kept, dir.join("out.png"));
+        assert_eq!(fs::read(&kept).unwrap(), b"original");
+        assert!(!target.exists());
+
+        let collided = dir.join("photo.png.webp");
+        fs::write(&collided, b"converted").unwrap();
+        let kept = replace_with_original(&source, &collided, &mut used).unwrap();
+        assert_eq!(kept, dir.join("photo.png"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     /// webp_target の委譲後も従来の例（photo.jpg と photo.png）どおりに動くこと
     #[test]
     fn webp_target_keeps_previous_behavior() {
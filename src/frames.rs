@@ -0,0 +1,116 @@
+//! 動画を圧縮する代わりに、フレームを連番の JPEG として書き出す（`--extract-frames`）。
+//!
+//! 機械学習のデータセット作りなどで、動画の一定間隔ごとのフレームを画像として使うためのもの。
+//! ffmpeg で取り出したフレームを、jpg の圧縮と同じ `rgb_image::path2compress` で1枚ずつ JPEG にする。
+
+use std::fmt;
+use std::path::Path;
+use crate::error::CompressError;
+use crate::file::work_dir;
+use crate::rgb_image;
+use crate::utilities::{FfmpegLogLevel, ImageOptions};
+use crate::video;
+
+/// 取り出すフレームの選び方
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameSelection {
+    /// 1秒あたりの枚数（例: `2fps`、`0.5fps`）
+    Rate(f64),
+    /// 動画全体から等間隔に取る枚数（例: `100`）
+    Count(u32),
+}
+
+impl fmt::Display for FrameSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameSelection::Rate(fps) => write!(f, "{fps}fps"),
+            FrameSelection::Count(count) => write!(f, "{count}"),
+        }
+    }
+}
+
+/// --extract-frames の値を読む。`fps` で終われば1秒あたりの枚数（0 より大きい）、整数なら全体の枚数（1 以上）。
+pub fn parse_selection(value: &str) -> Result<FrameSelection, String> {
+    if let Some(fps) = value.strip_suffix("fps") {
+        return match fps.parse::<f64>() {
+            Ok(fps) if fps.is_finite() && fps > 0.0 => Ok(FrameSelection::Rate(fps)),
+            _ => Err(format!("fps の前には 0 より大きい数値を指定してください: {value}")),
+        };
+    }
+    match value.parse::<u32>() {
+        Ok(count) if count > 0 => Ok(FrameSelection::Count(count)),
+        _ => Err(format!("1 以上の枚数か、2fps のような1秒あたりの枚数で指定してください: {value}")),
+    }
+}
+
+/// `index` 番目（1 から）のフレームのファイル名。例: clip の 1 枚目 → clip-000001.jpg
+pub fn frame_name(stem: &str, index: usize) -> String {
+    format!("{stem}-{index:06}.jpg")
+}
+
+/// `video` のフレームを `selection` に従って取り出し、`output_dir` に連番の JPEG で書き出す。書き出した枚数を返す。
+///
+/// フレームは `temp_dir`（`None` なら一時ディレクトリ）の下に PNG で取り出し、終わったら消す。
+/// 前の実行で書き出した連番のうち、今回の枚数を超えるものは消す（枚数を減らしてやり直した場合に古いフレームが混ざらないよう）。
+pub fn extract(
+    video: &Path,
+    output_dir: &Path,
+    selection: FrameSelection,
+    options: &ImageOptions,
+    temp_dir: Option<&Path>,
+    loglevel: FfmpegLogLevel,
+) -> Result<usize, CompressError> {
+    let dir = work_dir(temp_dir, "frames");
+    std::fs::create_dir_all(&dir)?;
+    let result = write_frames(video, &dir, output_dir, selection, options, loglevel);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn write_frames(
+    video: &Path,
+    dir: &Path,
+    output_dir: &Path,
+    selection: FrameSelection,
+    options: &ImageOptions,
+    loglevel: FfmpegLogLevel,
+) -> Result<usize, CompressError> {
+    let frames = match selection {
+        FrameSelection::Rate(fps) => video::extract_frames_at_rate(video, fps, dir, loglevel)?,
+        FrameSelection::Count(count) => {
+            video::extract_frames(video, count, dir, loglevel)?.into_iter().map(|(_, frame)| frame).collect()
+        }
+    };
+    if frames.is_empty() {
+        return Err(CompressError::InvalidInput(format!("フレームを取り出せませんでした: {:?}", video)));
+    }
+
+    std::fs::create_dir_all(output_dir)?;
+    let stem = video.file_stem().unwrap_or_default().to_string_lossy();
+    for (index, frame) in frames.iter().enumerate() {
+        rgb_image::path2compress(frame, &output_dir.join(frame_name(&stem, index + 1)), options)?;
+    }
+    let mut stale = frames.len() + 1;
+    while std::fs::remove_file(output_dir.join(frame_name(&stem, stale))).is_ok() {
+        stale += 1;
+    }
+    Ok(frames.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fps で終わる値は1秒あたりの枚数、整数は全体の枚数として読み、0 以下や数値でない値は受け付けないこと
+    #[test]
+    fn parses_selection() {
+        assert_eq!(parse_selection("2fps"), Ok(FrameSelection::Rate(2.0)));
+        assert_eq!(parse_selection("0.5fps"), Ok(FrameSelection::Rate(0.5)));
+        assert_eq!(parse_selection("100"), Ok(FrameSelection::Count(100)));
+        assert!(parse_selection("0").is_err());
+        assert!(parse_selection("0fps").is_err());
+        assert!(parse_selection("fps").is_err());
+        assert!(parse_selection("1.5").is_err());
+        assert_eq!(frame_name("clip", 12), "clip-000012.jpg");
+    }
+}
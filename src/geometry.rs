@@ -0,0 +1,117 @@
+use image::DynamicImage;
+use crate::error::CompressError;
+
+/// 画像の切り抜き範囲
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Crop {
+    /// 左上 (x, y) から幅 × 高さを切り抜く（`--crop WxH+X+Y`）
+    Region { width: u32, height: u32, x: u32, y: u32 },
+    /// 中央から幅 × 高さを切り抜く（`--crop-center WxH`）
+    Center { width: u32, height: u32 },
+}
+
+impl Crop {
+    /// 画像を切り抜く。範囲が画像からはみ出す部分は画像内に収める（ImageMagick と同じ）。
+    pub fn apply(self, img: DynamicImage) -> Result<DynamicImage, CompressError> {
+        let (x, y, width, height) = self.bounds(img.width(), img.height()).ok_or_else(|| {
+            CompressError::InvalidInput(format!("切り抜き範囲が画像（{}x{}）の外にあります", img.width(), img.height()))
+        })?;
+        Ok(img.crop_imm(x, y, width, height))
+    }
+
+    /// 画像の寸法に収めた (x, y, 幅, 高さ)。画像と重ならない場合は `None`。
+    fn bounds(self, image_width: u32, image_height: u32) -> Option<(u32, u32, u32, u32)> {
+        let (x, y, width, height) = match self {
+            Crop::Region { width, height, x, y } => (x, y, width, height),
+            Crop::Center { width, height } => {
+                let width = width.min(image_width);
+                let height = height.min(image_height);
+                ((image_width - width) / 2, (image_height - height) / 2, width, height)
+            }
+        };
+        if x >= image_width || y >= image_height {
+            return None;
+        }
+
+        Some((x, y, width.min(image_width - x), height.min(image_height - y)))
+    }
+}
+
+/// `WxH+X+Y` 形式（オフセットは省略可）を読む（--crop 用）
+pub fn parse_crop(value: &str) -> Result<Crop, String> {
+    let (size, offset) = match value.find('+') {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+    let (width, height) = parse_size(size)?;
+
+    let (x, y) = match offset {
+        "" => (0, 0),
+        offset => {
+            let parts: Vec<&str> = offset[1..].split('+').collect();
+            let [x, y] = parts[..] else {
+                return Err(format!("オフセットは +X+Y の形式で指定してください: {value}"));
+            };
+            let parse = |n: &str| n.parse::<u32>().map_err(|_| format!("オフセットが不正です: {value}"));
+            (parse(x)?, parse(y)?)
+        }
+    };
+
+    Ok(Crop::Region { width, height, x, y })
+}
+
+/// `WxH` 形式を読む（--crop-center 用）
+pub fn parse_crop_center(value: &str) -> Result<Crop, String> {
+    let (width, height) = parse_size(value)?;
+    Ok(Crop::Center { width, height })
+}
+
+/// `WxH` を読む（--only-larger-than にも使う）。幅・高さは 1 以上。
+pub fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("WxH の形式で指定してください: {value}");
+    let (width, height) = value.split_once(['x', 'X']).ok_or_else(invalid)?;
+    let width: u32 = width.parse().map_err(|_| invalid())?;
+    let height: u32 = height.parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err(format!("幅と高さは 1 以上にしてください: {value}"));
+    }
+
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// オフセットの有無と大文字の X を受け付け、不正な値は拒否すること
+    #[test]
+    fn parses_geometry() {
+        assert_eq!(parse_crop("100x50+10+20"), Ok(Crop::Region { width: 100, height: 50, x: 10, y: 20 }));
+        assert_eq!(parse_crop("100X50"), Ok(Crop::Region { width: 100, height: 50, x: 0, y: 0 }));
+        assert_eq!(parse_crop_center("256x256"), Ok(Crop::Center { width: 256, height: 256 }));
+        assert!(parse_crop("100x50+10").is_err());
+        assert!(parse_crop("0x50").is_err());
+        assert!(parse_crop("100x50-10-20").is_err());
+        assert!(parse_crop_center("256").is_err());
+        assert_eq!(parse_size("1920x1080"), Ok((1920, 1080)));
+    }
+
+    /// はみ出す範囲は画像内に収め、重ならない範囲は拒否すること
+    #[test]
+    fn clamps_to_image() {
+        let region = Crop::Region { width: 100, height: 100, x: 50, y: 0 };
+        assert_eq!(region.bounds(120, 80), Some((50, 0, 70, 80)));
+        assert_eq!(Crop::Region { width: 10, height: 10, x: 120, y: 0 }.bounds(120, 80), None);
+    }
+
+    /// 中央の切り抜きは余白を左右（上下）均等に取ること
+    #[test]
+    fn crops_center() {
+        assert_eq!(Crop::Center { width: 80, height: 80 }.bounds(120, 80), Some((20, 0, 80, 80)));
+        assert_eq!(Crop::Center { width: 500, height: 500 }.bounds(120, 80), Some((0, 0, 120, 80)));
+
+        let img = DynamicImage::new_rgb8(120, 80);
+        let cropped = Crop::Center { width: 64, height: 64 }.apply(img).unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (64, 64));
+    }
+}
@@ -0,0 +1,71 @@
+//! 圧縮した出力ごとに実行するコマンド（`--post-hook`）。
+//!
+//! 出力のアップロードなど、圧縮に続ける処理をこのツールに組み込まずにつなぐためのもの。
+//! コマンドはシェル（Windows では cmd）で実行し、`{input}`・`{output}`・`{reduction}` を置き換えてから渡す。
+
+use std::path::Path;
+use std::process::Command;
+
+/// `template` の置き換え文字を埋める。パスはシェルに1つの引数として渡るようクォートする（テンプレートの側ではクォートしない）。
+/// `{reduction}` は削減率（%）を小数第1位までの数値にしたもの。知らない `{...}` はそのまま残す。
+pub fn expand(template: &str, input: &Path, output: &Path, reduction: f64) -> String {
+    template
+        .replace("{input}", &quote(&input.to_string_lossy()))
+        .replace("{output}", &quote(&output.to_string_lossy()))
+        .replace("{reduction}", &format!("{reduction:.1}"))
+}
+
+/// コマンドを実行して終わるまで待つ。出力は端末にそのまま流す。失敗したら理由を返す
+pub fn run(command: &str) -> Result<(), String> {
+    match shell(command).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(status.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(unix)]
+fn quote(arg: &str) -> String {
+    crate::utilities::shell_quote(arg)
+}
+
+#[cfg(not(unix))]
+fn quote(arg: &str) -> String {
+    format!("\"{arg}\"")
+}
+
+// 引用の仕方とコマンドを動かすシェルが Unix のものを前提にしている
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// 置き換え文字を埋め、空白や引用符を含むパスも1つの引数として渡すこと
+    #[test]
+    fn expands_and_runs_hook() {
+        let command = expand("echo {input} {output} {reduction} {size}", Path::new("in/a.png"), Path::new("out/my photo's.jpg"), 42.06);
+        assert_eq!(command, "echo in/a.png 'out/my photo'\\''s.jpg' 42.1 {size}");
+
+        let dir = std::env::temp_dir().join("compressor_hook_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("my photo's.jpg");
+        assert!(run(&expand("touch {output}", Path::new("a.png"), &output, 0.0)).is_ok());
+        assert!(output.is_file());
+        assert!(run("exit 3").unwrap_err().contains('3'));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
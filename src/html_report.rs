@@ -0,0 +1,142 @@
+//! サムネイル付きの HTML レポート（`--html-report`）。
+//!
+//! JSON や CSV を読まない人と結果を共有するためのもの。ファイルごとに元と圧縮後のサムネイル・サイズ・削減率を表に並べる。
+//! サムネイルは webp にして data URI で埋め込み、1つのファイルだけで開けるようにする。
+
+use std::fmt::Write;
+use std::path::Path;
+use base64::Engine;
+use crate::error::CompressError;
+use crate::report::{format_bytes, reduction_percent, FileRecord, Status, Summary};
+
+/// サムネイルの大きさ（縦横ともこの大きさに収める）
+const THUMB_SIZE: u32 = 160;
+/// サムネイルの webp の品質
+const THUMB_QUALITY: f32 = 75.0;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse}\
+th,td{border:1px solid #ccc;padding:4px 8px;vertical-align:middle}\
+th{background:#f4f4f4}\
+td.size,td.percent{text-align:right;white-space:nowrap}\
+td.thumb{width:160px;height:160px;text-align:center;background:#282828;color:#999}\
+img{display:block;margin:auto;max-width:160px;max-height:160px}\
+tr.failed td{background:#fdecea}";
+
+/// HTML レポートを組み立てる。サムネイルは `thumbnail` で作る（作れないファイルは空欄にする）
+pub fn render(summary: &Summary, thumbnail: impl Fn(&Path) -> Option<String>) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html lang=\"ja\">\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(html, "<title>compressor レポート</title>\n<style>{STYLE}</style>\n</head>\n<body>");
+    let _ = writeln!(html, "<h1>compressor レポート</h1>");
+    let _ = writeln!(
+        html,
+        "<p>処理 {} 件、スキップ {} 件、失敗 {} 件。{} → {}（削減 {}、{:.1}%）</p>",
+        summary.processed,
+        summary.skipped,
+        summary.errors,
+        format_bytes(summary.original_bytes as i64),
+        format_bytes(summary.compressed_bytes as i64),
+        format_bytes(summary.bytes_saved()),
+        reduction_percent(summary.original_bytes, summary.compressed_bytes),
+    );
+    let _ = writeln!(html, "<table>\n<tr><th>元</th><th>圧縮後</th><th>ファイル</th><th>状態</th><th>元のサイズ</th><th>圧縮後のサイズ</th><th>削減率</th></tr>");
+    for record in &summary.files {
+        let _ = writeln!(html, "{}", row(record, &thumbnail));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// HTML レポートをファイルに書き出す。サムネイルは画像の入力と出力を読み直して作る
+pub fn write(path: &Path, summary: &Summary) -> std::io::Result<()> {
+    crate::file::ensure_parent_dir(path)?;
+    std::fs::write(path, render(summary, |path| thumbnail(path).ok()))
+}
+
+/// 画像を読み込み、縦横とも [`THUMB_SIZE`] に収まるよう縮小して webp の data URI にする
+pub fn thumbnail(path: &Path) -> Result<String, CompressError> {
+    let thumb = image::open(path)?.thumbnail(THUMB_SIZE, THUMB_SIZE).to_rgba8();
+    let webp = webp::Encoder::from_rgba(&thumb, thumb.width(), thumb.height()).encode(THUMB_QUALITY);
+    Ok(format!("data:image/webp;base64,{}", base64::engine::general_purpose::STANDARD.encode(&*webp)))
+}
+
+fn row(record: &FileRecord, thumbnail: impl Fn(&Path) -> Option<String>) -> String {
+    // 画像以外（動画・音声など）はサムネイルを作らない
+    let images = record.kind == Some("images") && record.status != Status::Failed;
+    let thumb = |path: Option<&Path>| match path.filter(|_| images).and_then(&thumbnail) {
+        Some(uri) => format!("<td class=\"thumb\"><img src=\"{uri}\" alt=\"\"></td>"),
+        None => String::from("<td class=\"thumb\">-</td>"),
+    };
+    let written = matches!(record.status, Status::Compressed | Status::KeptOriginal);
+    let size = |bytes: u64| if written { format_bytes(bytes as i64) } else { String::from("-") };
+    let percent = if written {
+        format!("{:.1}%", reduction_percent(record.original_bytes, record.compressed_bytes))
+    } else {
+        String::from("-")
+    };
+    let mut file = escape(&record.input.to_string_lossy());
+    if let Some(output) = &record.output {
+        let _ = write!(file, "<br>→ {}", escape(&output.to_string_lossy()));
+    }
+    if let Some(error) = &record.error {
+        let _ = write!(file, "<br>{}", escape(error));
+    }
+    format!(
+        "<tr class=\"{}\">{}{}<td>{file}</td><td>{}</td><td class=\"size\">{}</td><td class=\"size\">{}</td><td class=\"percent\">{percent}</td></tr>",
+        record.status.name(),
+        thumb(Some(&record.input)),
+        thumb(record.output.as_deref()),
+        label(record.status),
+        size(record.original_bytes),
+        size(record.compressed_bytes),
+    )
+}
+
+/// 表に載せる状態の名前
+fn label(status: Status) -> &'static str {
+    match status {
+        Status::Compressed => "圧縮",
+        Status::KeptOriginal => "元のまま",
+        Status::Skipped => "スキップ",
+        Status::Failed => "失敗",
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 画像の行にだけ元と圧縮後のサムネイルを埋め込み、パスとエラーをエスケープすること
+    #[test]
+    fn renders_rows_with_thumbnails() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("photo.jpg"), Path::new("out/photo.jpg"), 1000, 250);
+        summary.record_job("images", Some(0.1));
+        summary.record_compressed(Path::new("clip.mp4"), Path::new("out/clip.mp4"), 1000, 500);
+        summary.record_job("videos", Some(1.0));
+        summary.record_failed(Path::new("<b>.png"), None, "broken & bad");
+
+        let html = render(&summary, |path| Some(format!("uri:{}", path.display())));
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<img src=\"uri:photo.jpg\" alt=\"\"></td><td class=\"thumb\"><img src=\"uri:out/photo.jpg\""), "{html}");
+        assert!(html.contains("<td class=\"percent\">75.0%</td>"));
+        assert!(!html.contains("uri:clip.mp4"));
+        assert!(html.contains("<tr class=\"failed\"><td class=\"thumb\">-</td><td class=\"thumb\">-</td><td>&lt;b&gt;.png<br>broken &amp; bad</td><td>失敗</td>"), "{html}");
+
+        let dir = std::env::temp_dir().join("compressor_html_report_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("wide.png");
+        image::RgbaImage::from_pixel(640, 320, image::Rgba([200, 10, 10, 255])).save(&image).unwrap();
+        let uri = thumbnail(&image).unwrap();
+        let webp = base64::engine::general_purpose::STANDARD.decode(uri.strip_prefix("data:image/webp;base64,").unwrap()).unwrap();
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (160, 80));
+        assert!(thumbnail(&dir.join("missing.png")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
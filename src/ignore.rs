@@ -0,0 +1,188 @@
+//! 入力から除くパスの一覧（`.compressorignore`）。
+//!
+//! 書き方は `.gitignore` と同じで、ファイルを置いたディレクトリの下にだけ効く。
+//! 深いディレクトリのファイルの行ほど優先し、同じファイルの中では後の行を優先する。
+//! 除いたディレクトリの中は調べないため、その中のファイルを `!` で戻すことはできない（git と同じ）。
+
+use std::path::Path;
+
+/// ディレクトリごとに置く除外の一覧のファイル名
+pub const FILE_NAME: &str = ".compressorignore";
+
+/// 1行分のパターン
+#[derive(Debug)]
+struct Rule {
+    /// `/` で区切ったパターン。`/` を含まないパターンは先頭に `**` を補い、どの深さの名前にも当てる
+    segments: Vec<String>,
+    /// `!` で始まり、除外を取り消す
+    negated: bool,
+    /// `/` で終わり、ディレクトリにだけ当てる
+    dir_only: bool,
+}
+
+/// 1つの `.compressorignore` の内容
+#[derive(Debug, Default)]
+pub struct IgnoreFile {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreFile {
+    /// `dir` の `.compressorignore` を読む。ないか読めなければ `None`
+    pub fn load(dir: &Path) -> Option<IgnoreFile> {
+        std::fs::read_to_string(dir.join(FILE_NAME)).ok().map(|text| IgnoreFile::parse(&text))
+    }
+
+    fn parse(text: &str) -> IgnoreFile {
+        let rules = text.lines().filter_map(parse_rule).collect();
+        IgnoreFile { rules }
+    }
+
+    /// ファイルを置いたディレクトリからの相対パス（`/` 区切り）に当てはまる最後の行が、除外か取り消しか。当てはまらなければ `None`
+    fn decide(&self, relative: &[&str], is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && matches_segments(&rule.segments, relative))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// 外側から順に積んだ `(置いたディレクトリ, 内容)` の一覧で、`path` を除くか
+pub fn is_ignored(stack: &[(&Path, &IgnoreFile)], path: &Path, is_dir: bool) -> bool {
+    stack
+        .iter()
+        .rev()
+        .find_map(|(base, file)| {
+            let relative = path.strip_prefix(base).ok()?;
+            let relative: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+            let relative: Vec<&str> = relative.iter().map(String::as_str).collect();
+            file.decide(&relative, is_dir)
+        })
+        .unwrap_or(false)
+}
+
+fn parse_rule(line: &str) -> Option<Rule> {
+    // 末尾の空白は無視する
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line.strip_prefix('\\').filter(|rest| rest.starts_with(['#', '!'])).unwrap_or(line)),
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    if line.is_empty() {
+        return None;
+    }
+    // 途中か先頭に `/` があれば、置いたディレクトリからのパスとして扱う
+    let anchored = line.contains('/');
+    let mut segments: Vec<String> = line.trim_start_matches('/').split('/').map(str::to_string).collect();
+    if !anchored {
+        segments.insert(0, "**".to_string());
+    }
+    Some(Rule { segments, negated, dir_only })
+}
+
+/// `/` で区切ったパターンとパスを比べる。`**` の区切りは0個以上の区切りに当たる
+fn matches_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((first, rest)) => {
+            path.split_first().is_some_and(|(name, remaining)| matches_name(first.as_bytes(), name.as_bytes()) && matches_segments(rest, remaining))
+        }
+    }
+}
+
+/// 1つの名前とワイルドカード（`*`, `?`, `[a-z]`, `[!abc]`, `\` のエスケープ）を比べる
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_name(rest, &name[1..]),
+        Some((b'[', rest)) => match (name.split_first(), class_end(rest)) {
+            (Some((&c, remaining)), Some(end)) => in_class(&rest[..end], c) && matches_name(&rest[end + 1..], remaining),
+            (Some((&c, remaining)), None) => c == b'[' && matches_name(rest, remaining),
+            (None, _) => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => name.first() == rest.first() && matches_name(&rest[1..], &name[1..]),
+        Some((&p, rest)) => name.first() == Some(&p) && matches_name(rest, &name[1..]),
+    }
+}
+
+/// `[` の後ろの文字クラスを閉じる `]` の位置。先頭の `]`（否定の後を含む）は文字として扱う
+fn class_end(class: &[u8]) -> Option<usize> {
+    let start = if matches!(class.first(), Some(b'!' | b'^')) { 1 } else { 0 };
+    (start + 1..class.len()).find(|&i| class[i] == b']')
+}
+
+fn in_class(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!' | b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(text: &str, path: &str, is_dir: bool) -> bool {
+        let file = IgnoreFile::parse(text);
+        is_ignored(&[(Path::new("root"), &file)], &Path::new("root").join(path), is_dir)
+    }
+
+    /// 名前だけのパターンはどの深さにも、`/` を含むパターンは置いた場所からのパスに当てること
+    #[test]
+    fn matches_gitignore_patterns() {
+        assert!(ignored("*.tmp\n", "a/b/c.tmp", false));
+        assert!(!ignored("*.tmp\n", "a/b/c.tmp.jpg", false));
+        assert!(ignored("/raw\n", "raw", true));
+        assert!(!ignored("/raw\n", "a/raw", true));
+        assert!(ignored("drafts/*.png\n", "drafts/x.png", false));
+        assert!(!ignored("drafts/*.png\n", "a/drafts/x.png", false));
+        assert!(ignored("**/cache/**\n", "a/cache/x/y.jpg", false));
+        assert!(ignored("a/**/z.jpg\n", "a/z.jpg", false));
+        assert!(ignored("a/**/z.jpg\n", "a/b/c/z.jpg", false));
+        assert!(ignored("photo_[0-9]?.jpg\n", "photo_12.jpg", false));
+        assert!(!ignored("photo_[!0-9]?.jpg\n", "photo_12.jpg", false));
+        assert!(ignored("\\#keep\n", "#keep", false));
+        assert!(!ignored("# comment\n\n", "# comment", false));
+    }
+
+    /// `/` で終わる行はディレクトリにだけ当て、後の `!` の行は前の除外を取り消すこと
+    #[test]
+    fn applies_dir_only_and_negation() {
+        assert!(ignored("build/\n", "build", true));
+        assert!(!ignored("build/\n", "build", false));
+        assert!(!ignored("*.jpg\n!keep.jpg\n", "keep.jpg", false));
+        assert!(ignored("!keep.jpg\n*.jpg\n", "keep.jpg", false));
+    }
+
+    /// 深いディレクトリの一覧を優先し、当てはまらなければ外側の一覧に従うこと
+    #[test]
+    fn nested_files_take_precedence() {
+        let outer = IgnoreFile::parse("*.png\n");
+        let inner = IgnoreFile::parse("!logo.png\n");
+        let stack = [(Path::new("root"), &outer), (Path::new("root/assets"), &inner)];
+        assert!(!is_ignored(&stack, Path::new("root/assets/logo.png"), false));
+        assert!(is_ignored(&stack, Path::new("root/assets/other.png"), false));
+        assert!(is_ignored(&stack, Path::new("root/logo.png"), false));
+    }
+}
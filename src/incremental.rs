@@ -0,0 +1,127 @@
+//! 出力ごとの元と設定の記録（`--incremental`）。
+//!
+//! 出力があるだけで処理を飛ばすと、元を差し替えたり設定を変えたりしても古い出力が残り、
+//! `--force` では変わっていない出力まで作り直すことになる。
+//! 出力ごとに、書き出したときの元の BLAKE3 のチェックサムと設定の指紋を出力先の記録ファイルに残し、
+//! 再実行ではどちらかが変わった出力とない出力だけを作る。形式（`--webp` など）が違う出力は別のファイルのため、別々に記録する。
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 記録ファイルの名前。出力先（--suffix ではカレントディレクトリ）に置く
+pub const FILE_NAME: &str = ".compressor-outputs";
+
+/// 1つの出力を書き出したときの元と設定
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Record {
+    /// 元の BLAKE3 のチェックサム
+    source: String,
+    /// 設定の指紋（[`fingerprint`]）
+    settings: String,
+}
+
+/// 出力ごとの記録。パスは記録ファイルのあるディレクトリからの相対パス
+#[derive(Debug, Default)]
+pub struct Outputs {
+    base: PathBuf,
+    records: BTreeMap<PathBuf, Record>,
+}
+
+impl Outputs {
+    /// `base` の記録ファイルを読む。まだなければ空。読めない行は記録がないものとして扱う（その出力は作り直す）
+    pub fn load(base: &Path) -> io::Result<Outputs> {
+        let text = match std::fs::read_to_string(base.join(FILE_NAME)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Outputs { base: base.to_path_buf(), records: parse(&text) })
+    }
+
+    /// `target` が、同じ元と同じ設定で書き出したものとして記録されているか
+    pub fn is_current(&self, target: &Path, source: &str, settings: &str) -> bool {
+        self.records
+            .get(&self.relative(target))
+            .is_some_and(|record| record.source == source && record.settings == settings)
+    }
+
+    /// `target` を書き出したことを記録する
+    pub fn record(&mut self, target: &Path, source: &str, settings: &str) {
+        let record = Record { source: source.to_string(), settings: settings.to_string() };
+        self.records.insert(self.relative(target), record);
+    }
+
+    /// `target` の記録を消す（書き出しに失敗した出力は、次の実行で作り直す）
+    pub fn forget(&mut self, target: &Path) {
+        self.records.remove(&self.relative(target));
+    }
+
+    /// 記録ファイルのパス
+    pub fn path(&self) -> PathBuf {
+        self.base.join(FILE_NAME)
+    }
+
+    /// 記録ファイルを書き直す。1行に「元のチェックサム、設定の指紋、出力のパス」
+    pub fn save(&self) -> io::Result<()> {
+        let mut text = String::from("# compressor --incremental\n");
+        for (path, record) in &self.records {
+            text.push_str(&format!("{} {} {}\n", record.source, record.settings, path.display()));
+        }
+        std::fs::write(self.path(), text)
+    }
+
+    fn relative(&self, target: &Path) -> PathBuf {
+        target.strip_prefix(&self.base).unwrap_or(target).to_path_buf()
+    }
+}
+
+/// 出力の中身を決める設定を並べた文字列の指紋（BLAKE3 の先頭 16 桁）
+pub fn fingerprint(settings: &str) -> String {
+    blake3::hash(settings.as_bytes()).to_hex()[..16].to_string()
+}
+
+fn parse(text: &str) -> BTreeMap<PathBuf, Record> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let (source, settings, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let record = Record { source: source.to_string(), settings: settings.to_string() };
+            (!path.is_empty()).then(|| (PathBuf::from(path), record))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 元と設定の両方が同じ出力だけを最新とし、記録を書き直して読み戻せること
+    #[test]
+    fn tracks_each_output() {
+        let dir = std::env::temp_dir().join("compressor_incremental_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (jpg, webp) = (dir.join("a/photo.jpg"), dir.join("a/photo.webp"));
+        let settings = fingerprint("rgb image; quality 70");
+
+        let mut outputs = Outputs::load(&dir).unwrap();
+        assert!(!outputs.is_current(&jpg, "aaaa", &settings));
+        outputs.record(&jpg, "aaaa", &settings);
+        outputs.record(&webp, "aaaa", &fingerprint("jpg -> webp (lossy); quality 70"));
+        outputs.save().unwrap();
+        assert!(std::fs::read_to_string(dir.join(FILE_NAME)).unwrap().contains(&format!("aaaa {settings} a/photo.jpg")));
+
+        let mut outputs = Outputs::load(&dir).unwrap();
+        assert!(outputs.is_current(&jpg, "aaaa", &settings));
+        assert!(!outputs.is_current(&jpg, "bbbb", &settings));
+        assert!(!outputs.is_current(&jpg, "aaaa", &fingerprint("rgb image; quality 80")));
+        assert!(!outputs.is_current(&webp, "aaaa", &settings));
+        outputs.forget(&jpg);
+        assert!(!outputs.is_current(&jpg, "aaaa", &settings));
+
+        assert!(parse("broken\n# comment\n").is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
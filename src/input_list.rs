@@ -0,0 +1,104 @@
+//! `--input-list` で渡す入力の一覧。
+//!
+//! 1行に1ファイル。パスの後にオプションを続けると、そのファイルだけに効く（例: `photo.jpg --quality 90`）。
+//! 空白を含むパスやオプションの値は `"` か `'` で囲む。`#` で始まる行と空行は読み飛ばす。
+
+use std::path::{Path, PathBuf};
+
+/// 一覧の1行
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// 入力ファイル
+    pub path: PathBuf,
+    /// このファイルだけに効くオプション（コマンドラインと同じ書式）
+    pub overrides: Vec<String>,
+}
+
+/// 一覧のファイルを読む。エラーにはファイル名と行番号を含める。
+pub fn load(path: &Path) -> Result<Vec<Entry>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}: {e}", path))?;
+    parse(&text).map_err(|e| format!("{:?}: {e}", path))
+}
+
+/// 一覧の内容を読む
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = split_words(line).map_err(|e| format!("{} 行目: {e}", index + 1))?.into_iter();
+        let Some(path) = words.next() else {
+            continue;
+        };
+        entries.push(Entry { path: PathBuf::from(path), overrides: words.collect() });
+    }
+
+    Ok(entries)
+}
+
+/// 空白で区切る。引用符で囲んだ部分は空白を含めて1語とする。
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err("引用符が閉じていません".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// パスの後ろのオプションをそのファイルの上書きとして読み、コメントと空行は飛ばすこと
+    #[test]
+    fn parses_entries_with_overrides() {
+        let entries = parse("# 手直しするファイル\nphoto.jpg --quality 90\n\n\"my scan.png\" --dpi 300\nplain.png\n").unwrap();
+        assert_eq!(
+            entries,
+            [
+                Entry { path: PathBuf::from("photo.jpg"), overrides: vec!["--quality".into(), "90".into()] },
+                Entry { path: PathBuf::from("my scan.png"), overrides: vec!["--dpi".into(), "300".into()] },
+                Entry { path: PathBuf::from("plain.png"), overrides: vec![] },
+            ]
+        );
+    }
+
+    /// 引用符が閉じていない行は行番号付きでエラーにすること
+    #[test]
+    fn rejects_unclosed_quote() {
+        assert!(parse("a.jpg\n'b.jpg --quality 90").unwrap_err().contains("2 行目"));
+        assert_eq!(split_words("a '' b").unwrap(), ["a", "", "b"]);
+    }
+}
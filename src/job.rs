@@ -0,0 +1,512 @@
+use std::path::Path;
+use std::time::Duration;
+use image::{DynamicImage, ImageDecoder};
+use crate::error::CompressError;
+use crate::content::{self, Content};
+use crate::frames::{self, FrameSelection};
+use crate::similarity::{self, Score};
+use crate::sniff::{self, Signature};
+use crate::watermark::Watermark;
+use crate::utilities::warning;
+use crate::{audio, file, gif_image, rgb_image, rgba_image, tiff_image, utilities, video, webp_image, AppArgs, OutputFormat};
+
+/// ffmpeg を1本動かすときに見積もるメモリ（--max-memory）
+const FFMPEG_MEMORY: u64 = 512 * 1024 * 1024;
+
+/// 入力1ファイルに対して行う圧縮処理の種類
+pub enum Job {
+    /// png → png (oxipng)
+    Png,
+    /// png → 可逆webp
+    PngToWebp,
+    /// png → jpg（--format auto で写真と判定した場合）
+    PngToJpg,
+    /// png → 非可逆webp（--format auto で写真と判定した場合）
+    PngToLossyWebp,
+    /// jpg/jpeg → jpg (mozjpeg)
+    Jpg,
+    /// jpg/jpeg → 非可逆webp
+    JpgToWebp,
+    /// アニメーションGIF → mp4
+    AnimatedGif(video::VideoCodec),
+    /// APNG → mp4
+    AnimatedPng(video::VideoCodec),
+    /// アニメーションWebP → mp4（フレームをデコードしてから ffmpeg に渡す）
+    AnimatedWebp(video::VideoCodec),
+    /// 静止GIF → 可逆webp
+    GifToWebp,
+    /// 静止GIF → png (oxipng)
+    GifToPng,
+    /// webp → webp（元と同じ可逆/非可逆で再圧縮）
+    Webp(webp_image::WebpKind),
+    /// tiff の1ページ → jpg/非可逆webp（1ページだけの tiff か、--tiff-page で選んだページ。ページは 0 から）
+    Tiff { page: usize, webp: bool },
+    /// 複数ページの tiff → ページごとの jpg/非可逆webp（出力はディレクトリ）
+    TiffPages { webp: bool },
+    /// 動画 → mp4
+    Video(video::VideoCodec),
+    /// 動画 → 連番の jpg（--extract-frames。出力はディレクトリ）
+    VideoFrames(FrameSelection),
+    /// 音声 → flac/m4a/opus
+    Audio(audio::AudioCodec),
+    /// PDF → ページを JPEG にして組み直した PDF
+    #[cfg(feature = "pdf")]
+    Pdf,
+    /// avif → jpg/非可逆webp（image クレートの dav1d でデコードする）
+    #[cfg(feature = "avif")]
+    Avif { webp: bool },
+    /// 対応していない形式を変換せずにコピーする（元の拡張子を保持）
+    Copy(String),
+    /// --format auto で内容から選んだ処理
+    Auto(Box<Job>, Content),
+}
+
+impl Job {
+    /// 入力の拡張子とオプションから処理の種類を決める。対応していない拡張子は `None`。
+    pub fn plan(ext: &str, filepath: &Path, args: &AppArgs) -> Result<Option<Job>, CompressError> {
+        Job::plan_probed(ext, filepath, args, None, None)
+    }
+
+    /// [`Job::plan`] と同じ。`probed` は先に ffprobe で調べておいた入力のストリーム、
+    /// `classified` は先に判定しておいた --format auto 用の画像の内容（どちらも `None` ならここで調べる）
+    pub fn plan_probed(
+        ext: &str,
+        filepath: &Path,
+        args: &AppArgs,
+        probed: Option<video::Streams>,
+        classified: Option<Content>,
+    ) -> Result<Option<Job>, CompressError> {
+        let video_codec = if args.hevc {
+            video::VideoCodec::Hevc
+        } else {
+            video::VideoCodec::Av1
+        };
+
+        // 画像はデコードする前にヘッダの寸法で巨大な入力を弾く
+        if matches!(ext, "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif") {
+            utilities::ensure_within_megapixels(filepath, args.max_megapixels)?;
+        }
+
+        #[cfg(feature = "pdf")]
+        if ext == "pdf" {
+            return Ok(Some(Job::Pdf));
+        }
+
+        #[cfg(feature = "avif")]
+        if ext == "avif" {
+            return Ok(Some(Job::Avif { webp: args.webp }));
+        }
+
+        let job = if ext == "png" && rgba_image::is_animated(&std::fs::read(filepath)?) {
+            // APNG は先頭フレームだけを画像にせず、動画として扱う
+            Job::AnimatedPng(video_codec)
+        } else if ext == "png" {
+            match args.format {
+                OutputFormat::Auto => Job::auto_png(filepath, args.webp, classified)?,
+                OutputFormat::Keep if args.webp => Job::PngToWebp,
+                OutputFormat::Keep => Job::Png,
+            }
+        } else if ext == "jpg" || ext == "jpeg" {
+            if args.webp { Job::JpgToWebp } else { Job::Jpg }
+        } else if ext == "gif" {
+            if gif_image::is_animated(filepath)? {
+                // アニメーションGIFは動画として扱う（READMEに従い --webp は対象外）
+                Job::AnimatedGif(video_codec)
+            } else if args.webp {
+                // 静止GIFは画像として扱う（可逆WebP）
+                Job::GifToWebp
+            } else {
+                // 静止GIFは画像として扱う（oxipngでPNG化）
+                Job::GifToPng
+            }
+        } else if ext == "webp" {
+            match webp_image::inspect(&std::fs::read(filepath)?) {
+                // アニメーションWebPは動画として扱う
+                Some(kind) if kind.animated => Job::AnimatedWebp(video_codec),
+                Some(kind) => Job::Webp(kind),
+                None => return Ok(None),
+            }
+        } else if ext == "tif" || ext == "tiff" {
+            let sizes = tiff_image::page_sizes(filepath)?;
+            let page = match args.tiff_page {
+                Some(page) if page as usize > sizes.len() => {
+                    return Err(CompressError::InvalidInput(format!("--tiff-page {page} のページがありません（{} ページ）", sizes.len())));
+                }
+                Some(page) => Some(page as usize - 1),
+                None => None,
+            };
+            tiff_image::ensure_within_megapixels(&sizes, page, args.max_megapixels)?;
+            match page {
+                Some(page) => Job::Tiff { page, webp: args.webp },
+                None if sizes.len() > 1 => Job::TiffPages { webp: args.webp },
+                None => Job::Tiff { page: 0, webp: args.webp },
+            }
+        } else if video::is_video_extension(ext) {
+            // 拡張子を信用して ffmpeg に渡すと、映像のない入力から壊れた mp4 ができるため中身を確かめる
+            if let Some(image_ext) = sniff::detect_file(filepath).and_then(Signature::image_extension) {
+                warning!("警告: 中身が画像（{image_ext}）のため、画像として処理します: {:?}", filepath);
+                return Job::plan(image_ext, filepath, args);
+            }
+            match probed.map_or_else(|| video::probe_streams(filepath), Ok) {
+                Ok(streams) if !streams.video && streams.audio => {
+                    warning!("警告: 映像のストリームがないため、音声として処理します: {:?}", filepath);
+                    Job::Audio(Job::audio_codec(ext, args))
+                }
+                Ok(streams) if !streams.video => {
+                    return Err(CompressError::InvalidInput("映像のストリームがありません（壊れているか、動画ではないファイルです）".to_string()));
+                }
+                // ffprobe がなければ、ffmpeg がないことを圧縮するときに報告する
+                _ => match args.extract_frames {
+                    Some(selection) => Job::VideoFrames(selection),
+                    None => Job::Video(video_codec),
+                },
+            }
+        } else if audio::is_audio_extension(ext) {
+            Job::Audio(Job::audio_codec(ext, args))
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(job))
+    }
+
+    /// 音声の出力コーデック。可逆の入力は可逆のまま（flac）にする
+    fn audio_codec(ext: &str, args: &AppArgs) -> audio::AudioCodec {
+        if audio::is_lossless_extension(ext) {
+            audio::AudioCodec::Flac
+        } else if args.opus {
+            audio::AudioCodec::Opus
+        } else {
+            audio::AudioCodec::Aac
+        }
+    }
+
+    /// 動画にする処理で ffmpeg に渡すオプション。品質は --crf（静止画の --quality は使わない）。動画にしない処理は `None`
+    pub fn video_options(&self, args: &AppArgs) -> Option<video::VideoOptions> {
+        let (Job::AnimatedGif(codec) | Job::AnimatedPng(codec) | Job::AnimatedWebp(codec) | Job::Video(codec)) = self else {
+            return None;
+        };
+        Some(video::VideoOptions {
+            codec: *codec,
+            crf: args.crf,
+            preset: args.video_preset,
+            loglevel: args.ffmpeg_loglevel,
+            sharpen: args.sharpen,
+            scale_align: args.scale_align,
+            watermark: watermark(args),
+            print_command: args.print_ffmpeg_command,
+            animation: !matches!(self, Job::Video(_)),
+            // アニメーション画像は短く、区間に分けると表示時間がずれるため、動画だけを分ける
+            segment_seconds: args.video_segments.filter(|_| matches!(self, Job::Video(_))),
+            // アニメーション画像には音声がない
+            normalize_audio: args.normalize_audio.filter(|_| matches!(self, Job::Video(_))),
+            temp_dir: args.temp_dir.clone(),
+            autorotate: args.video_autorotate,
+            keyframe_interval: args.keyframe_interval,
+        })
+    }
+
+    /// png の内容を判定し、透過や図なら可逆、写真なら非可逆の処理を選ぶ（`classified` があれば判定し直さない）
+    fn auto_png(filepath: &Path, webp: bool, classified: Option<Content>) -> Result<Job, CompressError> {
+        let content = match classified {
+            Some(content) => content,
+            None => content::classify_file(filepath)?,
+        };
+        let job = match (content.is_lossless(), webp) {
+            (true, false) => Job::Png,
+            (true, true) => Job::PngToWebp,
+            (false, false) => Job::PngToJpg,
+            (false, true) => Job::PngToLossyWebp,
+        };
+        Ok(Job::Auto(Box::new(job), content))
+    }
+
+    /// 出力の拡張子
+    pub fn extension(&self) -> &str {
+        match self {
+            Job::Png | Job::GifToPng => "png",
+            Job::PngToWebp | Job::PngToLossyWebp | Job::JpgToWebp | Job::GifToWebp | Job::Webp(_) => "webp",
+            Job::Jpg | Job::PngToJpg => "jpg",
+            Job::Tiff { webp, .. } => if *webp { "webp" } else { "jpg" },
+            Job::AnimatedGif(_) | Job::AnimatedPng(_) | Job::AnimatedWebp(_) | Job::Video(_) => "mp4",
+            // 出力は拡張子のないディレクトリ（clip.mp4 → clip/、scan.tiff → scan/）
+            Job::VideoFrames(_) | Job::TiffPages { .. } => "",
+            Job::Audio(codec) => codec.extension(),
+            #[cfg(feature = "pdf")]
+            Job::Pdf => "pdf",
+            #[cfg(feature = "avif")]
+            Job::Avif { webp } => if *webp { "webp" } else { "jpg" },
+            Job::Copy(ext) => ext,
+            Job::Auto(job, _) => job.extension(),
+        }
+    }
+
+    /// 出力先テンプレートの `{type}` に使う種類名
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::Png
+            | Job::PngToWebp
+            | Job::PngToJpg
+            | Job::PngToLossyWebp
+            | Job::Jpg
+            | Job::JpgToWebp
+            | Job::GifToWebp
+            | Job::GifToPng
+            | Job::Webp(_)
+            | Job::Tiff { .. }
+            | Job::TiffPages { .. }
+            | Job::VideoFrames(_) => "images",
+            Job::AnimatedGif(_) | Job::AnimatedPng(_) | Job::AnimatedWebp(_) | Job::Video(_) => "videos",
+            Job::Audio(_) => "audio",
+            #[cfg(feature = "pdf")]
+            Job::Pdf => "images",
+            #[cfg(feature = "avif")]
+            Job::Avif { .. } => "images",
+            Job::Copy(_) => "others",
+            Job::Auto(job, _) => job.kind(),
+        }
+    }
+
+    /// 動画ファイルの処理か（--sprite-sheet を作る）
+    pub fn is_video(&self) -> bool {
+        match self {
+            Job::Video(_) => true,
+            Job::Auto(job, _) => job.is_video(),
+            _ => false,
+        }
+    }
+
+    /// 出力が静止画1枚の処理か（--contact-sheet の一覧画像に並べる）
+    pub fn is_still_image(&self) -> bool {
+        match self {
+            Job::Png
+            | Job::PngToWebp
+            | Job::PngToJpg
+            | Job::PngToLossyWebp
+            | Job::Jpg
+            | Job::JpgToWebp
+            | Job::GifToWebp
+            | Job::GifToPng
+            | Job::Webp(_) => true,
+            #[cfg(feature = "avif")]
+            Job::Avif { .. } => true,
+            Job::Auto(job, _) => job.is_still_image(),
+            _ => false,
+        }
+    }
+
+    /// 圧縮した静止画 `output` を、元の画像に同じ切り抜きと透かしを施したものと比べる（--diff-report）。
+    /// 静止画以外の処理と、寸法が合わず比べられない場合は `None`。
+    pub fn score(&self, filepath: &Path, output: &Path, args: &AppArgs) -> Result<Option<Score>, CompressError> {
+        if !self.is_still_image() {
+            return Ok(None);
+        }
+        let (reference, output) = self.reference_and_output(filepath, output, args)?;
+        Ok(similarity::compare(&reference, &output))
+    }
+
+    /// 元の画像に圧縮と同じ向きの補正・切り抜き・透かしを施したものと、圧縮した静止画 `output`。どちらも見た目の向きにする
+    pub fn reference_and_output(&self, filepath: &Path, output: &Path, args: &AppArgs) -> Result<(DynamicImage, DynamicImage), CompressError> {
+        let reference = image_options(filepath, args).edit(webp_image::open_with_orientation(filepath)?)?;
+        Ok((reference, webp_image::open_with_orientation(output)?))
+    }
+
+    /// 圧縮に使うメモリの見積もり（バイト。--max-memory）。
+    /// 静止画はデコードした画素の大きさ（ヘッダの幅×高さ×1画素のバイト数）とし、ffmpeg を使う処理は ffmpeg 自身が
+    /// 確保するため寸法によらず [`FFMPEG_MEMORY`] とする。ヘッダを読めない入力とコピーは 0 とする
+    pub fn estimated_memory(&self, filepath: &Path) -> u64 {
+        match self {
+            _ if self.needs_ffmpeg() => FFMPEG_MEMORY,
+            Job::Copy(_) => 0,
+            Job::Auto(job, _) => job.estimated_memory(filepath),
+            _ => image::ImageReader::open(filepath)
+                .ok()
+                .and_then(|reader| reader.with_guessed_format().ok())
+                .and_then(|reader| reader.into_decoder().ok())
+                .map_or(0, |decoder| {
+                    let (width, height) = decoder.dimensions();
+                    width as u64 * height as u64 * decoder.color_type().bytes_per_pixel() as u64
+                }),
+        }
+    }
+
+    /// FFmpegが必要な処理か
+    pub fn needs_ffmpeg(&self) -> bool {
+        matches!(
+            self,
+            Job::AnimatedGif(_)
+                | Job::AnimatedPng(_)
+                | Job::AnimatedWebp(_)
+                | Job::Video(_)
+                | Job::VideoFrames(_)
+                | Job::Audio(_)
+        )
+    }
+
+    /// ログに表示する処理の名前
+    pub fn label(&self) -> String {
+        match self {
+            Job::Png => "rgba image".to_string(),
+            Job::PngToWebp => "png -> webp (lossless)".to_string(),
+            Job::PngToJpg => "png -> jpg".to_string(),
+            Job::PngToLossyWebp => "png -> webp (lossy)".to_string(),
+            Job::Jpg => "rgb image".to_string(),
+            Job::JpgToWebp => "jpg -> webp (lossy)".to_string(),
+            Job::AnimatedGif(codec) => format!("gif ({})", codec.name()),
+            Job::AnimatedPng(codec) => format!("apng ({})", codec.name()),
+            Job::AnimatedWebp(codec) => format!("webp animation ({})", codec.name()),
+            Job::GifToWebp => "gif -> webp (lossless)".to_string(),
+            Job::GifToPng => "gif -> png".to_string(),
+            Job::Webp(kind) if kind.lossless => "webp (lossless)".to_string(),
+            Job::Webp(_) => "webp (lossy)".to_string(),
+            Job::Video(codec) => format!("video ({})", codec.name()),
+            Job::VideoFrames(selection) => format!("video -> jpg frames ({selection})"),
+            Job::Tiff { page, webp } => format!("tiff (page {}) -> {}", page + 1, if *webp { "webp (lossy)" } else { "jpg" }),
+            Job::TiffPages { webp } => format!("tiff -> {} pages", if *webp { "webp" } else { "jpg" }),
+            Job::Audio(codec) => format!("audio ({})", codec.extension()),
+            #[cfg(feature = "pdf")]
+            Job::Pdf => "pdf (jpg pages)".to_string(),
+            #[cfg(feature = "avif")]
+            Job::Avif { webp } => format!("avif -> {}", if *webp { "webp (lossy)" } else { "jpg" }),
+            Job::Copy(_) => "copy".to_string(),
+            Job::Auto(job, content) => format!("{} [auto: {}]", job.label(), content.name()),
+        }
+    }
+
+    /// 出力の中身を決める設定を並べた文字列（--incremental で、前に書き出したときの設定と比べる）。
+    /// 処理の名前と、その処理が使うオプション。ログの詳しさや作業ディレクトリなど、出力の中身に関わらないものは含めない
+    pub fn settings(&self, filepath: &Path, args: &AppArgs) -> String {
+        let label = self.label();
+        match self {
+            Job::AnimatedGif(_) | Job::AnimatedPng(_) | Job::AnimatedWebp(_) | Job::Video(_) => {
+                let options = self.video_options(args).expect("動画にする処理");
+                let options = video::VideoOptions { loglevel: Default::default(), print_command: false, temp_dir: None, ..options };
+                format!("{label}; {options:?}")
+            }
+            Job::Audio(_) => format!("{label}; bitrate {}", args.audio_bitrate),
+            #[cfg(feature = "pdf")]
+            Job::Pdf => format!("{label}; dpi {}, quality {}, pages {}", args.pdf_dpi, args.quality, args.pdf_pages),
+            Job::Copy(_) => label,
+            Job::Auto(job, _) => format!("{label}; {}", job.settings(filepath, args)),
+            _ => {
+                let options = utilities::ImageOptions { png_timeout: None, ..image_options(filepath, args) };
+                format!("{label}; {options:?}")
+            }
+        }
+    }
+
+    /// 圧縮を実行する
+    pub fn run(&self, filepath: &Path, target: &Path, args: &AppArgs) -> Result<(), CompressError> {
+        let image_options = || image_options(filepath, args);
+
+        match self {
+            Job::Png => rgba_image::path2compress(filepath, target, &image_options()),
+            Job::PngToWebp | Job::GifToWebp => webp_image::path2compress_lossless(filepath, target, &image_options()),
+            Job::Jpg | Job::PngToJpg => rgb_image::path2compress(filepath, target, &image_options()),
+            // 元の形式が違うため、どちらも元のほうが小さくても書き戻さない
+            #[cfg(feature = "avif")]
+            Job::Avif { webp: false } => rgb_image::path2compress(filepath, target, &image_options()),
+            #[cfg(feature = "avif")]
+            Job::Avif { webp: true } => webp_image::path2compress_lossy(filepath, target, &image_options()),
+            Job::JpgToWebp | Job::PngToLossyWebp => webp_image::path2compress_lossy(filepath, target, &image_options()),
+            Job::GifToPng => gif_image::path2compress_png(filepath, target, &image_options()),
+            Job::Webp(kind) => webp_image::path2compress_webp(filepath, target, *kind, &image_options()),
+            Job::AnimatedGif(_) | Job::AnimatedPng(_) | Job::AnimatedWebp(_) | Job::Video(_) => {
+                let options = self.video_options(args).expect("動画にする処理");
+                match self {
+                    Job::AnimatedWebp(_) => video::compress_frames(filepath, &webp_image::frames(filepath)?, target, &options),
+                    _ => video::path2compress(filepath, target, &options),
+                }
+                .map(|_| ())
+            }
+            Job::VideoFrames(selection) => {
+                let count = frames::extract(
+                    filepath,
+                    target,
+                    *selection,
+                    &image_options(),
+                    args.temp_dir.as_deref(),
+                    args.ffmpeg_loglevel,
+                )?;
+                if !args.summary_only {
+                    println!("  フレームを {count} 枚書き出しました: {:?}", target);
+                }
+                Ok(())
+            }
+            Job::Tiff { page, webp } => tiff_image::path2compress(filepath, target, *page, *webp, &image_options(), args.temp_dir.as_deref()),
+            Job::TiffPages { webp } => {
+                let count = tiff_image::path2compress_pages(filepath, target, *webp, &image_options(), args.temp_dir.as_deref())?;
+                if !args.summary_only {
+                    println!("  ページを {count} 枚書き出しました: {:?}", target);
+                }
+                Ok(())
+            }
+            Job::Audio(codec) => audio::path2compress(filepath, target, *codec, &args.audio_bitrate, args.ffmpeg_loglevel),
+            #[cfg(feature = "pdf")]
+            Job::Pdf => {
+                let options = crate::pdf::PdfOptions {
+                    dpi: args.pdf_dpi,
+                    quality: args.quality,
+                    pages_dir: args.pdf_pages.then(|| target.with_extension("")),
+                    temp_dir: args.temp_dir.clone(),
+                };
+                crate::pdf::path2compress(filepath, target, &options)
+            }
+            Job::Copy(_) => {
+                std::fs::copy(filepath, target)?;
+                // そのまま配置できるミラーになるよう、更新日時も元に合わせる
+                let modified = std::fs::metadata(filepath)?.modified()?;
+                std::fs::File::options().write(true).open(target)?.set_modified(modified)?;
+                Ok(())
+            }
+            Job::Auto(job, _) => job.run(filepath, target, args),
+        }
+    }
+}
+
+/// 静止画の圧縮のオプション
+pub fn image_options(filepath: &Path, args: &AppArgs) -> utilities::ImageOptions {
+    utilities::ImageOptions {
+        quality: args.quality,
+        exif_source: args.exif_source.clone().or_else(|| {
+            args.copy_exif_from_sibling
+                .then(|| file::find_jpeg_sibling(filepath))
+                .flatten()
+        }),
+        dpi: dpi(args),
+        crop: args.crop.or(args.crop_center),
+        tonemap: args.tonemap,
+        tag: args.tag.clone(),
+        watermark: watermark(args),
+        baseline: args.baseline,
+        optimize_scans: args.jpeg_optimize_scans,
+        scan_preset: args.scan_preset,
+        restart_interval: args.jpeg_restart_interval,
+        lossless_orient: args.lossless_orient,
+        png_timeout: (args.encode_timeout_image > 0).then(|| Duration::from_secs(args.encode_timeout_image)),
+        png_filters: args.png_filters.clone(),
+        text_aware: args.text_aware,
+        background: args.flatten_background,
+        strip_gps: args.strip_gps,
+        sharpen: args.sharpen,
+    }
+}
+
+/// 重ねる透かし（--watermark とその位置・不透明度・余白）
+fn watermark(args: &AppArgs) -> Option<Watermark> {
+    args.watermark.as_ref().map(|source| Watermark {
+        source: source.clone(),
+        position: args.watermark_position,
+        opacity: args.watermark_opacity,
+        margin: args.watermark_margin,
+    })
+}
+
+/// 画像の解像度の扱い（--dpi / --strip-dpi）
+fn dpi(args: &AppArgs) -> utilities::Dpi {
+    match args.dpi {
+        Some(value) => utilities::Dpi::Set(value),
+        None if args.strip_dpi => utilities::Dpi::Strip,
+        None => utilities::Dpi::Keep,
+    }
+}
@@ -0,0 +1,344 @@
+//! JPEG の可逆変換（`--lossless-orient` と `--scan-preset`、`--jpeg-restart-interval`）。
+//!
+//! mozjpeg に同梱の transupp（jpegtran の変換部分）で DCT 係数のブロックを並べ替え、
+//! Exif の向きを再量子化せずにピクセルへ反映する。スキャンの組み方を変える場合やリスタートマーカーを入れる場合も、
+//! 係数をそのまま書き直す。mozjpeg クレートには変換やスキャンの組み方、リスタートの間隔を指定する API がないため、
+//! mozjpeg-sys から libjpeg を直接呼ぶ。
+
+use std::mem;
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use mozjpeg::PixelDensity;
+use mozjpeg_sys::{
+    boolean, jcopy_markers_execute, jcopy_markers_setup, jpeg_common_struct, jpeg_compress_struct,
+    jpeg_copy_critical_parameters, jpeg_create_compress, jpeg_create_decompress, jpeg_decompress_struct,
+    jpeg_c_set_bool_param, jpeg_destroy_compress, jpeg_destroy_decompress, jpeg_error_mgr, jpeg_finish_compress,
+    jpeg_finish_decompress, jpeg_mem_dest, jpeg_mem_src, jpeg_read_coefficients, jpeg_read_header, jpeg_scan_info,
+    jpeg_std_error, jpeg_write_coefficients, jvirt_barray_ptr, JCOPY_OPTION_JCOPYOPT_ALL, JDIMENSION, J_BOOLEAN_PARAM,
+};
+use crate::error::CompressError;
+use crate::exif;
+
+/// transupp.h の `jpeg_transform_info`。
+///
+/// mozjpeg-sys の定義には `drop_*` のフィールドが欠けていて C 側と大きさが合わないため、
+/// 同梱のヘッダーに合わせて定義し直す。呼び出し側が設定するのは `transform` と `perfect` だけ。
+#[repr(C)]
+struct TransformInfo {
+    transform: c_uint,
+    perfect: boolean,
+    trim: boolean,
+    force_grayscale: boolean,
+    crop: boolean,
+    slow_hflip: boolean,
+    crop_width: JDIMENSION,
+    crop_width_set: c_uint,
+    crop_height: JDIMENSION,
+    crop_height_set: c_uint,
+    crop_xoffset: JDIMENSION,
+    crop_xoffset_set: c_uint,
+    crop_yoffset: JDIMENSION,
+    crop_yoffset_set: c_uint,
+    drop_ptr: *mut jpeg_decompress_struct,
+    drop_coef_arrays: *mut jvirt_barray_ptr,
+    num_components: c_int,
+    workspace_coef_arrays: *mut jvirt_barray_ptr,
+    output_width: JDIMENSION,
+    output_height: JDIMENSION,
+    x_crop_offset: JDIMENSION,
+    y_crop_offset: JDIMENSION,
+    drop_width: JDIMENSION,
+    drop_height: JDIMENSION,
+    imcu_sample_width: c_int,
+    imcu_sample_height: c_int,
+}
+
+extern "C-unwind" {
+    fn jtransform_request_workspace(srcinfo: *mut jpeg_decompress_struct, info: *mut TransformInfo) -> boolean;
+    fn jtransform_adjust_parameters(
+        srcinfo: *mut jpeg_decompress_struct,
+        dstinfo: *mut jpeg_compress_struct,
+        src_coef_arrays: *mut jvirt_barray_ptr,
+        info: *mut TransformInfo,
+    ) -> *mut jvirt_barray_ptr;
+    fn jtransform_execute_transform(
+        srcinfo: *mut jpeg_decompress_struct,
+        dstinfo: *mut jpeg_compress_struct,
+        src_coef_arrays: *mut jvirt_barray_ptr,
+        info: *mut TransformInfo,
+    );
+}
+
+/// Exif の Orientation（1-8）を、ピクセルを見た目の向きにする変換（transupp の JXFORM_CODE）にする。
+/// 変換が要らない 1 と不正な値は `None`。
+fn transform_code(orientation: u8) -> Option<c_uint> {
+    use mozjpeg_sys::*;
+    match orientation {
+        2 => Some(JXFORM_CODE_JXFORM_FLIP_H),
+        3 => Some(JXFORM_CODE_JXFORM_ROT_180),
+        4 => Some(JXFORM_CODE_JXFORM_FLIP_V),
+        5 => Some(JXFORM_CODE_JXFORM_TRANSPOSE),
+        6 => Some(JXFORM_CODE_JXFORM_ROT_90),
+        7 => Some(JXFORM_CODE_JXFORM_TRANSVERSE),
+        8 => Some(JXFORM_CODE_JXFORM_ROT_270),
+        _ => None,
+    }
+}
+
+/// Exif の向きを可逆変換でピクセルへ反映し、Orientation を 1 にした JPEG を返す。
+///
+/// Exif や ICC プロファイルなどのマーカーはそのまま引き継ぎ、JFIF の解像度は `density` にする（`None` なら書かない）。
+/// 向きが 1 の場合や、寸法が MCU（8 または 16 ピクセル）の倍数でなく端のブロックを欠かさずに変換できない場合は `None`。
+pub fn apply_orientation(
+    jpeg: &[u8],
+    orientation: u8,
+    density: Option<PixelDensity>,
+) -> Result<Option<Vec<u8>>, CompressError> {
+    let Some(code) = transform_code(orientation) else {
+        return Ok(None);
+    };
+
+    unsafe {
+        let mut src_err: jpeg_error_mgr = mem::zeroed();
+        let mut dst_err: jpeg_error_mgr = mem::zeroed();
+        let mut src: jpeg_decompress_struct = mem::zeroed();
+        let mut dst: jpeg_compress_struct = mem::zeroed();
+        src.common.err = unwinding_error_mgr(&mut src_err);
+        dst.common.err = unwinding_error_mgr(&mut dst_err);
+        jpeg_create_decompress(&mut src);
+        jpeg_create_compress(&mut dst);
+
+        let mut buffer: *mut u8 = std::ptr::null_mut();
+        let mut size: c_ulong = 0;
+        // libjpeg はエラーを error_exit で通知し、ここでは panic として巻き戻るため、バッチ全体を止めないよう捕まえる
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            transform(&mut src, &mut dst, jpeg, code, density, &mut buffer, &mut size)
+        }));
+        jpeg_destroy_compress(&mut dst);
+        jpeg_destroy_decompress(&mut src);
+
+        let output = (!buffer.is_null()).then(|| std::slice::from_raw_parts(buffer, size as usize).to_vec());
+        libc::free(buffer.cast());
+
+        match result {
+            Ok(true) => Ok(output),
+            Ok(false) => Ok(None),
+            Err(_) => Err(CompressError::Decode("JPEG の可逆変換に失敗しました".to_string())),
+        }
+    }
+}
+
+/// jpegtran と同じ手順で係数を読み、並べ替えて書き出す。端のブロックのせいで変換できない場合は `false`。
+unsafe fn transform(
+    src: &mut jpeg_decompress_struct,
+    dst: &mut jpeg_compress_struct,
+    jpeg: &[u8],
+    code: c_uint,
+    density: Option<PixelDensity>,
+    buffer: &mut *mut u8,
+    size: &mut c_ulong,
+) -> bool {
+    jpeg_mem_src(src, jpeg.as_ptr(), jpeg.len() as c_ulong);
+    jcopy_markers_setup(src, JCOPY_OPTION_JCOPYOPT_ALL);
+    jpeg_read_header(src, 1);
+    reset_orientation(src);
+
+    let mut info: TransformInfo = mem::zeroed();
+    info.transform = code;
+    info.perfect = 1;
+    if jtransform_request_workspace(src, &mut info) == 0 {
+        return false;
+    }
+
+    let src_coefs = jpeg_read_coefficients(src);
+    jpeg_copy_critical_parameters(src, dst);
+    let dst_coefs = jtransform_adjust_parameters(src, dst, src_coefs, &mut info);
+    (dst.density_unit, dst.X_density, dst.Y_density) = match density {
+        Some(density) => (density.unit as u8, density.x, density.y),
+        None => (0, 1, 1),
+    };
+
+    jpeg_mem_dest(dst, buffer, size);
+    jpeg_write_coefficients(dst, dst_coefs);
+    jcopy_markers_execute(src, dst, JCOPY_OPTION_JCOPYOPT_ALL);
+    jtransform_execute_transform(src, dst, src_coefs, &mut info);
+    jpeg_finish_compress(dst);
+    jpeg_finish_decompress(src);
+    true
+}
+
+/// 係数を書き直すときのスキャンの組み方
+#[derive(Clone, Copy)]
+enum Layout {
+    /// [`preview_first_scans`] の組み方
+    PreviewFirst,
+    /// 1つのスキャンに、`restart_interval` 個の MCU ごとのリスタートマーカーを入れる
+    Sequential { restart_interval: u16 },
+}
+
+/// `jpeg` を、最初のスキャンで全成分の直流成分（1/8 の解像度のカラー画像）を送るプログレッシブの JPEG に書き直す。
+///
+/// 続けて輝度の低い周波数（ジグザグ順の 1-5）、色差の交流成分、輝度の残りの順に送る。
+/// 係数はそのまま使うため画質は変わらない。マーカーと JFIF の解像度は引き継ぐ。
+pub fn preview_first_scans(jpeg: &[u8]) -> Result<Vec<u8>, CompressError> {
+    rewrite(jpeg, Layout::PreviewFirst)
+}
+
+/// `jpeg` を、`mcus` 個の MCU ごとにリスタートマーカーを入れたシーケンシャルの JPEG に書き直す（伝送中に壊れても次のマーカーから復帰できる）。
+///
+/// プログレッシブの JPEG もシーケンシャルにする。プログレッシブにリスタートマーカーを入れると、
+/// image クレートのデコーダ（zune-jpeg）では読めなくなるため。係数とマーカー、JFIF の解像度はそのまま引き継ぐ。
+pub fn with_restart_interval(jpeg: &[u8], mcus: u16) -> Result<Vec<u8>, CompressError> {
+    rewrite(jpeg, Layout::Sequential { restart_interval: mcus })
+}
+
+fn rewrite(jpeg: &[u8], layout: Layout) -> Result<Vec<u8>, CompressError> {
+    unsafe {
+        let mut src_err: jpeg_error_mgr = mem::zeroed();
+        let mut dst_err: jpeg_error_mgr = mem::zeroed();
+        let mut src: jpeg_decompress_struct = mem::zeroed();
+        let mut dst: jpeg_compress_struct = mem::zeroed();
+        src.common.err = unwinding_error_mgr(&mut src_err);
+        dst.common.err = unwinding_error_mgr(&mut dst_err);
+        jpeg_create_decompress(&mut src);
+        jpeg_create_compress(&mut dst);
+
+        let mut buffer: *mut u8 = std::ptr::null_mut();
+        let mut size: c_ulong = 0;
+        let result = catch_unwind(AssertUnwindSafe(|| rescan(&mut src, &mut dst, jpeg, layout, &mut buffer, &mut size)));
+        jpeg_destroy_compress(&mut dst);
+        jpeg_destroy_decompress(&mut src);
+
+        let output = (!buffer.is_null()).then(|| std::slice::from_raw_parts(buffer, size as usize).to_vec());
+        libc::free(buffer.cast());
+
+        match (result, output) {
+            (Ok(()), Some(output)) => Ok(output),
+            _ => Err(CompressError::Decode("JPEG のスキャンを組み直せませんでした".to_string())),
+        }
+    }
+}
+
+unsafe fn rescan(
+    src: &mut jpeg_decompress_struct,
+    dst: &mut jpeg_compress_struct,
+    jpeg: &[u8],
+    layout: Layout,
+    buffer: &mut *mut u8,
+    size: &mut c_ulong,
+) {
+    jpeg_mem_src(src, jpeg.as_ptr(), jpeg.len() as c_ulong);
+    jcopy_markers_setup(src, JCOPY_OPTION_JCOPYOPT_ALL);
+    jpeg_read_header(src, 1);
+
+    let coefs = jpeg_read_coefficients(src);
+    jpeg_copy_critical_parameters(src, dst);
+    (dst.density_unit, dst.X_density, dst.Y_density) = (src.density_unit, src.X_density, src.Y_density);
+    // mozjpeg のスキャンの探索は指定したスキャンを置き換えてしまう
+    jpeg_c_set_bool_param(dst, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, 0);
+    dst.optimize_coding = 1;
+    let script = match layout {
+        Layout::PreviewFirst => preview_first_script(dst.num_components),
+        Layout::Sequential { restart_interval } => {
+            dst.restart_interval = c_uint::from(restart_interval);
+            Vec::new()
+        }
+    };
+    // 空なら mozjpeg の既定（プログレッシブ）のスキャンの指定を外し、シーケンシャルにする
+    dst.scan_info = if script.is_empty() { std::ptr::null() } else { script.as_ptr() };
+    dst.num_scans = script.len() as c_int;
+
+    jpeg_mem_dest(dst, buffer, size);
+    jpeg_write_coefficients(dst, coefs);
+    jcopy_markers_execute(src, dst, JCOPY_OPTION_JCOPYOPT_ALL);
+    jpeg_finish_compress(dst);
+    jpeg_finish_decompress(src);
+}
+
+/// 直流成分をまとめたスキャン、輝度の低い周波数、色差の交流成分、輝度の残りのスキャンの順
+fn preview_first_script(components: c_int) -> Vec<jpeg_scan_info> {
+    let scan = |indices: &[c_int], ss: c_int, se: c_int| {
+        let mut info = jpeg_scan_info { comps_in_scan: indices.len() as c_int, Ss: ss, Se: se, ..Default::default() };
+        info.component_index[..indices.len()].copy_from_slice(indices);
+        info
+    };
+    let all: Vec<c_int> = (0..components).collect();
+    let mut script = vec![scan(&all, 0, 0), scan(&[0], 1, 5)];
+    script.extend((1..components).map(|component| scan(&[component], 1, 63)));
+    script.push(scan(&[0], 6, 63));
+    script
+}
+
+/// 引き継ぐために読み込んだ Exif の Orientation を 1 にする（変換後はピクセルが見た目の向きになるため）
+unsafe fn reset_orientation(src: &jpeg_decompress_struct) {
+    let mut marker = src.marker_list;
+    while let Some(saved) = marker.as_ref() {
+        if saved.marker == 0xE1 && !saved.data.is_null() {
+            let payload = std::slice::from_raw_parts_mut(saved.data, saved.data_length as usize);
+            if payload.starts_with(b"Exif\0\0") {
+                exif::reset_orientation(payload);
+            }
+        }
+        marker = saved.next;
+    }
+}
+
+/// エラーを panic として巻き戻し、警告は表示しないエラーマネージャ（mozjpeg クレートと同じ扱い）
+unsafe fn unwinding_error_mgr(err: &mut jpeg_error_mgr) -> *mut jpeg_error_mgr {
+    jpeg_std_error(err);
+    err.error_exit = Some(error_exit);
+    err.emit_message = Some(silence_message);
+    err
+}
+
+extern "C-unwind" fn error_exit(cinfo: &mut jpeg_common_struct) {
+    let code = unsafe { cinfo.err.as_ref() }.map_or(0, |err| err.msg_code);
+    std::panic::resume_unwind(Box::new(code));
+}
+
+extern "C-unwind" fn silence_message(_cinfo: &mut jpeg_common_struct, _level: c_int) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mozjpeg::{ColorSpace, Compress, Marker};
+
+    /// Orientation を持つ Exif の APP1 ペイロード
+    fn app1(orientation: u8) -> Vec<u8> {
+        let mut app1 = b"Exif\0\0II*\0".to_vec();
+        app1.extend_from_slice(&8u32.to_le_bytes());
+        app1.extend_from_slice(&1u16.to_le_bytes());
+        app1.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, orientation, 0, 0, 0]);
+        app1.extend_from_slice(&0u32.to_le_bytes());
+        app1
+    }
+
+    /// 左半分が白、右半分が黒の JPEG
+    fn jpeg(width: usize, height: usize, orientation: u8) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..width * height).flat_map(|i| [if i % width < width / 2 { 255 } else { 0 }; 3]).collect();
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_marker(Marker::APP(1), &app1(orientation));
+        comp.write_scanlines(&pixels).unwrap();
+        comp.finish().unwrap()
+    }
+
+    /// 向きをピクセルに反映して Orientation を 1 にし、MCU に揃わない寸法は変換しないこと
+    #[test]
+    fn rotates_losslessly() {
+        let output = apply_orientation(&jpeg(32, 16, 6), 6, None).unwrap().unwrap();
+        let img = image::load_from_memory(&output).unwrap().to_luma8();
+        // 時計回りに 90 度回すと、左半分の白は上半分になる
+        assert_eq!(img.dimensions(), (16, 32));
+        assert!(img.get_pixel(8, 4).0[0] > 200);
+        assert!(img.get_pixel(8, 28).0[0] < 50);
+
+        let app1 = crate::rgb_image::extract_metadata_markers(&output).remove(0).1;
+        assert_eq!(exif::orientation(&app1), Some(1));
+
+        assert_eq!(apply_orientation(&jpeg(40, 20, 6), 6, None).unwrap(), None);
+        assert_eq!(apply_orientation(&jpeg(32, 16, 1), 1, None).unwrap(), None);
+        assert!(apply_orientation(b"\xFF\xD8broken", 6, None).is_err());
+    }
+}
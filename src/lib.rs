@@ -1,8 +1,23 @@
 mod utilities;
+mod exif;
+mod jpeg_transform;
+mod ignore;
+pub mod geometry;
+pub mod watermark;
 pub mod error;
+pub mod file;
 pub mod rgb_image;
 pub mod rgba_image;
 pub mod webp_image;
 pub mod gif_image;
 pub mod video;
 pub mod audio;
+pub mod batch;
+pub mod pixels;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+
+pub use batch::{compress_images, estimate_size, ProgressEvent};
+pub use pixels::{compress_rgb, compress_rgba, OutputFormat};
+pub use rgb_image::{compress_many, OutputSpec};
+pub use utilities::{Background, Dpi, Encoded, ImageOptions, PngFilter, ScanPreset, Tonemap};
@@ -0,0 +1,299 @@
+//! 表示した行を、時刻を付けてファイルにも残す（`--log-file`）。
+//!
+//! 見ていられない定期実行でも、何を処理してどこで失敗したかを後から確かめるためのもの。
+//! ffmpeg などの子プロセスの出力も含めて残すよう、標準出力と標準エラーのファイル記述子をパイプに付け替えて読み、
+//! 読んだ内容はそのまま元の出力先にも書く。ファイル記述子を付け替えるため Unix でしか使えない。
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use crate::utilities::Level;
+
+/// 終えるときに、パイプに溜まっている分を読む時間の上限
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// 付け替えた出力。落とすと元の出力先に戻し、その時点で届いている行を書き終えるまで待つ
+pub struct LogFile {
+    /// 付け替えたファイル記述子（1 と 2）と、付け替える前の出力先
+    saved: Vec<(RawFd, OwnedFd)>,
+    /// 閉じると、読む側に残りを書いて終えるよう知らせる
+    stop: Option<File>,
+    reader: Option<JoinHandle<()>>,
+}
+
+/// 付け替えた1つの出力
+struct Source {
+    pipe: File,
+    echo: File,
+    /// 改行が届いていない行
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl LogFile {
+    /// `path` に追記で書き始める。前の実行の記録は消さない
+    pub fn start(path: &Path) -> io::Result<LogFile> {
+        let log = File::options().create(true).append(true).open(path)?;
+        // 途中で失敗したら、落とすときに付け替えた分だけを元に戻す
+        let mut redirected = LogFile { saved: Vec::new(), stop: None, reader: None };
+        let mut sources = Vec::new();
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            let (original, pipe, echo) = redirect(fd)?;
+            redirected.saved.push((fd, original));
+            sources.push(Source { pipe, echo, pending: Vec::new(), closed: false });
+        }
+        let (stop_read, stop_write) = pipe()?;
+        redirected.stop = Some(File::from(stop_write));
+        Level::set_marking(true);
+        // 標準出力と標準エラーを1つのスレッドで読み、書かれた順になるべく近い順に表示する
+        let stop = File::from(stop_read);
+        redirected.reader = Some(std::thread::spawn(move || relay(sources, stop, log)));
+        Ok(redirected)
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        Level::set_marking(false);
+        for (fd, original) in self.saved.drain(..) {
+            unsafe {
+                libc::dup2(original.as_raw_fd(), fd);
+            }
+        }
+        // 裏で動き続ける子プロセス（--post-hook が起動したものなど）がパイプを開いたままだと読み終わりが来ないため、
+        // 読み終わりを待たずに、届いている分を書いたら終えるよう知らせる
+        self.stop.take();
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// 子プロセスに引き継がないパイプ（読む側, 書く側）
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // ここから先はどの道を通っても閉じる
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [&read, &write] {
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((read, write))
+}
+
+/// 子プロセスに引き継がない `fd` の複製
+fn duplicate(fd: RawFd) -> io::Result<OwnedFd> {
+    let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if copy < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(copy) })
+}
+
+/// `fd` をパイプに付け替え、元の出力先の複製（戻す用）、パイプの読む側、元の出力先の複製（表示用）を返す。
+/// 付け替えた `fd` だけは子プロセスに引き継ぎ、ffmpeg などの出力も読めるようにする。失敗した場合は作ったものをすべて閉じる
+fn redirect(fd: RawFd) -> io::Result<(OwnedFd, File, File)> {
+    let (read, write) = pipe()?;
+    let original = duplicate(fd)?;
+    let echo = duplicate(fd)?;
+    if unsafe { libc::dup2(write.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((original, File::from(read), File::from(echo)))
+}
+
+impl Source {
+    /// 1回読み、すぐ元の出力先に写し（改行のない確認の問いもすぐ表示するため）、行がそろったらログに書く
+    fn read(&mut self, log: &mut File, buffer: &mut [u8]) {
+        match self.pipe.read(buffer) {
+            Ok(0) | Err(_) => {
+                self.closed = true;
+                self.finish(log);
+            }
+            Ok(read) => {
+                let shown: Vec<u8> = buffer[..read].iter().copied().filter(|&byte| !Level::is_marker(byte)).collect();
+                let _ = self.echo.write_all(&shown);
+                let lines = take_lines(&mut self.pending, &buffer[..read]);
+                write_lines(log, lines);
+            }
+        }
+    }
+
+    /// 改行が届かないまま終わった行を書く
+    fn finish(&mut self, log: &mut File) {
+        let rest = std::mem::take(&mut self.pending);
+        if !rest.is_empty() {
+            write_lines(log, vec![String::from_utf8_lossy(&rest).into_owned()]);
+        }
+    }
+}
+
+/// 読めるようになった出力から読む。`stop` が閉じられたら、その時点で届いている分を書いて終える
+fn relay(mut sources: Vec<Source>, stop: File, mut log: File) {
+    let mut buffer = [0; 8192];
+    while sources.iter().any(|source| !source.closed) {
+        let mut fds = poll_fds(&sources);
+        fds.push(libc::pollfd { fd: stop.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        if fds.last().is_some_and(|fd| fd.revents != 0) {
+            drain(&mut sources, &mut log, &mut buffer);
+            break;
+        }
+        for (source, fd) in sources.iter_mut().zip(&fds) {
+            if fd.revents != 0 {
+                source.read(&mut log, &mut buffer);
+            }
+        }
+    }
+    for source in &mut sources {
+        source.finish(&mut log);
+    }
+}
+
+/// パイプに溜まっている分を読む。書き続ける子プロセスがいても [`DRAIN_TIMEOUT`] で打ち切る
+fn drain(sources: &mut [Source], log: &mut File, buffer: &mut [u8]) {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while Instant::now() < deadline {
+        let mut fds = poll_fds(sources);
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0) } <= 0 {
+            break;
+        }
+        for (source, fd) in sources.iter_mut().zip(&fds) {
+            if fd.revents != 0 {
+                source.read(log, buffer);
+            }
+        }
+    }
+}
+
+/// まだ閉じていない出力を読めるまで待つための一覧（閉じたものは -1 にして poll に無視させる）
+fn poll_fds(sources: &[Source]) -> Vec<libc::pollfd> {
+    sources
+        .iter()
+        .map(|source| libc::pollfd { fd: if source.closed { -1 } else { source.pipe.as_raw_fd() }, events: libc::POLLIN, revents: 0 })
+        .collect()
+}
+
+/// 行を、書いたときに指定した種類（[`Level::split`]）を付けてログに書く
+fn write_lines(log: &mut File, lines: Vec<String>) {
+    if lines.is_empty() {
+        return;
+    }
+    let now = timestamp(SystemTime::now());
+    for line in lines {
+        let (level, line) = Level::split(&line);
+        let _ = writeln!(log, "{now} {:<5} {line}", name(level));
+    }
+}
+
+/// ログに書く種類の名前
+fn name(level: Level) -> &'static str {
+    match level {
+        Level::Info => "INFO",
+        Level::Warn => "WARN",
+        Level::Error => "ERROR",
+    }
+}
+
+/// `pending` に `chunk` を足し、改行で終わった行を取り出す。`\r` で上書きしながら表示する行も1行ずつにする
+fn take_lines(pending: &mut Vec<u8>, chunk: &[u8]) -> Vec<String> {
+    pending.extend_from_slice(chunk);
+    let Some(end) = pending.iter().rposition(|&b| b == b'\n' || b == b'\r') else {
+        return Vec::new();
+    };
+    let complete: Vec<u8> = pending.drain(..=end).collect();
+    String::from_utf8_lossy(&complete)
+        .split(['\n', '\r'])
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// ローカル時刻の `2026-10-14 09:30:00.123`
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&seconds, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 改行までをまとめて行にし、途中の行は次に読んだ分とつなげること
+    #[test]
+    fn splits_lines_across_chunks() {
+        let mut pending = Vec::new();
+        assert!(take_lines(&mut pending, "rgb im".as_bytes()).is_empty());
+        assert_eq!(take_lines(&mut pending, "age: a\n警告: b\n失".as_bytes()), ["rgb image: a", "警告: b"]);
+        assert_eq!(take_lines(&mut pending, "敗\r\n".as_bytes()), ["失敗"]);
+        assert!(pending.is_empty());
+        assert_eq!(timestamp(SystemTime::now()).len(), "2026-10-14 09:30:00.123".len());
+    }
+
+    /// 種類は行の内容からではなく、書いたときの指定から決めること
+    #[test]
+    fn classifies_lines_by_marker() {
+        assert_eq!(Level::split("\u{1}圧縮できませんでした"), (Level::Error, "圧縮できませんでした"));
+        assert_eq!(Level::split("\u{2}警告: b"), (Level::Warn, "警告: b"));
+        // 進み具合や ffmpeg の出力は、標準エラーに書かれても失敗ではない
+        assert_eq!(Level::split("[3/10] photo.jpg"), (Level::Info, "[3/10] photo.jpg"));
+        assert_eq!(Level::split("警告で始まる ffmpeg の行"), (Level::Info, "警告で始まる ffmpeg の行"));
+    }
+
+    /// 書く側を開いたままの子プロセスがいても、知らせれば届いている分を書いて終え、目印は画面にもログにも残さないこと
+    #[test]
+    fn stops_without_waiting_for_writers() {
+        let dir = std::env::temp_dir().join("compressor_log_relay_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (read, write) = pipe().unwrap();
+        let (stop_read, stop_write) = pipe().unwrap();
+        let echo = File::create(dir.join("echo")).unwrap();
+        let log = File::create(dir.join("log")).unwrap();
+        let source = Source { pipe: File::from(read), echo, pending: Vec::new(), closed: false };
+        let reader = std::thread::spawn(move || relay(vec![source], File::from(stop_read), log));
+
+        // 裏で動き続ける子プロセスの代わりに、書く側を閉じずに持ち続ける
+        let mut writer = File::from(write);
+        writer.write_all("\u{1}失敗\n途中".as_bytes()).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        drop(stop_write);
+        reader.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(std::fs::read_to_string(dir.join("echo")).unwrap(), "失敗\n途中");
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let kinds: Vec<&str> = log.lines().map(|line| line.split_whitespace().nth(2).unwrap()).collect();
+        assert_eq!(kinds, ["ERROR", "INFO"]);
+        assert!(log.contains(" ERROR 失敗\n") && log.ends_with(" INFO  途中\n"));
+        drop(writer);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,45 +1,317 @@
-use std::{collections::HashSet, fs, path::PathBuf};
-use clap::Parser;
+use std::{collections::{BTreeMap, HashMap, HashSet}, ffi::OsString, fs, num::NonZeroUsize, path::{Path, PathBuf}, process::ExitCode, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::{Duration, Instant, SystemTime}};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use error::CompressError;
+use image::RgbaImage;
+use utilities::{failure, warning};
 mod file;
 mod utilities;
 mod error;
 mod rgb_image;
+// ライブラリの公開 API と同じく公開しておく（バイナリ自身は使わない）
+pub use rgb_image::{compress_many, OutputSpec};
 mod rgba_image;
 mod webp_image;
 mod gif_image;
 mod video;
 mod audio;
+mod job;
+mod exif;
+mod jpeg_transform;
+mod rename;
+mod prompt;
+mod report;
+mod config;
+mod geometry;
+mod watermark;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod input_list;
+mod content;
+mod scheduler;
+mod batch;
+mod contact_sheet;
+mod sprite;
+mod frames;
+mod manifest;
+mod encoders;
+mod ignore;
+mod similarity;
+mod sniff;
+mod preview;
+mod archive;
+#[cfg(unix)]
+mod log_file;
+mod tiff_image;
+mod hook;
+mod incremental;
+mod html_report;
+mod xmp_instruction;
 
-#[derive(Parser)]
+/// 対応していない形式のファイルの扱い
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum UnsupportedPolicy {
+    /// 何もせずスキップする
+    Skip,
+    /// 変換せずに出力先へそのままコピーする
+    Copy,
+    /// エラーとして実行を中断する
+    Error,
+}
+
+/// 画像の出力形式の選び方
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// 入力と同じ形式（--webp 指定時は WebP）
+    Keep,
+    /// png の内容から可逆（png）か非可逆（jpg）かを選ぶ。--webp 指定時はどちらも WebP
+    Auto,
+}
+
+/// 同じ実行の別の入力と出力先が重なった場合の扱い
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ConflictPolicy {
+    /// 元の拡張子や連番を付けた別の名前にする（既定）
+    Rename,
+    /// 後の入力の出力で置き換える（先の入力は処理しない）
+    Overwrite,
+    /// 先の入力の出力を残し、後の入力は処理しない
+    Skip,
+    /// エラーとして実行を中断する
+    Error,
+}
+
+/// 処理する順序
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FileOrder {
+    /// パス順（入力指定時は指定順）
+    Name,
+    /// サイズの大きい順
+    SizeDesc,
+    /// サイズの小さい順
+    SizeAsc,
+}
+
+#[derive(Parser, Clone)]
 struct AppArgs {
-    /// 圧縮済みファイルの保存先
+    /// 圧縮済みファイルの保存先。`{type}`（images, videos, audio, others）や `{ext}`（出力の拡張子）で種類ごとに分けられる
     #[clap(short, long, default_value = "compress")]
     output_dir: String,
     
-    /// 圧縮したいファイル（入力のない場合は全て）
-    #[clap(short, long, num_args = 1..)]
+    /// 出力ファイル名の付け方（seq: 連番, date: Exif の撮影日時, slug: 小文字・ハイフン区切り）。未指定時は元の名前
+    #[clap(long, value_enum)]
+    rename_scheme: Option<rename::RenameScheme>,
+
+    /// 同じ実行の別の入力と出力先が重なった場合の扱い（rename: 別の名前にする, overwrite: 後の入力で置き換える, skip: 先の入力を残す, error: 中断する）
+    #[clap(long, value_enum, value_name = "POLICY", default_value_t = ConflictPolicy::Rename)]
+    on_conflict: ConflictPolicy,
+
+    /// 出力先ディレクトリを使わず、元ファイルと同じ場所に接尾辞付きで出力する（例: .min → photo.min.jpg）
+    #[clap(long)]
+    suffix: Option<String>,
+
+    /// 1つのディレクトリのファイルが N 件を超える場合、出力を N 件ずつ番号付きのサブディレクトリ（000/, 001/, ...）に分ける
+    #[clap(long, value_name = "N", conflicts_with = "suffix", value_parser = clap::value_parser!(u32).range(1..))]
+    max_files_per_dir: Option<u32>,
+
+    /// 出力先が存在しない場合に作成せずエラーにする
+    #[clap(long)]
+    no_dir_create: bool,
+
+    /// 処理後、入力のサブディレクトリ（空のものも含む）をすべて出力先に作成する
+    #[clap(long, conflicts_with_all = ["suffix", "input_file"])]
+    keep_structure_empty_dirs: bool,
+
+    /// 圧縮したいファイル（入力のない場合は全て）。繰り返し指定やカンマ区切りも可
+    #[clap(short, long, visible_alias = "input", num_args = 1.., value_delimiter = ',')]
     input_file: Option<Vec<PathBuf>>,
 
-    /// RGB画像の圧縮率
-    #[clap(short, long, default_value="70.0")]
+    /// 入力ファイルの一覧（1行に1ファイル。パスの後ろにそのファイルだけに効くオプションを書ける）
+    #[clap(long, value_name = "FILE", conflicts_with = "input_file")]
+    input_list: Option<PathBuf>,
+
+    /// 静止画の圧縮率。数値のほか low (50), medium (70), high (85), max (95) で指定できる（動画には効かない）
+    #[clap(short, long, visible_alias = "image-quality", default_value="70.0", value_parser = utilities::parse_quality)]
     quality: f32,
 
-    /// 圧縮済みファイルを上書きして再圧縮するか
+    /// 削減率（%）がこの値に届かない場合は圧縮結果を捨て、元のファイルをそのまま出力する
+    #[clap(long)]
+    min_reduction: Option<f64>,
+
+    /// 圧縮済みファイルを上書きして再圧縮するか（--interactive で上書き前に確認できる）
     #[clap(short, long)]
     force: bool,
 
+    /// --force で上書きする前にファイルごとに確認する
+    #[clap(long)]
+    interactive: bool,
+
+    /// 出力があるかどうかを確認せず、常にすべてのファイルを処理する（確認なしで上書き。--interactive とは併用できない）
+    #[clap(long, conflicts_with = "interactive")]
+    no_skip: bool,
+
+    /// 出力ごとに元のチェックサムと設定を出力先の記録ファイル（.compressor-outputs）に残し、再実行では元か設定が変わった出力だけを作り直す
+    #[clap(long, conflicts_with = "no_skip")]
+    incremental: bool,
+
+    /// --quality がこれより低いと、打ち間違いでないか確認してから圧縮する
+    #[clap(long, value_name = "QUALITY", default_value = "30")]
+    min_safe_quality: f32,
+
+    /// --crf がこれより高いと、打ち間違いでないか確認してから圧縮する
+    #[clap(long, value_name = "CRF", default_value = "40")]
+    max_safe_crf: u8,
+
+    /// 極端に低い品質の確認を省いて圧縮する（端末でない場合は、付けないと中断する）
+    #[clap(short, long)]
+    yes: bool,
+
+    /// 画像の画素数の上限（メガピクセル）。超える画像はデコードせずにエラーにする。0 で無制限
+    #[clap(long, default_value = "100")]
+    max_megapixels: f64,
+
+    /// 画像1枚あたりの PNG 最適化の時間の上限（秒）。超えるとそれまでの結果で打ち切る。0 で無制限
+    #[clap(long, value_name = "SECS", default_value = "60")]
+    encode_timeout_image: u64,
+
+    /// PNG の最適化で oxipng に試させる行フィルタ（カンマ区切り。none, sub, up, average, paeth, minsum, entropy, bigrams, bigent, brute）。既定は oxipng に任せる
+    #[clap(long, value_enum, value_name = "LIST", value_delimiter = ',')]
+    png_filters: Vec<utilities::PngFilter>,
+
+    /// 画像の解像度（DPI）を指定した値で上書きする。未指定時は元ファイルの解像度を引き継ぐ
+    #[clap(long, conflicts_with = "strip_dpi")]
+    dpi: Option<u16>,
+
+    /// 画像の解像度（DPI）を出力に書き込まない
+    #[clap(long)]
+    strip_dpi: bool,
+
+    /// 画像を切り抜く（WxH+X+Y。例: 800x600+100+50）。位置は見た目の向きの左上から数える
+    #[clap(long, value_name = "WxH+X+Y", value_parser = geometry::parse_crop, conflicts_with = "crop_center")]
+    crop: Option<geometry::Crop>,
+
+    /// 画像を中央から切り抜く（WxH。例: 512x512）。画像より大きい場合は画像の寸法に収める
+    #[clap(long, value_name = "WxH", value_parser = geometry::parse_crop_center)]
+    crop_center: Option<geometry::Crop>,
+
+    /// 見える透かしを重ねる（png/jpg/webp ならロゴ、それ以外はテキストファイルの中身の文字列）
+    #[clap(long, value_name = "FILE")]
+    watermark: Option<PathBuf>,
+
+    /// 透かしを置く位置
+    #[clap(long, value_enum, value_name = "POSITION", default_value_t = watermark::Position::BottomRight)]
+    watermark_position: watermark::Position,
+
+    /// 透かしの不透明度（0 より大きく 1 以下）
+    #[clap(long, value_name = "OPACITY", default_value = "0.5", value_parser = watermark::parse_opacity)]
+    watermark_opacity: f32,
+
+    /// 透かしと画像の端との余白（ピクセル）
+    #[clap(long, value_name = "PX", default_value = "16")]
+    watermark_margin: u32,
+
+    /// jpg をプログレッシブではなくベースラインで出力する（古いハードウェアデコーダ向け）
+    #[clap(long)]
+    baseline: bool,
+
+    /// jpg のプログレッシブのスキャンの分け方を画像ごとに探して小さくする（エンコードは数倍遅くなる。保管向け）
+    #[clap(long, conflicts_with = "baseline")]
+    jpeg_optimize_scans: bool,
+
+    /// jpg のプログレッシブの最初のスキャンの組み方（preview-first: 全成分の直流成分を先に送り、すぐカラーの縮小表示を出せるようにする）
+    #[clap(long, value_enum, default_value_t = utilities::ScanPreset::Default, conflicts_with_all = ["baseline", "jpeg_optimize_scans"])]
+    scan_preset: utilities::ScanPreset,
+
+    /// jpg に MCU（既定の 4:2:0 では 16x16 画素）の数ごとのリスタートマーカーを入れる（伝送中に壊れても次のマーカーから復帰できる）。
+    /// 出力はプログレッシブではなく1つのスキャンになり、少し大きくなる
+    #[clap(long, value_name = "MCUS", value_parser = clap::value_parser!(u16).range(1..), conflicts_with_all = ["jpeg_optimize_scans", "scan_preset"])]
+    jpeg_restart_interval: Option<u16>,
+
+    /// jpg の Exif の向きを、再エンコードせずに可逆変換（ブロック単位の回転・反転）でピクセルへ反映する
+    #[clap(long, conflicts_with_all = ["crop", "crop_center", "watermark", "webp", "baseline", "exif_source", "copy_exif_from_sibling"])]
+    lossless_orient: bool,
+
+    /// 中身（先頭のシグネチャ）が拡張子と合わないファイルは、警告して処理しない（信用できないアップロード向け）
+    #[clap(long)]
+    strict_extensions: bool,
+
+    /// 文字や線画の多い jpg は、品質を上げて色差を間引かずに出力する（スクリーンショットや図表向け）
+    #[clap(long)]
+    text_aware: bool,
+
     /// 画像をWebPで出力する（jpg/jpeg→非可逆, png→可逆）
     #[clap(short, long)]
     webp: bool,
 
+    /// 画像の出力形式の選び方（auto: 透過や色数から png を可逆・非可逆に振り分ける）
+    #[clap(long, value_enum, default_value_t = OutputFormat::Keep)]
+    format: OutputFormat,
+
+    /// jpg出力時、同じ名前の別のJPEG（例: photo.tif に対する photo.jpg）の Exif を引き継ぐ
+    #[clap(long)]
+    copy_exif_from_sibling: bool,
+
+    /// jpg出力時、指定したJPEGの Exif を引き継ぐ（--copy-exif-from-sibling より優先）
+    #[clap(long)]
+    exif_source: Option<PathBuf>,
+
+    /// 画像の Exif から位置情報（GPS）だけを消す。撮影日時やカメラなどの他の Exif は残す
+    #[clap(long)]
+    strip_gps: bool,
+
     /// 動画をHEVC(H.265)で出力する（既定はAV1。HEVCは旧来デバイスでの再生互換性が高い）
     #[clap(long)]
     hevc: bool,
 
-    /// 動画の品質 (CRF)。低いほど高品質・大きいファイル。未指定時はコーデックごとの既定値
-    #[clap(long)]
+    /// 動画の品質 (CRF)。低いほど高品質・大きいファイル。未指定時はコーデックごとの既定値（静止画には効かない）
+    #[clap(long, visible_alias = "video-crf")]
     crf: Option<u8>,
 
+    /// 動画のエンコード速度（遅いほど同じ品質で小さくなる）
+    #[clap(long, value_enum, default_value_t = video::VideoPreset::Slow)]
+    video_preset: video::VideoPreset,
+
+    /// アンシャープマスクをかける強さ（0 より大きく 1.5 以下。既定はかけない）。jpg と png の出力はエンコードの前に、動画は縮小した場合にかける
+    #[clap(long, value_name = "AMOUNT", value_parser = utilities::parse_sharpen)]
+    sharpen: Option<f32>,
+
+    /// 動画をフル HD に縮小するとき、高さをこの倍数にそろえる（エンコーダによっては 4 や 16 の倍数が必要）
+    #[clap(long, value_enum, value_name = "N", default_value_t = video::ScaleAlign::Two)]
+    scale_align: video::ScaleAlign,
+
+    /// 動画を指定した秒数ごとの区間に分けてエンコードしてからつなぐ。中断しても次の実行で終わった区間から再開する
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u32).range(1..))]
+    video_segments: Option<u32>,
+
+    /// 動画の音声の音量を EBU R128（loudnorm）で揃える。two-pass は先に音量を測ってから補正する
+    #[clap(long, value_enum, value_name = "MODE", num_args = 0..=1, default_missing_value = "one-pass")]
+    normalize_audio: Option<video::NormalizeAudio>,
+
+    /// 回転の情報を持つ動画（縦に撮ったスマートフォンの動画など）の扱い（bake: 表示の向きに回してエンコードする, keep: 回さずに回転の情報を引き継ぐ）
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = video::Autorotate::Bake)]
+    video_autorotate: video::Autorotate,
+
+    /// 動画のキーフレームの間隔（48 のようなフレーム数か、2s のような秒数）。短いほどシークしやすく、配信の区間に分けやすいがファイルは大きくなる
+    #[clap(long, value_name = "FRAMES|SECONDS", value_parser = video::parse_keyframe_interval)]
+    keyframe_interval: Option<video::KeyframeInterval>,
+
+    /// 動画の圧縮時に実行する ffmpeg コマンドを表示する
+    #[clap(long)]
+    print_ffmpeg_command: bool,
+
+    /// ffmpeg 自身のログの詳しさ（調査時は info）
+    #[clap(long, value_enum, value_name = "LEVEL", default_value_t = utilities::FfmpegLogLevel::Error)]
+    ffmpeg_loglevel: utilities::FfmpegLogLevel,
+
+    /// PDF のページを画像にするときの解像度
+    #[cfg(feature = "pdf")]
+    #[clap(long, value_name = "DPI", default_value = "150", value_parser = clap::value_parser!(u16).range(1..))]
+    pdf_dpi: u16,
+
+    /// PDF を組み直すのに加え、圧縮したページの JPEG を出力と同じ名前のディレクトリに書き出す
+    #[cfg(feature = "pdf")]
+    #[clap(long)]
+    pdf_pages: bool,
+
     /// 音声をOpusで出力する（既定はAAC）。非可逆圧縮時のみ有効
     #[clap(long)]
     opus: bool,
@@ -47,172 +319,1675 @@ struct AppArgs {
     /// 音声の非可逆圧縮時のビットレート
     #[clap(long, default_value = "128k")]
     audio_bitrate: String,
+
+    /// ファイルごとの出力を抑え、最後の集計結果だけを表示する
+    #[clap(long)]
+    summary_only: bool,
+
+    /// 圧縮を終えるたびに、終えた件数と総数、サイズの変化を標準エラー出力に表示する
+    #[clap(long)]
+    progress: bool,
+
+    /// --progress の行を、終わった順ではなく入力の順に表示する（並列のまま。CI のログを比べやすくする）
+    #[clap(long)]
+    ordered_output: bool,
+
+    /// HDR 画像を 8bit に落とすときの扱い（none: 範囲外ならエラー, reinhard: トーンマッピング, clip: 切り捨て）
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = utilities::Tonemap::Clip)]
+    tonemap: utilities::Tonemap,
+
+    /// 透過した画像を JPEG にするとき、透過した部分を合成する背景色（#ffffff のような16進数か、white, black などの名前）
+    #[clap(long, value_name = "COLOR", default_value = "white", value_parser = utilities::parse_background)]
+    flatten_background: utilities::Background,
+
+    /// 画像に埋め込む追跡用の文字列（例: build=1234。jpg は COM マーカー、png は tEXt チャンク）
+    #[clap(long, value_name = "TEXT", value_parser = utilities::parse_tag)]
+    tag: Option<String>,
+
+    /// 既存のファイルを上書きする前に、そのファイルをこのディレクトリへ退避する
+    #[clap(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// 作業ファイル（アニメーションのフレーム、PDF のページ、--video-segments の区間）を置くディレクトリ。既定はシステムの一時ディレクトリ
+    #[clap(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// ファイルごとの処理結果を JSON で書き出す
+    #[clap(long, value_name = "FILE")]
+    json_report: Option<PathBuf>,
+
+    /// 表示したすべての行（ffmpeg の出力を含む）を、時刻と種類（INFO/WARN/ERROR）を付けてこのファイルに追記する
+    #[clap(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// 出力を書き終えたファイルごとに実行するコマンド。{input}, {output}, {reduction}（削減率 %）を置き換える
+    #[clap(long, value_name = "COMMAND")]
+    post_hook: Option<String>,
+
+    /// ファイルごとの処理結果を CSV で書き出す（表計算ソフト向け）
+    #[clap(long, value_name = "FILE")]
+    csv_report: Option<PathBuf>,
+
+    /// ファイルごとの処理結果を、元と圧縮後のサムネイル付きの HTML で書き出す（1つのファイルだけで開ける）
+    #[clap(long, value_name = "FILE")]
+    html_report: Option<PathBuf>,
+
+    /// 画像の XMP のこの項目（例: dam:compress）に書かれた指示で、ファイルごとに品質や形式を変える。
+    /// 値は .compressorrc と同じ key = value を ; で区切ったものか、--xmp-preset の名前
+    #[clap(long, value_name = "PREFIX:NAME")]
+    xmp_instruction: Option<String>,
+
+    /// --xmp-instruction の値として使える名前付きの設定（例: web-optimize="quality = 60; webp = true"）。繰り返し指定できる
+    #[clap(long, value_name = "NAME=SETTINGS", value_parser = xmp_instruction::parse_preset, requires = "xmp_instruction")]
+    xmp_preset: Vec<(String, config::DirConfig)>,
+
+    /// 対応していない形式のファイルの扱い
+    #[clap(long, value_enum, default_value_t = UnsupportedPolicy::Skip)]
+    on_unsupported: UnsupportedPolicy,
+
+    /// 対応していない形式のファイル（CSS, JS, HTML など）を、変換せずに更新日時ごと出力先へコピーする（--on-unsupported copy と同じ）
+    #[clap(long, conflicts_with = "on_unsupported")]
+    copy_others: bool,
+
+    /// 圧縮する前に、入力の BLAKE3 のチェックサムを一覧（b3sum の出力と同じ形式）と照合する
+    #[clap(long, value_name = "MANIFEST")]
+    verify_inputs: Option<PathBuf>,
+
+    /// --verify-inputs でチェックサムが合わない・一覧にない入力の扱い
+    #[clap(long, value_enum, value_name = "POLICY", default_value_t = manifest::MismatchPolicy::Abort, requires = "verify_inputs")]
+    on_mismatch: manifest::MismatchPolicy,
+
+    /// 最初に失敗したファイルで処理を打ち切り、残りのファイルは処理しない
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// 起動から指定した分数を過ぎたら新しいファイルの圧縮を始めない（実行中のファイルは最後まで処理する）
+    #[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    max_runtime: Option<u64>,
+
+    /// 対応している形式のファイルを、処理順に最大 N 件だけ処理する（設定を試す用途）
+    #[clap(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// 入力のうち更新日時の新しい N 件だけを処理する（新しい順に処理する）
+    #[clap(long, value_name = "N", conflicts_with = "oldest")]
+    newest: Option<usize>,
+
+    /// 入力のうち更新日時の古い N 件だけを処理する（古い順に処理する）
+    #[clap(long, value_name = "N")]
+    oldest: Option<usize>,
+
+    /// 処理する順序（name は従来どおりのパス順）
+    #[clap(long, value_enum, default_value_t = FileOrder::Name)]
+    order: FileOrder,
+
+    /// 画像（ffmpeg を使わない処理）を同時に圧縮する数。既定は CPU 数
+    #[clap(long, value_name = "N")]
+    image_jobs: Option<NonZeroUsize>,
+
+    /// 動画・音声（ffmpeg を使う処理）を同時に圧縮する数。既定は CPU 数の 1/4
+    #[clap(long, value_name = "N")]
+    video_jobs: Option<NonZeroUsize>,
+
+    /// 処理を決める前に、動画の ffprobe を同時に動かす数。既定は CPU 数
+    #[clap(long, value_name = "N", alias = "max-concurrent-ffprobe")]
+    probe_jobs: Option<NonZeroUsize>,
+
+    /// 同時に圧縮する処理の見積もりメモリの合計の上限（MB）。大きな画像は空きができるまで始めない
+    #[clap(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    max_memory: Option<u64>,
+
+    /// 処理した画像のサムネイルを並べた一覧画像（JPEG）をこのパスに書き出す
+    #[clap(long, value_name = "FILE")]
+    contact_sheet: Option<PathBuf>,
+
+    /// 出力を出力先と同じディレクトリ構成で tar.gz にまとめ、このパスに書き出す（出力のファイルも残す）
+    #[clap(long, value_name = "FILE")]
+    output_archive: Option<PathBuf>,
+
+    /// 一覧画像の列数
+    #[clap(long, value_name = "N", default_value = "6", value_parser = clap::value_parser!(u32).range(1..))]
+    contact_sheet_columns: u32,
+
+    /// 一覧画像のサムネイルの大きさ（縦横ともこのピクセル数に収める）
+    #[clap(long, value_name = "PX", default_value = "240", value_parser = clap::value_parser!(u32).range(1..))]
+    contact_sheet_size: u32,
+
+    /// 動画を圧縮する代わりに、フレームを連番の jpg として書き出す（2fps のように1秒あたりの枚数か、全体の枚数）
+    #[clap(long, value_name = "FPS|COUNT", value_parser = frames::parse_selection, conflicts_with_all = ["sprite_sheet", "video_segments"])]
+    extract_frames: Option<frames::FrameSelection>,
+
+    /// 複数ページの tiff を、ページごとに書き出す代わりに N ページ目（1 から）だけ圧縮する
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    tiff_page: Option<u32>,
+
+    /// 動画ごとに、等間隔のフレームを並べたスプライトシート（clip.sprite.jpg）を出力の隣に書き出す
+    #[clap(long)]
+    sprite_sheet: bool,
+
+    /// スプライトシートに並べるフレームの数
+    #[clap(long, value_name = "N", default_value = "20", value_parser = clap::value_parser!(u32).range(1..))]
+    sprite_frames: u32,
+
+    /// スプライトシートの列数
+    #[clap(long, value_name = "N", default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+    sprite_columns: u32,
+
+    /// スプライトシートのタイルの大きさ（縦横ともこのピクセル数に収める）
+    #[clap(long, value_name = "PX", default_value = "160", value_parser = clap::value_parser!(u32).range(1..))]
+    sprite_size: u32,
+
+    /// 静止画ごとに元の画像との差（SSIM・PSNR）を求めて表示し、JSON レポートに載せる
+    #[clap(long)]
+    diff_report: bool,
+
+    /// SSIM がこの値に届かない静止画を品質の基準未満として報告し、終了コードを 1 にする（--diff-report を含む）
+    #[clap(long, value_name = "SSIM", value_parser = similarity::parse_min_score)]
+    min_quality_score: Option<f64>,
+
+    /// 出力のパーミッション（Unix のモード）を入力に合わせる。Unix 以外では何もしない
+    #[clap(long)]
+    preserve_permissions: bool,
+
+    /// 拡張子を対応している形式の拡張子として扱う（例: "jpe=jpg,qt=mov"）。.jpe/.jfif などは指定しなくても jpg として扱う
+    #[clap(long, value_name = "FROM=TO,...", value_parser = file::parse_extension_map, default_value = "", hide_default_value = true)]
+    ext_map: file::ExtensionMap,
+
+    /// 実行の最後に、ファイルごとの削減率の分布を棒グラフで表示する
+    #[clap(long)]
+    histogram: bool,
+
+    /// 寸法（WxH）に収まる小さな画像は圧縮もコピーもしない。幅か高さのどちらかが超える画像だけを処理する
+    #[clap(long, value_name = "WxH", value_parser = geometry::parse_size)]
+    only_larger_than: Option<(u32, u32)>,
+
+    /// 最初に見つかった静止画だけを今の設定で圧縮し、元と圧縮後の同じ範囲を左右に並べた PNG をこのパスに書き出す（全体は処理しない）
+    #[clap(long, value_name = "FILE", conflicts_with = "compare_encoders")]
+    preview: Option<PathBuf>,
+
+    /// --preview で比べる範囲（WxH+X+Y。既定は中央の 512x512）
+    #[clap(long, value_name = "WxH+X+Y", value_parser = geometry::parse_crop, requires = "preview")]
+    preview_region: Option<geometry::Crop>,
+
+    /// jpg/png/webp を入力と同じ形式でメモリ上に圧縮し、元より大きくなるファイルを一覧にする（何も書き出さない）
+    #[clap(long, conflicts_with_all = ["compare_encoders", "preview"])]
+    find_bloat: bool,
+
+    /// 画像をデコードし、動画と音声を ffprobe で読んで、読めない（壊れた）ファイルを一覧にする（何も書き出さない）
+    #[clap(long, conflicts_with_all = ["compare_encoders", "preview", "find_bloat"])]
+    check: bool,
+
+    /// 開発用: jpg/png ごとに mozjpeg と image クレートの JPEG エンコーダを比べて表示する（出力は書き出さない）
+    #[clap(long, hide = true)]
+    compare_encoders: bool,
+
+    /// 開発用: --compare-encoders で小さかったほうの JPEG を出力先に書き出す
+    #[clap(long, hide = true, requires = "compare_encoders")]
+    keep_winner: bool,
 }
 
-fn main() {
-    let args = AppArgs::parse();
+/// 実行全体に関わるため、--input-list の行ごとには指定できない引数
+const GLOBAL_ONLY_ARGS: [&str; 44] = [
+    "input_file",
+    "input_list",
+    "verify_inputs",
+    "on_mismatch",
+    "output_dir",
+    "suffix",
+    "on_conflict",
+    "max_files_per_dir",
+    "no_dir_create",
+    "keep_structure_empty_dirs",
+    "backup_dir",
+    "temp_dir",
+    "json_report",
+    "csv_report",
+    "html_report",
+    "xmp_instruction",
+    "xmp_preset",
+    "summary_only",
+    "progress",
+    "ordered_output",
+    "fail_fast",
+    "max_files",
+    "max_runtime",
+    "newest",
+    "oldest",
+    "order",
+    "image_jobs",
+    "video_jobs",
+    "probe_jobs",
+    "max_memory",
+    "contact_sheet",
+    "output_archive",
+    "contact_sheet_columns",
+    "contact_sheet_size",
+    "histogram",
+    "preview",
+    "preview_region",
+    "find_bloat",
+    "min_safe_quality",
+    "max_safe_crf",
+    "yes",
+    "log_file",
+    "check",
+    "incremental",
+];
+
+/// 終了コード: 一部のファイルの圧縮に失敗した
+const EXIT_FAILED: u8 = 1;
+/// 終了コード: 処理を始める前の準備に失敗した（出力先を作成できない、FFmpegがないなど）
+const EXIT_FATAL: u8 = 2;
+
+fn main() -> ExitCode {
+    // --max-runtime は起動した時点から数える
+    let launched = Instant::now();
+    // 設定ファイルよりコマンドラインを優先するため、どの引数が明示されたかも取っておく
+    let matches = AppArgs::command().get_matches();
+    let mut args = match AppArgs::from_arg_matches(&matches) {
+        Ok(args) => args,
+        Err(e) => e.exit(),
+    };
+    // 終わるまで持っておき、main を抜けるときに残りの行を書き終える
+    #[cfg(unix)]
+    let _log_file = match &args.log_file {
+        Some(path) => match log_file::LogFile::start(path) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                failure!("ログファイルを開けません: {:?}: {e}", path);
+                return ExitCode::from(EXIT_FATAL);
+            }
+        },
+        None => None,
+    };
+    // 子プロセスの出力も残すためにファイル記述子を付け替えるので、Unix でしか使えない
+    #[cfg(not(unix))]
+    if args.log_file.is_some() {
+        failure!("--log-file は Unix 以外では使えません");
+        return ExitCode::from(EXIT_FATAL);
+    }
 
-    let mut input_files = args.input_file.unwrap_or_default();
-    if input_files.len() == 0 {
-        input_files = file::get_files(".");
+    // ファイルごとに引数を複製するため、入力の一覧は取り出しておく
+    let mut input_files = args.input_file.take().unwrap_or_default();
+    // --input-list の行ごとのオプションを反映した引数
+    let mut overrides: HashMap<PathBuf, (AppArgs, ArgMatches)> = HashMap::new();
+    if let Some(list) = &args.input_list {
+        let argv: Vec<OsString> = std::env::args_os().collect();
+        let entries = match input_list::load(list) {
+            Ok(entries) => entries,
+            Err(e) => {
+                failure!("入力の一覧を読めません: {e}");
+                return ExitCode::from(EXIT_FATAL);
+            }
+        };
+        for entry in entries {
+            if !entry.overrides.is_empty() {
+                let parsed = override_matches(&argv, &entry.overrides).and_then(|file_matches| {
+                    let file_args = AppArgs::from_arg_matches(&file_matches).map_err(clap_message)?;
+                    Ok((file_args, file_matches))
+                });
+                match parsed {
+                    Ok(parsed) => {
+                        overrides.insert(entry.path.clone(), parsed);
+                    }
+                    Err(e) => {
+                        failure!("入力の一覧のオプションが不正です: {:?}: {e}", entry.path);
+                        return ExitCode::from(EXIT_FATAL);
+                    }
+                }
+            }
+            input_files.push(entry.path);
+        }
+    } else if input_files.is_empty() {
+        input_files = file::get_files(Path::new("."));
+    }
+    if let Some(count) = args.newest {
+        file::keep_by_modified(&mut input_files, count, true);
+    } else if let Some(count) = args.oldest {
+        file::keep_by_modified(&mut input_files, count, false);
+    }
+    match args.order {
+        FileOrder::Name => {}
+        FileOrder::SizeDesc => file::sort_by_size(&mut input_files, true),
+        FileOrder::SizeAsc => file::sort_by_size(&mut input_files, false),
     }
 
-    std::fs::create_dir_all(&args.output_dir).unwrap();
+    // 開発用の比較は通常の圧縮を行わず、--keep-winner がなければ何も書き出さない
+    if args.compare_encoders {
+        return compare_encoders(&input_files, &args);
+    }
+    if let Some(output) = &args.preview {
+        return preview(&input_files, &args, output);
+    }
+    if args.find_bloat {
+        return find_bloat(&input_files, &args);
+    }
+    if args.check {
+        return check(&input_files, &args);
+    }
+
+    // 打ち間違いの品質で全体を劣化させないよう、何か書き出す前に確かめる。
+    // .compressorrc と XMP の指示による値は、ファイルごとに引数を決めたところで確かめる
+    let mut warned: Vec<String> = Vec::new();
+    let warnings = std::iter::once(&args).chain(overrides.values().map(|(file_args, _)| file_args)).flat_map(quality_warnings);
+    if !confirm_quality(warnings, &mut warned, args.yes, None) {
+        eprintln!("中断しました（確認せずに圧縮するには --yes を付けてください）");
+        return ExitCode::from(EXIT_FATAL);
+    }
+
+    // --output-dir に {type} などのテンプレートを使う場合も、すべての出力はこの下に入る
+    let output_root = file::output_dir_root(&args.output_dir);
+    if let Some(suffix) = &args.suffix {
+        // 空の接尾辞では元ファイルを上書きしてしまう
+        if suffix.is_empty() {
+            failure!("--suffix に空文字列は指定できません");
+            return ExitCode::from(EXIT_FATAL);
+        }
+    } else if output_root.as_os_str().is_empty() {
+        // 出力が入力と同じ階層に散らばり、再実行時に圧縮済みと判定できなくなる
+        failure!("--output-dir のテンプレートは固定のディレクトリから始めてください: {}", args.output_dir);
+        return ExitCode::from(EXIT_FATAL);
+    } else if args.keep_structure_empty_dirs && output_root != Path::new(&args.output_dir) {
+        // 種類ごとに分かれる出力先では、どの種類の下にディレクトリを作るか決められない
+        failure!("--keep-structure-empty-dirs はテンプレートを含む --output-dir と併用できません: {}", args.output_dir);
+        return ExitCode::from(EXIT_FATAL);
+    } else if args.no_dir_create {
+        if !output_root.is_dir() {
+            failure!("出力先ディレクトリが存在しません: {:?}", output_root);
+            return ExitCode::from(EXIT_FATAL);
+        }
+    } else if let Err(e) = file::ensure_dir(&output_root) {
+        failure!("出力先ディレクトリを作成できません: {:?}: {e}", output_root);
+        return ExitCode::from(EXIT_FATAL);
+    }
     let root_dir = PathBuf::from(".");
+    // 退避したファイルを次の実行で入力として拾わないよう、絶対パスで覚えておく
+    let backup_root = match &args.backup_dir {
+        Some(dir) => match file::ensure_dir(dir).and_then(|_| file::get_absolute_path(dir)) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                failure!("バックアップ先ディレクトリを作成できません: {:?}: {e}", dir);
+                return ExitCode::from(EXIT_FATAL);
+            }
+        },
+        None => None,
+    };
+    // 作業ファイルを書けないと動画や PDF がすべて失敗するため、先に確かめる。中の作業ファイルは入力として扱わない
+    let temp_root = match &args.temp_dir {
+        Some(dir) => match file::ensure_writable_dir(dir).and_then(|_| file::get_absolute_path(dir)) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                failure!("一時ディレクトリに書き込めません: {:?}: {e}", dir);
+                return ExitCode::from(EXIT_FATAL);
+            }
+        },
+        None => None,
+    };
+    // 透かしが読めないとすべての画像が失敗するため、先に確かめる
+    if let Some(path) = &args.watermark {
+        if let Err(e) = watermark::Mark::load(path) {
+            failure!("透かしを読み込めません: {:?}: {e}", path);
+            return ExitCode::from(EXIT_FATAL);
+        }
+    }
+    // チェックサムの一覧が読めなければ1件も照合できないため、先に確かめる
+    let manifest = match &args.verify_inputs {
+        Some(path) => match manifest::Manifest::load(path) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                failure!("チェックサムの一覧を読み込めません: {e}");
+                return ExitCode::from(EXIT_FATAL);
+            }
+        },
+        None => None,
+    };
+    // 記録が読めないまま進めると、すべての出力を作り直して記録も失うため、先に確かめる
+    let mut outputs = if args.incremental {
+        let base = if args.suffix.is_some() { PathBuf::from(".") } else { output_root.clone() };
+        match incremental::Outputs::load(&base) {
+            Ok(outputs) => Some(outputs),
+            Err(e) => {
+                failure!("出力の記録を読み込めません: {:?}: {e}", base.join(incremental::FILE_NAME));
+                return ExitCode::from(EXIT_FATAL);
+            }
+        }
+    } else {
+        None
+    };
+    // .compressorrc はカレントディレクトリから入力ファイルのディレクトリまでを探す
+    let config_root = file::get_absolute_path(&root_dir).unwrap_or_else(|_| root_dir.clone());
+    let mut configs = config::ConfigCache::default();
+
+    // --max-files-per-dir で番号付きのサブディレクトリに分ける入力と、その名前
+    let shards = args.max_files_per_dir.map(|max| file::shard_names(&input_files, max as usize)).unwrap_or_default();
 
     // 生成済みの出力先を記録し、同名衝突を回避する。
     // 出力の拡張子は入力より種類が少ない（jpeg→jpg, mov/mkv→mp4, mp3/ogg→m4a など）ため、
     // 拡張子だけ違う同名ファイルは出力先が衝突しうる。
     let mut used_outputs: HashSet<PathBuf> = HashSet::new();
+    // 出力先ごとの、それを書き出す tasks の位置（--on-conflict overwrite で後の入力に置き換える）
+    let mut planned_targets: HashMap<PathBuf, usize> = HashMap::new();
+    let mut summary = report::Summary::default();
+    // --interactive で "all" と答えた後は確認せずに上書きする
+    let mut overwrite_all = false;
+    // --rename-scheme seq の連番。対応している形式のファイルだけを数える
+    let mut renamed = 0;
+    // --max-files の対象として数えたファイル数
+    let mut planned = 0;
+    // 出力先まで決めて実行を待つ圧縮。すべて決めてから種類ごとのワーカーで並列に実行する
+    let mut tasks: Vec<Task> = Vec::new();
+    // 途中で打ち切った場合の終了コード。それまでに決めた分は実行する
+    let mut stopped: Option<u8> = None;
+    // --on-mismatch abort か --interactive の quit で打ち切った（先に決めた分も圧縮しない）
+    let mut aborted = false;
+
+    // 圧縮済みのファイル（出力先の中と、--suffix の付いたファイル）
+    let is_compressed = |input_file: &Path| match &args.suffix {
+        Some(suffix) => file::has_suffix(input_file, suffix),
+        None => input_file.to_string_lossy().contains(format!("/{}/", output_root.to_string_lossy()).as_str()),
+    };
+    // 動画の多いディレクトリでは処理を決めながら ffprobe を1本ずつ待つと始まるまでが長いため、先にまとめて並列で調べる
+    let videos: Vec<PathBuf> = input_files
+        .iter()
+        .filter(|input_file| !is_compressed(input_file) && video::is_video_extension(&args.ext_map.resolve(&file::extension_of(input_file))))
+        .filter_map(|input_file| file::get_absolute_path(input_file).ok())
+        .collect();
+    let probe_jobs = args.probe_jobs.map_or_else(|| scheduler::Pool::Image.default_jobs(), NonZeroUsize::get);
+    let probed = video::probe_streams_all(&videos, probe_jobs);
+    // --format auto で png の内容を判定するにはデコードが要るため、同じく先にまとめて並列で判定する
+    let pngs: Vec<PathBuf> = input_files
+        .iter()
+        .filter(|input_file| args.format == OutputFormat::Auto && !is_compressed(input_file))
+        .filter(|input_file| args.ext_map.resolve(&file::extension_of(input_file)) == "png")
+        .filter_map(|input_file| file::get_absolute_path(input_file).ok())
+        .collect();
+    let image_jobs = args.image_jobs.map_or_else(|| scheduler::Pool::Image.default_jobs(), NonZeroUsize::get);
+    let classified = content::classify_all(&pngs, image_jobs, args.max_megapixels);
 
     for input_file in input_files.iter() {
-        let filepath = input_file.to_str().unwrap();
-        let extension = input_file.extension();
+        if args.fail_fast && summary.errors > 0 {
+            break;
+        }
 
         // 圧縮済みのファイルはスキップする
-        if filepath.contains(format!("/{}/", &args.output_dir).as_str()) {
+        if is_compressed(input_file) {
+            continue;
+        }
+
+        // 設定ファイルと --incremental の記録自体は圧縮の対象にしない
+        if input_file.file_name().is_some_and(|name| name == config::FILE_NAME || name == incremental::FILE_NAME) {
             continue;
         }
 
         let filepath = match file::get_absolute_path(input_file) {
             Ok(path) => path,
             Err(e) => {
-                eprintln!("圧縮に失敗しました: {:?}: {e}", input_file);
+                failure!("圧縮に失敗しました: {:?}: {e}", input_file);
+                summary.record_failed(input_file, None, &e);
+                continue;
+            }
+        };
+
+        if backup_root.iter().chain(&temp_root).any(|dir| filepath.starts_with(dir)) {
+            continue;
+        }
+
+        // このファイルに効く --input-list のオプションと .compressorrc を反映した引数
+        let (base_args, file_matches) = match overrides.get(input_file) {
+            Some((file_args, file_matches)) => (file_args, file_matches),
+            None => (&args, &matches),
+        };
+        let args = match configs.resolve(&filepath, &config_root) {
+            Ok(mut config) => {
+                // XMP の指示は .compressorrc より優先し、コマンドラインと --input-list の行のオプションよりは優先しない
+                if let Some(key) = &base_args.xmp_instruction {
+                    let ext = base_args.ext_map.resolve(&file::extension_of(input_file));
+                    match xmp_instruction::instruction(&filepath, &ext, key, &base_args.xmp_preset) {
+                        Ok(Some(instruction)) => config.merge(&instruction),
+                        Ok(None) => {}
+                        Err(e) => warning!("警告: XMP の指示を使えないため、指示なしで処理します: {:?}: {e}", filepath),
+                    }
+                }
+                let mut file_args = base_args.clone();
+                config.apply(&mut file_args, |id| file_matches.value_source(id) == Some(ValueSource::CommandLine));
+                file_args
+            }
+            Err(e) => {
+                failure!("設定ファイルを読めません: {e}");
+                summary.record_failed(&filepath, None, &e);
+                continue;
+            }
+        };
+
+        // 先に確かめた値と同じなら聞き直さない。断ったら、先に決めた分も圧縮しない
+        if !confirm_quality(quality_warnings(&args), &mut warned, args.yes, Some(&filepath)) {
+            eprintln!("中断しました（確認せずに圧縮するには --yes を付けてください）");
+            stopped = Some(EXIT_FATAL);
+            aborted = true;
+            break;
+        }
+
+        let ext = args.ext_map.resolve(&file::extension_of(input_file));
+
+        // 中身を読んで処理を決める前に、拡張子を偽ったファイルや壊れたファイルを除く
+        if args.strict_extensions {
+            if let Some(actual) = sniff::mismatch(&filepath, &ext) {
+                match actual {
+                    Some(actual) => warning!("警告: 中身（{actual:?}）が拡張子 {ext} と合わないため処理しません: {:?}", filepath),
+                    None => warning!("警告: 中身の形式を判定できず拡張子 {ext} と合わないため処理しません: {:?}", filepath),
+                }
+                summary.mismatched += 1;
+                continue;
+            }
+        }
+
+        let on_unsupported = if args.copy_others { UnsupportedPolicy::Copy } else { args.on_unsupported };
+        let job = match job::Job::plan_probed(&ext, &filepath, &args, probed.get(&filepath).copied(), classified.get(&filepath).copied()) {
+            Ok(Some(job)) => job,
+            Ok(None) => match on_unsupported {
+                UnsupportedPolicy::Skip => continue,
+                UnsupportedPolicy::Copy => job::Job::Copy(file::extension_of(input_file)),
+                UnsupportedPolicy::Error => {
+                    failure!("対応していない形式です: {:?}", filepath);
+                    summary.record_failed(&filepath, None, "対応していない形式です");
+                    stopped = Some(EXIT_FAILED);
+                    break;
+                }
+            },
+            Err(e) => {
+                report_failure(&filepath, &e);
+                summary.record_failed(&filepath, None, &e);
+                if e.is_fatal() {
+                    stopped = Some(EXIT_FATAL);
+                    break;
+                }
                 continue;
             }
         };
 
-        let relative_path = file::get_relative_path(&root_dir, &input_file);
-        let output_path = PathBuf::from(args.output_dir.clone()).join(relative_path);
+        // 壊れた元ファイルを圧縮して、壊れたまま残さないよう先に照合する（処理しない形式のファイルは照合しない）
+        if let Some(manifest) = &manifest {
+            let problem = match manifest.verify(&filepath) {
+                Ok(manifest::Verification::Match) => None,
+                Ok(manifest::Verification::Mismatch(actual)) => Some(format!("チェックサムが一覧と違います: {actual}")),
+                Ok(manifest::Verification::Missing) => Some("チェックサムの一覧に載っていません".to_string()),
+                Err(e) => Some(format!("チェックサムを求められません: {e}")),
+            };
+            if let Some(problem) = problem {
+                match args.on_mismatch {
+                    manifest::MismatchPolicy::Warn => warning!("警告: {problem}: {:?}", filepath),
+                    manifest::MismatchPolicy::Skip => {
+                        failure!("処理しません（{problem}）: {:?}", filepath);
+                        summary.record_failed(&filepath, None, &problem);
+                        continue;
+                    }
+                    manifest::MismatchPolicy::Abort => {
+                        failure!("中断します（{problem}）: {:?}", filepath);
+                        summary.record_failed(&filepath, None, &problem);
+                        stopped = Some(EXIT_FAILED);
+                        aborted = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // 寸法を読めない画像は、ここでは除かずに圧縮の失敗として報告する
+        if let Some((max_width, max_height)) = args.only_larger_than.filter(|_| job.is_still_image()) {
+            if utilities::image_dimensions(&filepath).is_ok_and(|(width, height)| width <= max_width && height <= max_height) {
+                summary.undersized += 1;
+                continue;
+            }
+        }
+
+        if args.max_files.is_some_and(|max| planned >= max) {
+            summary.limited += 1;
+            continue;
+        }
+        planned += 1;
+
+        if job.needs_ffmpeg() && !utilities::is_ffmpeg_available() {
+            failure!("FFmpegがインストールされていないか、PATHに含まれていません: {:?}", filepath);
+            summary.record_failed(&filepath, None, "FFmpegが見つかりません");
+            stopped = Some(EXIT_FATAL);
+            break;
+        }
+
+        let output_path = match &args.suffix {
+            Some(suffix) => file::suffixed_path(input_file, suffix),
+            None => {
+                // 種類ごとの出力先の下に、入力のディレクトリ構成をそのまま再現する
+                let mut relative_path = file::get_relative_path(&root_dir, input_file);
+                if let Some(shard) = shards.get(input_file) {
+                    relative_path = relative_path.with_file_name(shard).join(input_file.file_name().unwrap_or_default());
+                }
+                file::expand_output_dir(&args.output_dir, job.kind(), job.extension()).join(relative_path)
+            }
+        };
 
         // 入力がサブディレクトリ配下の場合、出力先の親ディレクトリを作成する
         if let Some(parent) = output_path.parent() {
-            std::fs::create_dir_all(parent).unwrap();
-        }
-
-        match extension {
-            Some(ext) => {
-                let ext = ext.to_string_lossy().to_lowercase();
-                if ext == "png" {
-                    if args.webp {
-                        let target = file::webp_target(&output_path, &mut used_outputs);
-                        println!("png -> webp (lossless): {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = webp_image::path2compress_lossless(&filepath, &target) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
-                    } else {
-                        let target = file::unique_target(&output_path, "png", &mut used_outputs);
-                        println!("rgba image: {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = rgba_image::path2compress(&filepath, &target) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
+            if let Err(e) = file::ensure_dir(parent) {
+                failure!("出力先ディレクトリを作成できません: {:?}: {e}", parent);
+                summary.record_failed(&filepath, Some(&output_path), &e);
+                continue;
+            }
+        }
+
+        let dir = output_path.parent().unwrap_or(Path::new(""));
+        let named = args.rename_scheme.map(|scheme| {
+            renamed += 1;
+            let stem = scheme.stem(&filepath, renamed);
+            // --suffix との併用時も、再実行で出力を圧縮済みと判定できるよう接尾辞を残す
+            format!("{stem}{}", args.suffix.as_deref().unwrap_or_default())
+        });
+        // 重ならなければ付ける名前
+        let claimed = match &named {
+            Some(stem) => dir.join(format!("{stem}.{}", job.extension())),
+            None => output_path.with_extension(job.extension()),
+        };
+        let target = match (used_outputs.contains(&claimed), args.on_conflict) {
+            (true, ConflictPolicy::Overwrite) => claimed,
+            (true, ConflictPolicy::Skip) => {
+                failure!("出力先が先の入力と重なるため処理しません: {:?} -> {:?}", filepath, claimed);
+                summary.record_skipped(&filepath, &claimed);
+                summary.record_job(job.kind(), None);
+                continue;
+            }
+            (true, ConflictPolicy::Error) => {
+                failure!("出力先が先の入力と重なるため中断します: {:?} -> {:?}", filepath, claimed);
+                summary.record_failed(&filepath, Some(&claimed), "出力先が先の入力と重なります");
+                stopped = Some(EXIT_FAILED);
+                aborted = true;
+                break;
+            }
+            _ => match &named {
+                Some(stem) => file::unique_named_target(dir, stem, job.extension(), &mut used_outputs),
+                None => file::unique_target(&output_path, job.extension(), &mut used_outputs),
+            },
+        };
+        if !args.summary_only {
+            println!("{}: {:?} -> {:?}", job.label(), filepath, target);
+        }
+        // --incremental で、出力を書き出したときと比べる元のチェックサムと設定の指紋
+        let variant = match outputs.as_ref().map(|_| manifest::checksum(&filepath)) {
+            Some(Ok(source)) => Some((source, incremental::fingerprint(&job.settings(&filepath, &args)))),
+            Some(Err(e)) => {
+                failure!("圧縮に失敗しました: {:?}: {e}", filepath);
+                summary.record_failed(&filepath, Some(&target), &e);
+                continue;
+            }
+            None => None,
+        };
+        if !args.no_skip && fs::metadata(&target).is_ok() {
+            let outdated = match (&outputs, &variant) {
+                (Some(outputs), Some((source, settings))) => !outputs.is_current(&target, source, settings),
+                _ => false,
+            };
+            if outdated && !args.summary_only {
+                println!("  元か設定が前に書き出したときと違うため作り直します");
+            }
+            if !args.force && !outdated {
+                summary.record_skipped(&filepath, &target);
+                summary.record_job(job.kind(), None);
+                continue;
+            }
+            if args.interactive && !overwrite_all {
+                match prompt::ask_overwrite(&target) {
+                    prompt::Answer::Yes => {}
+                    prompt::Answer::All => overwrite_all = true,
+                    prompt::Answer::No => {
+                        summary.record_skipped(&filepath, &target);
+                        summary.record_job(job.kind(), None);
+                        continue;
                     }
-                } else if ext == "jpg" || ext == "jpeg" {
-                    if args.webp {
-                        let target = file::webp_target(&output_path, &mut used_outputs);
-                        println!("jpg -> webp (lossy): {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = webp_image::path2compress_lossy(&filepath, &target, args.quality) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
-                    } else {
-                        let target = file::unique_target(&output_path, "jpg", &mut used_outputs);
-                        println!("rgb image: {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = rgb_image::path2compress(&filepath, &target, args.quality) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
+                    // 確認を済ませた分だけ圧縮すると、中断したつもりでも既存の出力が上書きされる
+                    prompt::Answer::Quit => {
+                        println!("中断しました。ファイルは圧縮していません");
+                        aborted = true;
+                        break;
                     }
-                } else if ext == "gif" {
-                    let is_animated = match gif_image::is_animated(&filepath) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                            continue;
-                        }
-                    };
-                    if is_animated {
-                        // アニメーションGIFは動画として扱う（READMEに従い --webp は対象外）
-                        let codec = if args.hevc {
-                            video::VideoCodec::Hevc
+                }
+            }
+        }
+
+        // 上書きで失われるファイルを先に退避する。退避できなければこのファイルは処理しない
+        if let (Some(backup_dir), true) = (&args.backup_dir, target.is_file()) {
+            match file::backup(&target, &root_dir, backup_dir) {
+                Ok(copied) => {
+                    if !args.summary_only {
+                        println!("  上書きする前に退避しました: {:?}", copied);
+                    }
+                }
+                Err(e) => {
+                    failure!("上書きする前に退避できませんでした: {:?}: {e}", target);
+                    summary.record_failed(&filepath, Some(&target), &e);
+                    continue;
+                }
+            }
+        }
+
+        // 出力先が入力そのもの（-o . で同じ拡張子など）の場合、書き込みで入力を壊さないよう一時ファイルに書いてから置き換える
+        let in_place = fs::canonicalize(&target).is_ok_and(|resolved| resolved == filepath);
+        let written = if in_place { file::temp_path(&target) } else { target.clone() };
+        let original_size = file::file_size(&filepath);
+        let previous = if in_place { None } else { fs::metadata(&target).and_then(|metadata| metadata.modified()).ok() };
+        // --on-conflict overwrite では、同じ出力先に決まっていた先の入力を処理せず、後の入力に置き換える
+        if let Some(&index) = planned_targets.get(&target) {
+            let earlier = &tasks[index];
+            warning!("警告: 出力先が重なるため、{:?} の出力を {:?} で上書きします: {:?}", earlier.filepath, filepath, target);
+            summary.record_skipped(&earlier.filepath, &target);
+            summary.record_job(earlier.job.kind(), None);
+            tasks[index] = Task { index, filepath, target, written, in_place, original_size, previous, job, args, variant };
+            continue;
+        }
+        let index = tasks.len();
+        planned_targets.insert(target.clone(), index);
+        tasks.push(Task { index, filepath, target, written, in_place, original_size, previous, job, args, variant });
+    }
+
+    if aborted {
+        tasks.clear();
+    }
+    // --fail-fast では、出力先を決める段階で失敗したら1件も圧縮しない
+    if let (true, Some(failed)) = (args.fail_fast, summary.files.iter().find(|record| record.status == report::Status::Failed)) {
+        report_fail_fast(&failed.input);
+        tasks.clear();
+        stopped = stopped.or(Some(EXIT_FAILED));
+    }
+
+    let video_jobs = args.video_jobs.map_or_else(|| scheduler::Pool::Video.default_jobs(), NonZeroUsize::get);
+    let contact_sheet = args.contact_sheet.as_ref().map(|_| contact_sheet::ContactSheet {
+        columns: args.contact_sheet_columns,
+        thumb_size: args.contact_sheet_size,
+    });
+    // --progress の表示も、ライブラリの compress_images と同じ進み具合の知らせを受け取って行う
+    let finished = AtomicUsize::new(0);
+    let ordered = Mutex::new(OrderedLines::default());
+    let on_progress = |event: batch::ProgressEvent| {
+        match event {
+            batch::ProgressEvent::Finished { index, .. } if args.progress && args.ordered_output => {
+                let line = progress_line(&event, index + 1).unwrap_or_default();
+                for line in ordered.lock().unwrap().push(index, line) {
+                    eprintln!("{line}");
+                }
+            }
+            _ if args.progress => print_progress(&event, &finished),
+            _ => {}
+        }
+    };
+    let limits = scheduler::Limits {
+        deadline: args.max_runtime.map(|minutes| launched + Duration::from_secs(minutes * 60)),
+        max_memory: args.max_memory.map(|megabytes| megabytes.saturating_mul(1024 * 1024)),
+    };
+    let results = scheduler::run(
+        &tasks,
+        limits,
+        |pool| match pool {
+            scheduler::Pool::Image => image_jobs,
+            scheduler::Pool::Video => video_jobs,
+        },
+        |task| if task.job.needs_ffmpeg() { scheduler::Pool::Video } else { scheduler::Pool::Image },
+        |task| task.job.estimated_memory(&task.filepath),
+        |task| execute(task, tasks.len(), contact_sheet.as_ref(), &on_progress),
+        |outcome| outcome.result.as_ref().is_err_and(|e| args.fail_fast || e.is_fatal()),
+    );
+    // 途中で打ち切って実行しなかった入力があると、その後ろの行は預けたままになる
+    for line in ordered.into_inner().unwrap().drain() {
+        eprintln!("{line}");
+    }
+    let mut thumbnails = Vec::new();
+    let unstarted = results.iter().filter(|result| result.is_none()).count();
+
+    for (task, result) in tasks.iter().zip(results) {
+        let Task { filepath, target, written, in_place, original_size, previous, job, args, variant, .. } = task;
+        let (in_place, original_size) = (*in_place, *original_size);
+        // 先に致命的なエラーで打ち切ったため、実行しなかった
+        let Some(Outcome { result, thumbnail, score, seconds, sprite }) = result else {
+            continue;
+        };
+        thumbnails.extend(thumbnail);
+
+        match result {
+            Ok(()) => {
+                let compressed_size = file::output_size(written);
+                let reduction = report::reduction_percent(original_size, compressed_size);
+                match args.min_reduction {
+                    // フレームを書き出したディレクトリは元の動画の代わりにならない。
+                    // --strip-gps では元の画像をそのまま写すと位置情報が残るため、圧縮した結果を使う
+                    Some(min) if reduction < min && !written.is_dir() && !(args.strip_gps && job.is_still_image()) => {
+                        let kept = if in_place {
+                            // 一時ファイルを捨てれば元のファイルがそのまま残る
+                            fs::remove_file(written).map(|_| target.clone())
                         } else {
-                            video::VideoCodec::Av1
+                            file::replace_with_original(filepath, target, &mut used_outputs)
                         };
-                        let target = file::unique_target(&output_path, "mp4", &mut used_outputs);
-                        println!("gif ({}): {:?} -> {:?}", if args.hevc { "hevc" } else { "av1" }, filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = video::path2compress(filepath.to_str().unwrap(), target.to_str().unwrap(), codec, args.crf) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
-                    } else if args.webp {
-                        // 静止GIFは画像として扱う（可逆WebP）
-                        let target = file::webp_target(&output_path, &mut used_outputs);
-                        println!("gif -> webp (lossless): {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
-                        }
-                        if let Err(e) = webp_image::path2compress_lossless(&filepath, &target) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
-                    } else {
-                        // 静止GIFは画像として扱う（oxipngでPNG化）
-                        let target = file::unique_target(&output_path, "png", &mut used_outputs);
-                        println!("gif -> png: {:?} -> {:?}", filepath, target);
-                        if fs::metadata(&target).is_ok() && !args.force {
-                            continue;
+                        match kept {
+                            Ok(kept) => {
+                                if !args.summary_only {
+                                    println!("  削減率 {reduction:.1}% が {min}% に届かないため元のファイルを使います: {:?}", kept);
+                                }
+                                summary.record_kept_original(filepath, &kept, original_size);
+                                if let (Some(outputs), Some((source, settings))) = (&mut outputs, variant) {
+                                    outputs.record(&kept, source, settings);
+                                }
+                                if args.preserve_permissions {
+                                    preserve_permissions(filepath, &kept);
+                                }
+                                run_post_hook(args, filepath, &kept, 0.0);
+                            }
+                            Err(e) => {
+                                failure!("元のファイルで置き換えられませんでした: {:?}: {e}", filepath);
+                                summary.record_failed(filepath, Some(target), &e);
+                            }
                         }
-                        if let Err(e) = gif_image::path2compress_png(&filepath, &target) {
-                            eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
-                        }
-                    }
-                } else if video::is_match_extension(filepath.to_str().unwrap()) {
-                    let codec = if args.hevc {
-                        video::VideoCodec::Hevc
-                    } else {
-                        video::VideoCodec::Av1
-                    };
-                    let target = file::unique_target(&output_path, "mp4", &mut used_outputs);
-                    println!("video ({}): {:?} -> {:?}", if args.hevc { "hevc" } else { "av1" }, filepath, target);
-                    if fs::metadata(&target).is_ok() && !args.force {
-                        continue;
-                    }
-                    if let Err(e) = video::path2compress(filepath.to_str().unwrap(), target.to_str().unwrap(), codec, args.crf) {
-                        eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
                     }
-                } else if audio::is_match_extension(filepath.to_str().unwrap()) {
-                    let source_lossless = audio::is_lossless_source(filepath.to_str().unwrap());
-
-                    let codec = if source_lossless {
-                        audio::AudioCodec::Flac
-                    } else if args.opus {
-                        audio::AudioCodec::Opus
-                    } else {
-                        audio::AudioCodec::Aac
-                    };
-
-                    let target = file::unique_target(&output_path, codec.extension(), &mut used_outputs);
-                    println!("audio ({}): {:?} -> {:?}", codec.extension(), filepath, target);
-                    if fs::metadata(&target).is_ok() && !args.force {
-                        continue;
+                    _ => {
+                        let replaced = if in_place { fs::rename(written, target) } else { Ok(()) };
+                        match replaced {
+                            Ok(()) => {
+                                summary.record_compressed(filepath, target, original_size, compressed_size);
+                                if let (Some(outputs), Some((source, settings))) = (&mut outputs, variant) {
+                                    outputs.record(target, source, settings);
+                                }
+                                // 書き出しで既存の出力と同じ内容だと分かり、書き直さなかった
+                                let modified = fs::metadata(target).and_then(|metadata| metadata.modified()).ok();
+                                if previous.is_some() && modified == *previous {
+                                    if !args.summary_only {
+                                        println!("  既存の出力と同じ内容のため書き直しませんでした（unchanged）");
+                                    }
+                                    summary.unchanged += 1;
+                                }
+                                if args.preserve_permissions {
+                                    preserve_permissions(filepath, target);
+                                }
+                                if let Some(score) = score {
+                                    let flagged = args.min_quality_score.is_some_and(|min| score.ssim < min);
+                                    if !args.summary_only {
+                                        println!("  SSIM {:.4}, PSNR {:.2} dB", score.ssim, score.psnr);
+                                    }
+                                    if flagged {
+                                        failure!(
+                                            "SSIM {:.4} が --min-quality-score {} に届きません: {:?}",
+                                            score.ssim,
+                                            args.min_quality_score.unwrap_or_default(),
+                                            target
+                                        );
+                                    }
+                                    summary.record_score(score, flagged);
+                                }
+                                run_post_hook(args, filepath, target, reduction);
+                            }
+                            Err(e) => {
+                                failure!("入力を圧縮結果で置き換えられませんでした: {:?}: {e}", filepath);
+                                let _ = fs::remove_file(written);
+                                summary.record_failed(filepath, Some(target), &e);
+                            }
+                        }
                     }
-                    if let Err(e) = audio::path2compress(&filepath, &target, codec, &args.audio_bitrate) {
-                        eprintln!("圧縮に失敗しました: {:?}: {e}", filepath);
+                }
+            }
+            Err(e) => {
+                report_failure(filepath, &e);
+                if in_place {
+                    let _ = fs::remove_file(written);
+                }
+                if let Some(outputs) = &mut outputs {
+                    outputs.forget(target);
+                }
+                summary.record_failed(filepath, Some(target), &e);
+                if e.is_fatal() {
+                    stopped = Some(EXIT_FATAL);
+                } else if args.fail_fast && stopped.is_none() {
+                    report_fail_fast(filepath);
+                    stopped = Some(EXIT_FAILED);
+                }
+            }
+        }
+        summary.record_job(job.kind(), Some(seconds));
+        if let Some(sprite) = sprite {
+            if !args.summary_only {
+                println!("  スプライトシートを書き出しました（{} 枚）: {:?}", sprite.tiles.len(), sprite.path);
+            }
+            summary.record_sprite(sprite);
+        }
+    }
+
+    // 打ち切った場合は stopped が設定されるため、それ以外で始めなかったファイルは --max-runtime の期限によるもの
+    if let (Some(minutes), None, true) = (args.max_runtime, stopped, unstarted > 0) {
+        println!("--max-runtime の {minutes} 分を過ぎたため、残り {unstarted} 件は処理しませんでした");
+        summary.timed_out = unstarted;
+    }
+
+    if args.keep_structure_empty_dirs && stopped.is_none() {
+        mirror_dirs(&root_dir, &output_root, backup_root.as_deref(), &mut summary);
+    }
+
+    let mut code = stopped.unwrap_or(if summary.errors > 0 || summary.flagged > 0 { EXIT_FAILED } else { 0 });
+    if let Some(outputs) = &outputs {
+        if let Err(e) = outputs.save() {
+            failure!("出力の記録を書き出せません: {:?}: {e}", outputs.path());
+            code = code.max(EXIT_FAILED);
+        }
+    }
+    if let (Some(path), Some(sheet)) = (&args.contact_sheet, contact_sheet) {
+        if thumbnails.is_empty() {
+            println!("一覧画像に並べる画像がないため書き出しませんでした: {:?}", path);
+        } else if let Err(e) = sheet.write(path, &thumbnails, args.quality) {
+            failure!("一覧画像を書き出せません: {:?}: {e}", path);
+            code = code.max(EXIT_FAILED);
+        } else if !args.summary_only {
+            println!("一覧画像を書き出しました（{} 枚）: {:?}", thumbnails.len(), path);
+        }
+    }
+    if let Some(path) = &args.output_archive {
+        match archive::write(path, &archive_entries(&summary, &args)) {
+            Ok(count) if !args.summary_only => println!("出力をまとめました（{count} 件）: {:?}", path),
+            Ok(_) => {}
+            Err(e) => {
+                failure!("出力をまとめた tar.gz を書き出せません: {:?}: {e}", path);
+                code = code.max(EXIT_FAILED);
+            }
+        }
+    }
+    finish(&summary, &args, code)
+}
+
+/// --output-archive にまとめる出力。失敗した入力を除き、同じ出力は一度だけまとめる。tar の中のパスは出力先からの相対パス
+fn archive_entries(summary: &report::Summary, args: &AppArgs) -> Vec<archive::Entry> {
+    // --suffix では入力の隣に書き出すため、カレントディレクトリからのパスにする
+    let base = if args.suffix.is_some() { PathBuf::from(".") } else { file::output_dir_root(&args.output_dir) };
+    let mut records: Vec<&report::FileRecord> = summary.files.iter().filter(|record| record.status != report::Status::Failed).collect();
+    // --on-conflict overwrite で置き換えられた入力ではなく、実際に書き出した入力の更新日時を付ける
+    records.sort_by_key(|record| record.status == report::Status::Skipped);
+    let mut seen = HashSet::new();
+    records
+        .into_iter()
+        .filter_map(|record| Some((record, record.output.as_ref()?)))
+        .filter(|(_, output)| output.exists() && seen.insert(output.to_path_buf()))
+        .map(|(record, output)| archive::Entry {
+            output: output.clone(),
+            name: file::get_relative_path(&base, output),
+            modified: fs::metadata(&record.input).and_then(|metadata| metadata.modified()).ok(),
+        })
+        .collect()
+}
+
+/// 出力先まで決めて実行を待つ、1ファイル分の圧縮
+struct Task {
+    /// 処理順での位置（0 から）
+    index: usize,
+    filepath: PathBuf,
+    target: PathBuf,
+    /// 実際に書き込む先（入力そのものを置き換える場合は一時ファイル）
+    written: PathBuf,
+    in_place: bool,
+    original_size: u64,
+    /// 既存の出力の更新日時。書き出しの後も変わらなければ、同じ内容のため書き直さなかったことが分かる
+    previous: Option<SystemTime>,
+    job: job::Job,
+    /// このファイルに効く --input-list のオプションと .compressorrc を反映した引数
+    args: AppArgs,
+    /// --incremental で記録する、元のチェックサムと設定の指紋
+    variant: Option<(String, String)>,
+}
+
+/// ワーカーで実行した1ファイル分の結果
+struct Outcome {
+    result: Result<(), CompressError>,
+    /// --contact-sheet の一覧画像に並べるサムネイル
+    thumbnail: Option<RgbaImage>,
+    /// --diff-report の元の画像との差
+    score: Option<similarity::Score>,
+    /// 圧縮にかかった時間（秒）
+    seconds: f64,
+    /// --sprite-sheet で書き出したスプライトシート
+    sprite: Option<sprite::Sprite>,
+}
+
+/// 圧縮を実行し、一覧画像のサムネイルと元の画像との差も求める（どちらも並列に動いているうちに済ませる）
+fn execute(
+    task: &Task,
+    total: usize,
+    contact_sheet: Option<&contact_sheet::ContactSheet>,
+    on_progress: &impl Fn(batch::ProgressEvent),
+) -> Outcome {
+    let (index, input, original_bytes) = (task.index, task.filepath.as_path(), task.original_size);
+    on_progress(batch::ProgressEvent::Started { index, total, input, original_bytes });
+    let start = Instant::now();
+    let result = task.job.run(&task.filepath, &task.written, &task.args);
+    on_progress(batch::ProgressEvent::Finished {
+        index,
+        total,
+        input,
+        output: &task.target,
+        original_bytes,
+        compressed_bytes: if result.is_ok() { file::file_size(&task.written) } else { 0 },
+        error: result.as_ref().err(),
+    });
+    let seconds = start.elapsed().as_secs_f64();
+    let mut outcome = Outcome { result, thumbnail: None, score: None, seconds, sprite: None };
+    if outcome.result.is_err() {
+        return outcome;
+    }
+
+    if let (Some(sheet), true) = (contact_sheet, task.job.is_still_image()) {
+        match sheet.thumbnail(&task.written) {
+            Ok(thumbnail) => outcome.thumbnail = Some(thumbnail),
+            Err(e) => failure!("一覧画像に加えられません: {:?}: {e}", task.filepath),
+        }
+    }
+    if task.args.sprite_sheet && task.job.is_video() {
+        let options = sprite::SpriteOptions {
+            frames: task.args.sprite_frames,
+            sheet: contact_sheet::ContactSheet { columns: task.args.sprite_columns, thumb_size: task.args.sprite_size },
+            quality: task.args.quality,
+            temp_dir: task.args.temp_dir.as_deref(),
+            loglevel: task.args.ffmpeg_loglevel,
+        };
+        // フレームは書き出した動画から取り、時刻が出力の動画と合うようにする
+        match sprite::generate(&task.written, &sprite::sprite_path(&task.target), &options) {
+            Ok(sprite) => outcome.sprite = Some(sprite),
+            Err(e) => failure!("スプライトシートを作れません: {:?}: {e}", task.filepath),
+        }
+    }
+    if task.args.diff_report || task.args.min_quality_score.is_some() {
+        match task.job.score(&task.filepath, &task.written, &task.args) {
+            Ok(score) => outcome.score = score,
+            Err(e) => failure!("元の画像と比べられません: {:?}: {e}", task.filepath),
+        }
+    }
+    outcome
+}
+
+/// 圧縮を終えたファイルを `[終えた件数/総数]` とともに表示する（--progress）。並列に終わった順に表示する
+fn print_progress(event: &batch::ProgressEvent, finished: &AtomicUsize) {
+    if let batch::ProgressEvent::Finished { .. } = event {
+        let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!("{}", progress_line(event, done).unwrap_or_default());
+    }
+}
+
+/// 終えた1件の --progress の行。`done` は表示する件数。開始の知らせは表示しないため `None`
+fn progress_line(event: &batch::ProgressEvent, done: usize) -> Option<String> {
+    let batch::ProgressEvent::Finished { total, input, original_bytes, compressed_bytes, error, .. } = event else {
+        return None;
+    };
+    Some(match error {
+        Some(_) => format!("[{done}/{total}] 失敗: {:?}", input),
+        None => format!(
+            "[{done}/{total}] 完了: {:?} ({} -> {})",
+            input,
+            report::format_bytes(*original_bytes as i64),
+            report::format_bytes(*compressed_bytes as i64)
+        ),
+    })
+}
+
+/// --ordered-output で、先の入力が終わるまで表示を待たせている行
+#[derive(Default)]
+struct OrderedLines {
+    /// 次に表示する入力の番号
+    next: usize,
+    pending: BTreeMap<usize, String>,
+}
+
+impl OrderedLines {
+    /// `index` 番目の入力の行を預け、先の入力の行がすべて揃った分を入力の順に返す
+    fn push(&mut self, index: usize, line: String) -> Vec<String> {
+        self.pending.insert(index, line);
+        let mut ready = Vec::new();
+        while let Some(line) = self.pending.remove(&self.next) {
+            ready.push(line);
+            self.next += 1;
+        }
+        ready
+    }
+
+    /// 預けたままの行を入力の順にすべて返す
+    fn drain(self) -> Vec<String> {
+        self.pending.into_values().collect()
+    }
+}
+
+/// 出力のパーミッションを入力に合わせる（--preserve-permissions）。合わせられなくても出力は残す
+fn preserve_permissions(source: &Path, output: &Path) {
+    if let Err(e) = file::copy_permissions(source, output) {
+        failure!("パーミッションを入力に合わせられませんでした: {:?}: {e}", output);
+    }
+}
+
+/// 圧縮の失敗を表示する。エラーの種類に応じて次に取れる手立てを添える
+fn report_failure(filepath: &Path, e: &CompressError) {
+    failure!("圧縮に失敗しました: {:?}: {e}", filepath);
+    if let Some(hint) = error_hint(e) {
+        failure!("  {hint}");
+    }
+}
+
+/// --compare-encoders: jpg/png を両方のエンコーダで --quality の JPEG にし、大きさと時間の差を表示する。
+/// --keep-winner を付けると、小さかったほうを通常と同じ出力先（拡張子は jpg）に書き出す。
+fn compare_encoders(input_files: &[PathBuf], args: &AppArgs) -> ExitCode {
+    let mut totals: HashMap<&'static str, (u64, Duration)> = HashMap::new();
+    let mut wins: HashMap<&'static str, usize> = HashMap::new();
+    let mut failed = false;
+    let mut used_outputs = HashSet::new();
+    for input in input_files {
+        if !matches!(args.ext_map.resolve(&file::extension_of(input)).as_str(), "jpg" | "jpeg" | "png") {
+            continue;
+        }
+        let trials = image::open(input).map_err(CompressError::from).and_then(|img| encoders::compare(&img, args.quality, args.flatten_background));
+        let trials = match trials {
+            Ok(trials) => trials,
+            Err(e) => {
+                failure!("比較できませんでした: {:?}: {e}", input);
+                failed = true;
+                continue;
+            }
+        };
+
+        let (winner, loser) = (&trials[0], &trials[1]);
+        let saved = report::reduction_percent(loser.jpeg.len() as u64, winner.jpeg.len() as u64);
+        let results: Vec<String> = trials
+            .iter()
+            .map(|trial| {
+                let total = totals.entry(trial.encoder.name()).or_default();
+                total.0 += trial.jpeg.len() as u64;
+                total.1 += trial.elapsed;
+                format!("{} {} ({} ms)", trial.encoder.name(), report::format_bytes(trial.jpeg.len() as i64), trial.elapsed.as_millis())
+            })
+            .collect();
+        *wins.entry(winner.encoder.name()).or_default() += 1;
+        println!("{:?}: {} → {} が {saved:.1}% 小さい", input, results.join(" / "), winner.encoder.name());
+
+        if args.keep_winner {
+            let base = file::expand_output_dir(&args.output_dir, "images", "jpg").join(file::get_relative_path(Path::new("."), input));
+            let output = file::unique_target(&base, "jpg", &mut used_outputs);
+            if let Err(e) = file::ensure_parent_dir(&output).and_then(|_| fs::write(&output, &winner.jpeg)) {
+                failure!("書き出せませんでした: {:?}: {e}", output);
+                failed = true;
+            }
+        }
+    }
+
+    let mut names: Vec<&&str> = totals.keys().collect();
+    names.sort();
+    for name in names {
+        let (bytes, elapsed) = totals[*name];
+        println!(
+            "合計 {name}: {} ({} ms), 小さかった件数 {}",
+            report::format_bytes(bytes as i64),
+            elapsed.as_millis(),
+            wins.get(*name).copied().unwrap_or(0)
+        );
+    }
+    ExitCode::from(if failed { EXIT_FAILED } else { 0 })
+}
+
+/// --min-safe-quality / --max-safe-crf を超える品質の指定
+fn quality_warnings(args: &AppArgs) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if args.quality < args.min_safe_quality {
+        warnings.push(format!("--quality {} は --min-safe-quality {} より低く、画像が大きく劣化します", args.quality, args.min_safe_quality));
+    }
+    if let Some(crf) = args.crf.filter(|crf| *crf > args.max_safe_crf) {
+        warnings.push(format!("--crf {crf} は --max-safe-crf {} より高く、動画が大きく劣化します", args.max_safe_crf));
+    }
+    warnings
+}
+
+/// `warnings` のうち `warned` にないものを表示し、この設定で圧縮してよいか確かめる（`yes` なら確かめずに続ける）。
+/// 表示した警告は `warned` に加え、同じ値では二度と確かめない。`path` はその値を使うファイルで、警告に添える
+fn confirm_quality(warnings: impl IntoIterator<Item = String>, warned: &mut Vec<String>, yes: bool, path: Option<&Path>) -> bool {
+    let mut fresh: Vec<String> = Vec::new();
+    for warning in warnings {
+        if !warned.contains(&warning) && !fresh.contains(&warning) {
+            fresh.push(warning);
+        }
+    }
+    if fresh.is_empty() {
+        return true;
+    }
+    for warning in &fresh {
+        match path {
+            Some(path) => warning!("警告: {warning}: {:?}", path),
+            None => warning!("警告: {warning}"),
+        }
+    }
+    warned.extend(fresh);
+    yes || prompt::confirm("この設定で圧縮しますか? [y]es/[n]o: ")
+}
+
+/// --check: 画像を最後までデコードし、動画と音声を ffprobe で読んで、読めないファイルを表示する
+fn check(input_files: &[PathBuf], args: &AppArgs) -> ExitCode {
+    let output_root = file::output_dir_root(&args.output_dir);
+    let (mut checked, mut broken) = (0, 0);
+    for input in input_files {
+        // 前回の出力と設定ファイルは、圧縮するときと同じく対象にしない
+        if input.to_string_lossy().contains(format!("/{}/", output_root.to_string_lossy()).as_str())
+            || input.file_name().is_some_and(|name| name == config::FILE_NAME)
+        {
+            continue;
+        }
+        let ext = args.ext_map.resolve(&file::extension_of(input));
+        let problem = if matches!(ext.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp") || (cfg!(feature = "avif") && ext == "avif") {
+            decode_image(input).err().map(|e| e.to_string())
+        } else if ext == "tif" || ext == "tiff" {
+            decode_tiff(input).err().map(|e| e.to_string())
+        } else if video::is_video_extension(&ext) || audio::is_audio_extension(&ext) {
+            match video::probe_problem(input, video::is_video_extension(&ext)) {
+                Ok(problem) => problem,
+                // ffprobe がなければ、残りの動画と音声もすべて調べられない
+                Err(e) => {
+                    failure!("調べられませんでした: {:?}: {e}", input);
+                    if let Some(hint) = error_hint(&e) {
+                        failure!("  {hint}");
                     }
+                    return ExitCode::from(EXIT_FATAL);
                 }
-            },
-            None => continue,
+            }
+        } else {
+            continue;
+        };
+        checked += 1;
+        if let Some(problem) = problem {
+            broken += 1;
+            println!("読めません: {:?}: {problem}", input);
         }
     }
-}
\ No newline at end of file
+    println!("{checked} 件のうち {broken} 件は読めませんでした");
+    ExitCode::from(if broken > 0 { EXIT_FAILED } else { 0 })
+}
+
+/// --post-hook のコマンドを実行する。失敗しても圧縮は成功のまま、警告だけを表示する
+fn run_post_hook(args: &AppArgs, input: &Path, output: &Path, reduction: f64) {
+    let Some(template) = &args.post_hook else {
+        return;
+    };
+    if let Err(e) = hook::run(&hook::expand(template, input, output, reduction)) {
+        warning!("警告: --post-hook のコマンドが失敗しました（{e}）: {:?}", output);
+    }
+}
+
+/// tiff のすべてのページをデコードする
+fn decode_tiff(input: &Path) -> Result<(), CompressError> {
+    (0..tiff_image::page_sizes(input)?.len()).try_for_each(|page| tiff_image::read_page(input, page).map(|_| ()))
+}
+
+/// 画像を最後までデコードする。GIF はすべてのフレームをデコードする
+fn decode_image(input: &Path) -> Result<(), CompressError> {
+    let reader = image::ImageReader::open(input)?.with_guessed_format()?;
+    if reader.format() == Some(image::ImageFormat::Jpeg) && !rgb_image::is_complete(&fs::read(input)?) {
+        return Err(CompressError::Decode("JPEG のデータが途中で切れています".to_string()));
+    }
+    if reader.format() == Some(image::ImageFormat::Gif) {
+        use image::AnimationDecoder;
+        image::codecs::gif::GifDecoder::new(reader.into_inner())?.into_frames().collect_frames()?;
+    } else {
+        reader.decode()?;
+    }
+    Ok(())
+}
+
+/// --find-bloat: jpg/png/静止画の webp を入力と同じ形式でメモリ上に圧縮し、元より大きくなるファイルを表示する
+fn find_bloat(input_files: &[PathBuf], args: &AppArgs) -> ExitCode {
+    let output_root = file::output_dir_root(&args.output_dir);
+    let (mut checked, mut bloated, mut failed) = (0, 0, false);
+    for input in input_files {
+        // 前回の出力と設定ファイルは、圧縮するときと同じく対象にしない
+        if input.to_string_lossy().contains(format!("/{}/", output_root.to_string_lossy()).as_str())
+            || input.file_name().is_some_and(|name| name == config::FILE_NAME)
+            || !matches!(args.ext_map.resolve(&file::extension_of(input)).as_str(), "jpg" | "jpeg" | "png" | "webp")
+        {
+            continue;
+        }
+        let encoded = match batch::encode_image(input, &args.ext_map, &job::image_options(input, args)) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                failure!("圧縮できませんでした: {:?}: {e}", input);
+                failed = true;
+                continue;
+            }
+        };
+        checked += 1;
+        // 元に戻す前の圧縮した大きさで比べる
+        let (original_size, compressed_size) = (file::file_size(input), encoded.data.len() as u64);
+        if compressed_size > original_size {
+            bloated += 1;
+            println!(
+                "{:?}: {} -> {}（{:.1}% 大きくなる）",
+                input,
+                report::format_bytes(original_size as i64),
+                report::format_bytes(compressed_size as i64),
+                -report::reduction_percent(original_size, compressed_size)
+            );
+        }
+    }
+    println!("{checked} 件のうち {bloated} 件は、圧縮すると元より大きくなります");
+    ExitCode::from(if failed { EXIT_FAILED } else { 0 })
+}
+
+/// --preview: 最初に見つかった静止画だけを、圧縮と同じ処理で作業ディレクトリに圧縮し、元と並べた比較画像を書き出す
+fn preview(input_files: &[PathBuf], args: &AppArgs, output: &Path) -> ExitCode {
+    let output_root = file::output_dir_root(&args.output_dir);
+    let found = input_files.iter().find_map(|input| {
+        // 前回の出力と設定ファイルは、圧縮するときと同じく対象にしない
+        if input.to_string_lossy().contains(format!("/{}/", output_root.to_string_lossy()).as_str())
+            || input.file_name().is_some_and(|name| name == config::FILE_NAME)
+        {
+            return None;
+        }
+        let filepath = file::get_absolute_path(input).ok()?;
+        let ext = args.ext_map.resolve(&file::extension_of(input));
+        let job = job::Job::plan(&ext, &filepath, args).ok().flatten().filter(job::Job::is_still_image)?;
+        Some((filepath, job))
+    });
+    let Some((filepath, job)) = found else {
+        failure!("プレビューにする静止画が見つかりません");
+        return ExitCode::from(EXIT_FAILED);
+    };
+
+    let dir = file::work_dir(args.temp_dir.as_deref(), "preview");
+    let target = dir.join("preview").with_extension(job.extension());
+    let result = file::ensure_dir(&dir)
+        .map_err(CompressError::from)
+        .and_then(|_| job.run(&filepath, &target, args))
+        .and_then(|_| {
+            let (reference, compressed) = job.reference_and_output(&filepath, &target, args)?;
+            let sheet = preview::compose(reference, compressed, args.preview_region.unwrap_or(preview::DEFAULT_REGION))?;
+            preview::write(output, &sheet)
+        });
+    let sizes = (file::file_size(&filepath), file::file_size(&target));
+    let _ = fs::remove_dir_all(&dir);
+
+    match result {
+        Ok(()) => {
+            println!("{}: {:?}", job.label(), filepath);
+            println!(
+                "プレビューを書き出しました（左が元、右が圧縮後。{} → {}）: {:?}",
+                report::format_bytes(sizes.0 as i64),
+                report::format_bytes(sizes.1 as i64),
+                output
+            );
+            ExitCode::from(0)
+        }
+        Err(e) => {
+            failure!("プレビューを作れませんでした: {:?}: {e}", filepath);
+            ExitCode::from(EXIT_FAILED)
+        }
+    }
+}
+
+/// --fail-fast で打ち切ったことを、最初に失敗したファイルとともに表示する（失敗の理由は先に表示済み）
+fn report_fail_fast(filepath: &Path) {
+    failure!("--fail-fast のため、最初の失敗で処理を打ち切りました: {:?}", filepath);
+}
+
+/// エラーの種類ごとの、利用者が次に取れる手立て
+fn error_hint(e: &CompressError) -> Option<&'static str> {
+    match e {
+        CompressError::ToolMissing("ffmpeg" | "ffprobe") => Some("FFmpeg（ffprobe を含む）をインストールし、PATH に通してください"),
+        CompressError::ToolMissing("pdftoppm") => Some("poppler-utils（pdftoppm）をインストールし、PATH に通してください"),
+        CompressError::ToolFailed { tool: "ffmpeg", .. } => {
+            Some("--print-ffmpeg-command で実行したコマンドを、--ffmpeg-loglevel info で詳しいログを確認できます")
+        }
+        CompressError::TooLarge { .. } => Some("--max-megapixels で上限を変えられます（0 で無制限）"),
+        _ => None,
+    }
+}
+
+/// コマンドライン `argv` の後ろに --input-list の行のオプションを足して解釈する。後から書いた値が優先する。
+fn override_matches(argv: &[OsString], overrides: &[String]) -> Result<ArgMatches, String> {
+    let program = argv.first().cloned().unwrap_or_else(|| "compressor".into());
+    let alone = AppArgs::command()
+        .try_get_matches_from(std::iter::once(program).chain(overrides.iter().map(OsString::from)))
+        .map_err(clap_message)?;
+    if let Some(id) = GLOBAL_ONLY_ARGS.iter().find(|id| alone.value_source(id) == Some(ValueSource::CommandLine)) {
+        return Err(format!("--{} はファイルごとに指定できません", id.replace('_', "-")));
+    }
+
+    AppArgs::command()
+        .args_override_self(true)
+        .try_get_matches_from(argv.iter().cloned().chain(overrides.iter().map(OsString::from)))
+        .map_err(clap_message)
+}
+
+/// clap のエラーから、使い方の案内を除いた1行目だけを取り出す
+fn clap_message(e: clap::Error) -> String {
+    let message = e.to_string();
+    let line = message.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+/// `root` 配下のサブディレクトリを `output_dir` の下に作成する（--keep-structure-empty-dirs）。
+/// 出力先とバックアップ先の中は入力として扱わないため再現しない。
+fn mirror_dirs(root: &Path, output_dir: &Path, backup_root: Option<&Path>, summary: &mut report::Summary) {
+    let excluded: Vec<PathBuf> = file::get_absolute_path(&output_dir.to_path_buf())
+        .into_iter()
+        .chain(backup_root.map(Path::to_path_buf))
+        .collect();
+
+    for dir in file::get_dirs(root) {
+        let Ok(absolute) = file::get_absolute_path(&dir) else {
+            continue;
+        };
+        if excluded.iter().any(|excluded| absolute.starts_with(excluded)) {
+            continue;
+        }
+
+        let mirrored = output_dir.join(file::get_relative_path(root, &dir));
+        if let Err(e) = file::ensure_dir(&mirrored) {
+            failure!("出力先ディレクトリを作成できません: {:?}: {e}", mirrored);
+            summary.record_failed(&dir, Some(&mirrored), &e);
+        }
+    }
+}
+
+/// 集計結果を表示し、指定があればレポート（JSON・CSV・HTML）を書き出して終了コードを返す
+fn finish(summary: &report::Summary, args: &AppArgs, code: u8) -> ExitCode {
+    summary.print();
+    if args.histogram {
+        print!("{}", summary.histogram());
+    }
+
+    if let Some(path) = &args.json_report {
+        if let Err(e) = summary.write_json(path) {
+            failure!("JSON レポートを書き出せません: {:?}: {e}", path);
+            return ExitCode::from(code.max(EXIT_FAILED));
+        }
+    }
+    if let Some(path) = &args.csv_report {
+        if let Err(e) = summary.write_csv(path) {
+            failure!("CSV レポートを書き出せません: {:?}: {e}", path);
+            return ExitCode::from(code.max(EXIT_FAILED));
+        }
+    }
+    if let Some(path) = &args.html_report {
+        if let Err(e) = html_report::write(path, summary) {
+            failure!("HTML レポートを書き出せません: {:?}: {e}", path);
+            return ExitCode::from(code.max(EXIT_FAILED));
+        }
+    }
+
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 繰り返し指定・カンマ区切り・複数値をまとめて受け取れること
+    #[test]
+    fn input_file_accepts_repeats_and_commas() {
+        let args = AppArgs::try_parse_from([
+            "compressor", "--input", "a.jpg", "-i", "b.png,c.gif", "--input-file", "d.mp4", "e.wav",
+        ])
+        .unwrap();
+        let inputs: Vec<PathBuf> = ["a.jpg", "b.png", "c.gif", "d.mp4", "e.wav"].iter().map(PathBuf::from).collect();
+        assert_eq!(args.input_file, Some(inputs));
+    }
+
+    /// コマンドラインで指定した引数は設定ファイルで上書きしないこと
+    #[test]
+    fn config_does_not_override_command_line() {
+        let matches = AppArgs::command().try_get_matches_from(["compressor", "-q", "80"]).unwrap();
+        let mut args = AppArgs::from_arg_matches(&matches).unwrap();
+        let config = config::DirConfig::parse("quality = 50\nwebp = true\n").unwrap();
+        config.apply(&mut args, |id| matches.value_source(id) == Some(ValueSource::CommandLine));
+        assert_eq!(args.quality, 80.0);
+        assert!(args.webp);
+    }
+
+    /// 一覧の行のオプションはコマンドラインより優先し、書かれていない引数はコマンドラインの値を使うこと
+    #[test]
+    fn input_list_overrides_apply_on_top_of_command_line() {
+        let argv: Vec<OsString> = ["compressor", "--input-list", "list.txt", "-q", "60", "--webp"].map(OsString::from).into();
+        let matches = override_matches(&argv, &["--quality".into(), "90".into()]).unwrap();
+        let args = AppArgs::from_arg_matches(&matches).unwrap();
+        assert_eq!(args.quality, 90.0);
+        assert!(args.webp);
+
+        assert!(override_matches(&argv, &["--output-dir".into(), "elsewhere".into()]).unwrap_err().contains("--output-dir"));
+        assert!(override_matches(&argv, &["--no-such-option".into()]).is_err());
+    }
+
+    /// 後続のオプションを入力ファイルとして取り込まないこと
+    #[test]
+    fn input_file_does_not_swallow_options() {
+        let args = AppArgs::try_parse_from(["compressor", "-i", "a.jpg", "b.jpg", "-q", "80", "--force"]).unwrap();
+        assert_eq!(args.input_file, Some(vec![PathBuf::from("a.jpg"), PathBuf::from("b.jpg")]));
+        assert_eq!(args.quality, 80.0);
+        assert!(args.force);
+    }
+
+    /// 外部ツールが見つからないときだけ実行を打ち切り、種類に応じた手立てを示すこと
+    #[test]
+    fn missing_tools_are_fatal_with_hints() {
+        let missing = CompressError::ToolMissing("ffprobe");
+        assert!(missing.is_fatal());
+        assert!(error_hint(&missing).unwrap().contains("FFmpeg"));
+
+        let too_large = CompressError::TooLarge { width: 20000, height: 20000, max_megapixels: 100.0 };
+        assert!(!too_large.is_fatal());
+        assert!(error_hint(&too_large).unwrap().contains("--max-megapixels"));
+        assert_eq!(error_hint(&CompressError::Decode("broken".into())), None);
+    }
+
+    /// 終わった順に預けた行を、先の入力の行が揃うまで待たせて入力の順に返すこと
+    #[test]
+    fn orders_lines_by_input() {
+        let mut lines = OrderedLines::default();
+        assert!(lines.push(2, "c".into()).is_empty());
+        assert!(lines.push(1, "b".into()).is_empty());
+        assert_eq!(lines.push(0, "a".into()), ["a", "b", "c"]);
+        assert!(lines.push(5, "f".into()).is_empty());
+        assert!(lines.push(4, "e".into()).is_empty());
+        assert_eq!(lines.drain(), ["e", "f"]);
+    }
+
+    /// しきい値を超える品質と CRF だけを警告し、しきい値を変えられること
+    #[test]
+    fn warns_about_extreme_quality() {
+        let args = AppArgs::try_parse_from(["compressor", "-q", "5", "--crf", "45"]).unwrap();
+        let warnings = quality_warnings(&args);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("--quality 5 "));
+        assert!(warnings[1].starts_with("--crf 45 "));
+
+        let args = AppArgs::try_parse_from(["compressor", "-q", "30", "--crf", "40"]).unwrap();
+        assert!(quality_warnings(&args).is_empty());
+        let args = AppArgs::try_parse_from(["compressor", "-q", "20", "--min-safe-quality", "10", "--crf", "45", "--max-safe-crf", "50"]).unwrap();
+        assert!(quality_warnings(&args).is_empty());
+    }
+
+    /// 品質の警告は値ごとに一度だけ確かめ、確かめた値は設定ファイルから来ても聞き直さないこと
+    #[test]
+    fn confirms_each_quality_warning_once() {
+        let args = AppArgs::try_parse_from(["compressor", "-q", "5", "--yes"]).unwrap();
+        let mut warned = Vec::new();
+        assert!(confirm_quality(quality_warnings(&args).into_iter().chain(quality_warnings(&args)), &mut warned, args.yes, None));
+        assert_eq!(warned.len(), 1);
+
+        // .compressorrc で同じ品質を指定したファイルは、--yes がなくても確かめずに続ける
+        let mut file_args = AppArgs::try_parse_from(["compressor"]).unwrap();
+        file_args.quality = 5.0;
+        assert!(confirm_quality(quality_warnings(&file_args), &mut warned, file_args.yes, Some(Path::new("a.jpg"))));
+        assert_eq!(warned.len(), 1);
+
+        // 別の値は新しく警告する
+        let file_args = AppArgs::try_parse_from(["compressor", "--crf", "45", "--yes"]).unwrap();
+        assert!(confirm_quality(quality_warnings(&file_args), &mut warned, file_args.yes, Some(Path::new("b.mp4"))));
+        assert_eq!(warned.len(), 2);
+    }
+
+    /// 静止画と動画が混ざったディレクトリで、静止画には --image-quality、動画には --video-crf だけが渡ること
+    #[test]
+    fn separates_image_quality_from_video_crf() {
+        let dir = std::env::temp_dir().join("compressor_mixed_quality_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (photo, clip) = (dir.join("photo.jpg"), dir.join("clip.mp4"));
+        image::RgbImage::from_pixel(8, 8, image::Rgb([200, 100, 50])).save(&photo).unwrap();
+        fs::write(&clip, b"not probed").unwrap();
+
+        let args = AppArgs::try_parse_from(["compressor", "-i", "x", "--image-quality", "85", "--video-crf", "30"]).unwrap();
+        assert_eq!((args.quality, args.crf), (85.0, Some(30)));
+
+        let image_job = job::Job::plan("jpg", &photo, &args).unwrap().unwrap();
+        assert!(image_job.video_options(&args).is_none());
+        assert_eq!(job::image_options(&photo, &args).quality, 85.0);
+
+        let video_job = job::Job::plan("mp4", &clip, &args).unwrap().unwrap();
+        let options = video_job.video_options(&args).unwrap();
+        assert_eq!(options.crf, Some(30));
+
+        // 片方だけを指定しても、もう片方は既定のまま
+        let args = AppArgs::try_parse_from(["compressor", "-i", "x", "--crf", "24"]).unwrap();
+        assert_eq!((args.quality, args.crf), (70.0, Some(24)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// 静止画はヘッダの寸法から、動画は寸法によらず一定のメモリを見積もること（--max-memory）
+    #[test]
+    fn estimates_memory_per_task() {
+        let dir = std::env::temp_dir().join("compressor_memory_estimate_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (photo, icon, clip) = (dir.join("photo.jpg"), dir.join("icon.png"), dir.join("clip.mp4"));
+        image::RgbImage::from_pixel(40, 30, image::Rgb([200, 100, 50])).save(&photo).unwrap();
+        image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 0])).save(&icon).unwrap();
+        fs::write(&clip, b"not probed").unwrap();
+
+        let args = AppArgs::try_parse_from(["compressor", "--max-memory", "64"]).unwrap();
+        assert_eq!(args.max_memory, Some(64));
+        assert_eq!(job::Job::Jpg.estimated_memory(&photo), 40 * 30 * 3);
+        assert_eq!(job::Job::Png.estimated_memory(&icon), 16 * 16 * 4);
+        let video_job = job::Job::plan("mp4", &clip, &args).unwrap().unwrap();
+        assert!(video_job.estimated_memory(&clip) > 100 * 1024 * 1024);
+        assert_eq!(job::Job::Copy("txt".to_string()).estimated_memory(&clip), 0);
+        assert!(AppArgs::try_parse_from(["compressor", "--max-memory", "0"]).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// avif は jpg（--webp なら非可逆の WebP）に変換する静止画として扱うこと
+    #[cfg(feature = "avif")]
+    #[test]
+    fn plans_avif_as_still_image() {
+        let dir = std::env::temp_dir().join("compressor_avif_plan_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let photo = dir.join("photo.avif");
+        fs::write(&photo, b"not decoded").unwrap();
+
+        // 寸法の確認ではヘッダを読むため、中身のない入力では上限を外す
+        let args = AppArgs::try_parse_from(["compressor", "--max-megapixels", "0"]).unwrap();
+        let job = job::Job::plan("avif", &photo, &args).unwrap().unwrap();
+        assert_eq!((job.extension(), job.kind()), ("jpg", "images"));
+        assert!(job.is_still_image());
+
+        let args = AppArgs::try_parse_from(["compressor", "--max-megapixels", "0", "--webp"]).unwrap();
+        assert_eq!(job::Job::plan("avif", &photo, &args).unwrap().unwrap().extension(), "webp");
+        assert_eq!(file::parse_extension_map("heif=avif").map(|map| map.resolve("heif")), Ok("avif".to_string()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
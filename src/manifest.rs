@@ -0,0 +1,121 @@
+//! 入力のチェックサムの照合（`--verify-inputs`）。
+//!
+//! 保管しておいた BLAKE3 のチェックサムの一覧（`b3sum` の出力と同じ形式）と入力を突き合わせ、
+//! 壊れた元ファイルを気づかずに圧縮してしまうことを防ぐ。
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+
+/// チェックサムが合わない入力の扱い
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum MismatchPolicy {
+    /// エラーとして実行を中断する
+    Abort,
+    /// 警告して処理しない（失敗として数える）
+    Skip,
+    /// 警告だけ表示して処理する
+    Warn,
+}
+
+/// 照合の結果
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Verification {
+    /// チェックサムが一致した
+    Match,
+    /// チェックサムが違う（読み取った値）
+    Mismatch(String),
+    /// 一覧に載っていない
+    Missing,
+}
+
+/// パスごとの期待するチェックサム（小文字の16進数）
+#[derive(Debug, Default)]
+pub struct Manifest {
+    checksums: HashMap<PathBuf, String>,
+}
+
+impl Manifest {
+    /// 一覧を読む。1行に「チェックサム、空白、パス」（`b3sum` や `sha256sum` と同じ。パスの前の `*` は無視する）。
+    /// 空行と `#` で始まる行は読み飛ばす。相対パスはカレントディレクトリを起点にする（`b3sum --check` と同じ）。
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Manifest::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Manifest, String> {
+        let mut checksums = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line.split_once(char::is_whitespace).and_then(|(checksum, file)| {
+                let file = file.trim_start();
+                let file = file.strip_prefix('*').unwrap_or(file);
+                let valid = checksum.len() == 64 && checksum.bytes().all(|b| b.is_ascii_hexdigit());
+                (valid && !file.is_empty()).then(|| (checksum.to_ascii_lowercase(), file))
+            });
+            let Some((checksum, file)) = entry else {
+                return Err(format!("{} 行目を読めません（BLAKE3 のチェックサムとパスを空白で区切ってください）: {line}", number + 1));
+            };
+            checksums.insert(normalize(Path::new(file)), checksum);
+        }
+        Ok(Manifest { checksums })
+    }
+
+    /// `path` の中身のチェックサムを一覧と照合する
+    pub fn verify(&self, path: &Path) -> std::io::Result<Verification> {
+        let Some(expected) = self.checksums.get(&normalize(path)) else {
+            return Ok(Verification::Missing);
+        };
+        let actual = checksum(path)?;
+        Ok(if actual == *expected { Verification::Match } else { Verification::Mismatch(actual) })
+    }
+}
+
+/// ファイルの BLAKE3 のチェックサム（小文字の16進数）。大きな動画もメモリに読み込まずに求める
+pub fn checksum(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// 入力のパスと一覧のパスを同じ形にそろえる（入力は実体のパスで渡されるため、できればシンボリックリンクも解決する）
+fn normalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 一致・不一致・一覧にないファイルを見分け、形式の崩れた行を報告すること
+    #[test]
+    fn verifies_against_manifest() {
+        let dir = std::env::temp_dir().join("compressor_manifest_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (good, bad, other) = (dir.join("good.jpg"), dir.join("bad.jpg"), dir.join("other.jpg"));
+        for file in [&good, &bad, &other] {
+            std::fs::write(file, b"original").unwrap();
+        }
+        let expected = checksum(&good).unwrap();
+        assert_eq!(expected, blake3::hash(b"original").to_hex().to_string());
+
+        let text = format!("# archive\n{expected}  {}\n\n{} *{}\n", good.display(), expected.to_uppercase(), bad.display());
+        let manifest = Manifest::parse(&text).unwrap();
+        std::fs::write(&bad, b"corrupted").unwrap();
+
+        assert_eq!(manifest.verify(&good).unwrap(), Verification::Match);
+        assert_eq!(manifest.verify(&bad).unwrap(), Verification::Mismatch(checksum(&bad).unwrap()));
+        assert_eq!(manifest.verify(&other).unwrap(), Verification::Missing);
+
+        assert!(Manifest::parse("abc  photo.jpg\n").unwrap_err().starts_with("1 行目"));
+        assert!(Manifest::parse(&format!("{expected}\n")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,178 @@
+//! PDF のページを画像として圧縮する（`pdf` フィーチャー）。
+//!
+//! スキャンした PDF 向け。各ページを poppler の `pdftoppm` で指定の DPI の画像にし、
+//! `rgb_image` の JPEG エンコーダで圧縮してから、JPEG のページだけからなる PDF に組み直す。
+//! 文字や図形もすべて画像になるため、テキストの検索や選択はできなくなる。
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+use crate::error::CompressError;
+use crate::file::work_dir;
+use crate::rgb_image;
+use crate::utilities::{run_tool, write_output, write_smaller};
+
+/// PDF 圧縮のオプション
+#[derive(Clone, Debug)]
+pub struct PdfOptions {
+    /// ページを画像にするときの解像度
+    pub dpi: u16,
+    /// JPEG の品質（0-100）
+    pub quality: f32,
+    /// 指定すると、圧縮したページの JPEG もこのディレクトリに書き出す（page-001.jpg, ...）
+    pub pages_dir: Option<PathBuf>,
+    /// ページの画像を置く作業ディレクトリの親。`None` ならシステムの一時ディレクトリ
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// 組み直す PDF の1ページ
+pub struct Page {
+    /// ページの画像（JPEG）
+    pub jpeg: Vec<u8>,
+    /// 画像の幅（ピクセル）
+    pub width: u32,
+    /// 画像の高さ（ピクセル）
+    pub height: u32,
+}
+
+/// pdftoppm が使えるかを判定する。プロセス起動を伴うため一度だけ実行して結果を使い回す。
+pub fn is_pdftoppm_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| Command::new("pdftoppm").arg("-v").output().is_ok())
+}
+
+/// PDF を圧縮する。組み直した PDF が元より大きい場合は元のまま出力する。
+pub fn path2compress(path: &Path, output_path: &Path, options: &PdfOptions) -> Result<(), CompressError> {
+    if !is_pdftoppm_available() {
+        return Err(CompressError::ToolMissing("pdftoppm"));
+    }
+
+    let work_dir = work_dir(options.temp_dir.as_deref(), "pdf");
+    std::fs::create_dir_all(&work_dir)?;
+    let result = compress_pages(path, &work_dir, options);
+    let _ = std::fs::remove_dir_all(&work_dir);
+    let pages = result?;
+
+    if let Some(dir) = &options.pages_dir {
+        for (index, page) in pages.iter().enumerate() {
+            write_output(&dir.join(format!("page-{:03}.jpg", index + 1)), &page.jpeg)?;
+        }
+    }
+
+    write_smaller(output_path, &write_pdf(&pages, options.dpi), &std::fs::read(path)?)
+}
+
+/// pdftoppm でページを PNG にし、それぞれを JPEG に圧縮する
+fn compress_pages(path: &Path, work_dir: &Path, options: &PdfOptions) -> Result<Vec<Page>, CompressError> {
+    let mut command = Command::new("pdftoppm");
+    command.args(["-r", &options.dpi.to_string(), "-png"]).arg(path).arg(work_dir.join("page"));
+    run_tool("pdftoppm", &mut command, true)?;
+
+    // pdftoppm はページ番号をページ数の桁に揃えて付けるため、名前順がページ順になる
+    let mut rendered: Vec<PathBuf> = std::fs::read_dir(work_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    rendered.sort();
+
+    rendered
+        .iter()
+        .map(|path| {
+            let img = image::open(path)?;
+            Ok(Page {
+                jpeg: rgb_image::image2jpeg(&img, options.quality, Some(options.dpi))?,
+                width: img.width(),
+                height: img.height(),
+            })
+        })
+        .collect()
+}
+
+/// JPEG のページだけからなる PDF を作る。ページの寸法は画像の寸法と `dpi` から求める。
+pub fn write_pdf(pages: &[Page], dpi: u16) -> Vec<u8> {
+    let points = |pixels: u32| pixels as f64 * 72.0 / dpi as f64;
+    // 1: カタログ, 2: ページツリー, 以降はページごとに ページ, 内容, 画像 の3つ
+    let page_ids: Vec<usize> = (0..pages.len()).map(|index| 3 + index * 3).collect();
+
+    let mut pdf = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::new();
+    let mut object = |pdf: &mut Vec<u8>, body: &[u8]| {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+        pdf.extend_from_slice(body);
+        pdf.extend_from_slice(b"\nendobj\n");
+    };
+
+    object(&mut pdf, b"<< /Type /Catalog /Pages 2 0 R >>");
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{id} 0 R")).collect();
+    object(&mut pdf, format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).as_bytes());
+
+    for (page, id) in pages.iter().zip(&page_ids) {
+        let (width, height) = (points(page.width), points(page.height));
+        object(
+            &mut pdf,
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {width:.2} {height:.2}] \
+                 /Resources << /XObject << /Im0 {} 0 R >> >> /Contents {} 0 R >>",
+                id + 2,
+                id + 1
+            )
+            .as_bytes(),
+        );
+
+        let content = format!("q {width:.2} 0 0 {height:.2} 0 0 cm /Im0 Do Q");
+        object(&mut pdf, format!("<< /Length {} >>\nstream\n{content}\nendstream", content.len()).as_bytes());
+
+        let mut image = format!(
+            "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB \
+             /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            page.width,
+            page.height,
+            page.jpeg.len()
+        )
+        .into_bytes();
+        image.extend_from_slice(&page.jpeg);
+        image.extend_from_slice(b"\nendstream");
+        object(&mut pdf, &image);
+    }
+
+    let xref = pdf.len();
+    pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes());
+    for offset in &offsets {
+        pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n", offsets.len() + 1).as_bytes(),
+    );
+
+    pdf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 相互参照表のオフセットが各オブジェクトの位置を指し、ページの寸法が DPI から求まること
+    #[test]
+    fn writes_pdf_with_valid_xref() {
+        let pages = [
+            Page { jpeg: b"\xFF\xD8page1\xFF\xD9".to_vec(), width: 300, height: 150 },
+            Page { jpeg: b"\xFF\xD8page2\xFF\xD9".to_vec(), width: 150, height: 300 },
+        ];
+        let pdf = write_pdf(&pages, 150);
+        let find = |needle: &str| pdf.windows(needle.len()).rposition(|window| window == needle.as_bytes());
+
+        assert!(find("/Kids [3 0 R 6 0 R] /Count 2").is_some());
+        assert!(find("/MediaBox [0 0 144.00 72.00]").is_some());
+        assert!(find("/Width 150 /Height 300").is_some());
+
+        let start = find("startxref\n").unwrap() + 10;
+        let xref: usize = String::from_utf8_lossy(&pdf[start..]).lines().next().unwrap().parse().unwrap();
+        let table = String::from_utf8_lossy(&pdf[xref..]).into_owned();
+        assert!(table.starts_with("xref\n0 9\n"));
+        for (index, line) in table.lines().skip(3).take(8).enumerate() {
+            let offset: usize = line[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj", index + 1).as_bytes()), "{index}: {line}");
+        }
+    }
+}
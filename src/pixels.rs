@@ -0,0 +1,117 @@
+//! メモリ上の画素の並びを直接圧縮する（ライブラリ向け）。
+//!
+//! 描画処理などで生成した画像を、PNG や JPEG のファイルを経由せずにエンコードするためのもの。
+//! エンコーダは CLI と同じもの（JPEG は mozjpeg、PNG は oxipng、WebP は libwebp）を使う。
+
+use std::io::Cursor;
+use image::{DynamicImage, ImageFormat, RgbImage, RgbaImage};
+use webp::Encoder;
+use crate::error::CompressError;
+use crate::{rgb_image, rgba_image, webp_image};
+
+/// 出力の形式
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    /// JPEG（非可逆）。透過は持てないため、RGBA の透過した部分は白の背景に合成する
+    Jpeg,
+    /// PNG（可逆）。品質は使わない
+    Png,
+    /// WebP（非可逆）
+    Webp,
+    /// WebP（可逆）。品質は使わない
+    WebpLossless,
+}
+
+/// 1画素 4 バイト（R, G, B, A）を左上から行ごとに並べた画素を圧縮する。`quality` は非可逆圧縮の品質（0-100）。
+///
+/// 画素の数が `width * height` と合わない場合は [`CompressError::InvalidInput`]。
+pub fn compress_rgba(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    format: OutputFormat,
+    quality: f32,
+) -> Result<Vec<u8>, CompressError> {
+    let img = Some(pixels).filter(|pixels| fits(pixels, width, height, 4));
+    let img = img.and_then(|pixels| RgbaImage::from_raw(width, height, pixels.to_vec()));
+    let img = img.ok_or_else(|| size_mismatch(pixels, width, height, 4))?;
+    match format {
+        OutputFormat::Webp => Ok(Encoder::from_rgba(pixels, width, height).encode(quality).to_vec()),
+        OutputFormat::WebpLossless => webp_image::encode_lossless(&img),
+        _ => encode(&DynamicImage::ImageRgba8(img), format, quality),
+    }
+}
+
+/// 1画素 3 バイト（R, G, B）を左上から行ごとに並べた画素を圧縮する。`quality` は非可逆圧縮の品質（0-100）。
+///
+/// 画素の数が `width * height` と合わない場合は [`CompressError::InvalidInput`]。
+pub fn compress_rgb(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    format: OutputFormat,
+    quality: f32,
+) -> Result<Vec<u8>, CompressError> {
+    let img = Some(pixels).filter(|pixels| fits(pixels, width, height, 3));
+    let img = img.and_then(|pixels| RgbImage::from_raw(width, height, pixels.to_vec()));
+    let img = img.ok_or_else(|| size_mismatch(pixels, width, height, 3))?;
+    match format {
+        OutputFormat::Webp => Ok(Encoder::from_rgb(pixels, width, height).encode(quality).to_vec()),
+        OutputFormat::WebpLossless => Ok(Encoder::from_rgb(pixels, width, height).encode_lossless().to_vec()),
+        _ => encode(&DynamicImage::ImageRgb8(img), format, quality),
+    }
+}
+
+/// JPEG と PNG のエンコード。PNG は一度そのまま書いてから oxipng で最適化する（最適化に時間の上限は設けない）
+fn encode(img: &DynamicImage, format: OutputFormat, quality: f32) -> Result<Vec<u8>, CompressError> {
+    if format == OutputFormat::Jpeg {
+        return rgb_image::image2jpeg(img, quality, None);
+    }
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    rgba_image::optimize(&png, None, &[])
+}
+
+/// 空でなく、画素の並びの長さがちょうど寸法に合うか（余分なバイトも受け付けない）
+fn fits(pixels: &[u8], width: u32, height: u32, channels: u64) -> bool {
+    width > 0 && height > 0 && pixels.len() as u64 == width as u64 * height as u64 * channels
+}
+
+fn size_mismatch(pixels: &[u8], width: u32, height: u32, channels: u64) -> CompressError {
+    CompressError::InvalidInput(format!(
+        "画素の並びの長さ {} バイトが {width}x{height}（1画素 {channels} バイト）に合いません",
+        pixels.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 各形式でデコードし直すと同じ寸法になり、可逆の形式では画素も一致すること
+    #[test]
+    fn compresses_raw_pixels() {
+        let rgba: Vec<u8> = (0..16u32 * 8).flat_map(|i| [(i * 2) as u8, 100, 200, if i % 2 == 0 { 255 } else { 128 }]).collect();
+        let rgb: Vec<u8> = rgba.chunks(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+
+        for format in [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp, OutputFormat::WebpLossless] {
+            let decoded = image::load_from_memory(&compress_rgba(&rgba, 16, 8, format, 80.0).unwrap()).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (16, 8), "{format:?}");
+            let decoded = image::load_from_memory(&compress_rgb(&rgb, 16, 8, format, 80.0).unwrap()).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (16, 8), "{format:?}");
+        }
+
+        let png = compress_rgba(&rgba, 16, 8, OutputFormat::Png, 80.0).unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8().into_raw(), rgba);
+        let webp = compress_rgb(&rgb, 16, 8, OutputFormat::WebpLossless, 80.0).unwrap();
+        assert_eq!(image::load_from_memory(&webp).unwrap().to_rgb8().into_raw(), rgb);
+    }
+
+    /// 画素の並びの長さが寸法に合わなければ、エンコードせずに入力の誤りとすること
+    #[test]
+    fn rejects_mismatched_buffer() {
+        assert!(matches!(compress_rgba(&[0; 12], 2, 2, OutputFormat::Png, 80.0), Err(CompressError::InvalidInput(_))));
+        assert!(matches!(compress_rgb(&[0; 13], 2, 2, OutputFormat::Jpeg, 80.0), Err(CompressError::InvalidInput(_))));
+        assert!(matches!(compress_rgb(&[], 0, 0, OutputFormat::Webp, 80.0), Err(CompressError::InvalidInput(_))));
+    }
+}
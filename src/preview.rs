@@ -0,0 +1,65 @@
+//! 圧縮の結果を目で確かめる比較画像（`--preview`）。
+//!
+//! 全体を処理する前に、1枚の画像の同じ範囲を元と圧縮後で左右に並べ、ブロックノイズやにじみを確かめるためのもの。
+
+use std::io::Cursor;
+use std::path::Path;
+use image::{imageops, DynamicImage, GenericImageView, ImageFormat, Rgba, RgbaImage};
+use crate::error::CompressError;
+use crate::geometry::Crop;
+use crate::utilities::write_output;
+
+/// 左右の間と縁の余白（ピクセル）
+const GAP: u32 = 4;
+/// 背景の色。透過した画像の透明な部分もこの色になる
+const BACKGROUND: Rgba<u8> = Rgba([40, 40, 40, 255]);
+
+/// --preview-region を指定しない場合に切り抜く範囲
+pub const DEFAULT_REGION: Crop = Crop::Center { width: 512, height: 512 };
+
+/// 元と圧縮後の画像から同じ `region` を切り抜き、左に元、右に圧縮後を並べる。寸法の違う画像は同じ範囲にならないためエラー。
+pub fn compose(original: DynamicImage, compressed: DynamicImage, region: Crop) -> Result<RgbaImage, CompressError> {
+    if original.dimensions() != compressed.dimensions() {
+        let ((w1, h1), (w2, h2)) = (original.dimensions(), compressed.dimensions());
+        return Err(CompressError::InvalidInput(format!(
+            "元（{w1}x{h1}）と圧縮後（{w2}x{h2}）の寸法が違うため、同じ範囲を比べられません"
+        )));
+    }
+    let left = region.apply(original)?.to_rgba8();
+    let right = region.apply(compressed)?.to_rgba8();
+
+    let (width, height) = left.dimensions();
+    let mut sheet = RgbaImage::from_pixel(width * 2 + GAP * 3, height + GAP * 2, BACKGROUND);
+    imageops::overlay(&mut sheet, &left, GAP as i64, GAP as i64);
+    imageops::overlay(&mut sheet, &right, (width + GAP * 2) as i64, GAP as i64);
+    Ok(sheet)
+}
+
+/// 比較画像を PNG で書き出す（JPEG にすると比較画像自体にノイズが乗るため）
+pub fn write(output_path: &Path, sheet: &RgbaImage) -> Result<(), CompressError> {
+    let mut png = Vec::new();
+    sheet.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    write_output(output_path, &png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 同じ範囲を左右に並べ、寸法の違う画像は比べないこと
+    #[test]
+    fn composes_side_by_side() {
+        let original = DynamicImage::ImageRgba8(RgbaImage::from_fn(20, 10, |x, _| Rgba([x as u8 * 10, 0, 0, 255])));
+        let compressed = DynamicImage::ImageRgba8(RgbaImage::from_fn(20, 10, |x, _| Rgba([0, x as u8 * 10, 0, 255])));
+        let region = Crop::Region { width: 5, height: 4, x: 10, y: 2 };
+        let sheet = compose(original.clone(), compressed, region).unwrap();
+
+        assert_eq!(sheet.dimensions(), (5 * 2 + GAP * 3, 4 + GAP * 2));
+        assert_eq!(*sheet.get_pixel(GAP, GAP), Rgba([100, 0, 0, 255]));
+        assert_eq!(*sheet.get_pixel(GAP * 2 + 5, GAP), Rgba([0, 100, 0, 255]));
+        assert_eq!(*sheet.get_pixel(GAP + 5, GAP), BACKGROUND);
+
+        let smaller = DynamicImage::ImageRgba8(RgbaImage::new(10, 10));
+        assert!(compose(original, smaller, DEFAULT_REGION).is_err());
+    }
+}
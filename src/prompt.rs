@@ -0,0 +1,96 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+/// 上書き確認への回答
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    /// このファイルを上書きする
+    Yes,
+    /// このファイルはスキップする
+    No,
+    /// 以降はすべて確認せずに上書きする
+    All,
+    /// 処理を中断する
+    Quit,
+}
+
+/// 入力された文字列を回答として解釈する。解釈できない場合は `None`。
+pub fn parse_answer(input: &str) -> Option<Answer> {
+    match input.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(Answer::Yes),
+        "n" | "no" => Some(Answer::No),
+        "a" | "all" => Some(Answer::All),
+        "q" | "quit" => Some(Answer::Quit),
+        _ => None,
+    }
+}
+
+/// 既存の出力を上書きしてよいか端末で確認する。
+/// 端末でない（パイプ・リダイレクト）場合や EOF に達した場合は、上書きしない側に倒して `No` を返す。
+pub fn ask_overwrite(target: &Path) -> Answer {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Answer::No;
+    }
+
+    loop {
+        // 標準出力をリダイレクトしても確認が見えるよう、標準エラー出力に出す
+        eprint!("{:?} を上書きしますか? [y]es/[n]o/[a]ll/[q]uit: ", target);
+        let _ = io::stderr().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return Answer::No,
+            Ok(_) => {
+                if let Some(answer) = parse_answer(&line) {
+                    return answer;
+                }
+            }
+        }
+    }
+}
+
+/// 続けてよいか端末で確認する。端末でない場合や EOF に達した場合は、続けない側に倒して `false` を返す。
+pub fn confirm(question: &str) -> bool {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+
+    loop {
+        eprint!("{question}");
+        let _ = io::stderr().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => match parse_answer(&line) {
+                Some(Answer::Yes) => return true,
+                Some(Answer::No | Answer::Quit) => return false,
+                // 「以降すべて」は1回きりの確認には当てはまらないため、聞き直す
+                Some(Answer::All) | None => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 省略形・大文字・改行付きでも解釈できること
+    #[test]
+    fn parses_answers() {
+        assert_eq!(parse_answer("y\n"), Some(Answer::Yes));
+        assert_eq!(parse_answer("No"), Some(Answer::No));
+        assert_eq!(parse_answer(" ALL "), Some(Answer::All));
+        assert_eq!(parse_answer("q"), Some(Answer::Quit));
+    }
+
+    /// 空行や想定外の入力は回答とみなさないこと（再入力を促す）
+    #[test]
+    fn rejects_unknown_answers() {
+        assert_eq!(parse_answer(""), None);
+        assert_eq!(parse_answer("maybe"), None);
+    }
+}
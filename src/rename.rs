@@ -0,0 +1,86 @@
+use std::path::Path;
+use clap::ValueEnum;
+use crate::{exif, rgb_image};
+
+/// 出力ファイル名の付け方
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RenameScheme {
+    /// 処理順の連番（0001, 0002, ...）
+    Seq,
+    /// Exif の撮影日時（YYYYMMDD-HHMMSS）。取得できない場合は元の名前
+    Date,
+    /// 小文字化し、記号や空白をハイフンにする
+    Slug,
+}
+
+impl RenameScheme {
+    /// 拡張子を除いた出力ファイル名を決める。`seq` は1始まりの処理順。
+    pub fn stem(self, path: &Path, seq: usize) -> String {
+        let original = path.file_stem().unwrap_or_default().to_string_lossy();
+        match self {
+            RenameScheme::Seq => format!("{seq:04}"),
+            RenameScheme::Date => jpeg_capture_date(path).unwrap_or_else(|| original.into_owned()),
+            RenameScheme::Slug => slugify(&original),
+        }
+    }
+}
+
+/// JPEG の Exif から撮影日時を読む。JPEG 以外や Exif がない場合は `None`。
+fn jpeg_capture_date(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    rgb_image::extract_metadata_markers(&data)
+        .into_iter()
+        .filter(|(app_number, _)| *app_number == 1)
+        .find_map(|(_, payload)| exif::capture_date(&payload))
+}
+
+/// 小文字化し、英数字（日本語などの文字を含む）以外をハイフンにまとめる
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        // 記号だけの名前は空になってしまうため元の名前を残す
+        return name.to_string();
+    }
+    slug.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 大文字・空白・記号をまとめてハイフンにすること
+    #[test]
+    fn slugifies_names() {
+        assert_eq!(slugify("IMG_0001 (Copy)"), "img-0001-copy");
+        assert_eq!(slugify("  Summer--Trip  "), "summer-trip");
+        assert_eq!(slugify("夏 休み"), "夏-休み");
+    }
+
+    /// 記号だけの名前は空にしないこと
+    #[test]
+    fn keeps_symbol_only_names() {
+        assert_eq!(slugify("___"), "___");
+    }
+
+    /// 連番は4桁でゼロ埋めすること
+    #[test]
+    fn seq_is_zero_padded() {
+        assert_eq!(RenameScheme::Seq.stem(Path::new("a/photo.jpg"), 7), "0007");
+        assert_eq!(RenameScheme::Seq.stem(Path::new("a/photo.jpg"), 12345), "12345");
+    }
+
+    /// Exif を読めない場合は元の名前を使うこと
+    #[test]
+    fn date_falls_back_to_original_name() {
+        assert_eq!(RenameScheme::Date.stem(Path::new("/nonexistent/photo.jpg"), 1), "photo");
+    }
+}
@@ -0,0 +1,526 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use crate::similarity::Score;
+use crate::sprite::Sprite;
+
+/// JSON レポートの形式のバージョン。
+/// フィールドの削除・改名・意味の変更など、既存の読み手を壊す変更をしたら上げる（追加だけなら上げない）。
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// --histogram の棒の最大の長さ（文字数）
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// 1ファイルの処理結果
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// 圧縮した
+    Compressed,
+    /// 削減率が --min-reduction に届かず、元のファイルをそのまま出力した
+    KeptOriginal,
+    /// 圧縮済みの出力があるためスキップした
+    Skipped,
+    /// 圧縮に失敗した
+    Failed,
+}
+
+impl Status {
+    /// JSON レポートでの名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Compressed => "compressed",
+            Status::KeptOriginal => "kept_original",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// JSON レポートに載せる1ファイル分の記録
+pub struct FileRecord {
+    pub input: PathBuf,
+    /// 出力先。出力先を決める前に失敗した場合は `None`
+    pub output: Option<PathBuf>,
+    pub status: Status,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    /// 失敗した場合のエラーメッセージ
+    pub error: Option<String>,
+    /// --diff-report で求めた元の画像との差
+    pub score: Option<Score>,
+    /// SSIM が --min-quality-score に届かなかったか
+    pub flagged: bool,
+    /// 処理の種類（images / videos / audio / others）。処理を決める前に失敗した場合は `None`
+    pub kind: Option<&'static str>,
+    /// 圧縮にかかった時間（秒）。圧縮を実行しなかった場合は `None`
+    pub seconds: Option<f64>,
+    /// --sprite-sheet で書き出したスプライトシート
+    pub sprite: Option<Sprite>,
+}
+
+/// 実行全体の集計結果
+#[derive(Default)]
+pub struct Summary {
+    /// 圧縮に成功したファイル数
+    pub processed: usize,
+    /// 圧縮済みの出力があるためスキップしたファイル数
+    pub skipped: usize,
+    /// 圧縮に失敗したファイル数
+    pub errors: usize,
+    /// 削減率が --min-reduction に届かず、元のファイルをそのまま出力した数（processed に含む）
+    pub kept_original: usize,
+    /// 圧縮前の合計サイズ（バイト）
+    pub original_bytes: u64,
+    /// 圧縮後の合計サイズ（バイト）
+    pub compressed_bytes: u64,
+    /// --max-files の上限に達したため処理しなかったファイル数
+    pub limited: usize,
+    /// SSIM が --min-quality-score に届かなかったファイル数（processed に含む）
+    pub flagged: usize,
+    /// 寸法が --only-larger-than 以下のため処理しなかった画像の数
+    pub undersized: usize,
+    /// 圧縮結果が既存の出力と同じで、書き直さなかったファイル数（processed に含む）
+    pub unchanged: usize,
+    /// --max-runtime の期限を過ぎたため圧縮を始めなかったファイル数
+    pub timed_out: usize,
+    /// 中身が拡張子と合わないため --strict-extensions で処理しなかったファイル数
+    pub mismatched: usize,
+    /// ファイルごとの記録（処理順）
+    pub files: Vec<FileRecord>,
+}
+
+impl Summary {
+    /// 圧縮に成功したファイルを記録する
+    pub fn record_compressed(&mut self, input: &Path, output: &Path, original_size: u64, compressed_size: u64) {
+        self.processed += 1;
+        self.original_bytes += original_size;
+        self.compressed_bytes += compressed_size;
+        self.push(input, Some(output), Status::Compressed, original_size, compressed_size, None);
+    }
+
+    /// 圧縮結果を捨てて元のファイルをそのまま出力したファイルを記録する
+    pub fn record_kept_original(&mut self, input: &Path, output: &Path, original_size: u64) {
+        self.processed += 1;
+        self.kept_original += 1;
+        self.original_bytes += original_size;
+        self.compressed_bytes += original_size;
+        self.push(input, Some(output), Status::KeptOriginal, original_size, original_size, None);
+    }
+
+    /// 直前に記録したファイルに、元の画像との差を書き添える
+    pub fn record_score(&mut self, score: Score, flagged: bool) {
+        if let Some(record) = self.files.last_mut() {
+            record.score = Some(score);
+            record.flagged = flagged;
+            self.flagged += flagged as usize;
+        }
+    }
+
+    /// 直前に記録したファイルに、処理の種類とかかった時間を書き添える
+    pub fn record_job(&mut self, kind: &'static str, seconds: Option<f64>) {
+        if let Some(record) = self.files.last_mut() {
+            record.kind = Some(kind);
+            record.seconds = seconds;
+        }
+    }
+
+    /// 直前に記録したファイルに、書き出したスプライトシートを書き添える
+    pub fn record_sprite(&mut self, sprite: Sprite) {
+        if let Some(record) = self.files.last_mut() {
+            record.sprite = Some(sprite);
+        }
+    }
+
+    /// 圧縮済みの出力があるためスキップしたファイルを記録する
+    pub fn record_skipped(&mut self, input: &Path, output: &Path) {
+        self.skipped += 1;
+        self.push(input, Some(output), Status::Skipped, 0, 0, None);
+    }
+
+    /// 圧縮に失敗したファイルを記録する
+    pub fn record_failed(&mut self, input: &Path, output: Option<&Path>, error: impl ToString) {
+        self.errors += 1;
+        self.push(input, output, Status::Failed, 0, 0, Some(error.to_string()));
+    }
+
+    fn push(
+        &mut self,
+        input: &Path,
+        output: Option<&Path>,
+        status: Status,
+        original_bytes: u64,
+        compressed_bytes: u64,
+        error: Option<String>,
+    ) {
+        self.files.push(FileRecord {
+            input: input.to_path_buf(),
+            output: output.map(Path::to_path_buf),
+            status,
+            original_bytes,
+            compressed_bytes,
+            error,
+            score: None,
+            flagged: false,
+            kind: None,
+            seconds: None,
+            sprite: None,
+        });
+    }
+
+    /// 削減できたバイト数。形式変換などで大きくなった場合は負になる。
+    pub fn bytes_saved(&self) -> i64 {
+        self.original_bytes as i64 - self.compressed_bytes as i64
+    }
+
+    /// 集計結果を表示する
+    pub fn print(&self) {
+        let kept = if self.kept_original > 0 {
+            format!(" (うち元のまま {} 件)", self.kept_original)
+        } else {
+            String::new()
+        };
+        let limited = if self.limited > 0 {
+            format!(", 上限により未処理 {} 件", self.limited)
+        } else {
+            String::new()
+        };
+        let undersized = if self.undersized > 0 {
+            format!(", 寸法が小さく未処理 {} 件", self.undersized)
+        } else {
+            String::new()
+        };
+        let timed_out = if self.timed_out > 0 {
+            format!(", 時間切れで未処理 {} 件", self.timed_out)
+        } else {
+            String::new()
+        };
+        let mismatched = if self.mismatched > 0 {
+            format!(", 拡張子と中身が合わず未処理 {} 件", self.mismatched)
+        } else {
+            String::new()
+        };
+        let flagged = if self.flagged > 0 {
+            format!(", 品質の基準未満 {} 件", self.flagged)
+        } else {
+            String::new()
+        };
+        let unchanged = if self.unchanged > 0 {
+            format!(" (うち変更なし {} 件)", self.unchanged)
+        } else {
+            String::new()
+        };
+        println!(
+            "完了: 処理 {} 件{kept}{unchanged}, スキップ {} 件, 失敗 {} 件{limited}{timed_out}{undersized}{mismatched}{flagged}, 削減 {}",
+            self.processed,
+            self.skipped,
+            self.errors,
+            format_bytes(self.bytes_saved()),
+        );
+    }
+
+    /// 出力したファイルの削減率の分布を、20% 刻みの棒グラフにする（--histogram）。
+    /// 元のまま出力したファイルは 0%、大きくなったファイルは `<0%` に数える。
+    pub fn histogram(&self) -> String {
+        const LABELS: [&str; 6] = ["<0%", "0-20%", "20-40%", "40-60%", "60-80%", "80-100%"];
+
+        let mut counts = [0usize; LABELS.len()];
+        for record in self.files.iter().filter(|record| matches!(record.status, Status::Compressed | Status::KeptOriginal)) {
+            let percent = reduction_percent(record.original_bytes, record.compressed_bytes);
+            let bucket = if percent < 0.0 { 0 } else { 1 + ((percent / 20.0) as usize).min(4) };
+            counts[bucket] += 1;
+        }
+
+        let total: usize = counts.iter().sum();
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+        let mut chart = format!("削減率の分布（{total} 件）:\n");
+        for (label, count) in LABELS.iter().zip(counts) {
+            // 1件でもあれば棒が見えるよう切り上げる
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max));
+            let _ = writeln!(chart, "{label:>7} | {bar:<HISTOGRAM_WIDTH$} {count}");
+        }
+        chart
+    }
+
+    /// JSON レポートを組み立てる。形式は README の「JSON レポート」を参照（[`SCHEMA_VERSION`] で版を管理する）。
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = writeln!(json, "{{");
+        let _ = writeln!(json, "  \"schema_version\": {SCHEMA_VERSION},");
+        let _ = writeln!(json, "  \"summary\": {{");
+        let _ = writeln!(json, "    \"processed\": {},", self.processed);
+        let _ = writeln!(json, "    \"skipped\": {},", self.skipped);
+        let _ = writeln!(json, "    \"errors\": {},", self.errors);
+        let _ = writeln!(json, "    \"kept_original\": {},", self.kept_original);
+        let _ = writeln!(json, "    \"original_bytes\": {},", self.original_bytes);
+        let _ = writeln!(json, "    \"compressed_bytes\": {},", self.compressed_bytes);
+        let _ = writeln!(json, "    \"limited\": {},", self.limited);
+        let _ = writeln!(json, "    \"timed_out\": {},", self.timed_out);
+        let _ = writeln!(json, "    \"undersized\": {},", self.undersized);
+        let _ = writeln!(json, "    \"unchanged\": {},", self.unchanged);
+        let _ = writeln!(json, "    \"mismatched\": {},", self.mismatched);
+        let _ = writeln!(json, "    \"flagged\": {}", self.flagged);
+        let _ = writeln!(json, "  }},");
+
+        let files: Vec<String> = self.files.iter().map(|record| format!("    {}", record.to_json())).collect();
+        if files.is_empty() {
+            let _ = writeln!(json, "  \"files\": []");
+        } else {
+            let _ = writeln!(json, "  \"files\": [\n{}\n  ]", files.join(",\n"));
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    /// JSON レポートをファイルに書き出す
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        crate::file::ensure_parent_dir(path)?;
+        std::fs::write(path, self.to_json())
+    }
+
+    /// CSV レポートを組み立てる。1行目は見出しで、以降はファイルごとに1行（処理順）
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("path,type,status,original_bytes,compressed_bytes,reduction_percent,duration_seconds\n");
+        for record in &self.files {
+            let _ = writeln!(csv, "{}", record.to_csv());
+        }
+        csv
+    }
+
+    /// CSV レポートをファイルに書き出す
+    pub fn write_csv(&self, path: &Path) -> std::io::Result<()> {
+        crate::file::ensure_parent_dir(path)?;
+        std::fs::write(path, self.to_csv())
+    }
+}
+
+impl FileRecord {
+    fn to_json(&self) -> String {
+        let path = |path: &Path| json_string(&path.to_string_lossy());
+        format!(
+            "{{\"input\": {}, \"output\": {}, \"status\": \"{}\", \"original_bytes\": {}, \"compressed_bytes\": {}, \"error\": {}, \"ssim\": {}, \"psnr\": {}, \"flagged\": {}, \"sprite\": {}}}",
+            path(&self.input),
+            self.output.as_deref().map(path).unwrap_or_else(|| "null".to_string()),
+            self.status.name(),
+            self.original_bytes,
+            self.compressed_bytes,
+            self.error.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+            self.score.map(|score| format!("{:.6}", score.ssim)).unwrap_or_else(|| "null".to_string()),
+            self.score.map(|score| format!("{:.2}", score.psnr)).unwrap_or_else(|| "null".to_string()),
+            self.flagged,
+            self.sprite.as_ref().map(|sprite| sprite_json(sprite, path)).unwrap_or_else(|| "null".to_string()),
+        )
+    }
+}
+
+/// スプライトシートのパスと、タイルごとの時刻と位置
+fn sprite_json(sprite: &Sprite, path: impl Fn(&Path) -> String) -> String {
+    let tiles: Vec<String> = sprite
+        .tiles
+        .iter()
+        .map(|tile| {
+            format!(
+                "{{\"time\": {:.3}, \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}}}",
+                tile.time, tile.x, tile.y, tile.width, tile.height
+            )
+        })
+        .collect();
+    format!("{{\"path\": {}, \"tiles\": [{}]}}", path(&sprite.path), tiles.join(", "))
+}
+
+impl FileRecord {
+    /// CSV の1行。削減率は出力したファイルだけに、時間は圧縮を実行したファイルだけに書き、それ以外は空欄にする
+    fn to_csv(&self) -> String {
+        let written = matches!(self.status, Status::Compressed | Status::KeptOriginal);
+        format!(
+            "{},{},{},{},{},{},{}",
+            csv_field(&self.input.to_string_lossy()),
+            self.kind.unwrap_or_default(),
+            self.status.name(),
+            self.original_bytes,
+            self.compressed_bytes,
+            if written { format!("{:.1}", reduction_percent(self.original_bytes, self.compressed_bytes)) } else { String::new() },
+            self.seconds.map(|seconds| format!("{seconds:.3}")).unwrap_or_default(),
+        )
+    }
+}
+
+/// CSV の1つの値にする。カンマ・引用符・改行を含む場合は引用符で囲み、中の引用符は二重にする（RFC 4180）
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// JSON の文字列リテラルにする（引用符と制御文字をエスケープする）
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// サイズの削減率（%）。元のサイズが 0 の場合は 0 とする。
+pub fn reduction_percent(original_size: u64, compressed_size: u64) -> f64 {
+    if original_size == 0 {
+        return 0.0;
+    }
+    100.0 * (1.0 - compressed_size as f64 / original_size as f64)
+}
+
+/// バイト数を人が読みやすい単位に整形する（1024 単位）
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    let sign = if bytes < 0 { "-" } else { "" };
+    let abs = bytes.unsigned_abs();
+    if abs < 1024 {
+        return format!("{sign}{abs} B");
+    }
+
+    let mut value = abs as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{sign}{value:.2} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 単位の切り替わりと符号の扱い
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.00 KB");
+        assert_eq!(format_bytes(5 * 1024 * 1024), "5.00 MB");
+        assert_eq!(format_bytes(-2048), "-2.00 KB");
+    }
+
+    /// 大きくなった場合は負、元が空の場合は 0 になること
+    #[test]
+    fn computes_reduction_percent() {
+        assert_eq!(reduction_percent(200, 50), 75.0);
+        assert_eq!(reduction_percent(100, 150), -50.0);
+        assert_eq!(reduction_percent(0, 10), 0.0);
+    }
+
+    /// 形式変換で大きくなったファイルは削減量をマイナスにすること
+    #[test]
+    fn bytes_saved_can_be_negative() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("a.jpg"), Path::new("out/a.jpg"), 100, 40);
+        summary.record_compressed(Path::new("b.png"), Path::new("out/b.webp"), 100, 200);
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.bytes_saved(), -40);
+    }
+
+    /// 引用符・バックスラッシュ・制御文字をエスケープすること
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("改行\n\u{1}"), "\"改行\\n\\u0001\"");
+    }
+
+    /// 先頭に schema_version を置き、ファイルごとの状態と失敗理由を載せること
+    #[test]
+    fn writes_versioned_json_report() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("a.jpg"), Path::new("out/a.jpg"), 100, 40);
+        summary.record_skipped(Path::new("b.jpg"), Path::new("out/b.jpg"));
+        summary.record_failed(Path::new("c.mp4"), None, "broken");
+
+        let json = summary.to_json();
+        assert!(json.starts_with("{\n  \"schema_version\": 1,\n"), "{json}");
+        assert!(json.contains("    \"limited\": 0,\n    \"timed_out\": 0,\n    \"undersized\": 0,\n    \"unchanged\": 0,\n    \"mismatched\": 0,\n"), "{json}");
+        assert!(json.contains("{\"input\": \"a.jpg\", \"output\": \"out/a.jpg\", \"status\": \"compressed\", \"original_bytes\": 100, \"compressed_bytes\": 40, \"error\": null, \"ssim\": null, \"psnr\": null, \"flagged\": false, \"sprite\": null}"));
+        assert!(json.contains("\"status\": \"skipped\""));
+        assert!(json.contains("\"output\": null, \"status\": \"failed\", \"original_bytes\": 0, \"compressed_bytes\": 0, \"error\": \"broken\""));
+        assert_eq!(summary.errors, 1);
+        assert_eq!(Summary::default().to_json().matches("\"files\": []").count(), 1);
+    }
+
+    /// 元の画像との差を直前の記録に書き添え、基準未満の数を数えること
+    #[test]
+    fn records_scores() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("a.jpg"), Path::new("out/a.jpg"), 100, 40);
+        summary.record_score(Score { ssim: 0.912345, psnr: 31.5 }, true);
+
+        assert_eq!(summary.flagged, 1);
+        let json = summary.to_json();
+        assert!(json.contains("\"ssim\": 0.912345, \"psnr\": 31.50, \"flagged\": true, \"sprite\": null}"), "{json}");
+        assert!(json.contains("\"flagged\": 1\n"), "{json}");
+    }
+
+    /// スプライトシートのタイルの時刻と位置を直前の記録に書き添えること
+    #[test]
+    fn records_sprite_tiles() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("clip.mp4"), Path::new("out/clip.mp4"), 100, 40);
+        let tile = |time, x| crate::sprite::Tile { time, x, y: 4, width: 160, height: 90 };
+        summary.record_sprite(Sprite { path: PathBuf::from("out/clip.sprite.jpg"), tiles: vec![tile(1.25, 4), tile(3.75, 168)] });
+
+        let json = summary.to_json();
+        assert!(
+            json.contains("\"sprite\": {\"path\": \"out/clip.sprite.jpg\", \"tiles\": [{\"time\": 1.250, \"x\": 4, \"y\": 4, \"width\": 160, \"height\": 90}, {\"time\": 3.750, \"x\": 168, \"y\": 4, \"width\": 160, \"height\": 90}]}}"),
+            "{json}"
+        );
+    }
+
+    /// 見出しに続けてファイルごとに1行を書き、カンマや引用符を含むパスを引用符で囲むこと
+    #[test]
+    fn writes_csv_report() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("photos/a,b.jpg"), Path::new("out/a,b.jpg"), 200, 50);
+        summary.record_job("images", Some(0.25));
+        summary.record_skipped(Path::new("say \"hi\".mp4"), Path::new("out/say.mp4"));
+        summary.record_job("videos", None);
+        summary.record_failed(Path::new("c.txt"), None, "対応していない形式です");
+
+        let csv = summary.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "path,type,status,original_bytes,compressed_bytes,reduction_percent,duration_seconds");
+        assert_eq!(lines[1], "\"photos/a,b.jpg\",images,compressed,200,50,75.0,0.250");
+        assert_eq!(lines[2], "\"say \"\"hi\"\".mp4\",videos,skipped,0,0,,");
+        assert_eq!(lines[3], "c.txt,,failed,0,0,,");
+        assert_eq!(lines.len(), 4);
+    }
+
+    /// 出力したファイルだけを 20% 刻みで数え、最も多い区間の棒を最大の長さにすること
+    #[test]
+    fn draws_reduction_histogram() {
+        let mut summary = Summary::default();
+        summary.record_compressed(Path::new("a.jpg"), Path::new("out/a.jpg"), 100, 90);
+        summary.record_compressed(Path::new("b.jpg"), Path::new("out/b.jpg"), 100, 85);
+        summary.record_compressed(Path::new("c.png"), Path::new("out/c.webp"), 100, 120);
+        summary.record_compressed(Path::new("d.png"), Path::new("out/d.png"), 100, 0);
+        summary.record_kept_original(Path::new("e.jpg"), Path::new("out/e.jpg"), 100);
+        summary.record_failed(Path::new("f.jpg"), None, "壊れています");
+
+        let chart = summary.histogram();
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines[0], "削減率の分布（5 件）:");
+        assert_eq!(lines[1], format!("    <0% | {:<40} 1", "#".repeat(14)));
+        assert_eq!(lines[2], format!("  0-20% | {} 3", "#".repeat(40)));
+        assert_eq!(lines[3], format!(" 20-40% | {:<40} 0", ""));
+        assert_eq!(lines[6], format!("80-100% | {:<40} 1", "#".repeat(14)));
+    }
+}
@@ -1,27 +1,149 @@
-use image::DynamicImage;
-use mozjpeg::{Compress, Marker};
-use std::path::Path;
+use image::metadata::Orientation;
+use image::{DynamicImage, RgbImage};
+use mozjpeg::{qtable, ColorSpace, Compress, Decompress, Marker, PixelDensity, PixelDensityUnit};
+use std::path::{Path, PathBuf};
 use crate::error::CompressError;
-use crate::utilities::{get_aspect_ratio, write_smaller};
+use crate::exif;
+use crate::jpeg_transform;
+use crate::utilities::{get_aspect_ratio, sharpen, write_output, write_smaller, warning, Background, Dpi, Encoded, ImageOptions, ScanPreset, Tonemap};
 
-pub fn path2compress(path: &Path, output_path: &Path, quality: f32) -> Result<(), CompressError> {
+/// `options.exif_source` を指定すると、そのJPEGの Exif で出力の Exif を置き換える（現像後に Exif が失われた場合など）。
+/// `options.dpi` は JFIF に書き込む解像度の扱い。
+pub fn path2compress(path: &Path, output_path: &Path, options: &ImageOptions) -> Result<(), CompressError> {
+    write_output(output_path, &path2jpeg(path, options)?.into_output())
+}
+
+/// `path2compress` と同じように圧縮し、ファイルに書かずに返す。
+pub fn path2jpeg(path: &Path, options: &ImageOptions) -> Result<Encoded, CompressError> {
     // 元データはメタデータの引き継ぎとサイズ比較の両方で使う
     let original = std::fs::read(path)?;
+    let exif_source = options.exif_source.as_deref().map(std::fs::read).transpose()?;
+
+    // 向きを可逆変換で反映できた場合は、再エンコードせずにそのまま出力する
+    if options.lossless_orient {
+        if let Some(mut jpeg_data) = lossless_orient(&original, options.dpi)? {
+            if Scans::of(options) == Scans::PreviewFirst {
+                jpeg_data = jpeg_transform::preview_first_scans(&jpeg_data)?;
+            }
+            if let Some(mcus) = options.restart_interval {
+                jpeg_data = jpeg_transform::with_restart_interval(&jpeg_data, mcus)?;
+            }
+            // jpegtran は元のマーカーをすべて写す
+            if options.strip_gps {
+                jpeg_data = without_gps(&jpeg_data);
+            }
+            if let Some(tag) = &options.tag {
+                jpeg_data = with_comment(&jpeg_data, tag);
+            }
+            return Ok(Encoded::new(jpeg_data));
+        }
+    }
 
     // 軽量画像の作成
-    let jpeg_data = compress(&original, quality)?;
+    let mut jpeg_data = compress(&original, options, exif_source.as_deref())?;
+
+    // 元のほうが小さく書き戻す場合も位置情報が残らないよう、比較する元からも消しておく
+    let original = if options.strip_gps { without_gps(&original) } else { original };
+
+    // 元のほうが小さく書き戻す場合も --dpi と --strip-dpi が効くよう、比較する元の JFIF の解像度も書き換えておく
+    let original = match options.dpi {
+        Dpi::Keep => original,
+        dpi => with_density(&original, output_density(&original, dpi)),
+    };
+
+    // 元のほうが小さい場合も追跡用のタグが残るよう、比較する両方に入れておく
+    let original = match &options.tag {
+        Some(tag) => {
+            jpeg_data = with_comment(&jpeg_data, tag);
+            with_comment(&original, tag)
+        }
+        None => original,
+    };
 
-    write_smaller(output_path, &jpeg_data, &original)
+    // 切り抜きや透かし、Exif の差し替えを施した場合は元と内容が違い、JPEG 以外（png からの変換）は元の形式が違うため、元のほうが小さくても書き戻さない。
+    // ベースラインを求められた場合も、元がベースラインでなければ書き戻さない。元のスキャンの組み方やリスタートマーカーは確かめられないため、
+    // preview-first やリスタートの間隔を求められた場合も書き戻さない
+    if options.is_edited()
+        || !is_jpeg(&original)
+        || (options.baseline && !is_baseline(&original))
+        || Scans::of(options) == Scans::PreviewFirst
+        || options.restart_interval.is_some()
+    {
+        return Ok(Encoded::new(jpeg_data));
+    }
+    Ok(Encoded::with_original(jpeg_data, original))
 }
 
 #[allow(dead_code)]
 pub fn data2compress(data: &[u8], output_path: &Path, quality: f32) -> Result<(), CompressError> {
     // 軽量画像の作成
-    let jpeg_data = compress(data, quality)?;
+    let options = ImageOptions { quality, ..Default::default() };
+    let jpeg_data = compress(data, &options, None)?;
 
     write_smaller(output_path, &jpeg_data, data)
 }
 
+/// デコード済みの画像を JPEG にする（Exif などのメタデータは付けない）。`dpi` を指定すると JFIF に解像度を書き込む。
+/// 透過した部分は白の背景に合成する。
+pub fn image2jpeg(img: &DynamicImage, quality: f32, dpi: Option<u16>) -> Result<Vec<u8>, CompressError> {
+    let density = dpi.map(|value| PixelDensity { unit: PixelDensityUnit::Inches, x: value, y: value });
+    encode(&to_rgb8(img, Tonemap::default(), Background::default())?, quality, Scans::default(), false, density, &[])
+}
+
+/// `compress_many` で書き出す1つの出力
+#[derive(Clone, Debug)]
+pub struct OutputSpec {
+    /// 出力先のパス
+    pub path: PathBuf,
+    /// 非可逆圧縮の品質（0-100）
+    pub quality: f32,
+    /// 指定すると、この幅を超える画像を縦横比を保って縮小する（拡大はしない）
+    pub max_width: Option<u32>,
+    /// 縮小した場合にかけるアンシャープマスクの強さ。`None` ならかけない
+    pub sharpen: Option<f32>,
+}
+
+/// デコード済みの画像を、品質や幅の違う複数の JPEG として書き出す。
+///
+/// RGB への変換は一度だけ行い、同じ幅への縮小も使い回すため、出力ごとにデコードし直すより速い。
+pub fn compress_many(img: &DynamicImage, specs: &[OutputSpec]) -> Result<(), CompressError> {
+    let rgb_img = to_rgb8(img, Tonemap::default(), Background::default())?;
+    let mut resized: Vec<(u32, RgbImage)> = Vec::new();
+
+    for spec in specs {
+        let width = spec.max_width.map_or(rgb_img.width(), |max| max.min(rgb_img.width()));
+        let pixels = if width == rgb_img.width() {
+            &rgb_img
+        } else {
+            let index = match resized.iter().position(|(w, _)| *w == width) {
+                Some(index) => index,
+                None => {
+                    let height = scaled_height(rgb_img.width(), rgb_img.height(), width);
+                    let img = image::imageops::resize(&rgb_img, width, height, image::imageops::FilterType::Lanczos3);
+                    resized.push((width, img));
+                    resized.len() - 1
+                }
+            };
+            &resized[index].1
+        };
+
+        let jpeg_data = match spec.sharpen {
+            // 縮小すると輪郭がぼやけるため、縮小したときだけ鮮鋭化する
+            Some(amount) if width != rgb_img.width() => encode(&sharpen(pixels, amount), spec.quality, Scans::default(), false, None, &[])?,
+            _ => encode(pixels, spec.quality, Scans::default(), false, None, &[])?,
+        };
+        write_output(&spec.path, &jpeg_data)?;
+    }
+
+    Ok(())
+}
+
+/// 幅を `width` にしたときの高さ（縦横比を保ち、1 以上）
+fn scaled_height(original_width: u32, original_height: u32, width: u32) -> u32 {
+    let height = (original_height as u64 * width as u64 + original_width as u64 / 2) / original_width as u64;
+    (height as u32).max(1)
+}
+
 #[allow(dead_code)]
 pub fn get_aspect_ratio_from_path(path: &Path) -> Result<f32, CompressError> {
     // 画像を読み込む
@@ -38,17 +160,116 @@ pub fn get_aspect_ratio_from_data(data: &[u8]) -> Result<f32, CompressError> {
     Ok(get_aspect_ratio(img.width(), img.height()))
 }
 
-/// JPEGから APP1(Exif) と APP2(ICCプロファイル) のセグメントを取り出す。
-///
-/// 再エンコードするとこれらは失われる。特に Exif の Orientation が消えると、
-/// 縦向きに撮影した写真が横倒しで表示されてしまうため、元ファイルから引き継ぐ。
-/// ピクセルの向きは変えないので、Orientation の値もそのままで正しく機能する。
-fn extract_metadata_markers(jpeg: &[u8]) -> Vec<(u8, Vec<u8>)> {
-    let mut markers = Vec::new();
+/// JPEG に COM（コメント）セグメントを挿入する。JFIF/Exif などの APPn セグメントの後ろに置く。
+/// JPEG として解釈できないデータはそのまま返す。
+fn with_comment(jpeg: &[u8], comment: &str) -> Vec<u8> {
+    if !is_jpeg(jpeg) {
+        return jpeg.to_vec();
+    }
+
+    let mut i = 2;
+    while i + 4 <= jpeg.len() && jpeg[i] == 0xFF && (0xE0..=0xEF).contains(&jpeg[i + 1]) {
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        if i + 2 + length > jpeg.len() {
+            break;
+        }
+        i += 2 + length;
+    }
+
+    let mut output = jpeg[..i].to_vec();
+    output.extend_from_slice(&[0xFF, 0xFE]);
+    output.extend_from_slice(&((comment.len() + 2) as u16).to_be_bytes());
+    output.extend_from_slice(comment.as_bytes());
+    output.extend_from_slice(&jpeg[i..]);
+    output
+}
+
+/// 先頭の APPn のうち、Exif の位置情報を消し、位置情報を含む XMP を落とす（--strip-gps）。
+/// 再エンコードせずに元のマーカーを残したまま出力する場合に使う。JPEG でないデータはそのまま返す。
+fn without_gps(jpeg: &[u8]) -> Vec<u8> {
+    if !is_jpeg(jpeg) {
+        return jpeg.to_vec();
+    }
+
+    let mut output = jpeg[..2].to_vec();
+    let mut i = 2;
+    while i + 4 <= jpeg.len() && jpeg[i] == 0xFF && (0xE0..=0xEF).contains(&jpeg[i + 1]) {
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        if length < 2 || i + 2 + length > jpeg.len() {
+            break;
+        }
+        let segment = &jpeg[i..i + 2 + length];
+        let payload = &segment[4..];
+        if jpeg[i + 1] == 0xE1 && payload.starts_with(b"Exif\0\0") {
+            let mut segment = segment.to_vec();
+            exif::strip_gps(&mut segment[4..]);
+            output.extend_from_slice(&segment);
+        } else if !(jpeg[i + 1] == 0xE1 && payload.starts_with(b"http://ns.adobe.com/") && exif::xmp_has_gps(payload)) {
+            output.extend_from_slice(segment);
+        }
+        i += 2 + length;
+    }
+    output.extend_from_slice(&jpeg[i..]);
+    output
+}
+
+/// SOI で始まる（JPEG である）か
+fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0xD8])
+}
+
+/// マーカーとスキャンをたどり、EOI まで揃っているか。途中で切れた JPEG は、デコーダによっては残りを埋めて読めてしまうため確かめる。
+/// EOI の後ろに付け足されたデータ（動画付きの写真など）は見ない。
+pub fn is_complete(jpeg: &[u8]) -> bool {
+    if !is_jpeg(jpeg) {
+        return false;
+    }
+    let mut i = 2;
+    while i + 2 <= jpeg.len() {
+        if jpeg[i] != 0xFF {
+            return false;
+        }
+        let marker = jpeg[i + 1];
+        match marker {
+            0xD9 => return true,
+            // 詰め物の 0xFF
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            0x01 | 0xD0..=0xD7 => {
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        if i + 4 > jpeg.len() {
+            return false;
+        }
+        i += 2 + u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        if marker == 0xDA {
+            // 圧縮データは、次のマーカー（0xFF00 のエスケープとリスタートマーカー以外）の手前まで
+            while i + 1 < jpeg.len() && !(jpeg[i] == 0xFF && jpeg[i + 1] != 0x00 && !(0xD0..=0xD7).contains(&jpeg[i + 1])) {
+                i += 1;
+            }
+        }
+    }
+    false
+}
+
+/// ベースライン（SOF0）の JPEG か
+fn is_baseline(jpeg: &[u8]) -> bool {
+    segments(jpeg).iter().any(|(marker, _)| *marker == 0xC0)
+}
+
+/// JPEG のマーカーセグメントを SOS（圧縮データ本体）の手前まで列挙する。（マーカー番号, ペイロード）の組を返す。
+/// SOI で始まらないものはJPEGとして扱わず空を返し、長さが壊れている場合はそこで打ち切る。
+fn segments(jpeg: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
 
     // SOI で始まらないものはJPEGとして扱わない
     if jpeg.len() < 4 || jpeg[0] != 0xFF || jpeg[1] != 0xD8 {
-        return markers;
+        return segments;
     }
 
     let mut i = 2;
@@ -76,44 +297,438 @@ fn extract_metadata_markers(jpeg: &[u8]) -> Vec<(u8, Vec<u8>)> {
             break;
         }
 
-        let payload = &jpeg[i + 4..i + 2 + length];
-        if marker == 0xE1 && payload.starts_with(b"Exif\0\0") {
-            markers.push((1, payload.to_vec()));
-        } else if marker == 0xE2 && payload.starts_with(b"ICC_PROFILE\0") {
-            markers.push((2, payload.to_vec()));
-        }
+        segments.push((marker, &jpeg[i + 4..i + 2 + length]));
+        i += 2 + length;
+    }
+
+    segments
+}
+
+/// JPEGから APP1(Exif) と APP2(ICCプロファイル) のセグメントを取り出す。
+///
+/// 再エンコードするとこれらは失われる。特に Exif の Orientation が消えると、
+/// 縦向きに撮影した写真が横倒しで表示されてしまうため、元ファイルから引き継ぐ。
+/// ピクセルの向きは変えないので、Orientation の値もそのままで正しく機能する。
+pub(crate) fn extract_metadata_markers(jpeg: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    segments(jpeg)
+        .into_iter()
+        .filter_map(|(marker, payload)| {
+            if marker == 0xE1 && payload.starts_with(b"Exif\0\0") {
+                Some((1, payload.to_vec()))
+            } else if marker == 0xE2 && payload.starts_with(b"ICC_PROFILE\0") {
+                Some((2, payload.to_vec()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// JFIF(APP0) に記録された解像度を返す。単位が縦横比のみ（0）の場合や JFIF がない場合は `None`。
+fn jfif_density(jpeg: &[u8]) -> Option<PixelDensity> {
+    // 識別子 "JFIF\0", バージョン(2), 単位(1), 横(2), 縦(2)
+    let (_, payload) = segments(jpeg)
+        .into_iter()
+        .find(|(marker, payload)| *marker == 0xE0 && payload.starts_with(b"JFIF\0"))?;
+    let unit = match payload.get(7)? {
+        1 => PixelDensityUnit::Inches,
+        2 => PixelDensityUnit::Centimeters,
+        _ => return None,
+    };
+    let x = u16::from_be_bytes([*payload.get(8)?, *payload.get(9)?]);
+    let y = u16::from_be_bytes([*payload.get(10)?, *payload.get(11)?]);
+    if x == 0 || y == 0 {
+        return None;
+    }
+
+    Some(PixelDensity { unit, x, y })
+}
+
+/// JFIF(APP0) の解像度を `density` に書き換える。`None` なら libjpeg の既定（縦横比 1:1、DPI なし）にする。
+/// JFIF がなければ SOI の直後に足す。JPEG でないデータはそのまま返す。
+fn with_density(jpeg: &[u8], density: Option<PixelDensity>) -> Vec<u8> {
+    if !is_jpeg(jpeg) {
+        return jpeg.to_vec();
+    }
+    let (unit, x, y) = match density {
+        Some(density) => (density.unit as u8, density.x, density.y),
+        None => (0, 1, 1),
+    };
+    let mut fields = vec![unit];
+    fields.extend_from_slice(&x.to_be_bytes());
+    fields.extend_from_slice(&y.to_be_bytes());
 
+    let mut i = 2;
+    while i + 4 <= jpeg.len() && jpeg[i] == 0xFF && (0xE0..=0xEF).contains(&jpeg[i + 1]) {
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        let payload = &jpeg[(i + 4).min(jpeg.len())..(i + 2 + length).min(jpeg.len())];
+        // 識別子 "JFIF\0", バージョン(2), 単位(1), 横(2), 縦(2)
+        if jpeg[i + 1] == 0xE0 && payload.starts_with(b"JFIF\0") && payload.len() >= 12 {
+            let mut output = jpeg.to_vec();
+            output[i + 4 + 7..i + 4 + 12].copy_from_slice(&fields);
+            return output;
+        }
         i += 2 + length;
     }
 
-    markers
+    let mut output = jpeg[..2].to_vec();
+    output.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+    output.extend_from_slice(b"JFIF\0\x01\x01");
+    output.extend_from_slice(&fields);
+    output.extend_from_slice(&[0, 0]);
+    output.extend_from_slice(&jpeg[2..]);
+    output
 }
 
-fn compress(original: &[u8], quality: f32) -> Result<Vec<u8>, CompressError> {
-    // 画像を読み込む
-    let img: DynamicImage = image::load_from_memory(original)?;
-    let rgb_img = img.to_rgb8();
+/// 出力に書き込む解像度を決める。`None` なら libjpeg の既定（縦横比 1:1、DPI なし）のまま。
+fn output_density(original: &[u8], dpi: Dpi) -> Option<PixelDensity> {
+    match dpi {
+        Dpi::Keep => jfif_density(original),
+        Dpi::Set(value) => Some(PixelDensity { unit: PixelDensityUnit::Inches, x: value, y: value }),
+        Dpi::Strip => None,
+    }
+}
+
+/// Exif の向きを可逆変換でピクセルへ反映する。向きの指定がない場合や、端のブロックのせいで変換できない場合は `None`。
+fn lossless_orient(original: &[u8], dpi: Dpi) -> Result<Option<Vec<u8>>, CompressError> {
+    if !is_jpeg(original) {
+        return Ok(None);
+    }
+    let orientation = extract_metadata_markers(original)
+        .into_iter()
+        .find(|(app_number, _)| *app_number == 1)
+        .and_then(|(_, app1)| exif::orientation(&app1));
+    match orientation {
+        Some(orientation) => jpeg_transform::apply_orientation(original, orientation, output_density(original, dpi)),
+        None => Ok(None),
+    }
+}
+
+/// CMYK（YCCK を含む）の JPEG かどうかを判定する。
+/// CMYK であれば、Adobe の APP14 マーカーを持つか（Photoshop 流に値が反転して格納されているか）を返す。
+fn detect_cmyk(jpeg: &[u8]) -> Option<bool> {
+    let segments = segments(jpeg);
+
+    // SOFn（DHT=C4, JPG=C8, DAC=CC を除く C0-CF）の成分数が4なら CMYK/YCCK
+    let is_cmyk = segments.iter().any(|(marker, payload)| {
+        matches!(marker, 0xC0..=0xCF)
+            && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+            && payload.get(5) == Some(&4)
+    });
+    if !is_cmyk {
+        return None;
+    }
 
-    // 画像の幅と高さを取得
-    let width = rgb_img.width() as usize;
-    let height = rgb_img.height() as usize;
-    let pixels = rgb_img.into_raw();
+    let adobe = segments
+        .iter()
+        .any(|(marker, payload)| *marker == 0xEE && payload.starts_with(b"Adobe"));
+    Some(adobe)
+}
+
+/// CMYK 1画素を RGB にする。`inverted` は値が反転して格納されている（Adobe）場合。
+fn cmyk_to_rgb(cmyk: [u8; 4], inverted: bool) -> [u8; 3] {
+    let [c, m, y, k] = if inverted {
+        cmyk
+    } else {
+        cmyk.map(|v| 255 - v)
+    };
+    // ここでの値は「インクが載っていない量」（255 でインクなし）
+    let mix = |v: u8| ((v as u32 * k as u32 + 127) / 255) as u8;
+    [mix(c), mix(m), mix(y)]
+}
+
+/// CMYK/YCCK の JPEG を libjpeg でデコードし、RGB にする。
+///
+/// image クレート経由の `to_rgb8` では Adobe の反転を考慮せず色が反転することがあるため、
+/// CMYK のまま取り出して自前で変換する。YCCK → CMYK の変換は libjpeg に任せる。
+fn decode_cmyk(jpeg: &[u8], inverted: bool) -> Result<RgbImage, CompressError> {
+    // mozjpeg はデコードエラーを panic で通知するため、バッチ全体を止めないよう捕まえる
+    let decoded = std::panic::catch_unwind(|| -> std::io::Result<(usize, usize, Vec<[u8; 4]>)> {
+        let mut started = Decompress::new_mem(jpeg)?.to_colorspace(ColorSpace::JCS_CMYK)?;
+        let (width, height) = (started.width(), started.height());
+        let pixels = started.read_scanlines::<[u8; 4]>()?;
+        started.finish()?;
+        Ok((width, height, pixels))
+    });
+    let (width, height, pixels) = match decoded {
+        Ok(result) => result?,
+        Err(_) => return Err(CompressError::Decode("CMYK JPEG のデコードに失敗しました".to_string())),
+    };
+
+    let rgb: Vec<u8> = pixels
+        .into_iter()
+        .flat_map(|cmyk| cmyk_to_rgb(cmyk, inverted))
+        .collect();
+    RgbImage::from_raw(width as u32, height as u32, rgb)
+        .ok_or_else(|| CompressError::Decode("CMYK JPEG の画素数が寸法と一致しません".to_string()))
+}
+
+/// 8bit の RGB にする。透過した部分は `background` に合成し、浮動小数点（HDR）の画像で 1.0 を超える値は `tonemap` に従って扱う。
+pub(crate) fn to_rgb8(img: &DynamicImage, tonemap: Tonemap, background: Background) -> Result<RgbImage, CompressError> {
+    // アルファを捨てるだけだと、透過した部分は保存されていた色（多くは黒）になる
+    let flattened;
+    let img = if img.color().has_alpha() {
+        flattened = flatten(img, background);
+        &flattened
+    } else {
+        img
+    };
+
+    // 8bit/16bit の整数は to_rgb8 で階調を縮めるだけで、値の切り捨ては起きない
+    if !matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) {
+        return Ok(img.to_rgb8());
+    }
+
+    let mut hdr = img.to_rgb32f();
+    let peak = hdr.pixels().map(|p| luminance(p.0)).fold(0.0f32, f32::max);
+    let clipped = hdr.pixels().any(|p| p.0.iter().any(|&v| v > 1.0));
+    if !clipped {
+        return Ok(img.to_rgb8());
+    }
+
+    match tonemap {
+        Tonemap::None => {
+            return Err(CompressError::InvalidInput(
+                "HDR の値が 8bit の範囲を超えています（--tonemap reinhard か clip を指定してください）".to_string(),
+            ))
+        }
+        Tonemap::Reinhard => {
+            for pixel in hdr.pixels_mut() {
+                pixel.0 = reinhard(pixel.0, peak);
+            }
+        }
+        Tonemap::Clip => warning!("警告: HDR の値が 8bit の範囲を超えたため切り捨てます（--tonemap reinhard で階調を残せます）"),
+    }
+
+    Ok(DynamicImage::ImageRgb32F(hdr).to_rgb8())
+}
+
+/// Rec.709 の係数による相対輝度
+/// 透過した画素を `background` に合成し、アルファのない画像にする。浮動小数点の画像は 1.0 を超える値を残すため浮動小数点のまま合成する
+fn flatten(img: &DynamicImage, background: Background) -> DynamicImage {
+    if let DynamicImage::ImageRgba32F(rgba) = img {
+        let [br, bg, bb] = background.0.map(|c| c as f32 / 255.0);
+        return DynamicImage::ImageRgb32F(image::Rgb32FImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            image::Rgb([r * a + br * (1.0 - a), g * a + bg * (1.0 - a), b * a + bb * (1.0 - a)])
+        }));
+    }
+    let rgba = img.to_rgba8();
+    let [br, bg, bb] = background.0;
+    DynamicImage::ImageRgb8(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8, back: u8| ((c as u32 * a as u32 + back as u32 * (255 - a as u32) + 127) / 255) as u8;
+        image::Rgb([blend(r, br), blend(g, bg), blend(b, bb)])
+    }))
+}
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// 拡張 Reinhard。画像で最も明るい輝度 `peak` がちょうど 1.0 になるよう輝度を圧縮し、色相は保つ。
+fn reinhard(rgb: [f32; 3], peak: f32) -> [f32; 3] {
+    let l = luminance(rgb);
+    if l <= 0.0 {
+        return rgb;
+    }
+    let mapped = l * (1.0 + l / (peak * peak)) / (1.0 + l);
+    rgb.map(|v| (v * mapped / l).clamp(0.0, 1.0))
+}
+
+/// `source` に Exif があれば、`markers` の Exif をそれで置き換える。ICC プロファイルは元のまま残す。
+fn replace_exif(markers: &mut Vec<(u8, Vec<u8>)>, source: &[u8]) {
+    let exif: Vec<(u8, Vec<u8>)> = extract_metadata_markers(source)
+        .into_iter()
+        .filter(|(app_number, _)| *app_number == 1)
+        .collect();
+    if exif.is_empty() {
+        return;
+    }
+
+    markers.retain(|(app_number, _)| *app_number != 1);
+    // Exif(APP1) は APP2 より前に置くのが慣例
+    markers.splice(0..0, exif);
+}
+
+fn compress(original: &[u8], options: &ImageOptions, exif_source: Option<&[u8]>) -> Result<Vec<u8>, CompressError> {
+    // 画像を読み込む。CMYK は image クレートに任せると色が反転することがあるため別経路でデコードする
+    let cmyk = detect_cmyk(original);
+    let rgb_img = match cmyk {
+        Some(inverted) => decode_cmyk(original, inverted)?,
+        None => {
+            let img: DynamicImage = image::load_from_memory(original)?;
+            to_rgb8(&img, options.tonemap, options.background)?
+        }
+    };
 
-    // mozjpegで圧縮する
-    let mut comp = Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    let mut markers = extract_metadata_markers(original);
+    if cmyk.is_some() {
+        // CMYK 用の ICC プロファイルを RGB の出力に付けると色が崩れるため落とす
+        markers.retain(|(app_number, _)| *app_number != 2);
+    }
+    if let Some(source) = exif_source {
+        replace_exif(&mut markers, source);
+    }
+    if options.strip_gps {
+        for (_, app1) in markers.iter_mut().filter(|(app_number, _)| *app_number == 1) {
+            exif::strip_gps(app1);
+        }
+    }
+
+    let rgb_img = if options.edits_pixels() {
+        // 切り抜く範囲や透かしの位置は見た目の向きで指定されるため、先に向きをピクセルに焼き込む
+        let mut img = DynamicImage::ImageRgb8(rgb_img);
+        if let Some((_, app1)) = markers.iter_mut().find(|(app_number, _)| *app_number == 1) {
+            if let Some(orientation) = exif::orientation(app1).and_then(Orientation::from_exif) {
+                img.apply_orientation(orientation);
+                exif::reset_orientation(app1);
+            }
+        }
+        options.edit(img)?.to_rgb8()
+    } else {
+        rgb_img
+    };
+    let rgb_img = match options.sharpen {
+        Some(amount) => sharpen(&rgb_img, amount),
+        None => rgb_img,
+    };
+
+    // 再エンコードすると JFIF の解像度が既定値に戻り、印刷時に 72 DPI 扱いされてしまう
+    let density = output_density(original, options.dpi);
+    if options.text_aware && is_text_like(&rgb_img) {
+        eprintln!("情報: 文字や線画の多い画像のため、品質を {TEXT_QUALITY} 以上にし、色差を間引かずに出力します（png のほうが小さく済む場合があります）");
+        let jpeg = encode(&rgb_img, options.quality.max(TEXT_QUALITY), Scans::of(options), true, density, &markers)?;
+        return with_restart_interval(jpeg, options);
+    }
+    let jpeg = encode(&rgb_img, options.quality, Scans::of(options), false, density, &markers)?;
+    with_restart_interval(jpeg, options)
+}
+
+/// --jpeg-restart-interval を指定した場合は、リスタートマーカーを入れる（mozjpeg クレートには間隔を指定する API がないため、係数をそのまま書き直す）
+fn with_restart_interval(jpeg: Vec<u8>, options: &ImageOptions) -> Result<Vec<u8>, CompressError> {
+    match options.restart_interval {
+        Some(mcus) => jpeg_transform::with_restart_interval(&jpeg, mcus),
+        None => Ok(jpeg),
+    }
+}
+
+/// 文字や線画とみなした画像に使う品質の下限
+const TEXT_QUALITY: f32 = 90.0;
+/// 隣り合う画素の輝度がこれより大きく違えば、くっきりした輪郭とみなす
+const EDGE_CONTRAST: i32 = 96;
+/// 隣り合う画素の組のうち、くっきりした輪郭がこの割合以上あれば文字や線画とみなす（写真ではまず届かない）
+const MIN_TEXT_EDGE_RATIO: f64 = 0.03;
+
+/// 文字や線画のように、輝度が急に変わる輪郭の多い画像か（`--text-aware`）。
+///
+/// 写真は隣り合う画素の輝度がなだらかに変わるのに対し、白地の黒文字などは1画素で大きく変わる。
+/// 右と下の隣の画素との輝度の差が [`EDGE_CONTRAST`] を超える組の割合で判断する。
+fn is_text_like(rgb_img: &RgbImage) -> bool {
+    let (width, height) = rgb_img.dimensions();
+    let luma = |x: u32, y: u32| {
+        let [r, g, b] = rgb_img.get_pixel(x, y).0;
+        (r as i32 * 299 + g as i32 * 587 + b as i32 * 114) / 1000
+    };
+    let mut pairs = 0u64;
+    let mut edges = 0u64;
+    for y in 0..height {
+        for x in 0..width {
+            let here = luma(x, y);
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if nx < width && ny < height {
+                    pairs += 1;
+                    edges += ((here - luma(nx, ny)).abs() > EDGE_CONTRAST) as u64;
+                }
+            }
+        }
+    }
+    pairs > 0 && edges as f64 / pairs as f64 >= MIN_TEXT_EDGE_RATIO
+}
+
+/// RGB の画素を mozjpeg で JPEG にする。`markers` は（APP番号, ペイロード）の組。
+///
+/// 出力はプログレッシブで、品質が低いと量子化テーブルが 8bit に収まらず拡張シーケンシャルになる。
+/// `baseline` を指定すると、スキャンを1つにし、テーブルを 8bit に収めてベースラインの JPEG にする（古いハードウェアデコーダ向け）。
+/// `optimize_scans` を指定すると、mozjpeg の既定と同じくスキャンの分け方を画像ごとに試して最も小さいものを選ぶ。
+/// 指定しなければ libjpeg の標準の分け方にする（何通りもエンコードし直さないぶん速い）。
+/// `full_chroma` を指定すると、色差を間引かない 4:4:4 にする（既定は 4:2:0 で、細い色付きの線や文字の縁がにじむ）。
+/// JPEG のスキャンの組み方
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Scans {
+    /// 1つのスキャンのベースライン
+    Baseline,
+    /// 1つのスキャンで、品質が低ければ拡張シーケンシャル（リスタートマーカーを入れる場合）
+    Sequential,
+    /// mozjpeg の既定のプログレッシブ
+    #[default]
+    Progressive,
+    /// 分け方を画像ごとに探すプログレッシブ
+    Optimized,
+    /// 最初のスキャンでカラーの縮小表示を出せるプログレッシブ（[`jpeg_transform::preview_first_scans`]）
+    PreviewFirst,
+}
+
+impl Scans {
+    fn of(options: &ImageOptions) -> Scans {
+        match options.scan_preset {
+            _ if options.baseline => Scans::Baseline,
+            _ if options.restart_interval.is_some() => Scans::Sequential,
+            ScanPreset::PreviewFirst => Scans::PreviewFirst,
+            ScanPreset::Default if options.optimize_scans => Scans::Optimized,
+            ScanPreset::Default => Scans::Progressive,
+        }
+    }
+}
+
+fn encode(
+    rgb_img: &RgbImage,
+    quality: f32,
+    scans: Scans,
+    full_chroma: bool,
+    density: Option<PixelDensity>,
+    markers: &[(u8, Vec<u8>)],
+) -> Result<Vec<u8>, CompressError> {
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
     comp.set_quality(quality);
-    comp.set_size(width, height);
+    match scans {
+        Scans::Baseline => {
+            comp.set_optimize_scans(false);
+            // mozjpeg の既定と同じテーブルを、値を 255 以下に収めて設定し直す
+            let table = qtable::NRobidoux.scaled(quality, quality);
+            comp.set_luma_qtable(&table);
+            comp.set_chroma_qtable(&table);
+        }
+        Scans::Sequential => comp.set_optimize_scans(false),
+        // スキャンは後で組み直すため、分け方を探さない
+        Scans::Progressive | Scans::PreviewFirst => {
+            comp.set_optimize_scans(false);
+            comp.set_progressive_mode();
+        }
+        Scans::Optimized => {}
+    }
+    if full_chroma {
+        comp.set_chroma_sampling_pixel_sizes((1, 1), (1, 1));
+    }
+    comp.set_size(rgb_img.width() as usize, rgb_img.height() as usize);
+    if let Some(density) = density {
+        comp.set_pixel_density(density);
+    }
 
     let mut comp = comp.start_compress(Vec::new())?;
 
     // メタデータはスキャンラインより先に書く必要がある
-    for (app_number, data) in extract_metadata_markers(original) {
-        comp.write_marker(Marker::APP(app_number), &data);
+    for (app_number, data) in markers {
+        comp.write_marker(Marker::APP(*app_number), data);
     }
 
-    comp.write_scanlines(&pixels)?;
+    comp.write_scanlines(rgb_img.as_raw())?;
 
-    Ok(comp.finish()?)
+    let jpeg = comp.finish()?;
+    if scans == Scans::PreviewFirst {
+        return jpeg_transform::preview_first_scans(&jpeg);
+    }
+    Ok(jpeg)
 }
 
 #[cfg(test)]
@@ -152,6 +767,437 @@ mod tests {
         assert_eq!(markers[0].1, payload);
     }
 
+    /// SOF の成分数で CMYK を判定し、Adobe マーカーの有無を返すこと
+    #[test]
+    fn detects_cmyk_and_adobe_marker() {
+        // SOF0: 精度, 高さ(2), 幅(2), 成分数
+        let sof = |components: u8| {
+            let payload = [8, 0, 1, 0, 1, components];
+            let mut segment = vec![0xFF, 0xC0];
+            segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+            segment.extend_from_slice(&payload);
+            segment
+        };
+        let adobe = {
+            let payload = b"Adobe\0\x64\0\0\0\0\x02";
+            let mut segment = vec![0xFF, 0xEE];
+            segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+            segment.extend_from_slice(payload);
+            segment
+        };
+
+        let mut rgb = vec![0xFF, 0xD8];
+        rgb.extend(sof(3));
+        assert_eq!(detect_cmyk(&rgb), None);
+
+        let mut cmyk = vec![0xFF, 0xD8];
+        cmyk.extend(sof(4));
+        assert_eq!(detect_cmyk(&cmyk), Some(false));
+
+        let mut ycck = vec![0xFF, 0xD8];
+        ycck.extend(adobe);
+        ycck.extend(sof(4));
+        assert_eq!(detect_cmyk(&ycck), Some(true));
+    }
+
+    /// Adobe の反転あり・なしで同じ色になること
+    #[test]
+    fn converts_cmyk_to_rgb() {
+        // インクなし → 白、K のみ全面 → 黒、C のみ全面 → シアン
+        assert_eq!(cmyk_to_rgb([0, 0, 0, 0], false), [255, 255, 255]);
+        assert_eq!(cmyk_to_rgb([0, 0, 0, 255], false), [0, 0, 0]);
+        assert_eq!(cmyk_to_rgb([255, 0, 0, 0], false), [0, 255, 255]);
+        assert_eq!(cmyk_to_rgb([255, 255, 255, 255], true), [255, 255, 255]);
+        assert_eq!(cmyk_to_rgb([0, 255, 255, 255], true), [0, 255, 255]);
+    }
+
+    /// Photoshop 流（Adobe マーカー付き・反転格納）の CMYK JPEG が反転せずに RGB になること
+    #[test]
+    fn decodes_adobe_cmyk_jpeg() {
+        // シアン1色（インク量 C=255, 他=0）を反転して格納する
+        let (width, height) = (16, 16);
+        let pixels: Vec<u8> = [0u8, 255, 255, 255].repeat(width * height);
+
+        let mut comp = Compress::new(ColorSpace::JCS_CMYK);
+        comp.set_quality(100.0);
+        comp.set_size(width, height);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_scanlines(&pixels).unwrap();
+        let jpeg = comp.finish().unwrap();
+
+        assert_eq!(detect_cmyk(&jpeg), Some(true), "libjpeg は CMYK に Adobe マーカーを付ける");
+        let rgb = decode_cmyk(&jpeg, true).unwrap();
+        let [r, g, b] = rgb.get_pixel(8, 8).0;
+        assert!(r < 16 && g > 239 && b > 239, "シアンにならなかった: {:?}", [r, g, b]);
+    }
+
+    /// JFIF の解像度を引き継ぎ、上書き・削除もできること
+    #[test]
+    fn preserves_jfif_density() {
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(8, 8);
+        comp.set_pixel_density(PixelDensity { unit: PixelDensityUnit::Inches, x: 300, y: 300 });
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_scanlines(&[128u8; 8 * 8 * 3]).unwrap();
+        let scanned = comp.finish().unwrap();
+
+        let density_of = |dpi| {
+            let options = ImageOptions { quality: 70.0, dpi, ..Default::default() };
+            let output = compress(&scanned, &options, None).unwrap();
+            jfif_density(&output).map(|density| (density.x, density.y))
+        };
+        assert_eq!(density_of(Dpi::Keep), Some((300, 300)));
+        assert_eq!(density_of(Dpi::Set(150)), Some((150, 150)));
+        assert_eq!(density_of(Dpi::Strip), None);
+    }
+
+    /// 再エンコードしても小さくならず元を書き出す場合も、--dpi と --strip-dpi が効くこと
+    #[test]
+    fn sets_density_of_already_small_input() {
+        let dir = std::env::temp_dir().join("compressor_rgb_dpi_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("small.jpg");
+        let noisy = RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 37 + y * 91) as u8, (x * y * 13) as u8, (x ^ y) as u8 * 8]));
+        let density = || PixelDensity { unit: PixelDensityUnit::Inches, x: 300, y: 300 };
+        let small = encode(&noisy, 40.0, Scans::default(), false, Some(density()), &[]).unwrap();
+        std::fs::write(&input, &small).unwrap();
+
+        let options = |dpi| ImageOptions { quality: 95.0, dpi, ..Default::default() };
+        assert_eq!(path2jpeg(&input, &options(Dpi::Keep)).unwrap().into_output(), small, "元のほうが小さい前提");
+        let density_of = |dpi| jfif_density(&path2jpeg(&input, &options(dpi)).unwrap().into_output()).map(|density| (density.x, density.y));
+        assert_eq!(density_of(Dpi::Set(150)), Some((150, 150)));
+        assert_eq!(density_of(Dpi::Strip), None);
+
+        // JFIF のない JPEG には足す
+        let bare = [&small[..2], &small[2 + 18..]].concat();
+        assert!(jfif_density(&bare).is_none());
+        let added = with_density(&bare, Some(density()));
+        assert_eq!(jfif_density(&added).map(|density| density.x), Some(300));
+        assert!(image::load_from_memory(&added).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// --strip-gps では再エンコードしても書き戻す元でも、Exif の位置情報と位置情報を含む XMP だけを消すこと
+    #[test]
+    fn strips_gps_from_exif() {
+        // IFD0 に Orientation=6 と GPS IFD へのポインタ、GPS IFD に GPSVersionID を持つ Exif
+        let mut app1 = b"Exif\0\0II*\0".to_vec();
+        app1.extend_from_slice(&8u32.to_le_bytes());
+        app1.extend_from_slice(&2u16.to_le_bytes());
+        app1.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        app1.extend_from_slice(&[0x25, 0x88, 4, 0, 1, 0, 0, 0, 38, 0, 0, 0]);
+        app1.extend_from_slice(&0u32.to_le_bytes());
+        app1.extend_from_slice(&1u16.to_le_bytes());
+        app1.extend_from_slice(&[0, 0, 1, 0, 4, 0, 0, 0, 2, 3, 0, 0]);
+        app1.extend_from_slice(&0u32.to_le_bytes());
+        let xmp = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta exif:GPSLatitude=\"35,40N\"/>";
+
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(8, 8);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_marker(Marker::APP(1), &app1);
+        comp.write_marker(Marker::APP(1), xmp);
+        comp.write_scanlines(&[128u8; 8 * 8 * 3]).unwrap();
+        let original = comp.finish().unwrap();
+
+        let has_gps = |jpeg: &[u8]| {
+            let markers = extract_metadata_markers(jpeg);
+            assert_eq!(exif::orientation(&markers[0].1), Some(6));
+            markers[0].1.windows(2).any(|tag| tag == [0x25, 0x88])
+        };
+        let options = ImageOptions { quality: 70.0, strip_gps: true, ..Default::default() };
+        assert!(has_gps(&original));
+        assert!(!has_gps(&compress(&original, &options, None).unwrap()));
+
+        let kept = without_gps(&original);
+        assert!(!has_gps(&kept));
+        assert!(!kept.windows(xmp.len()).any(|window| window == xmp));
+        assert!(image::load_from_memory(&kept).is_ok());
+    }
+
+    /// 切り抜いた寸法で出力し、焼き込んだ向きを Exif から外すこと
+    #[test]
+    fn crops_in_display_orientation() {
+        // 横長 40x20 の画素を Orientation=6（90度回転して表示）で持つ JPEG
+        let mut app1 = b"Exif\0\0II*\0".to_vec();
+        app1.extend_from_slice(&8u32.to_le_bytes());
+        app1.extend_from_slice(&1u16.to_le_bytes());
+        app1.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        app1.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(40, 20);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_marker(Marker::APP(1), &app1);
+        comp.write_scanlines(&[128u8; 40 * 20 * 3]).unwrap();
+        let rotated = comp.finish().unwrap();
+
+        // 見た目は 20x40 の縦長なので、縦 30 の切り抜きができる
+        let options = ImageOptions {
+            quality: 70.0,
+            crop: Some(crate::geometry::Crop::Region { width: 16, height: 30, x: 0, y: 0 }),
+            ..Default::default()
+        };
+        let output = compress(&rotated, &options, None).unwrap();
+        let img = image::load_from_memory(&output).unwrap();
+        assert_eq!((img.width(), img.height()), (16, 30));
+
+        let markers = extract_metadata_markers(&output);
+        assert_eq!(exif::orientation(&markers[0].1), Some(1));
+    }
+
+    /// 1回のデコードから品質と幅の違う出力をそれぞれ書き出すこと
+    #[test]
+    fn compresses_many_outputs() {
+        let dir = std::env::temp_dir().join("compressor_compress_many_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])));
+
+        let spec = |name: &str, quality, max_width| OutputSpec { path: dir.join(name), quality, max_width, sharpen: None };
+        let specs = [
+            spec("high.jpg", 95.0, None),
+            spec("low.jpg", 30.0, None),
+            spec("small.jpg", 80.0, Some(32)),
+            spec("wide.jpg", 80.0, Some(1000)),
+        ];
+        compress_many(&img, &specs).unwrap();
+
+        let size = |name: &str| std::fs::metadata(dir.join(name)).unwrap().len();
+        assert!(size("high.jpg") > size("low.jpg"));
+        let dimensions = |name: &str| image::image_dimensions(dir.join(name)).unwrap();
+        assert_eq!(dimensions("small.jpg"), (32, 24));
+        assert_eq!(dimensions("wide.jpg"), (64, 48), "拡大してはいけない");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+
+    /// 範囲を超える HDR 画像は指定に従って扱い、範囲内なら指定に関係なくそのまま変換すること
+    #[test]
+    fn downconverts_hdr() {
+        let bright = |value: f32| DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(2, 1, image::Rgb([value, value, value])));
+
+        assert!(to_rgb8(&bright(4.0), Tonemap::None, Background::default()).is_err());
+        assert_eq!(to_rgb8(&bright(4.0), Tonemap::Clip, Background::default()).unwrap().get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(to_rgb8(&bright(0.5), Tonemap::None, Background::default()).unwrap().get_pixel(0, 0).0, [128, 128, 128]);
+
+        // 最も明るい画素は白になり、暗い画素は切り捨てより階調が残る（0.5 → 0.5 より暗く、0 ではない）
+        let mut hdr = image::Rgb32FImage::from_pixel(2, 1, image::Rgb([4.0, 4.0, 4.0]));
+        hdr.put_pixel(1, 0, image::Rgb([0.5, 0.5, 0.5]));
+        let mapped = to_rgb8(&DynamicImage::ImageRgb32F(hdr), Tonemap::Reinhard, Background::default()).unwrap();
+        assert_eq!(mapped.get_pixel(0, 0).0, [255, 255, 255]);
+        let [dark, _, _] = mapped.get_pixel(1, 0).0;
+        assert!(dark > 0 && dark < 128, "{dark}");
+
+        // 16bit は切り捨てではなく階調の縮小になる
+        let deep = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(1, 1, image::Rgb([65535u16, 32896, 0])));
+        assert_eq!(to_rgb8(&deep, Tonemap::None, Background::default()).unwrap().get_pixel(0, 0).0, [255, 128, 0]);
+    }
+
+    /// 透過した部分は指定した背景色に合成し、半透明は背景と混ぜ、不透明な画素はそのまま残すこと
+    #[test]
+    fn flattens_transparency_onto_background() {
+        let mut rgba = image::RgbaImage::from_pixel(3, 1, image::Rgba([0, 0, 0, 0]));
+        rgba.put_pixel(1, 0, image::Rgba([200, 100, 0, 255]));
+        rgba.put_pixel(2, 0, image::Rgba([0, 0, 0, 128]));
+        let img = DynamicImage::ImageRgba8(rgba);
+
+        let white = to_rgb8(&img, Tonemap::default(), Background::default()).unwrap();
+        assert_eq!(white.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(white.get_pixel(1, 0).0, [200, 100, 0]);
+        assert_eq!(white.get_pixel(2, 0).0, [127, 127, 127]);
+        let red = to_rgb8(&img, Tonemap::default(), Background([255, 0, 0])).unwrap();
+        assert_eq!(red.get_pixel(0, 0).0, [255, 0, 0]);
+
+        let hdr = DynamicImage::ImageRgba32F(image::Rgba32FImage::from_pixel(1, 1, image::Rgba([0.0, 0.0, 0.0, 0.0])));
+        assert_eq!(to_rgb8(&hdr, Tonemap::None, Background([0, 0, 255])).unwrap().get_pixel(0, 0).0, [0, 0, 255]);
+
+        // png から JPEG にしても透過した部分が黒くならない
+        let dir = std::env::temp_dir().join("compressor_flatten_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 0])).save(dir.join("clear.png")).unwrap();
+        path2compress(&dir.join("clear.png"), &dir.join("clear.jpg"), &ImageOptions { quality: 90.0, ..Default::default() }).unwrap();
+        let jpeg = image::open(dir.join("clear.jpg")).unwrap().to_rgb8();
+        assert!(jpeg.get_pixel(8, 8).0.iter().all(|&c| c > 250), "{:?}", jpeg.get_pixel(8, 8));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// 細かい白黒の縞は文字や線画とみなし、なだらかな画像はみなさないこと。みなした場合は色差を間引かないこと
+    #[test]
+    fn detects_text_like_content() {
+        let text = RgbImage::from_fn(32, 32, |x, y| if (x / 2 + y / 4) % 3 == 0 { image::Rgb([0; 3]) } else { image::Rgb([255; 3]) });
+        let photo = RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8]));
+        assert!(is_text_like(&text));
+        assert!(!is_text_like(&photo));
+        assert!(!is_text_like(&RgbImage::new(1, 1)));
+
+        // SOF の1つ目の成分（輝度）のサンプリング係数。4:4:4 なら 1x1、既定の 4:2:0 なら 2x2
+        let luma_sampling = |jpeg: &[u8]| {
+            let sof = jpeg.windows(2).position(|w| w[0] == 0xFF && (w[1] == 0xC0 || w[1] == 0xC2)).unwrap();
+            jpeg[sof + 11]
+        };
+        assert_eq!(luma_sampling(&encode(&text, 75.0, Scans::default(), true, None, &[]).unwrap()), 0x11);
+        assert_eq!(luma_sampling(&encode(&text, 75.0, Scans::default(), false, None, &[]).unwrap()), 0x22);
+    }
+
+    /// タグを APPn の後ろに COM として入れ、JPEG として読める状態を保つこと
+    #[test]
+    fn inserts_comment_after_app_segments() {
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(8, 8);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_scanlines(&[128u8; 8 * 8 * 3]).unwrap();
+        let jpeg = comp.finish().unwrap();
+
+        let tagged = with_comment(&jpeg, "build=1234");
+        let segments = segments(&tagged);
+        assert_eq!(segments[0].0, 0xE0, "JFIF は SOI の直後のまま");
+        assert!(segments.contains(&(0xFE, b"build=1234".as_slice())));
+        assert!(image::load_from_memory(&tagged).is_ok());
+        assert_eq!(with_comment(b"not a jpeg", "x"), b"not a jpeg");
+    }
+
+    /// 既定はプログレッシブで、baseline では品質が低くてもベースライン（SOF0）の1スキャンになること
+    #[test]
+    fn encodes_baseline_on_request() {
+        let img = RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 8) as u8, (y * 8) as u8, 128]));
+        let markers = |jpeg: &[u8]| segments(jpeg).into_iter().map(|(marker, _)| marker).collect::<Vec<_>>();
+
+        assert!(!is_baseline(&encode(&img, 75.0, Scans::default(), false, None, &[]).unwrap()));
+        for quality in [10.0, 75.0] {
+            let jpeg = encode(&img, quality, Scans::Baseline, false, None, &[]).unwrap();
+            assert!(is_baseline(&jpeg), "quality {quality}: {:x?}", markers(&jpeg));
+            assert_eq!(jpeg.windows(2).filter(|w| *w == [0xFF, 0xDA]).count(), 1);
+        }
+    }
+
+    /// スキャンの分け方を探す場合も探さない場合もプログレッシブ（SOF2）の複数スキャンで、元の寸法にデコードできること
+    #[test]
+    fn encodes_progressive_with_or_without_scan_search() {
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]));
+        for optimize_scans in [false, true] {
+            let jpeg = encode(&img, 75.0, if optimize_scans { Scans::Optimized } else { Scans::Progressive }, false, None, &[]).unwrap();
+            assert!(segments(&jpeg).iter().any(|(marker, _)| *marker == 0xC2), "optimize_scans {optimize_scans}");
+            assert!(jpeg.windows(2).filter(|w| *w == [0xFF, 0xDA]).count() > 1);
+            let decoded = image::load_from_memory(&jpeg).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        }
+    }
+
+    /// preview-first では最初のスキャンに3成分の直流成分をまとめ、画素を変えずに組み直すこと
+    #[test]
+    fn preview_first_scan_carries_all_components() {
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]));
+        // SOS ごとの（成分数, Ss, Se）
+        let scans = |jpeg: &[u8]| -> Vec<(u8, u8, u8)> {
+            (0..jpeg.len() - 1)
+                .filter(|&i| jpeg[i..i + 2] == [0xFF, 0xDA])
+                .map(|i| {
+                    let count = jpeg[i + 4];
+                    let rest = i + 5 + 2 * count as usize;
+                    (count, jpeg[rest], jpeg[rest + 1])
+                })
+                .collect()
+        };
+        let jpeg = encode(&img, 75.0, Scans::PreviewFirst, false, None, &[]).unwrap();
+        assert_eq!(scans(&jpeg), [(3, 0, 0), (1, 1, 5), (1, 1, 63), (1, 1, 63), (1, 6, 63)]);
+        assert!(segments(&jpeg).iter().any(|(marker, _)| *marker == 0xC2));
+
+        let progressive = encode(&img, 75.0, Scans::Progressive, false, None, &[]).unwrap();
+        let decode = |jpeg: &[u8]| image::load_from_memory(jpeg).unwrap().to_rgb8();
+        assert_eq!(decode(&jpeg), decode(&progressive));
+    }
+
+    /// restart-interval では 1つのスキャンに DRI とリスタートマーカーを入れ、画素を変えないこと
+    #[test]
+    fn writes_restart_markers() {
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]));
+        let restarts = |jpeg: &[u8]| jpeg.windows(2).filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1])).count();
+        let options = ImageOptions { restart_interval: Some(2), ..Default::default() };
+        let plain = encode(&img, 75.0, Scans::of(&options), false, None, &[]).unwrap();
+        assert_eq!(restarts(&plain), 0);
+
+        let jpeg = with_restart_interval(plain.clone(), &options).unwrap();
+        // 64x48 の 4:2:0 は 4x3 の MCU で、2 個ごとに6つの区間（マーカーは間の5つ）
+        assert_eq!(restarts(&jpeg), 5);
+        assert!(segments(&jpeg).contains(&(0xDD, [0, 2].as_slice())));
+        assert_eq!(jpeg.windows(2).filter(|w| *w == [0xFF, 0xDA]).count(), 1);
+        let decode = |jpeg: &[u8]| image::load_from_memory(jpeg).unwrap().to_rgb8();
+        assert_eq!(decode(&jpeg), decode(&plain));
+
+        // プログレッシブの JPEG も1つのスキャンに書き直す
+        let progressive = encode(&img, 75.0, Scans::Progressive, false, None, &[]).unwrap();
+        let rewritten = jpeg_transform::with_restart_interval(&progressive, 2).unwrap();
+        assert_eq!(rewritten.windows(2).filter(|w| *w == [0xFF, 0xDA]).count(), 1);
+        assert_eq!(decode(&rewritten), decode(&progressive));
+    }
+
+    /// EOI まで揃った JPEG だけを完全とし、後ろに付け足したデータは無視すること
+    #[test]
+    fn detects_truncated_jpeg() {
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]));
+        for scans in [Scans::Baseline, Scans::Progressive] {
+            let jpeg = encode(&img, 75.0, scans, false, None, &[]).unwrap();
+            assert!(is_complete(&jpeg), "{scans:?}");
+            assert!(!is_complete(&jpeg[..jpeg.len() - 2]), "{scans:?}");
+            assert!(!is_complete(&jpeg[..jpeg.len() / 2]), "{scans:?}");
+            let mut trailer = jpeg.clone();
+            trailer.extend_from_slice(b"\xFF\xDAftypmp42");
+            assert!(is_complete(&trailer), "{scans:?}");
+        }
+        assert!(!is_complete(b"\x89PNG"));
+    }
+
+    /// 外部の Exif で置き換えても ICC プロファイルは残ること
+    #[test]
+    fn replaces_exif_keeping_icc() {
+        let mut markers = vec![(1, b"Exif\0\0old".to_vec()), (2, b"ICC_PROFILE\0icc".to_vec())];
+
+        let payload = b"Exif\0\0new";
+        let length = (payload.len() + 2) as u16;
+        let mut source = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        source.extend_from_slice(&length.to_be_bytes());
+        source.extend_from_slice(payload);
+        source.extend_from_slice(&[0xFF, 0xDA]);
+
+        replace_exif(&mut markers, &source);
+        assert_eq!(markers, vec![(1, payload.to_vec()), (2, b"ICC_PROFILE\0icc".to_vec())]);
+    }
+
+    /// 再エンコードしても小さくならない入力でも、元を書き戻さずに差し替えた Exif を出力すること
+    #[test]
+    fn replaces_exif_of_already_small_input() {
+        let dir = std::env::temp_dir().join("compressor_exif_source_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("small.jpg");
+        let noisy = RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 37 + y * 91) as u8, (x * y * 13) as u8, (x ^ y) as u8 * 8]));
+        let small = encode(&noisy, 40.0, Scans::default(), false, None, &[]).unwrap();
+        std::fs::write(&input, &small).unwrap();
+        let payload = b"Exif\0\0new";
+        let mut source = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        source.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        source.extend_from_slice(payload);
+        source.extend_from_slice(&[0xFF, 0xDA]);
+        std::fs::write(dir.join("source.jpg"), &source).unwrap();
+
+        let plain = ImageOptions { quality: 95.0, ..Default::default() };
+        assert_eq!(path2jpeg(&input, &plain).unwrap().into_output(), small, "元のほうが小さい前提");
+        let options = ImageOptions { exif_source: Some(dir.join("source.jpg")), ..plain };
+        let output = path2jpeg(&input, &options).unwrap().into_output();
+        assert_eq!(extract_metadata_markers(&output), vec![(1, payload.to_vec())]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Exif を持たない参照元では元の Exif を消さないこと
+    #[test]
+    fn keeps_exif_when_source_has_none() {
+        let mut markers = vec![(1, b"Exif\0\0old".to_vec())];
+        replace_exif(&mut markers, &[0xFF, 0xD8, 0xFF, 0xDA]);
+        assert_eq!(markers, vec![(1, b"Exif\0\0old".to_vec())]);
+    }
+
     /// Exif以外のAPP1（XMPなど）は対象にしないこと
     #[test]
     fn ignores_non_exif_app1() {
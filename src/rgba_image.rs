@@ -1,22 +1,300 @@
-use image::DynamicImage;
-use oxipng::{optimize_from_memory, Options};
+use image::{DynamicImage, ImageFormat};
+use oxipng::{optimize_from_memory, Options, RowFilter};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Cursor};
 use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use crate::error::CompressError;
-use crate::utilities::{get_aspect_ratio, write_smaller};
+use crate::exif;
+use crate::file::ensure_parent_dir;
+use crate::utilities::{get_aspect_ratio, sharpen, warning, write_output, Dpi, Encoded, ImageOptions, PngFilter};
 
-pub fn path2compress(path: &Path, output_path: &Path) -> Result<(), CompressError> {
+/// 1インチあたりのメートル数。PNG の pHYs は 1 メートルあたりの画素数で記録する。
+const METERS_PER_INCH: f64 = 0.0254;
+
+/// PNG のシグネチャ
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// `options.dpi` は pHYs チャンクに書き込む解像度の扱い。`options.crop` や `options.watermark` を指定すると、切り抜きや透かしを施してから最適化する。
+pub fn path2compress(path: &Path, output_path: &Path, options: &ImageOptions) -> Result<(), CompressError> {
+    write_output(output_path, &path2png(path, options)?.into_output())
+}
+
+/// `path2compress` と同じように圧縮し、ファイルに書かずに返す。
+pub fn path2png(path: &Path, options: &ImageOptions) -> Result<Encoded, CompressError> {
     // 元データはサイズ比較に使う
-    let original = std::fs::read(path)?;
+    let mut original = std::fs::read(path)?;
 
-    let mut options = Options::from_preset(2);
-    // 改善がなくても結果を受け取り、元と比較して小さいほうを書く
-    options.force = true;
+    let source = if options.edits_pixels() {
+        let mut img = options.edit(image::load_from_memory(&original)?)?;
+        if let Some(amount) = options.sharpen {
+            img = sharpen_image(&img, amount);
+        }
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        png
+    } else {
+        original.clone()
+    };
+
+    // oxipng は pHYs を残すため、元の PNG をそのまま最適化する場合は解像度に手を入れなくてよい
+    let mut optimized = optimize(&source, options.png_timeout, &options.png_filters)?;
+    // 元のほうが小さい場合も解像度の指定が効くよう、比較する両方に書き込む
+    let phys = match options.dpi {
+        Dpi::Keep if options.edits_pixels() => {
+            // 再エンコードで失われた元の解像度を戻す
+            optimized = replace_phys(&optimized, phys_chunk(&original).as_deref());
+            None
+        }
+        Dpi::Keep => None,
+        Dpi::Set(value) => Some(Some(phys_for_dpi(value))),
+        Dpi::Strip => Some(None),
+    };
+    if let Some(phys) = phys {
+        optimized = replace_phys(&optimized, phys.as_ref().map(<[u8; 9]>::as_slice));
+        original = replace_phys(&original, phys.as_ref().map(<[u8; 9]>::as_slice));
+    }
+
+    // oxipng は eXIf と XMP を残すため、元のほうが小さい場合に備えて比較する元からも消しておく
+    let original = if options.strip_gps {
+        optimized = without_gps(&optimized);
+        without_gps(&original)
+    } else {
+        original
+    };
+
+    // 元のほうが小さい場合も追跡用のタグが残るよう、比較する両方に入れておく
+    let original = match &options.tag {
+        Some(tag) => {
+            optimized = with_text(&optimized, tag);
+            with_text(&original, tag)
+        }
+        None => original,
+    };
+
+    // 切り抜きや透かしを施した場合は元と内容が違うため、元のほうが小さくても書き戻さない
+    if options.is_edited() {
+        return Ok(Encoded::new(optimized));
+    }
+    Ok(Encoded::with_original(optimized, original))
+}
+
+/// ビット深度を保ってアンシャープマスクをかける。色の形式は RGBA にするが、oxipng が元の形式まで落とす
+fn sharpen_image(img: &DynamicImage, amount: f32) -> DynamicImage {
+    let color = img.color();
+    if color.bytes_per_pixel() / color.channel_count() > 1 {
+        DynamicImage::ImageRgba16(sharpen(&img.to_rgba16(), amount))
+    } else {
+        DynamicImage::ImageRgba8(sharpen(&img.to_rgba8(), amount))
+    }
+}
+
+/// PNG を oxipng で最適化する。改善がなくても結果を受け取る（呼び出し側で元と比較する）。
+///
+/// 細工された PNG では最適化が極端に長引くことがあるため、`timeout` を過ぎたら新しい試行を打ち切り、
+/// それまでの最良の結果（まだなければ最適化していない入力）を使って警告する。
+/// oxipng は試行の合間にしか打ち切らないため、最適化は別のスレッドで行い、[`HARD_TIMEOUT_FACTOR`] 倍まで待っても
+/// 終わらなければ待つのをやめ、最適化していない入力を返す（[`optimize_within`]）。
+/// `filters` を指定すると、oxipng が試す行フィルタをそれだけにする（空なら oxipng の既定）。
+pub(crate) fn optimize(png: &[u8], timeout: Option<Duration>, filters: &[PngFilter]) -> Result<Vec<u8>, CompressError> {
+    let mut png_options = Options::from_preset(2);
+    png_options.force = true;
+    png_options.timeout = timeout;
+    // 灰色だけの RGB をグレースケールに、色の少ない画像をパレットにする。不透明な RGBA はアルファを落とし、ビット深度も下げる。
+    // oxipng の既定でも有効だが、プリセットの変更で無効にならないよう明示する
+    png_options.color_type_reduction = true;
+    png_options.grayscale_reduction = true;
+    png_options.palette_reduction = true;
+    png_options.bit_depth_reduction = true;
+    if !filters.is_empty() {
+        png_options.filter = filters.iter().map(|&filter| row_filter(filter)).collect();
+    }
+
+    let Some(timeout) = timeout else {
+        return Ok(optimize_from_memory(png, &png_options)?);
+    };
+    let start = Instant::now();
+    let optimized = optimize_within(png, timeout.mul_f64(HARD_TIMEOUT_FACTOR), move |png| {
+        optimize_from_memory(&png, &png_options).map_err(CompressError::from)
+    })?;
+    if start.elapsed() > timeout {
+        warning!("警告: PNG の最適化が {} 秒を超えたため打ち切りました（--encode-timeout-image で延ばせます）", timeout.as_secs());
+    }
+
+    Ok(optimized)
+}
+
+/// oxipng の打ち切りが効かない場合に待つ時間の、--encode-timeout-image に対する倍率。
+/// 打ち切った後の oxipng が実行中の試行を終えてそれまでの最良の結果を返すまでの猶予
+const HARD_TIMEOUT_FACTOR: f64 = 1.5;
 
-    let optimized = optimize_from_memory(&original, &options)?;
+/// `optimize` を別のスレッドで実行し、`limit` までに終わらなければ最適化していない `png` を返す。
+/// 待つのをやめたスレッドは止められないため、終わるまで裏で動き続ける（結果は捨てる）
+fn optimize_within(
+    png: &[u8],
+    limit: Duration,
+    optimize: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, CompressError> + Send + 'static,
+) -> Result<Vec<u8>, CompressError> {
+    let (sender, receiver) = mpsc::channel();
+    let input = png.to_vec();
+    std::thread::spawn(move || {
+        let _ = sender.send(optimize(input));
+    });
+    match receiver.recv_timeout(limit) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Ok(png.to_vec()),
+        Err(RecvTimeoutError::Disconnected) => Err(CompressError::Png(oxipng::PngError::new("PNG の最適化が異常終了しました"))),
+    }
+}
+
+fn row_filter(filter: PngFilter) -> RowFilter {
+    match filter {
+        PngFilter::None => RowFilter::None,
+        PngFilter::Sub => RowFilter::Sub,
+        PngFilter::Up => RowFilter::Up,
+        PngFilter::Average => RowFilter::Average,
+        PngFilter::Paeth => RowFilter::Paeth,
+        PngFilter::MinSum => RowFilter::MinSum,
+        PngFilter::Entropy => RowFilter::Entropy,
+        PngFilter::Bigrams => RowFilter::Bigrams,
+        PngFilter::BigEnt => RowFilter::BigEnt,
+        PngFilter::Brute => RowFilter::Brute,
+    }
+}
+
+/// PNG のチャンク（種類, データ, 長さと CRC を含むチャンク全体）
+type Chunk<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+/// PNG のチャンクを列挙する。PNG として解釈できない場合は `None`。
+fn chunks(png: &[u8]) -> Option<Vec<Chunk<'_>>> {
+    if !png.starts_with(SIGNATURE) {
+        return None;
+    }
+
+    let mut chunks = Vec::new();
+    let mut i = SIGNATURE.len();
+    // チャンクは 長さ(4), 種類(4), データ, CRC(4)
+    while i + 12 <= png.len() {
+        let length = u32::from_be_bytes([png[i], png[i + 1], png[i + 2], png[i + 3]]) as usize;
+        let end = (i + 12).checked_add(length).filter(|&end| end <= png.len())?;
+        chunks.push((&png[i + 4..i + 8], &png[i + 8..end - 4], &png[i..end]));
+        i = end;
+    }
+    Some(chunks)
+}
+
+/// APNG（acTL チャンクで2フレーム以上を持つ PNG）かを判定する。PNG として解釈できないデータは `false`。
+pub fn is_animated(png: &[u8]) -> bool {
+    chunks(png).is_some_and(|chunks| {
+        chunks.iter().any(|(kind, data, _)| {
+            *kind == b"acTL" && data.len() == 8 && u32::from_be_bytes([data[0], data[1], data[2], data[3]]) > 1
+        })
+    })
+}
 
-    write_smaller(output_path, &optimized, &original)
+/// pHYs チャンクのデータ（9バイト）を取り出す
+fn phys_chunk(png: &[u8]) -> Option<Vec<u8>> {
+    chunks(png)?
+        .into_iter()
+        .find(|(kind, data, _)| *kind == b"pHYs" && data.len() == 9)
+        .map(|(_, data, _)| data.to_vec())
+}
+
+/// DPI を pHYs チャンクのデータ（1メートルあたりの画素数, 単位=メートル）にする
+fn phys_for_dpi(dpi: u16) -> [u8; 9] {
+    let ppm = (dpi as f64 / METERS_PER_INCH).round() as u32;
+    let mut data = [0u8; 9];
+    data[0..4].copy_from_slice(&ppm.to_be_bytes());
+    data[4..8].copy_from_slice(&ppm.to_be_bytes());
+    data[8] = 1; // 単位: メートル
+    data
+}
+
+/// PNG の pHYs チャンクを取り除き、`phys` を指定した場合は IHDR の直後に書き直す。
+/// PNG として解釈できないデータはそのまま返す。
+fn replace_phys(png: &[u8], phys: Option<&[u8]>) -> Vec<u8> {
+    let Some(chunks) = chunks(png) else {
+        return png.to_vec();
+    };
+
+    let mut output = SIGNATURE.to_vec();
+    for (kind, _, chunk) in chunks {
+        if kind != b"pHYs" {
+            output.extend_from_slice(chunk);
+        }
+        if let (b"IHDR", Some(phys)) = (kind, phys) {
+            write_chunk(&mut output, b"pHYs", phys);
+        }
+    }
+    output
+}
+
+/// eXIf チャンクの Exif から位置情報を消し、位置情報を含む XMP（iTXt）を落とす（--strip-gps）。
+/// PNG として解釈できないデータはそのまま返す。
+fn without_gps(png: &[u8]) -> Vec<u8> {
+    let Some(chunks) = chunks(png) else {
+        return png.to_vec();
+    };
+
+    let mut output = SIGNATURE.to_vec();
+    for (kind, data, chunk) in chunks {
+        match kind {
+            b"eXIf" => {
+                let mut exif = data.to_vec();
+                exif::strip_gps_tiff(&mut exif);
+                write_chunk(&mut output, b"eXIf", &exif);
+            }
+            b"iTXt" if data.starts_with(b"XML:com.adobe.xmp\0") && exif::xmp_has_gps(data) => {}
+            _ => output.extend_from_slice(chunk),
+        }
+    }
+    output
+}
+
+/// PNG の IEND の直前に、キーワード `Comment` のテキストチャンクを挿入する。
+/// tEXt は Latin-1 しか扱えないため、ASCII 以外を含む場合は UTF-8 の iTXt にする。
+/// PNG として解釈できないデータはそのまま返す。
+pub(crate) fn with_text(png: &[u8], text: &str) -> Vec<u8> {
+    let Some(chunks) = chunks(png) else {
+        return png.to_vec();
+    };
+
+    let (kind, data) = if text.is_ascii() {
+        (b"tEXt", [b"Comment\0".as_slice(), text.as_bytes()].concat())
+    } else {
+        // キーワード, 圧縮フラグ, 圧縮方式, 言語タグ, 翻訳したキーワード, 本文
+        (b"iTXt", [b"Comment\0\0\0\0\0".as_slice(), text.as_bytes()].concat())
+    };
+
+    let mut output = SIGNATURE.to_vec();
+    for (chunk_kind, _, chunk) in chunks {
+        if chunk_kind == b"IEND" {
+            write_chunk(&mut output, kind, &data);
+        }
+        output.extend_from_slice(chunk);
+    }
+    output
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let crc = crc32(kind.iter().chain(data));
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// PNG のチャンクに使う CRC-32（ISO-HDLC）
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
 }
 
 #[allow(dead_code)]
@@ -54,9 +332,212 @@ pub fn compress(img: &DynamicImage, output_path: &Path) -> Result<(), CompressEr
 
     let png_data = optimize_from_memory(&rgba_img, &options)?;
 
+    ensure_parent_dir(output_path)?;
     let file = File::create(output_path)?;
     let mut writer = BufWriter::new(file);
     std::io::copy(&mut &png_data[..], &mut writer)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// pHYs チャンクの解像度を DPI で返す。単位が不明（縦横比のみ）の場合や pHYs がない場合は `None`。
+    fn phys_dpi(png: &[u8]) -> Option<u16> {
+        let data = phys_chunk(png)?;
+        if data[8] != 1 {
+            return None;
+        }
+        let ppm = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        Some((ppm as f64 * METERS_PER_INCH).round() as u16)
+    }
+
+    fn encode_png() -> Vec<u8> {
+        let img = DynamicImage::new_rgba8(4, 4);
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        png
+    }
+
+    /// pHYs を書き込み、置き換え、取り除けること（PNG として読める状態を保つ）
+    #[test]
+    fn rewrites_phys_chunk() {
+        let png = encode_png();
+        assert_eq!(phys_dpi(&png), None);
+
+        let with_dpi = replace_phys(&png, Some(&phys_for_dpi(300)));
+        assert_eq!(phys_dpi(&with_dpi), Some(300));
+        assert!(image::load_from_memory(&with_dpi).is_ok(), "CRC が壊れている");
+
+        let replaced = replace_phys(&with_dpi, Some(&phys_for_dpi(72)));
+        assert_eq!(phys_dpi(&replaced), Some(72));
+        assert_eq!(replaced.len(), with_dpi.len(), "pHYs が重複している");
+
+        assert_eq!(phys_dpi(&replace_phys(&with_dpi, None)), None);
+    }
+
+    /// 切り抜いて再エンコードしても元の解像度を引き継ぐこと
+    #[test]
+    fn crop_keeps_phys() {
+        let dir = std::env::temp_dir().join("compressor_rgba_crop_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("scan.png");
+        std::fs::write(&input, replace_phys(&encode_png(), Some(&phys_for_dpi(300)))).unwrap();
+
+        let output = dir.join("out.png");
+        let options = ImageOptions {
+            crop: Some(crate::geometry::Crop::Center { width: 2, height: 3 }),
+            ..Default::default()
+        };
+        path2compress(&input, &output, &options).unwrap();
+
+        let png = std::fs::read(&output).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 3));
+        assert_eq!(phys_dpi(&png), Some(300));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// 最適化しても小さくならず元を書き出す場合も、解像度の指定が効くこと
+    #[test]
+    fn sets_phys_of_already_optimal_input() {
+        let dir = std::env::temp_dir().join("compressor_rgba_dpi_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("optimal.png");
+        let optimal = replace_phys(&optimize(&encode_png(), None, &[]).unwrap(), Some(&phys_for_dpi(300)));
+        std::fs::write(&input, &optimal).unwrap();
+
+        assert_eq!(path2png(&input, &ImageOptions::default()).unwrap().into_output(), optimal, "元のほうが小さい前提");
+        let dpi_of = |dpi| phys_dpi(&path2png(&input, &ImageOptions { dpi, ..Default::default() }).unwrap().into_output());
+        assert_eq!(dpi_of(Dpi::Set(150)), Some(150));
+        assert_eq!(dpi_of(Dpi::Strip), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// --sharpen で輪郭の両側のコントラストを強め、平坦な部分と色の形式は変えないこと
+    #[test]
+    fn sharpens_before_optimizing() {
+        let dir = std::env::temp_dir().join("compressor_rgba_sharpen_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("edge.png");
+        let gray = image::GrayImage::from_fn(16, 4, |x, _| image::Luma([if x < 8 { 100 } else { 160 }]));
+        gray.save(&input).unwrap();
+
+        let output = path2png(&input, &ImageOptions { sharpen: Some(1.0), ..Default::default() }).unwrap().into_output();
+        let img = image::load_from_memory(&output).unwrap();
+        assert_eq!(img.color(), image::ColorType::L8);
+        let row: Vec<u8> = (0..16).map(|x| img.to_luma8().get_pixel(x, 1).0[0]).collect();
+        assert_eq!((row[0], row[15]), (100, 160));
+        assert!(row[7] < 100 && row[8] > 160, "{row:?}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// タグをテキストチャンクとして入れ、PNG として読める状態を保つこと
+    #[test]
+    fn inserts_text_chunk() {
+        let tagged = with_text(&encode_png(), "build=1234");
+        let tagged_chunks = chunks(&tagged).unwrap();
+        assert!(tagged_chunks.iter().any(|(kind, data, _)| *kind == b"tEXt" && *data == b"Comment\0build=1234"));
+        assert_eq!(tagged_chunks.last().unwrap().0, b"IEND");
+        assert!(image::load_from_memory(&tagged).is_ok(), "CRC が壊れている");
+
+        let unicode = with_text(&encode_png(), "ビルド=1234");
+        assert!(chunks(&unicode).unwrap().iter().any(|(kind, _, _)| *kind == b"iTXt"));
+    }
+
+    /// 時間切れでも PNG として読める結果を返すこと（最適化しない入力で代用する）
+    #[test]
+    fn optimize_times_out_gracefully() {
+        let png = encode_png();
+        let optimized = optimize(&png, Some(Duration::ZERO), &[]).unwrap();
+        let expected = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(image::load_from_memory(&optimized).unwrap().to_rgba8(), expected);
+    }
+
+    /// 1回の試行が終わらなくても上限の時間で待つのをやめ、最適化していない入力を返すこと
+    #[test]
+    fn optimize_falls_back_when_stuck() {
+        let png = encode_png();
+        let start = Instant::now();
+        let stuck = |_| {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(Vec::new())
+        };
+        assert_eq!(optimize_within(&png, Duration::from_millis(50), stuck).unwrap(), png);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(optimize_within(&png, Duration::from_secs(5), |_| Ok(vec![1])).unwrap(), [1]);
+        assert!(optimize_within(&png, Duration::from_secs(5), |_| panic!("oxipng")).is_err());
+    }
+
+    /// 行フィルタを指定すると結果が変わり、どの指定でも画素は変わらないこと
+    #[test]
+    fn optimizes_with_chosen_filters() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])));
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+
+        let unfiltered = optimize(&png, None, &[PngFilter::None]).unwrap();
+        let paeth = optimize(&png, None, &[PngFilter::Paeth]).unwrap();
+        assert_ne!(unfiltered, paeth);
+        for optimized in [&unfiltered, &paeth] {
+            assert_eq!(image::load_from_memory(optimized).unwrap().to_rgb8(), img.to_rgb8());
+        }
+        // なだらかな画像では差を取るフィルタのほうが小さい
+        assert!(paeth.len() < unfiltered.len(), "{} {}", paeth.len(), unfiltered.len());
+    }
+
+    /// 灰色だけの RGB の PNG はグレースケール（色の種類 0）になって小さくなり、画素は変わらないこと
+    #[test]
+    fn reduces_gray_rgb_to_grayscale() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([((x + y) * 2) as u8; 3])));
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        // IHDR の色の種類（シグネチャ 8、チャンクの長さと種類 8、幅と高さ 8、ビット深度 1 の後）
+        assert_eq!(png[25], 2);
+
+        let optimized = optimize(&png, None, &[]).unwrap();
+        assert_eq!(optimized[25], 0);
+        assert!(optimized.len() < png.len());
+        assert_eq!(image::load_from_memory(&optimized).unwrap().to_rgb8(), img.to_rgb8());
+    }
+
+    /// PNG 仕様の CRC と一致すること（IEND チャンクの既知の値）
+    #[test]
+    fn computes_png_crc() {
+        assert_eq!(crc32(b"IEND".iter()), 0xAE42_6082);
+    }
+
+    /// acTL で2フレーム以上を持つ PNG だけを APNG と判定すること
+    #[test]
+    fn detects_apng() {
+        let png = encode_png();
+        assert!(!is_animated(&png));
+
+        let with_actl = |frames: u32| {
+            let mut apng = SIGNATURE.to_vec();
+            for (kind, _, chunk) in chunks(&png).unwrap() {
+                apng.extend_from_slice(chunk);
+                if kind == b"IHDR" {
+                    let mut actl = frames.to_be_bytes().to_vec();
+                    actl.extend_from_slice(&0u32.to_be_bytes());
+                    write_chunk(&mut apng, b"acTL", &actl);
+                }
+            }
+            apng
+        };
+        assert!(is_animated(&with_actl(3)));
+        assert!(!is_animated(&with_actl(1)));
+        assert!(!is_animated(b"not a png"));
+    }
+
+    /// PNG でないデータは変更しないこと
+    #[test]
+    fn ignores_non_png() {
+        assert_eq!(replace_phys(b"not a png", Some(&phys_for_dpi(300))), b"not a png");
+    }
+}
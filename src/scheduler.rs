@@ -0,0 +1,273 @@
+//! 圧縮を種類ごとのワーカーで並列に実行する（`--image-jobs` / `--video-jobs`）。
+//!
+//! 画像は1枚ずつが1スレッドで動くため多くのワーカーで並べ、動画・音声は ffmpeg 自身が
+//! 複数スレッドを使うため少ないワーカーで並べる。`--max-memory` を指定すると、同時に実行する処理の
+//! 見積もりメモリの合計がそれを超えないよう、大きな画像は空きができるまで待たせる。
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// ワーカーを分ける処理の種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pool {
+    /// ffmpeg を使わない処理（画像・PDF・コピー）
+    Image,
+    /// ffmpeg を使う処理（動画・音声・アニメーションGIF）
+    Video,
+}
+
+impl Pool {
+    /// 指定がないときのワーカー数。画像は CPU 数、動画はその 1/4（最低 1）
+    pub fn default_jobs(self) -> usize {
+        let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        match self {
+            Pool::Image => cpus,
+            Pool::Video => (cpus / 4).max(1),
+        }
+    }
+}
+
+/// 処理の始め方の制限
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// この時刻を過ぎたら新しい処理を始めない（--max-runtime）
+    pub deadline: Option<Instant>,
+    /// 同時に実行する処理の見積もりメモリの合計の上限（バイト。--max-memory）
+    pub max_memory: Option<u64>,
+}
+
+/// 見積もりメモリの重みを付けたセマフォ。待っている処理は来た順に確保する
+struct Budget {
+    limit: u64,
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    /// 確保中の見積もりメモリの合計
+    used: u64,
+    /// 次に来た処理に渡す順番
+    next_ticket: u64,
+    /// 確保してよい処理の順番
+    serving: u64,
+}
+
+impl Budget {
+    fn new(limit: u64) -> Self {
+        Budget { limit, state: Mutex::new(BudgetState::default()), released: Condvar::new() }
+    }
+
+    /// `cost` の空きができるまで待って確保する。
+    /// 先に待っている処理がある間は、空きがあっても追い越さない（小さな処理が続いて大きな処理がいつまでも始まらないのを防ぐ）。
+    /// 1件で上限を超える処理は、待っても空きができないため、ほかに実行中の処理がなくなってから1件だけで実行する
+    fn acquire(&self, cost: u64) {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || (state.used > 0 && state.used.saturating_add(cost) > self.limit) {
+            state = self.released.wait(state).unwrap();
+        }
+        state.used += cost;
+        state.serving += 1;
+        // 次の順番の処理も空きがあれば続けて始められる
+        self.released.notify_all();
+    }
+
+    fn release(&self, cost: u64) {
+        self.state.lock().unwrap().used -= cost;
+        self.released.notify_all();
+    }
+}
+
+/// `tasks` を種類ごとのワーカーで実行し、結果を `tasks` と同じ順に返す。
+/// `is_fatal` が真になる結果が出たら、まだ始めていない処理は実行せずに `None` とする。
+/// `limits.deadline` を過ぎた後も新しい処理は始めずに `None` とする（実行中の処理は最後まで続ける）。
+/// `limits.max_memory` を指定すると、`memory_of` で見積もった実行中の処理のメモリの合計が上限を超えないよう、
+/// 空きができるまで処理を始めない（指定しなければ `memory_of` は呼ばない）。
+pub fn run<T, R>(
+    tasks: &[T],
+    limits: Limits,
+    jobs: impl Fn(Pool) -> usize,
+    pool_of: impl Fn(&T) -> Pool,
+    memory_of: impl Fn(&T) -> u64 + Sync,
+    work: impl Fn(&T) -> R + Sync,
+    is_fatal: impl Fn(&R) -> bool + Sync,
+) -> Vec<Option<R>>
+where
+    T: Sync,
+    R: Send,
+{
+    let queues: Vec<(Pool, Vec<usize>)> = [Pool::Image, Pool::Video]
+        .into_iter()
+        .map(|pool| (pool, (0..tasks.len()).filter(|&index| pool_of(&tasks[index]) == pool).collect()))
+        .collect();
+    // 種類ごとに、次に取り出す位置
+    let cursors: Vec<AtomicUsize> = queues.iter().map(|_| AtomicUsize::new(0)).collect();
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..tasks.len()).map(|_| None).collect());
+    let aborted = AtomicBool::new(false);
+    let budget = limits.max_memory.map(Budget::new);
+    let stopped = || aborted.load(Ordering::Relaxed) || limits.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+    std::thread::scope(|scope| {
+        for ((pool, queue), next) in queues.iter().zip(&cursors) {
+            for _ in 0..jobs(*pool).max(1).min(queue.len()) {
+                scope.spawn(|| loop {
+                    if stopped() {
+                        break;
+                    }
+                    let Some(&index) = queue.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    let reserved = budget.as_ref().map(|budget| {
+                        let cost = memory_of(&tasks[index]);
+                        budget.acquire(cost);
+                        (budget, cost)
+                    });
+                    // 空きを待つ間に打ち切られたか、期限を過ぎた
+                    let result = (!stopped()).then(|| work(&tasks[index]));
+                    if let Some((budget, cost)) = reserved {
+                        budget.release(cost);
+                    }
+                    let Some(result) = result else {
+                        break;
+                    };
+                    if is_fatal(&result) {
+                        aborted.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                });
+            }
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 種類ごとの同時実行数を守り、結果を入力の順に返すこと
+    #[test]
+    fn limits_workers_per_pool() {
+        let tasks: Vec<(Pool, usize)> = (0..12).map(|i| (if i % 3 == 0 { Pool::Video } else { Pool::Image }, i)).collect();
+        let running = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let peak = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+        let results = run(
+            &tasks,
+            Limits::default(),
+            |pool| if pool == Pool::Image { 3 } else { 1 },
+            |task| task.0,
+            |_| unreachable!("--max-memory を指定しなければ見積もらない"),
+            |&(pool, value)| {
+                let slot = pool as usize;
+                let now = running[slot].fetch_add(1, Ordering::SeqCst) + 1;
+                peak[slot].fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running[slot].fetch_sub(1, Ordering::SeqCst);
+                value * 10
+            },
+            |_| false,
+        );
+
+        assert_eq!(results, (0..12).map(|i| Some(i * 10)).collect::<Vec<_>>());
+        assert!(peak[Pool::Image as usize].load(Ordering::SeqCst) <= 3);
+        assert_eq!(peak[Pool::Video as usize].load(Ordering::SeqCst), 1);
+    }
+
+    /// 致命的な結果が出たら、まだ始めていない処理を実行しないこと
+    #[test]
+    fn stops_after_fatal_result() {
+        let tasks: Vec<usize> = (0..5).collect();
+        let results = run(&tasks, Limits::default(), |_| 1, |_| Pool::Video, |_| 0, |&i| i, |&i| i == 1);
+        assert_eq!(results, vec![Some(0), Some(1), None, None, None]);
+    }
+
+    /// 期限を過ぎたら新しい処理を始めず、実行中の処理は最後まで終えること
+    #[test]
+    fn stops_starting_after_deadline() {
+        let tasks: Vec<u64> = (0..5).collect();
+        // 1件目が期限を過ぎるまで終わらないため、1件目は最後まで続け、2件目からは始めない
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let slow = |&i: &u64| {
+            while Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            i
+        };
+        let limits = Limits { deadline: Some(deadline), ..Limits::default() };
+        let results = run(&tasks, limits, |_| 1, |_| Pool::Image, |_| 0, slow, |_| false);
+        assert_eq!(results, vec![Some(0), None, None, None, None]);
+
+        let limits = Limits { deadline: Some(Instant::now()), ..Limits::default() };
+        assert_eq!(run(&tasks, limits, |_| 1, |_| Pool::Image, |_| 0, |&i| i, |_| false), vec![None; 5]);
+    }
+
+    /// 見積もりメモリの合計が上限を超えないよう同時実行数を絞り、上限より大きい処理も1件だけでは実行すること
+    #[test]
+    fn limits_concurrency_by_memory() {
+        // 40 ずつの処理は上限 100 に 2 件まで、500 の処理はほかの処理と並べない
+        let tasks: Vec<u64> = vec![40, 40, 40, 500, 40, 40, 10, 10];
+        let running = AtomicUsize::new(0);
+        let used = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let peak_alongside_large = AtomicUsize::new(0);
+
+        let limits = Limits { max_memory: Some(100), ..Limits::default() };
+        let results = run(
+            &tasks,
+            limits,
+            |_| 8,
+            |_| Pool::Image,
+            |&cost| cost,
+            |&cost| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                let total = used.fetch_add(cost as usize, Ordering::SeqCst) + cost as usize;
+                if cost == 500 {
+                    peak_alongside_large.fetch_max(now, Ordering::SeqCst);
+                } else {
+                    peak.fetch_max(total, Ordering::SeqCst);
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                used.fetch_sub(cost as usize, Ordering::SeqCst);
+                running.fetch_sub(1, Ordering::SeqCst);
+                cost
+            },
+            |_| false,
+        );
+
+        assert_eq!(results, tasks.iter().map(|&cost| Some(cost)).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 100, "{}", peak.load(Ordering::SeqCst));
+        assert_eq!(peak_alongside_large.load(Ordering::SeqCst), 1);
+    }
+
+    /// 空きを待っている処理があるとき、後から来た処理は空きがあっても追い越さないこと
+    #[test]
+    fn admits_waiters_in_order() {
+        let budget = Budget::new(100);
+        budget.acquire(60);
+        let queued = |count: u64| {
+            while budget.state.lock().unwrap().next_ticket < count {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        std::thread::scope(|scope| {
+            let large = scope.spawn(|| budget.acquire(80));
+            queued(2);
+            let small = scope.spawn(|| budget.acquire(10));
+            queued(3);
+            // 10 は空きに収まるが、先に待っている 80 を追い越さない
+            assert_eq!(budget.state.lock().unwrap().used, 60);
+
+            budget.release(60);
+            large.join().unwrap();
+            small.join().unwrap();
+        });
+        assert_eq!(budget.state.lock().unwrap().used, 90);
+    }
+}
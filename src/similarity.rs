@@ -0,0 +1,140 @@
+//! 元の画像と圧縮した画像の見た目の差（`--diff-report` / `--min-quality-score`）。
+//!
+//! SSIM は輝度（BT.601）の 8x8 ブロックごとに求めた値の平均、PSNR は RGB 全体の平均二乗誤差から求める。
+//! 透過した画素は白の背景に合成してから比べる（見えない画素の色の違いを差に数えないため）。
+
+use image::{DynamicImage, RgbImage};
+
+/// SSIM を求めるブロックの大きさ
+const BLOCK: u32 = 8;
+/// 完全に一致する場合の PSNR（dB）。無限大は JSON に書けないため、この値にそろえる
+const MAX_PSNR: f64 = 100.0;
+
+/// 元の画像との差
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Score {
+    /// 構造的類似度（1 で一致、低いほど劣化が大きい）
+    pub ssim: f64,
+    /// ピーク信号対雑音比（dB。高いほど差が小さい）
+    pub psnr: f64,
+}
+
+/// --min-quality-score の値を読む。0 より大きく 1 以下の SSIM。
+pub fn parse_min_score(value: &str) -> Result<f64, String> {
+    let score: f64 = value.parse().map_err(|_| format!("数値で指定してください: {value}"))?;
+    if !(score > 0.0 && score <= 1.0) {
+        return Err(format!("0 より大きく 1 以下で指定してください: {value}"));
+    }
+    Ok(score)
+}
+
+/// 2つの画像の差を求める。寸法が違う場合は比べられないため `None`。
+pub fn compare(reference: &DynamicImage, output: &DynamicImage) -> Option<Score> {
+    if reference.width() != output.width() || reference.height() != output.height() {
+        return None;
+    }
+    let (reference, output) = (flatten(reference), flatten(output));
+    Some(Score { ssim: ssim(&reference, &output), psnr: psnr(&reference, &output) })
+}
+
+/// 透過した画素を白の背景に合成した RGB 画像にする
+fn flatten(img: &DynamicImage) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// 画素の輝度（BT.601）
+fn luma(img: &RgbImage, x: u32, y: u32) -> f64 {
+    let [r, g, b] = img.get_pixel(x, y).0;
+    0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+}
+
+/// 輝度の 8x8 ブロックごとの SSIM の平均。8 ピクセルに満たない辺は画像全体を1つのブロックにする。
+fn ssim(a: &RgbImage, b: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let (block_width, block_height) = (BLOCK.min(width), BLOCK.min(height));
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for top in (0..=height - block_height).step_by(block_height as usize) {
+        for left in (0..=width - block_width).step_by(block_width as usize) {
+            let pixels: Vec<(f64, f64)> = (top..top + block_height)
+                .flat_map(|y| (left..left + block_width).map(move |x| (x, y)))
+                .map(|(x, y)| (luma(a, x, y), luma(b, x, y)))
+                .collect();
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let var_a = pixels.iter().map(|p| (p.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = pixels.iter().map(|p| (p.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = pixels.iter().map(|p| (p.0 - mean_a) * (p.1 - mean_b)).sum::<f64>() / n;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+    if blocks == 0 { 1.0 } else { total / blocks as f64 }
+}
+
+/// RGB 全体の PSNR。完全に一致する場合は [`MAX_PSNR`]。
+fn psnr(a: &RgbImage, b: &RgbImage) -> f64 {
+    let samples = a.as_raw().len().max(1) as f64;
+    let squared: f64 = a.as_raw().iter().zip(b.as_raw()).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum();
+    let mse = squared / samples;
+    if mse == 0.0 {
+        return MAX_PSNR;
+    }
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, Rgba, RgbaImage};
+
+    /// 写真のように色が変化する画像
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8]))
+    }
+
+    /// 一致すれば SSIM 1 と PSNR の上限、ノイズを加えれば両方とも下がること
+    #[test]
+    fn scores_identical_and_noisy_images() {
+        let reference = DynamicImage::ImageRgb8(gradient());
+        assert_eq!(compare(&reference, &reference), Some(Score { ssim: 1.0, psnr: MAX_PSNR }));
+
+        let mut noisy = gradient();
+        for (i, pixel) in noisy.pixels_mut().enumerate() {
+            if i % 3 == 0 {
+                pixel.0[0] = pixel.0[0].wrapping_add(60);
+            }
+        }
+        let score = compare(&reference, &DynamicImage::ImageRgb8(noisy)).unwrap();
+        assert!(score.ssim < 0.95, "{score:?}");
+        assert!(score.psnr < 30.0, "{score:?}");
+
+        assert_eq!(compare(&reference, &DynamicImage::ImageRgb8(RgbImage::new(16, 16))), None);
+    }
+
+    /// 透明な画素の色の違いは差に数えないこと
+    #[test]
+    fn ignores_hidden_colors() {
+        let a = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 0]));
+        let b = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 0]));
+        let score = compare(&DynamicImage::ImageRgba8(a), &DynamicImage::ImageRgba8(b)).unwrap();
+        assert_eq!(score.psnr, MAX_PSNR);
+    }
+}
@@ -0,0 +1,169 @@
+//! ファイルの先頭のバイト列から実際の形式を判定する（`--strict-extensions`）。
+//!
+//! 拡張子を信用せずに中身と突き合わせ、壊れたファイルや拡張子を偽ったファイルを処理の前に除くためのもの。
+//! 判定するのは先頭のシグネチャだけで、ファイル全体が正しいかまでは確かめない。
+
+use std::io::Read;
+use std::path::Path;
+
+/// 判定に読む先頭のバイト数
+const HEAD_LEN: usize = 16;
+
+/// 先頭のバイト列から判定した形式。同じ入れ物を使う拡張子（mp4/mov/m4a など）は区別しない
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Signature {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Pdf,
+    /// ISO BMFF（mp4, mov, m4a）
+    IsoBmff,
+    /// Matroska（mkv, webm）
+    Matroska,
+    Avi,
+    Wav,
+    Aiff,
+    Flac,
+    Ogg,
+    /// MPEG オーディオのフレーム（mp3, ADTS の aac）。ID3 タグで始まるものを含む
+    MpegAudio,
+    /// ASF（wma）
+    Asf,
+}
+
+impl Signature {
+    /// 拡張子（小文字に正規化したもの）が表す形式。中身を判定できない拡張子は `None`
+    pub fn of_extension(ext: &str) -> Option<Signature> {
+        let signature = match ext {
+            "jpg" | "jpeg" => Signature::Jpeg,
+            "png" => Signature::Png,
+            "gif" => Signature::Gif,
+            "webp" => Signature::Webp,
+            "pdf" => Signature::Pdf,
+            "mp4" | "mov" | "m4a" => Signature::IsoBmff,
+            "mkv" | "webm" => Signature::Matroska,
+            "avi" => Signature::Avi,
+            "wav" => Signature::Wav,
+            "aiff" | "aif" => Signature::Aiff,
+            "flac" => Signature::Flac,
+            "ogg" => Signature::Ogg,
+            "mp3" | "aac" => Signature::MpegAudio,
+            "wma" => Signature::Asf,
+            _ => return None,
+        };
+        Some(signature)
+    }
+
+    /// 画像の形式なら、その形式として処理する拡張子
+    pub fn image_extension(self) -> Option<&'static str> {
+        match self {
+            Signature::Jpeg => Some("jpg"),
+            Signature::Png => Some("png"),
+            Signature::Gif => Some("gif"),
+            Signature::Webp => Some("webp"),
+            _ => None,
+        }
+    }
+
+    /// 先頭のバイト列から形式を判定する。どれにも当てはまらなければ `None`
+    pub fn detect(head: &[u8]) -> Option<Signature> {
+        let riff = |kind: &[u8]| head.starts_with(b"RIFF") && head.get(8..12) == Some(kind);
+        let signature = if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Signature::Jpeg
+        } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Signature::Png
+        } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+            Signature::Gif
+        } else if riff(b"WEBP") {
+            Signature::Webp
+        } else if riff(b"AVI ") {
+            Signature::Avi
+        } else if riff(b"WAVE") {
+            Signature::Wav
+        } else if head.starts_with(b"FORM") && matches!(head.get(8..12), Some(b"AIFF" | b"AIFC")) {
+            Signature::Aiff
+        } else if head.starts_with(b"%PDF-") {
+            Signature::Pdf
+        } else if matches!(head.get(4..8), Some(b"ftyp" | b"moov" | b"mdat" | b"wide" | b"free" | b"skip")) {
+            // 古い QuickTime は ftyp を持たず、いきなり他のボックスで始まる
+            Signature::IsoBmff
+        } else if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            Signature::Matroska
+        } else if head.starts_with(b"fLaC") {
+            Signature::Flac
+        } else if head.starts_with(b"OggS") {
+            Signature::Ogg
+        } else if head.starts_with(b"ID3") || (head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE0 == 0xE0) {
+            Signature::MpegAudio
+        } else if head.starts_with(&[0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11]) {
+            Signature::Asf
+        } else {
+            return None;
+        };
+        Some(signature)
+    }
+}
+
+/// 中身が拡張子の表す形式と食い違うか。
+///
+/// 中身を判定できない拡張子と、読めないファイル（読めない理由は圧縮の失敗として報告される）は食い違いとしない。
+/// 空や途中で切れていて形式を判定できないファイルは食い違いとする。
+pub fn mismatch(path: &Path, ext: &str) -> Option<Option<Signature>> {
+    let expected = Signature::of_extension(ext)?;
+    let actual = Signature::detect(&head(path)?);
+    (actual != Some(expected)).then_some(actual)
+}
+
+/// ファイルの中身から形式を判定する。読めないか判定できなければ `None`
+pub fn detect_file(path: &Path) -> Option<Signature> {
+    Signature::detect(&head(path)?)
+}
+
+fn head(path: &Path) -> Option<Vec<u8>> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    std::fs::File::open(path).ok()?.take(HEAD_LEN as u64).read_to_end(&mut head).ok()?;
+    Some(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 代表的なシグネチャを判定し、同じ入れ物の拡張子は同じ形式とみなすこと
+    #[test]
+    fn detects_signatures() {
+        assert_eq!(Signature::detect(b"\xFF\xD8\xFF\xE0\0\x10JFIF"), Some(Signature::Jpeg));
+        assert_eq!(Signature::detect(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"), Some(Signature::Png));
+        assert_eq!(Signature::detect(b"RIFF\0\0\0\0WEBPVP8 "), Some(Signature::Webp));
+        assert_eq!(Signature::detect(b"RIFF\0\0\0\0WAVEfmt "), Some(Signature::Wav));
+        assert_eq!(Signature::detect(b"\0\0\0\x20ftypisom"), Some(Signature::IsoBmff));
+        assert_eq!(Signature::detect(b"ID3\x04\0"), Some(Signature::MpegAudio));
+        assert_eq!(Signature::detect(b"hello"), None);
+        assert_eq!(Signature::detect(b""), None);
+
+        assert_eq!(Signature::of_extension("mov"), Signature::of_extension("m4a"));
+        assert_eq!(Signature::of_extension("txt"), None);
+    }
+
+    /// 中身が拡張子と合わないファイルと空のファイルは食い違いとし、判定できない拡張子は調べないこと
+    #[test]
+    fn finds_mismatched_files() {
+        let dir = std::env::temp_dir().join("compressor_sniff_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR").unwrap();
+        std::fs::write(dir.join("b.png"), b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR").unwrap();
+        std::fs::write(dir.join("c.png"), b"").unwrap();
+        std::fs::write(dir.join("d.txt"), b"text").unwrap();
+
+        assert_eq!(mismatch(&dir.join("a.jpg"), "jpg"), Some(Some(Signature::Png)));
+        assert_eq!(mismatch(&dir.join("b.png"), "png"), None);
+        assert_eq!(mismatch(&dir.join("c.png"), "png"), Some(None));
+        assert_eq!(mismatch(&dir.join("d.txt"), "txt"), None);
+        assert_eq!(mismatch(&dir.join("missing.png"), "png"), None);
+        assert_eq!(detect_file(&dir.join("a.jpg")).and_then(Signature::image_extension), Some("png"));
+        assert_eq!(detect_file(&dir.join("c.png")), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
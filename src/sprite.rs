@@ -0,0 +1,84 @@
+//! 動画のフレームを格子状に並べたスプライトシート（`--sprite-sheet`）。
+//!
+//! 動画プレーヤーのシークバーでプレビューを出すためのもの。並べ方は `--contact-sheet` と同じで、
+//! 各タイルの時刻と位置を JSON レポートに載せ、プレーヤーが時刻からタイルを引けるようにする。
+
+use std::path::{Path, PathBuf};
+use crate::contact_sheet::ContactSheet;
+use crate::error::CompressError;
+use crate::file::work_dir;
+use crate::utilities::FfmpegLogLevel;
+use crate::video;
+
+/// スプライトシートの1枚のタイル
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tile {
+    /// フレームを取った時刻（秒）
+    pub time: f64,
+    /// タイルの左上の位置と大きさ（ピクセル）
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 書き出したスプライトシート
+#[derive(Clone, PartialEq, Debug)]
+pub struct Sprite {
+    pub path: PathBuf,
+    /// 時刻順のタイル
+    pub tiles: Vec<Tile>,
+}
+
+/// スプライトシートの作り方
+pub struct SpriteOptions<'a> {
+    /// 並べるフレームの数
+    pub frames: u32,
+    /// 並べ方（列数とタイルの大きさ）
+    pub sheet: ContactSheet,
+    /// JPEG の品質（0-100）
+    pub quality: f32,
+    /// フレームを置く作業ディレクトリの親（--temp-dir）
+    pub temp_dir: Option<&'a Path>,
+    pub loglevel: FfmpegLogLevel,
+}
+
+/// 動画の隣に置くスプライトシートのパス。例: clip.mp4 → clip.sprite.jpg
+pub fn sprite_path(video: &Path) -> PathBuf {
+    video.with_extension("sprite.jpg")
+}
+
+/// `video` から等間隔にフレームを取り出して並べ、`output_path` に JPEG で書き出す
+pub fn generate(video: &Path, output_path: &Path, options: &SpriteOptions) -> Result<Sprite, CompressError> {
+    let dir = work_dir(options.temp_dir, "sprite");
+    std::fs::create_dir_all(&dir)?;
+    let result = compose(video, &dir, output_path, options);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn compose(video: &Path, dir: &Path, output_path: &Path, options: &SpriteOptions) -> Result<Sprite, CompressError> {
+    let frames = video::extract_frames(video, options.frames, dir, options.loglevel)?;
+    let thumbs = frames.iter().map(|(_, frame)| options.sheet.thumbnail(frame)).collect::<Result<Vec<_>, _>>()?;
+    options.sheet.write(output_path, &thumbs, options.quality)?;
+
+    let sizes: Vec<(u32, u32)> = thumbs.iter().map(|thumb| thumb.dimensions()).collect();
+    let tiles = frames
+        .iter()
+        .zip(&sizes)
+        .zip(options.sheet.positions(&sizes))
+        .map(|(((time, _), &(width, height)), (x, y))| Tile { time: *time, x, y, width, height })
+        .collect();
+    Ok(Sprite { path: output_path.to_path_buf(), tiles })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 出力の拡張子を置き換えた名前にすること
+    #[test]
+    fn names_sprite_next_to_video() {
+        assert_eq!(sprite_path(Path::new("out/clip.mp4")), PathBuf::from("out/clip.sprite.jpg"));
+    }
+}
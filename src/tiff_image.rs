@@ -0,0 +1,173 @@
+//! tiff の圧縮。スキャナーの tiff は1つのファイルに複数のページを持つことが多いため、ページごとに出力する。
+//!
+//! image クレートは先頭のページしか読まないため、ページは tiff クレートで読む。
+//! 読んだページは一度 PNG にして、jpg（`--webp` なら非可逆 webp）の圧縮と同じ経路で圧縮する。
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use image::{DynamicImage, ImageBuffer, ImageFormat};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+use crate::error::CompressError;
+use crate::file::work_dir;
+use crate::utilities::{self, ImageOptions};
+use crate::{rgb_image, webp_image};
+
+/// `index` 番目（1 から）のページのファイル名。例: scan の 2 ページ目 → scan-p2.jpg
+pub fn page_name(stem: &str, index: usize, ext: &str) -> String {
+    format!("{stem}-p{index}.{ext}")
+}
+
+/// ページごとの寸法。デコードはしない
+pub fn page_sizes(path: &Path) -> Result<Vec<(u32, u32)>, CompressError> {
+    let mut decoder = open(path)?;
+    let mut sizes = vec![decoder.dimensions().map_err(decode_error)?];
+    while decoder.more_images() {
+        decoder.next_image().map_err(decode_error)?;
+        sizes.push(decoder.dimensions().map_err(decode_error)?);
+    }
+    Ok(sizes)
+}
+
+/// 寸法が --max-megapixels を超えるページがあればエラー（`page` を指定した場合はそのページだけを確かめる）
+pub fn ensure_within_megapixels(sizes: &[(u32, u32)], page: Option<usize>, max_megapixels: f64) -> Result<(), CompressError> {
+    if max_megapixels <= 0.0 {
+        return Ok(());
+    }
+    let checked = match page {
+        Some(page) => &sizes[page..=page],
+        None => sizes,
+    };
+    match checked.iter().find(|&&(width, height)| utilities::exceeds_megapixels(width, height, max_megapixels)) {
+        Some(&(width, height)) => Err(CompressError::TooLarge { width, height, max_megapixels }),
+        None => Ok(()),
+    }
+}
+
+/// `index` 番目（0 から）のページを読む
+pub fn read_page(path: &Path, index: usize) -> Result<DynamicImage, CompressError> {
+    let mut decoder = open(path)?;
+    decoder.seek_to_image(index).map_err(decode_error)?;
+    let (width, height) = decoder.dimensions().map_err(decode_error)?;
+    let colortype = decoder.colortype().map_err(decode_error)?;
+    let image = match (colortype, decoder.read_image().map_err(decode_error)?) {
+        // 白黒のスキャン（fax の圧縮など）は1画素1ビットで、行ごとにバイトの境界までそろえてある
+        (ColorType::Gray(1), DecodingResult::U8(data)) => {
+            let row_bytes = width.div_ceil(8) as usize;
+            (data.len() >= row_bytes * height as usize).then(|| {
+                DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, y| {
+                    let byte = data[y as usize * row_bytes + x as usize / 8];
+                    image::Luma([if byte & (0x80 >> (x % 8)) != 0 { 255 } else { 0 }])
+                }))
+            })
+        }
+        (ColorType::Gray(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16),
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16),
+        (colortype, _) => return Err(CompressError::Decode(format!("対応していない tiff の色の形式です: {colortype:?}"))),
+    };
+    image.ok_or_else(|| CompressError::Decode(format!("tiff の {} ページ目の画素の数が寸法と合いません", index + 1)))
+}
+
+/// `index` 番目（0 から）のページを `output_path` に圧縮する。`webp` なら非可逆 webp、そうでなければ jpg
+pub fn path2compress(
+    path: &Path,
+    output_path: &Path,
+    index: usize,
+    webp: bool,
+    options: &ImageOptions,
+    temp_dir: Option<&Path>,
+) -> Result<(), CompressError> {
+    let dir = work_dir(temp_dir, "tiff");
+    std::fs::create_dir_all(&dir)?;
+    let result = compress_page(path, &dir, output_path, index, webp, options);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+/// すべてのページを `output_dir` にページごとに圧縮する。書き出したページの数を返す。
+///
+/// 前の実行で書き出したページのうち、今回のページの数を超えるものは消す（ページを減らした tiff で古いページが混ざらないよう）。
+pub fn path2compress_pages(
+    path: &Path,
+    output_dir: &Path,
+    webp: bool,
+    options: &ImageOptions,
+    temp_dir: Option<&Path>,
+) -> Result<usize, CompressError> {
+    let pages = page_sizes(path)?.len();
+    let ext = if webp { "webp" } else { "jpg" };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let dir = work_dir(temp_dir, "tiff");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::create_dir_all(output_dir)?;
+    let result = (0..pages).try_for_each(|index| {
+        compress_page(path, &dir, &output_dir.join(page_name(&stem, index + 1, ext)), index, webp, options)
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    result?;
+
+    let mut stale = pages + 1;
+    while std::fs::remove_file(output_dir.join(page_name(&stem, stale, ext))).is_ok() {
+        stale += 1;
+    }
+    Ok(pages)
+}
+
+fn compress_page(path: &Path, dir: &Path, output_path: &Path, index: usize, webp: bool, options: &ImageOptions) -> Result<(), CompressError> {
+    let page = dir.join(format!("page-{}.png", index + 1));
+    read_page(path, index)?.save_with_format(&page, ImageFormat::Png)?;
+    if webp {
+        webp_image::path2compress_lossy(&page, output_path, options)
+    } else {
+        rgb_image::path2compress(&page, output_path, options)
+    }
+}
+
+fn open(path: &Path) -> Result<Decoder<BufReader<File>>, CompressError> {
+    Decoder::new(BufReader::new(File::open(path)?)).map_err(decode_error)
+}
+
+fn decode_error(e: tiff::TiffError) -> CompressError {
+    match e {
+        tiff::TiffError::IoError(e) => CompressError::Io(e),
+        e => CompressError::Decode(format!("tiff: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    /// 複数のページを数え、指定したページを読み、寸法が上限を超えるページを見つけること
+    #[test]
+    fn reads_each_page() {
+        let dir = std::env::temp_dir().join("compressor_tiff_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scan.tiff");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        encoder.write_image::<colortype::Gray8>(4, 2, &[10; 8]).unwrap();
+        encoder.write_image::<colortype::RGB8>(3, 5, &[200; 45]).unwrap();
+        drop(encoder);
+
+        let sizes = page_sizes(&path).unwrap();
+        assert_eq!(sizes, [(4, 2), (3, 5)]);
+        let second = read_page(&path, 1).unwrap();
+        assert_eq!((second.width(), second.height()), (3, 5));
+        assert_eq!(second.to_rgb8().get_pixel(0, 0).0, [200, 200, 200]);
+        assert_eq!(read_page(&path, 0).unwrap().to_luma8().get_pixel(3, 1).0, [10]);
+        assert!(read_page(&path, 2).is_err());
+
+        assert!(ensure_within_megapixels(&sizes, None, 0.00001).is_err());
+        assert!(ensure_within_megapixels(&sizes, Some(0), 0.00001).is_ok());
+        assert_eq!(page_name("scan", 2, "jpg"), "scan-p2.jpg");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
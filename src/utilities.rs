@@ -1,9 +1,388 @@
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::OnceLock;
+use std::time::Duration;
+use clap::ValueEnum;
 use crate::error::CompressError;
+use crate::file::ensure_parent_dir;
+use crate::geometry::Crop;
+use crate::watermark::Watermark;
+use image::{imageops, DynamicImage, ImageBuffer, Pixel, Primitive};
+
+/// 標準エラーに書いた行の種類。--log-file の記録には行ごとにこの種類を残す
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Level {
+    /// 進み具合や外部ツールの出力など、種類を指定せずに書いた行
+    Info,
+    /// [`warning!`] で書いた行
+    Warn,
+    /// [`failure!`] で書いた行
+    Error,
+}
+
+/// 行の種類の目印を付けるか（--log-file が標準エラーを読んでいる間だけ）
+static MARK_LEVELS: AtomicBool = AtomicBool::new(false);
+
+impl Level {
+    /// 行の先頭に付ける目印。--log-file が画面に写す前とファイルに書く前に取り除く。読んでいなければ空
+    pub fn marker(self) -> &'static str {
+        if !MARK_LEVELS.load(Ordering::Relaxed) {
+            return "";
+        }
+        match self {
+            Level::Info => "",
+            Level::Warn => "\u{2}",
+            Level::Error => "\u{1}",
+        }
+    }
+
+    /// 行の先頭の目印から種類を読み、目印を除いた行と返す。目印がなければ `Info`
+    #[allow(dead_code)]
+    pub fn split(line: &str) -> (Level, &str) {
+        if let Some(rest) = line.strip_prefix('\u{1}') {
+            (Level::Error, rest)
+        } else if let Some(rest) = line.strip_prefix('\u{2}') {
+            (Level::Warn, rest)
+        } else {
+            (Level::Info, line)
+        }
+    }
+
+    /// 目印に使うバイトか
+    #[allow(dead_code)]
+    pub fn is_marker(byte: u8) -> bool {
+        matches!(byte, 1 | 2)
+    }
+
+    /// 目印を付け始める・やめる
+    #[allow(dead_code)]
+    pub fn set_marking(enabled: bool) {
+        MARK_LEVELS.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// 警告を `eprintln!` と同じ書式で標準エラーに書く。--log-file の記録には WARN として残る
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::utilities::Level::Warn.marker(), format_args!($($arg)*))
+    };
+}
+pub(crate) use warning;
+
+/// 失敗を `eprintln!` と同じ書式で標準エラーに書く。--log-file の記録には ERROR として残る
+#[allow(unused_macros)]
+macro_rules! failure {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::utilities::Level::Error.marker(), format_args!($($arg)*))
+    };
+}
+#[allow(unused_imports)]
+pub(crate) use failure;
+
+/// 画像の解像度（DPI）メタデータの扱い
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Dpi {
+    /// 元ファイルの解像度を引き継ぐ
+    #[default]
+    Keep,
+    /// 指定した DPI で上書きする
+    Set(u16),
+    /// 解像度を書き込まない
+    Strip,
+}
+
+/// 8bit の範囲を超える HDR（浮動小数点）画像を 8bit に落とすときの扱い。
+/// 16bit 整数の画像は範囲内に収まっているため、どの指定でも階調を縮めるだけで切り捨ては起きない。
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum Tonemap {
+    /// 変換せず、範囲を超える画像はエラーにする
+    None,
+    /// Reinhard のトーンマッピングで範囲内に収める
+    Reinhard,
+    /// 範囲外の値を切り捨てる。切り捨てが起きた場合は警告する（既定）
+    #[default]
+    Clip,
+}
+
+/// oxipng に試させる PNG の行フィルタ（--png-filters）。5種類の標準のフィルタと、行ごとに選ぶ方法がある
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum PngFilter {
+    /// フィルタをかけない
+    None,
+    /// 左の画素との差
+    Sub,
+    /// 上の画素との差
+    Up,
+    /// 左と上の平均との差
+    Average,
+    /// 左・上・左上から予測した値との差
+    Paeth,
+    /// 行ごとに差の合計が最小のフィルタを選ぶ
+    #[value(name = "minsum")]
+    MinSum,
+    /// 行ごとにエントロピーが最小のフィルタを選ぶ
+    Entropy,
+    /// 行ごとに異なるバイトの組の数が最小のフィルタを選ぶ
+    Bigrams,
+    /// 行ごとにバイトの組のエントロピーが最小のフィルタを選ぶ
+    #[value(name = "bigent")]
+    BigEnt,
+    /// 行ごとに実際に圧縮して最小のフィルタを選ぶ（遅い）
+    Brute,
+}
+
+/// 透過を持てない JPEG に変換するとき、透過した部分を合成する背景色（RGB）
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Background(pub [u8; 3]);
+
+impl Default for Background {
+    /// 白
+    fn default() -> Self {
+        Background([255, 255, 255])
+    }
+}
+
+/// --flatten-background で名前で指定できる色
+pub const NAMED_COLORS: [(&str, [u8; 3]); 8] = [
+    ("white", [255, 255, 255]),
+    ("black", [0, 0, 0]),
+    ("gray", [128, 128, 128]),
+    ("silver", [192, 192, 192]),
+    ("red", [255, 0, 0]),
+    ("green", [0, 128, 0]),
+    ("blue", [0, 0, 255]),
+    ("yellow", [255, 255, 0]),
+];
+
+/// --flatten-background の値を読む。`#ffffff` や `#fff` の16進数（`#` は省略可）か、[`NAMED_COLORS`] の名前（大文字小文字は区別しない）。
+#[allow(dead_code)]
+pub fn parse_background(value: &str) -> Result<Background, String> {
+    if let Some((_, rgb)) = NAMED_COLORS.iter().find(|(name, _)| name.eq_ignore_ascii_case(value)) {
+        return Ok(Background(*rgb));
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let digits: Option<Vec<u8>> = hex.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect();
+    let rgb = match digits.as_deref() {
+        Some(&[r, g, b]) => [r * 17, g * 17, b * 17],
+        Some(&[r1, r2, g1, g2, b1, b2]) => [r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2],
+        _ => return Err(format!("#ffffff のような16進数か、white, black などの色の名前で指定してください: {value}")),
+    };
+    Ok(Background(rgb))
+}
+
+/// プログレッシブの JPEG のスキャンの組み方
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ScanPreset {
+    /// mozjpeg の既定の組み方（--jpeg-optimize-scans を付けると画像ごとに探す）
+    #[default]
+    Default,
+    /// 最初のスキャンに全成分の直流成分だけを入れ、1/8 の解像度のカラーの縮小表示をすぐ出せるようにする
+    PreviewFirst,
+}
+
+/// 画像圧縮のオプション
+#[derive(Clone, Debug, Default)]
+pub struct ImageOptions {
+    /// 非可逆圧縮の品質（0-100）
+    pub quality: f32,
+    /// 指定すると、そのJPEGの Exif で出力の Exif を置き換える（jpg出力時のみ）
+    pub exif_source: Option<PathBuf>,
+    /// 解像度（DPI）の扱い
+    pub dpi: Dpi,
+    /// 切り抜く範囲
+    pub crop: Option<Crop>,
+    /// HDR 画像を 8bit に落とすときの扱い
+    pub tonemap: Tonemap,
+    /// 出力に埋め込む追跡用の文字列（JPEG の COM マーカー / PNG の tEXt チャンク）
+    pub tag: Option<String>,
+    /// 重ねる見える透かし
+    pub watermark: Option<Watermark>,
+    /// JPEG をプログレッシブではなくベースラインで出力する
+    pub baseline: bool,
+    /// プログレッシブの JPEG のスキャンの分け方を画像ごとに探し、小さくする（エンコードは遅くなる）
+    pub optimize_scans: bool,
+    /// プログレッシブの JPEG の最初のスキャンに何を入れるか
+    pub scan_preset: ScanPreset,
+    /// JPEG にリスタートマーカーを入れる間隔（MCU の数）。`None` なら入れない
+    pub restart_interval: Option<u16>,
+    /// JPEG の Exif の向きを、再エンコードせずに可逆変換でピクセルへ反映する
+    pub lossless_orient: bool,
+    /// PNG の最適化（oxipng）にかける時間の上限。`None` なら制限しない
+    pub png_timeout: Option<Duration>,
+    /// oxipng に試させる行フィルタ。空なら oxipng の既定（None, Sub, Entropy, Bigrams）
+    pub png_filters: Vec<PngFilter>,
+    /// 文字や線画の多い JPEG は品質を上げ、色差を間引かずに出力する
+    pub text_aware: bool,
+    /// 透過した画像を JPEG にするときに合成する背景色
+    pub background: Background,
+    /// Exif（PNG の eXIf を含む）から位置情報だけを消し、位置情報を含む XMP を落とす。他の Exif は残す
+    pub strip_gps: bool,
+    /// エンコードの前にかけるアンシャープマスクの強さ（jpg と png の出力。[`sharpen`]）
+    pub sharpen: Option<f32>,
+}
+
+impl ImageOptions {
+    /// 切り抜きや透かし、鮮鋭化で画素を加工するか（デコードして [`edit`](Self::edit) を通す必要があるか）
+    pub fn edits_pixels(&self) -> bool {
+        self.crop.is_some() || self.watermark.is_some() || self.sharpen.is_some()
+    }
+
+    /// 画素の加工や Exif の差し替えで元と内容が変わるか。変わる場合は元のほうが小さくても元を書き戻さない。
+    /// --tonemap や --flatten-background を既定から変えた場合も、元の画素をそのまま残さないよう加工として扱う
+    pub fn is_edited(&self) -> bool {
+        self.edits_pixels()
+            || self.exif_source.is_some()
+            || self.tonemap != Tonemap::default()
+            || self.background != Background::default()
+    }
+
+    /// 切り抜いてから透かしを重ねる（どちらも見た目の向きの画像に対して行う）
+    pub fn edit(&self, mut img: DynamicImage) -> Result<DynamicImage, CompressError> {
+        if let Some(crop) = self.crop {
+            img = crop.apply(img)?;
+        }
+        if let Some(watermark) = &self.watermark {
+            img = watermark.apply(img)?;
+        }
+
+        Ok(img)
+    }
+}
+
+/// ffmpeg 自身のログの詳しさ（`-loglevel`）
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum FfmpegLogLevel {
+    Quiet,
+    Panic,
+    Fatal,
+    /// エラーだけを表示する（既定）
+    #[default]
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl FfmpegLogLevel {
+    /// エラー以下しか出さない（出力を取り込んで失敗時のエラーに添えられる程度の量）か
+    pub fn is_quiet(self) -> bool {
+        matches!(self, FfmpegLogLevel::Quiet | FfmpegLogLevel::Panic | FfmpegLogLevel::Fatal | FfmpegLogLevel::Error)
+    }
+
+    /// `-loglevel` に渡す名前
+    pub fn name(self) -> &'static str {
+        match self {
+            FfmpegLogLevel::Quiet => "quiet",
+            FfmpegLogLevel::Panic => "panic",
+            FfmpegLogLevel::Fatal => "fatal",
+            FfmpegLogLevel::Error => "error",
+            FfmpegLogLevel::Warning => "warning",
+            FfmpegLogLevel::Info => "info",
+            FfmpegLogLevel::Verbose => "verbose",
+            FfmpegLogLevel::Debug => "debug",
+            FfmpegLogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// アンシャープマスクのぼかしの強さ（σ）
+const SHARPEN_SIGMA: f32 = 1.0;
+
+/// アンシャープマスク。[`imageops::unsharpen`] で強めた輪郭と元との差を `amount` 倍して元に足す（1.0 で unsharpen と同じ）
+pub fn sharpen<P>(img: &ImageBuffer<P, Vec<P::Subpixel>>, amount: f32) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+    P::Subpixel: Into<f32> + TryFrom<u16>,
+{
+    let max: f32 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    let mut sharpened = imageops::unsharpen(img, SHARPEN_SIGMA, 0);
+    for (pixel, original) in sharpened.pixels_mut().zip(img.pixels()) {
+        *pixel = original.map2(pixel, |original, unsharpened| {
+            let (original, unsharpened): (f32, f32) = (original.into(), unsharpened.into());
+            let value = (original + amount * (unsharpened - original)).round().clamp(0.0, max);
+            P::Subpixel::try_from(value as u16).unwrap_or(P::Subpixel::DEFAULT_MAX_VALUE)
+        });
+    }
+    sharpened
+}
+
+/// アンシャープマスクの強さの上限（ffmpeg の unsharp の上限に合わせる）
+#[allow(dead_code)]
+pub const MAX_SHARPEN: f32 = 1.5;
+
+/// --sharpen の値を読む。0 より大きく [`MAX_SHARPEN`] 以下。
+#[allow(dead_code)]
+pub fn parse_sharpen(value: &str) -> Result<f32, String> {
+    let amount: f32 = value.parse().map_err(|_| format!("数値で指定してください: {value}"))?;
+    if !(amount > 0.0 && amount <= MAX_SHARPEN) {
+        return Err(format!("0 より大きく {MAX_SHARPEN} 以下で指定してください: {value}"));
+    }
+
+    Ok(amount)
+}
+
+/// --quality の名前付きの段階と、対応する品質
+pub const QUALITY_LEVELS: [(&str, f32); 4] = [("low", 50.0), ("medium", 70.0), ("high", 85.0), ("max", 95.0)];
+
+/// --quality の値を読む。数値のほか、[`QUALITY_LEVELS`] の名前（大文字小文字は区別しない）を受け付ける。
+#[allow(dead_code)]
+pub fn parse_quality(value: &str) -> Result<f32, String> {
+    match QUALITY_LEVELS.iter().find(|(name, _)| name.eq_ignore_ascii_case(value)) {
+        Some((_, quality)) => Ok(*quality),
+        None => value.parse().map_err(|_| format!("数値か low, medium, high, max で指定してください: {value}")),
+    }
+}
+
+/// JPEG の COM セグメントに収まるタグの最大バイト数（長さ 2 バイトを除く）
+const MAX_TAG_BYTES: usize = 65533;
+
+/// --tag の値を読む。空でなく、NUL を含まず、JPEG の COM セグメントに収まる長さ。
+#[allow(dead_code)]
+pub fn parse_tag(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains('\0') {
+        return Err("空文字列や NUL を含む文字列は指定できません".to_string());
+    }
+    if value.len() > MAX_TAG_BYTES {
+        return Err(format!("{MAX_TAG_BYTES} バイト以下で指定してください"));
+    }
+
+    Ok(value.to_string())
+}
+
+/// メモリ上で圧縮した結果。
+///
+/// 入力と同じ形式で書き出す場合は元のデータも持ち、書き出すときに小さいほうを選ぶ（[`into_output`](Self::into_output)）。
+pub struct Encoded {
+    /// 圧縮したデータ
+    pub data: Vec<u8>,
+    /// 圧縮したほうが小さくなければ代わりに書き出す元のデータ。切り抜きなどで元に戻せない場合は `None`
+    pub original: Option<Vec<u8>>,
+}
+
+impl Encoded {
+    /// 元に戻せない圧縮の結果
+    pub fn new(data: Vec<u8>) -> Encoded {
+        Encoded { data, original: None }
+    }
+
+    /// 元のほうが小さくなければ元に戻せる圧縮の結果
+    pub fn with_original(data: Vec<u8>, original: Vec<u8>) -> Encoded {
+        Encoded { data, original: Some(original) }
+    }
+
+    /// 書き出す内容。圧縮後と元のうち小さいほう（同じ大きさなら元）
+    pub fn into_output(self) -> Vec<u8> {
+        match self.original {
+            Some(original) if original.len() <= self.data.len() => original,
+            _ => self.data,
+        }
+    }
+}
 
 /// 圧縮結果と元データのうち、小さいほうを書き出す。
 ///
@@ -20,6 +399,16 @@ pub fn write_smaller(
         original
     };
 
+    write_output(output_path, data)
+}
+
+/// 出力先の親ディレクトリを作成してから書き出す。
+/// 出力先に同じ内容のファイルがあれば書き直さない（--force で再実行しても更新日時を変えないため）。
+pub fn write_output(output_path: &Path, data: &[u8]) -> Result<(), CompressError> {
+    if is_same_content(output_path, data) {
+        return Ok(());
+    }
+    ensure_parent_dir(output_path)?;
     let file = File::create(output_path)?;
     let mut writer = BufWriter::new(file);
     writer.write_all(data)?;
@@ -27,12 +416,100 @@ pub fn write_smaller(
     Ok(())
 }
 
+/// `path` のファイルの内容が `data` と同じか。大きさが違えば読まずに判定する
+fn is_same_content(path: &Path, data: &[u8]) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == data.len() as u64)
+        && std::fs::read(path).is_ok_and(|existing| existing == data)
+}
+
 /// FFmpegが使えるかを判定する。プロセス起動を伴うため一度だけ実行して結果を使い回す。
 pub fn is_ffmpeg_available() -> bool {
     static AVAILABLE: OnceLock<bool> = OnceLock::new();
     *AVAILABLE.get_or_init(|| Command::new("ffmpeg").arg("-version").output().is_ok())
 }
 
+/// 失敗時のエラーに添える標準エラー出力の行数
+const STDERR_TAIL_LINES: usize = 10;
+
+/// 外部コマンドを実行し、終了コードが 0 でなければ [`CompressError::ToolFailed`] にする。
+///
+/// `capture_stderr` を指定すると標準エラー出力を取り込み、失敗時は末尾をエラーに添える（成功時はそのまま表示する）。
+/// 指定しなければ取り込まずに端末へそのまま流す（ffmpeg の詳しいログを見ながら実行する場合）。
+pub fn run_tool(tool: &'static str, command: &mut Command, capture_stderr: bool) -> Result<(), CompressError> {
+    let to_error = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::NotFound => CompressError::ToolMissing(tool),
+        _ => CompressError::Io(e),
+    };
+    if !capture_stderr {
+        let status = command.status().map_err(to_error)?;
+        if status.success() {
+            return Ok(());
+        }
+        return Err(CompressError::ToolFailed { tool, status: status.to_string(), stderr: String::new() });
+    }
+
+    let output = command.stderr(Stdio::piped()).output().map_err(to_error)?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        eprint!("{stderr}");
+        return Ok(());
+    }
+
+    let lines: Vec<&str> = stderr.trim_end().lines().collect();
+    Err(CompressError::ToolFailed {
+        tool,
+        status: output.status.to_string(),
+        stderr: lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n"),
+    })
+}
+
+/// コマンドをシェルに貼り付けて再実行できる形の文字列にする。
+/// 空白や記号を含む引数はシングルクォートで囲む。
+pub fn format_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=+,%@".contains(c));
+    if is_safe {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// 画像のヘッダだけを読み、画素数が `max_megapixels` を超えていればエラーにする。
+/// 巨大な画像（デコンプレッション・ボム）を丸ごとデコードしてメモリを使い果たすのを防ぐ。
+/// `max_megapixels` が 0 以下の場合は制限しない。
+#[allow(dead_code)]
+pub fn ensure_within_megapixels(path: &Path, max_megapixels: f64) -> Result<(), CompressError> {
+    if max_megapixels <= 0.0 {
+        return Ok(());
+    }
+
+    let (width, height) = image_dimensions(path)?;
+    if exceeds_megapixels(width, height, max_megapixels) {
+        return Err(CompressError::TooLarge { width, height, max_megapixels });
+    }
+
+    Ok(())
+}
+
+/// 画像の寸法をヘッダから読む（デコードはしない）。形式は拡張子ではなく中身から判定する。
+pub fn image_dimensions(path: &Path) -> Result<(u32, u32), CompressError> {
+    Ok(image::ImageReader::open(path)?.with_guessed_format()?.into_dimensions()?)
+}
+
+pub fn exceeds_megapixels(width: u32, height: u32, max_megapixels: f64) -> bool {
+    (width as f64) * (height as f64) > max_megapixels * 1_000_000.0
+}
+
 pub fn get_aspect_ratio(width: u32, height: u32) -> f32 {
     if width == 0 || height == 0 {
         return 0.0;
@@ -40,3 +517,196 @@ pub fn get_aspect_ratio(width: u32, height: u32) -> f32 {
 
     (width as f32) / (height as f32)
 }
+
+/// 横長の 16:9 とみなすか。
+/// エンコーダが高さを16の倍数に揃えた 1920x1088 のような映像も 16:9 として扱えるよう、1% の誤差を許す。
+/// 縦長（9:16）や寸法が 0 の場合は `false`。
+pub fn is_16_9(width: u32, height: u32) -> bool {
+    if width == 0 || height == 0 {
+        return false;
+    }
+
+    let expected = 16.0 / 9.0;
+    (get_aspect_ratio(width, height) - expected).abs() <= expected * 0.01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 同じ内容なら書き直さずに更新日時を保ち、違えば書き直すこと
+    #[test]
+    fn keeps_identical_output() {
+        let path = std::env::temp_dir().join("compressor_identical_output_test.jpg");
+        std::fs::write(&path, b"same").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+
+        write_output(&path, b"same").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+
+        write_output(&path, b"other").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"other");
+        assert_ne!(std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// 名前付きの段階を数値に置き換え、数値はそのまま受け付けること
+    #[test]
+    fn parses_quality_levels() {
+        assert_eq!(parse_quality("low"), Ok(50.0));
+        assert_eq!(parse_quality("High"), Ok(85.0));
+        assert_eq!(parse_quality("82.5"), Ok(82.5));
+        assert!(parse_quality("best").is_err());
+    }
+
+    /// 元より小さい場合だけ圧縮後を書き出し、元に戻せない結果は大きくてもそのまま書き出すこと
+    #[test]
+    fn keeps_smaller_output() {
+        assert_eq!(Encoded::with_original(vec![0; 3], vec![1; 5]).into_output(), vec![0; 3]);
+        assert_eq!(Encoded::with_original(vec![0; 5], vec![1; 5]).into_output(), vec![1; 5]);
+        assert_eq!(Encoded::new(vec![0; 5]).into_output(), vec![0; 5]);
+    }
+
+    /// 差のない平坦な部分は変えず、輪郭のコントラストだけを強めること。強さに比例して強くかかり、8bit 以外の画素も扱えること
+    #[test]
+    fn sharpens_edges_only() {
+        let img = image::RgbImage::from_fn(9, 1, |x, _| if x < 4 { image::Rgb([100; 3]) } else { image::Rgb([160; 3]) });
+        let sharpened = sharpen(&img, 1.0);
+        assert_eq!(sharpened.get_pixel(0, 0).0, [100; 3]);
+        assert!(sharpened.get_pixel(3, 0).0[0] < 100);
+        assert!(sharpened.get_pixel(4, 0).0[0] > 160);
+        let mild = sharpen(&img, 0.5);
+        assert!((sharpened.get_pixel(3, 0).0[0]..100).contains(&mild.get_pixel(3, 0).0[0]));
+
+        let deep = image::ImageBuffer::<image::Luma<u16>, _>::from_fn(9, 1, |x, _| image::Luma([if x < 4 { 20000 } else { 40000 }]));
+        assert!(sharpen(&deep, 1.0).get_pixel(4, 0).0[0] > 40000);
+    }
+
+    /// 画素を変える指定があれば、元を書き戻さない加工として扱うこと
+    #[test]
+    fn counts_pixel_changes_as_edits() {
+        assert!(!ImageOptions::default().is_edited());
+        assert!(ImageOptions { tonemap: Tonemap::Reinhard, ..Default::default() }.is_edited());
+        assert!(ImageOptions { background: Background([0, 0, 0]), ..Default::default() }.is_edited());
+    }
+
+    /// 16進数（3桁と6桁、`#` の有無）と色の名前を読み、それ以外は受け付けないこと
+    #[test]
+    fn parses_background_colors() {
+        assert_eq!(parse_background("#ffffff"), Ok(Background([255, 255, 255])));
+        assert_eq!(parse_background("1a2B3c"), Ok(Background([0x1a, 0x2b, 0x3c])));
+        assert_eq!(parse_background("#f80"), Ok(Background([255, 136, 0])));
+        assert_eq!(parse_background("Black"), Ok(Background([0, 0, 0])));
+        assert_eq!(Background::default(), parse_background("white").unwrap());
+        assert!(parse_background("#ffff").is_err());
+        assert!(parse_background("#gggggg").is_err());
+        assert!(parse_background("").is_err());
+        assert!(parse_background("transparent").is_err());
+    }
+
+    /// 上限ちょうどは許可し、超えた場合のみ拒否すること
+    #[test]
+    fn megapixel_limit_boundary() {
+        assert!(!exceeds_megapixels(1000, 1000, 1.0));
+        assert!(exceeds_megapixels(1000, 1001, 1.0));
+        assert!(!exceeds_megapixels(65535, 65535, 5000.0));
+    }
+
+    /// 空白やクォートを含む引数はシェルで1引数として解釈されるよう囲むこと
+    #[test]
+    fn formats_command_for_shell() {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-i", "my clip.mov", "-vf", "scale=1920:-2", "it's.mp4", ""]);
+        assert_eq!(
+            format_command(&command),
+            "ffmpeg -i 'my clip.mov' -vf scale=1920:-2 'it'\\''s.mp4' ''"
+        );
+    }
+
+    /// コマンドラインで受け付ける名前をそのまま ffmpeg の -loglevel に渡せること
+    #[test]
+    fn ffmpeg_loglevel_names_match_cli() {
+        for level in FfmpegLogLevel::value_variants() {
+            assert_eq!(level.to_possible_value().unwrap().get_name(), level.name());
+        }
+        assert_eq!(FfmpegLogLevel::default().name(), "error");
+    }
+
+    /// 0 以下や ffmpeg の上限を超える強さは拒否すること
+    #[test]
+    fn parses_sharpen_amount() {
+        assert_eq!(parse_sharpen("0.8"), Ok(0.8));
+        assert_eq!(parse_sharpen("1.5"), Ok(1.5));
+        assert!(parse_sharpen("0").is_err());
+        assert!(parse_sharpen("2").is_err());
+        assert!(parse_sharpen("NaN").is_err());
+    }
+
+    /// 寸法が 0 の場合は 0 を返すこと（ゼロ除算しない）
+    #[test]
+    fn aspect_ratio_of_zero_dimensions() {
+        assert_eq!(get_aspect_ratio(0, 1080), 0.0);
+        assert_eq!(get_aspect_ratio(1920, 0), 0.0);
+        assert_eq!(get_aspect_ratio(1920, 1080), 1920.0 / 1080.0);
+    }
+
+    /// 16:9 と、高さが16の倍数に揃えられた映像だけを 16:9 とみなすこと
+    #[test]
+    fn detects_16_9() {
+        assert!(is_16_9(1920, 1080));
+        assert!(is_16_9(3840, 2160));
+        assert!(is_16_9(1920, 1088));
+        assert!(is_16_9(854, 480));
+        assert!(!is_16_9(1440, 1080), "4:3");
+        assert!(!is_16_9(1998, 1080), "1.85:1");
+        assert!(!is_16_9(1080, 1920), "縦長");
+        assert!(!is_16_9(0, 0));
+        assert!(!is_16_9(1920, 0));
+    }
+
+    /// 失敗したコマンドは標準エラー出力の末尾を添え、見つからないコマンドは ToolMissing にすること
+    #[cfg(unix)]
+    #[test]
+    fn reports_tool_failures() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "for i in $(seq 1 20); do echo line$i >&2; done; exit 3"]);
+        match run_tool("sh", &mut command, true) {
+            Err(CompressError::ToolFailed { tool: "sh", stderr, .. }) => {
+                assert_eq!(stderr.lines().count(), STDERR_TAIL_LINES);
+                assert!(stderr.ends_with("line20"), "{stderr}");
+            }
+            other => panic!("{other:?}"),
+        }
+
+        let result = run_tool("missing", &mut Command::new("compressor-missing-tool"), true);
+        assert!(matches!(result, Err(CompressError::ToolMissing("missing"))), "{result:?}");
+        assert!(run_tool("true", &mut Command::new("true"), true).is_ok());
+    }
+
+    /// 取り込まない場合は標準出力・標準エラー出力を親と共有し、失敗してもエラーに出力を添えないこと
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn streams_output_without_capturing() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "[ /proc/self/fd/1 -ef /proc/$PPID/fd/1 ] && [ /proc/self/fd/2 -ef /proc/$PPID/fd/2 ]"]);
+        assert!(run_tool("sh", &mut command, false).is_ok());
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]);
+        match run_tool("sh", &mut command, false) {
+            Err(CompressError::ToolFailed { tool: "sh", stderr, .. }) => assert!(stderr.is_empty(), "{stderr}"),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    /// 画像として読めないファイルはエラーになること（panicしない）
+    #[test]
+    fn rejects_undecodable_header() {
+        let path = std::env::temp_dir().join("compressor_megapixels_test.png");
+        std::fs::write(&path, b"not an image").unwrap();
+        assert!(ensure_within_megapixels(&path, 1.0).is_err());
+        assert!(ensure_within_megapixels(&path, 0.0).is_ok(), "0 は無制限として扱う");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,9 +1,18 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::fs;
-use std::time::Instant;
+use std::time::{Instant, UNIX_EPOCH};
+use clap::ValueEnum;
+use image::Frame;
 use crate::error::CompressError;
-use crate::utilities::{get_aspect_ratio, is_ffmpeg_available};
+use crate::file::{ensure_parent_dir, temp_path, work_dir};
+use crate::utilities::{format_command, is_16_9, is_ffmpeg_available, run_tool, warning, FfmpegLogLevel};
+use crate::watermark::Watermark;
 
 /// 動画圧縮の結果統計情報
 #[allow(dead_code)]
@@ -19,15 +28,24 @@ pub struct CompressionStats {
 }
 
 /// 動画の出力コーデック
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
 pub enum VideoCodec {
     /// AV1 (libsvtav1)。最も圧縮率が高い。既定。
+    #[default]
     Av1,
     /// HEVC/H.265 (libx265, hvc1 タグ)。iOS など旧来デバイスでの再生互換性が高い。
     Hevc,
 }
 
 impl VideoCodec {
+    /// ログ表示用のコーデック名
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoCodec::Av1 => "av1",
+            VideoCodec::Hevc => "hevc",
+        }
+    }
+
     /// 未指定時に使う、コーデックごとの既定 CRF。
     /// CRF スケールはコーデック間で異なるため値を分ける。
     fn default_crf(self) -> u8 {
@@ -38,35 +56,278 @@ impl VideoCodec {
     }
 }
 
+/// エンコード速度と圧縮効率のトレードオフ（x265 の preset 名に合わせる）。
+/// 遅いほど同じ品質でファイルが小さくなる。
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum VideoPreset {
+    Ultrafast,
+    Superfast,
+    Veryfast,
+    Faster,
+    Fast,
+    Medium,
+    /// 既定。速度と圧縮効率のバランスがよい
+    #[default]
+    Slow,
+    Slower,
+    Veryslow,
+}
+
+impl VideoPreset {
+    /// libx265 に渡す preset 名
+    fn x265_name(self) -> &'static str {
+        match self {
+            VideoPreset::Ultrafast => "ultrafast",
+            VideoPreset::Superfast => "superfast",
+            VideoPreset::Veryfast => "veryfast",
+            VideoPreset::Faster => "faster",
+            VideoPreset::Fast => "fast",
+            VideoPreset::Medium => "medium",
+            VideoPreset::Slow => "slow",
+            VideoPreset::Slower => "slower",
+            VideoPreset::Veryslow => "veryslow",
+        }
+    }
+
+    /// libsvtav1 に渡す数値の preset（0 が最も遅く高効率、13 が最速）
+    fn svtav1_level(self) -> u8 {
+        match self {
+            VideoPreset::Ultrafast => 12,
+            VideoPreset::Superfast => 11,
+            VideoPreset::Veryfast => 10,
+            VideoPreset::Faster => 8,
+            VideoPreset::Fast => 7,
+            VideoPreset::Medium => 6,
+            VideoPreset::Slow => 5,
+            VideoPreset::Slower => 4,
+            VideoPreset::Veryslow => 2,
+        }
+    }
+
+    /// コーデックごとの `-preset` の値
+    fn value(self, codec: VideoCodec) -> String {
+        match codec {
+            VideoCodec::Av1 => self.svtav1_level().to_string(),
+            VideoCodec::Hevc => self.x265_name().to_string(),
+        }
+    }
+}
+
+/// 音量の正規化（--normalize-audio）。EBU R128 に沿って ffmpeg の loudnorm で揃える
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum NormalizeAudio {
+    /// 1回で正規化する（loudnorm が再生しながら補正する）
+    OnePass,
+    /// 先に音量を測り、その値で全体を同じだけ補正する（より正確だが、音声を2回読む）
+    TwoPass,
+}
+
+/// 回転の情報（スマートフォンで縦に持って撮った動画など）を持つ動画の扱い（--video-autorotate）
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum Autorotate {
+    /// ffmpeg の自動回転で表示の向きに回してからエンコードし、回転の情報は残さない（既定）。縮小するかは表示の向きの寸法で決める
+    #[default]
+    Bake,
+    /// 画素は回さずにエンコードし、回転の情報を出力に引き継ぐ（再生時にプレーヤーが回す）
+    Keep,
+}
+
+/// キーフレームの間隔（--keyframe-interval）。短いほどシークや区切りの位置を細かく選べるが、ファイルは大きくなる
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyframeInterval {
+    /// フレーム数（例: `48`）
+    Frames(u32),
+    /// 秒数（例: `2s`）。入力のフレームレートからフレーム数にする
+    Seconds(f64),
+}
+
+impl KeyframeInterval {
+    /// フレーム数にする。`frame_rate` は1秒あたりのフレーム数。秒数で指定してフレームレートが分からなければ `None`
+    fn frames(self, frame_rate: Option<f64>) -> Option<u32> {
+        match self {
+            KeyframeInterval::Frames(frames) => Some(frames),
+            KeyframeInterval::Seconds(seconds) => frame_rate.map(|rate| (seconds * rate).round().max(1.0) as u32),
+        }
+    }
+}
+
+/// --keyframe-interval の値を読む。`s` で終われば秒数（0 より大きい）、整数ならフレーム数（1 以上）。
+pub fn parse_keyframe_interval(value: &str) -> Result<KeyframeInterval, String> {
+    if let Some(seconds) = value.strip_suffix('s') {
+        return match seconds.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(KeyframeInterval::Seconds(seconds)),
+            _ => Err(format!("s の前には 0 より大きい秒数を指定してください: {value}")),
+        };
+    }
+    match value.parse::<u32>() {
+        Ok(frames) if frames > 0 => Ok(KeyframeInterval::Frames(frames)),
+        _ => Err(format!("1 以上のフレーム数か、2s のような秒数で指定してください: {value}")),
+    }
+}
+
+/// loudnorm の目標（統合ラウドネス -16 LUFS、トゥルーピーク -1.5 dBTP、ラウドネスレンジ 11 LU）
+const LOUDNORM_TARGET: &str = "I=-16:TP=-1.5:LRA=11";
+
+/// フル HD に縮小するときに寸法をそろえる倍数（--scale-align）。エンコーダや 10bit の処理によっては 4 や 16 の倍数が必要になる
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ScaleAlign {
+    #[default]
+    #[value(name = "2")]
+    Two,
+    #[value(name = "4")]
+    Four,
+    #[value(name = "16")]
+    Sixteen,
+}
+
+impl ScaleAlign {
+    pub fn divisor(self) -> u32 {
+        match self {
+            ScaleAlign::Two => 2,
+            ScaleAlign::Four => 4,
+            ScaleAlign::Sixteen => 16,
+        }
+    }
+}
+
+/// 動画圧縮のオプション
+#[derive(Clone, Debug, Default)]
+pub struct VideoOptions {
+    /// 出力コーデック
+    pub codec: VideoCodec,
+    /// Constant Rate Factor（低いほど高画質・大きいファイル）。未指定時はコーデックごとの既定値
+    pub crf: Option<u8>,
+    /// エンコード速度と圧縮効率のトレードオフ
+    pub preset: VideoPreset,
+    /// ffmpeg 自身のログの詳しさ
+    pub loglevel: FfmpegLogLevel,
+    /// 縮小した場合にかけるアンシャープマスクの強さ。`None` ならかけない
+    pub sharpen: Option<f32>,
+    /// 縮小した場合に寸法をそろえる倍数
+    pub scale_align: ScaleAlign,
+    /// 重ねる見える透かし
+    pub watermark: Option<Watermark>,
+    /// 実行する ffmpeg コマンドを表示する（手元で調整して再実行できるように）
+    pub print_command: bool,
+    /// アニメーション画像（GIF/APNG/WebP）からの変換か。フレームごとの表示時間を保つため可変フレームレートで出力する
+    pub animation: bool,
+    /// 指定すると、動画をこの秒数ごとの区間に分けてエンコードしてからつなぐ（中断しても終わった区間から再開できる）
+    pub segment_seconds: Option<u32>,
+    /// 指定すると、音声の音量を正規化する
+    pub normalize_audio: Option<NormalizeAudio>,
+    /// 作業ファイルを置くディレクトリ。`None` ならフレームはシステムの一時ディレクトリに、区間は出力の隣に置く
+    pub temp_dir: Option<PathBuf>,
+    /// 回転の情報を持つ動画の扱い
+    pub autorotate: Autorotate,
+    /// キーフレームの間隔。`None` ならエンコーダの既定
+    pub keyframe_interval: Option<KeyframeInterval>,
+}
+
+impl VideoOptions {
+    /// 実際に使う CRF
+    fn crf(&self) -> u8 {
+        self.crf.unwrap_or_else(|| self.codec.default_crf())
+    }
+}
+
 pub fn path2compress(
-    input_path: &str,
-    output_path: &str,
-    codec: VideoCodec,
-    crf: Option<u8>,
+    input_path: &Path,
+    output_path: &Path,
+    options: &VideoOptions,
 ) -> Result<CompressionStats, CompressError> {
-    let crf = crf.unwrap_or_else(|| codec.default_crf());
-    compress_video(input_path, output_path, codec, crf)
+    compress_video(input_path, output_path, options)
 }
 
-pub fn is_match_extension(input_path: &str) -> bool {
-    let path = Path::new(input_path);
-    
+#[allow(dead_code)]
+pub fn is_match_extension(path: &Path) -> bool {
     // 入力ファイルの存在チェック
     if !path.exists() {
         return false;
     }
 
-    let video_extensions = [".mov", ".mp4", ".avi", ".mkv", ".webm"];
     let extension = path.extension()
         .and_then(|ext| ext.to_str())
-        .map(|ext| format!(".{}", ext.to_lowercase()));
-    
-    match extension {
-        Some(ext) if video_extensions.contains(&ext.as_str()) => true,
-        _ => false,
+        .map(|ext| ext.to_lowercase());
+
+    // 拡張子が動画でも、映像のストリームがないファイル（音声だけ、壊れたファイルなど）は動画としない。ffprobe がなければ拡張子だけで決める
+    matches!(extension, Some(ext) if is_video_extension(&ext)) && probe_streams(path).map_or(true, |streams| streams.video)
+}
+
+/// 対応する動画の拡張子（小文字）か
+pub fn is_video_extension(ext: &str) -> bool {
+    ["mov", "mp4", "avi", "mkv", "webm"].contains(&ext)
+}
+
+/// フル HD に縮小するフィルタ。高さは縦横比を保ったまま `align` の倍数にする（幅の 1920 はどの倍数でも割り切れる）。
+/// `sharpen` を指定すると縮小後に unsharp（5x5, 輝度のみ）をかける。
+fn resize_filter_chain(sharpen: Option<f32>, align: ScaleAlign) -> String {
+    let scale = format!("scale=1920:-{}", align.divisor());
+    match sharpen {
+        Some(amount) => format!("{scale},unsharp=5:5:{amount}"),
+        None => scale,
     }
 }
 
+/// 縮小と透かしのフィルタをかける引数（入力の `-i` の直後に置く）。
+/// テキストは `-vf` の drawtext で描き、ロゴは2つ目の入力にして `-filter_complex` の overlay で重ねる。
+fn filter_args(resize: bool, sharpen: Option<f32>, align: ScaleAlign, watermark: Option<&Watermark>) -> Vec<OsString> {
+    let mut chain = Vec::new();
+    if resize {
+        chain.push(resize_filter_chain(sharpen, align));
+    }
+
+    let mut args: Vec<OsString> = Vec::new();
+    match watermark {
+        Some(watermark) if watermark.is_logo() => {
+            let (x, y) = watermark.ffmpeg_offset(("W", "H"), ("w", "h"));
+            let base = if chain.is_empty() { "null".to_string() } else { chain.join(",") };
+            args.push("-i".into());
+            args.push(watermark.source.clone().into());
+            args.push("-filter_complex".into());
+            args.push(
+                format!(
+                    "[0:v]{base}[base];[1:v]format=rgba,colorchannelmixer=aa={}[mark];[base][mark]overlay={x}:{y}[v]",
+                    watermark.opacity
+                )
+                .into(),
+            );
+            // フィルタの出力を使うと映像は自動で選ばれないため、音声と合わせて明示する
+            args.extend(["-map", "[v]", "-map", "0:a:0?"].map(OsString::from));
+            return args;
+        }
+        Some(watermark) => {
+            let (x, y) = watermark.ffmpeg_offset(("w", "h"), ("tw", "th"));
+            chain.push(format!(
+                "drawtext=textfile={}:fontsize=h/30:fontcolor=white@{opacity}:shadowcolor=black@{opacity}:shadowx=2:shadowy=2:x={x}:y={y}",
+                escape_filter_value(&watermark.source.to_string_lossy()),
+                opacity = watermark.opacity,
+            ));
+        }
+        None => {}
+    }
+
+    if !chain.is_empty() {
+        args.push("-vf".into());
+        args.push(chain.join(",").into());
+    }
+    args
+}
+
+/// フィルタの引数の値に使えるようエスケープする。
+/// フィルタの引数としての `\ ' :` と、フィルタグラフとしての `\ ' [ ] , ;` の2段階がある。
+fn escape_filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &str| {
+        value.chars().fold(String::new(), |mut escaped, c| {
+            if special.contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        })
+    };
+    escape(&escape(value, "\\':"), "\\'[],;")
+}
+
 /// 動画ファイルを圧縮する関数
 ///
 /// CRF を尊重するソフトウェアエンコーダ（AV1: libsvtav1, HEVC: libx265）を用いる。
@@ -76,8 +337,7 @@ pub fn is_match_extension(input_path: &str) -> bool {
 ///
 /// * `input_path` - 入力元の動画ファイルパス
 /// * `output_path` - 圧縮後の出力先ファイルパス
-/// * `codec` - 出力コーデック（AV1 もしくは HEVC）
-/// * `crf` - Constant Rate Factor（低いほど高画質・大きいファイル）
+/// * `options` - 出力コーデック（AV1 もしくは HEVC）や CRF などのオプション
 ///
 /// # 戻り値
 ///
@@ -86,43 +346,21 @@ pub fn is_match_extension(input_path: &str) -> bool {
 /// # 例
 ///
 /// ```ignore
-/// let result = compress_video(
-///     "/path/to/input.mp4",
-///     "/path/to/output.mp4",
-///     VideoCodec::Av1,
-///     40,
-/// );
+/// let options = VideoOptions { codec: VideoCodec::Av1, crf: Some(40), ..Default::default() };
+/// let result = compress_video(Path::new("/path/to/input.mp4"), Path::new("/path/to/output.mp4"), &options);
 /// match result {
 ///     Ok(stats) => println!("圧縮完了: {}% 削減", stats.size_reduction_percent),
 ///     Err(e) => eprintln!("エラー: {}", e),
 /// }
 /// ```
 pub fn compress_video(
-    input_path: &str,
-    output_path: &str,
-    codec: VideoCodec,
-    crf: u8,
+    input_path: &Path,
+    output_path: &Path,
+    options: &VideoOptions,
 ) -> Result<CompressionStats, CompressError> {
-    // 開始時間を記録
-    let start = Instant::now();
-    let output_file_path = PathBuf::from(output_path);
-
-    // 出力ディレクトリの存在チェックと作成
-    if let Some(parent) = output_file_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-
-    // 元のファイルサイズを取得
-    let metadata = fs::metadata(input_path)?;
-    let original_size = metadata.len();
-
     // FFmpegの存在チェック
     if !is_ffmpeg_available() {
-        return Err(CompressError::Ffmpeg(
-            "FFmpegがインストールされていないか、PATHに含まれていません".to_string(),
-        ));
+        return Err(CompressError::ToolMissing("ffmpeg"));
     }
 
     // 動画の解像度とアスペクト比を取得
@@ -137,65 +375,595 @@ pub fn compress_video(
         .arg("csv=p=0")
         .arg(input_path)
         .output()
-        .map_err(|e| CompressError::Ffmpeg(format!("ffprobeの実行に失敗: {e}")))?;
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
     
     let dimensions = String::from_utf8_lossy(&probe_output.stdout);
     let dimensions: Vec<&str> = dimensions.trim().split(',').collect();
     
-    let mut resize_filter = String::new();
+    let mut resize = false;
     
     // 解像度情報が正しく取得できた場合
     if dimensions.len() == 2 {
         if let (Ok(width), Ok(height)) = (dimensions[0].parse::<u32>(), dimensions[1].parse::<u32>()) {
-            // アスペクト比を計算
-            let aspect_ratio = get_aspect_ratio(width, height);
-
-            // 16:9のアスペクト比は約1.778
-            let is_16_9 = aspect_ratio >= 1.775 && aspect_ratio <= 1.781;
-            
-            // 16:9かつフルHD（1920x1080）を超える場合
-            if is_16_9 && (width > 1920 || height > 1080) {
-                resize_filter = "-vf scale=1920:-2".to_string();
-            }
+            // ffprobe の寸法は回転前のもの。自動回転するとフィルタには回した後の画素が渡るため、表示の向きで判定する
+            let quarter_turn = options.autorotate == Autorotate::Bake && probe_rotation(input_path)? % 180 == 90;
+            resize = if quarter_turn { needs_resize(height, width) } else { needs_resize(width, height) };
         }
     }
-    
-    // FFmpegコマンドの実行
-    let crf = crf.to_string();
+
+    // フレームレートは秒数で指定された間隔をフレーム数にするときだけ調べる
+    let frame_rate = match options.keyframe_interval {
+        Some(KeyframeInterval::Seconds(_)) => probe_frame_rate(input_path)?,
+        _ => None,
+    };
+    let keyframes = keyframe_frames(input_path, options, frame_rate);
+
+    if let Some(seconds) = options.segment_seconds {
+        // 長さが分からない入力は区間に分けられないため、まとめてエンコードする
+        if let Some(duration) = probe_duration(input_path)? {
+            return encode_segments(input_path, duration, seconds, resize, keyframes, output_path, options);
+        }
+    }
+
+    let mut input = input_args(options);
+    input.extend(["-i".into(), input_path.into()]);
+    encode(input_path, &input, resize, keyframes, output_path, options)
+}
+
+/// キーフレームの間隔のフレーム数。秒数で指定してフレームレートが分からない場合は警告してエンコーダの既定に任せる
+fn keyframe_frames(input_path: &Path, options: &VideoOptions, frame_rate: Option<f64>) -> Option<u32> {
+    let interval = options.keyframe_interval?;
+    let frames = interval.frames(frame_rate);
+    if frames.is_none() {
+        warning!("警告: フレームレートが分からないため、キーフレームの間隔はエンコーダの既定のままにします: {:?}", input_path);
+    }
+    frames
+}
+
+/// ffprobe で映像の平均フレームレート（1秒あたりのフレーム数）を求める。求められない場合は `None`。
+fn probe_frame_rate(input_path: &Path) -> Result<Option<f64>, CompressError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries", "stream=avg_frame_rate", "-of", "csv=p=0"])
+        .arg(input_path)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
+    Ok(parse_frame_rate(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// ffprobe のフレームレート（`30000/1001` のような分数か、数値）を読む。求められない入力は `0/0` になる
+fn parse_frame_rate(probe: &str) -> Option<f64> {
+    let probe = probe.trim().trim_end_matches(',');
+    let rate = match probe.split_once('/') {
+        Some((numer, denom)) => numer.parse::<f64>().ok()? / denom.parse::<f64>().ok()?,
+        None => probe.parse::<f64>().ok()?,
+    };
+    (rate.is_finite() && rate > 0.0).then_some(rate)
+}
+
+/// ffprobe で映像の回転（表示するときに時計回りに回す角度。0, 90, 180, 270）を求める。回転の情報がなければ 0。
+fn probe_rotation(input_path: &Path) -> Result<u32, CompressError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream_tags=rotate:stream_side_data=rotation", "-of", "default=noprint_wrappers=1"])
+        .arg(input_path)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
+    Ok(parse_rotation(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// ffprobe の出力から回転を読む。新しい ffmpeg の display matrix（`rotation=-90`、反時計回り）を、
+/// 古い ffmpeg の `rotate` タグ（`TAG:rotate=90`、時計回り）より優先する。90 度単位に丸める。
+fn parse_rotation(probe: &str) -> u32 {
+    let value = |key: &str| {
+        probe
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('=')?.parse::<f64>().ok())
+            .filter(|degrees| degrees.is_finite())
+    };
+    let clockwise = value("rotation").map(|degrees| -degrees).or_else(|| value("TAG:rotate")).unwrap_or(0.0);
+    ((clockwise / 90.0).round() as i64).rem_euclid(4) as u32 * 90
+}
+
+/// 入力の `-i` の前に置く引数。回転の情報を引き継ぐ場合は、ffmpeg が画素を自動で回さないようにする
+fn input_args(options: &VideoOptions) -> Vec<OsString> {
+    match options.autorotate {
+        Autorotate::Bake => Vec::new(),
+        Autorotate::Keep => vec!["-noautorotate".into()],
+    }
+}
+
+/// 入力に含まれるストリームの種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Streams {
+    pub video: bool,
+    pub audio: bool,
+}
+
+/// ffprobe で入力に映像と音声のストリームがあるかを調べる。ffprobe が読めないファイルはどちらもないとする。
+pub fn probe_streams(input_path: &Path) -> Result<Streams, CompressError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "stream=codec_type", "-of", "csv=p=0"])
+        .arg(input_path)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
+    Ok(parse_streams(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// `paths` のストリームを、最大 `jobs` 本の ffprobe を同時に動かして調べる（`--probe-jobs`）。
+///
+/// 動画の多いディレクトリでは、処理を決めながら ffprobe を1本ずつ待つと始まるまでが長いため、先にまとめて調べるためのもの。
+/// ffprobe がないなどで調べられなかった入力は含めない（処理を決めるときに改めて調べ、その理由を報告する）。
+pub fn probe_streams_all(paths: &[PathBuf], jobs: usize) -> HashMap<PathBuf, Streams> {
+    probe_all_with(paths, jobs, probe_streams)
+}
+
+fn probe_all_with(paths: &[PathBuf], jobs: usize, probe: impl Fn(&Path) -> Result<Streams, CompressError> + Sync) -> HashMap<PathBuf, Streams> {
+    let next = AtomicUsize::new(0);
+    let found = Mutex::new(HashMap::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, paths.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Ok(streams) = probe(path) {
+                        found.lock().unwrap().insert(path.clone(), streams);
+                    }
+                }
+            });
+        }
+    });
+    found.into_inner().unwrap()
+}
+
+/// ffprobe で入力のヘッダとストリームを読み、読めない場合はその理由を返す。`video` なら映像、そうでなければ音声のストリームがなければ読めないとする。
+///
+/// ffprobe はデコードせずに読むため、途中のデータの破損までは見つけられない。
+pub fn probe_problem(input_path: &Path, video: bool) -> Result<Option<String>, CompressError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "stream=codec_type", "-of", "csv=p=0"])
+        .arg(input_path)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
+    Ok(find_problem(output.status.success(), &String::from_utf8_lossy(&output.stdout), &String::from_utf8_lossy(&output.stderr), video))
+}
+
+/// ffprobe の終了状態と出力から、読めない理由を決める。エラーの出力があれば最初の行を理由にする
+fn find_problem(success: bool, stdout: &str, stderr: &str, video: bool) -> Option<String> {
+    let error = stderr.lines().map(str::trim).find(|line| !line.is_empty());
+    if !success || error.is_some() {
+        return Some(error.unwrap_or("ffprobe で読めません").to_string());
+    }
+    let streams = parse_streams(stdout);
+    match (video, streams.video, streams.audio) {
+        (true, false, _) => Some("映像のストリームがありません".to_string()),
+        (false, _, false) => Some("音声のストリームがありません".to_string()),
+        _ => None,
+    }
+}
+
+/// ffprobe の出力（1行に1つのストリームの種類）を読む
+fn parse_streams(probe: &str) -> Streams {
+    let has = |kind: &str| probe.lines().any(|line| line.trim().trim_end_matches(',') == kind);
+    Streams { video: has("video"), audio: has("audio") }
+}
+
+/// ffprobe で動画の長さ（秒）を求める。求められない場合は `None`。
+fn probe_duration(input_path: &Path) -> Result<Option<f64>, CompressError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "csv=p=0"])
+        .arg(input_path)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => CompressError::ToolMissing("ffprobe"),
+            _ => CompressError::Io(e),
+        })?;
+    let duration = String::from_utf8_lossy(&output.stdout).trim().parse::<f64>().ok();
+    Ok(duration.filter(|duration| duration.is_finite() && *duration > 0.0))
+}
+
+/// 動画の全体から等間隔に `count` 枚のフレームを PNG として `dir` に書き出し、`(秒, パス)` を時刻順に返す（--sprite-sheet）。
+///
+/// 各フレームは `count` 等分した区間の中央の時刻から取る（先頭の黒い画面や末尾を避けるため）。長さが分からない動画はエラー。
+pub fn extract_frames(
+    input_path: &Path,
+    count: u32,
+    dir: &Path,
+    loglevel: FfmpegLogLevel,
+) -> Result<Vec<(f64, PathBuf)>, CompressError> {
+    if !is_ffmpeg_available() {
+        return Err(CompressError::ToolMissing("ffmpeg"));
+    }
+    let Some(duration) = probe_duration(input_path)? else {
+        return Err(CompressError::InvalidInput(format!("動画の長さが分かりません: {:?}", input_path)));
+    };
+
+    frame_times(duration, count)
+        .into_iter()
+        .enumerate()
+        .map(|(index, time)| {
+            let frame = dir.join(format!("frame-{index:05}.png"));
+            let mut command = Command::new("ffmpeg");
+            command.args(["-loglevel", loglevel.name(), "-ss", &format!("{time:.3}")]).arg("-i").arg(input_path);
+            command.args(["-frames:v", "1", "-y"]).arg(&frame);
+            run_tool("ffmpeg", &mut command, loglevel.is_quiet())?;
+            Ok((time, frame))
+        })
+        .collect()
+}
+
+/// 動画の先頭から1秒あたり `fps` 枚のフレームを PNG として `dir` に書き出し、パスを時刻順に返す（--extract-frames）
+pub fn extract_frames_at_rate(
+    input_path: &Path,
+    fps: f64,
+    dir: &Path,
+    loglevel: FfmpegLogLevel,
+) -> Result<Vec<PathBuf>, CompressError> {
+    if !is_ffmpeg_available() {
+        return Err(CompressError::ToolMissing("ffmpeg"));
+    }
+
     let mut command = Command::new("ffmpeg");
-    command.args(&["-i", input_path]);
-    match codec {
-        // AV1: 圧縮率最優先。preset 5 は速度と効率のバランス（小さいほど高効率）。
-        VideoCodec::Av1 => {
-            command.args(&["-c:v", "libsvtav1", "-preset", "5", "-crf", &crf]);
+    command.args(["-loglevel", loglevel.name()]).arg("-i").arg(input_path);
+    command.args(["-vf", &format!("fps={fps}"), "-y"]).arg(dir.join("frame-%06d.png"));
+    run_tool("ffmpeg", &mut command, loglevel.is_quiet())?;
+
+    let mut frames: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    // 連番は桁をそろえてあるため、名前順が時刻順になる
+    frames.sort();
+    Ok(frames)
+}
+
+/// 長さ `duration` 秒を `count` 等分した区間それぞれの中央の時刻
+fn frame_times(duration: f64, count: u32) -> Vec<f64> {
+    (0..count).map(|index| duration * (index as f64 + 0.5) / count as f64).collect()
+}
+
+/// 動画を `seconds` 秒ごとの区間に分けてエンコードし、concat デマルチプレクサで再エンコードせずにつなぐ（--video-segments）。
+///
+/// 区間は出力の隣（`temp_dir` があればその下）の作業ディレクトリ（`.clip.mp4.segments`）に書き、書き終えたものだけを正式な名前にする。
+/// 途中で止まっても、次の実行では同じ設定で書き終えた区間を飛ばして続きから始める。
+/// 音声は区間の継ぎ目で途切れないよう、つなぐときに元の動画から通しでエンコードする。
+fn encode_segments(
+    input_path: &Path,
+    duration: f64,
+    seconds: u32,
+    resize: bool,
+    keyframes: Option<u32>,
+    output_path: &Path,
+    options: &VideoOptions,
+) -> Result<CompressionStats, CompressError> {
+    let start = Instant::now();
+    ensure_parent_dir(output_path)?;
+    let original_size = fs::metadata(input_path)?.len();
+
+    let dir = segments_dir(output_path, options.temp_dir.as_deref());
+    let input_args = input_args(options);
+    let video_args = video_args(resize, keyframes, options);
+    let settings: Vec<OsString> = input_args.iter().chain(&video_args).cloned().collect();
+    prepare_segments_dir(&dir, &segments_fingerprint(input_path, seconds, &settings)?)?;
+
+    let segments = segment_ranges(duration, seconds);
+    for (index, (offset, length)) in segments.iter().enumerate() {
+        let done = dir.join(segment_name(index));
+        // 前回の実行で書き終えた区間
+        if done.exists() {
+            continue;
         }
-        // HEVC: hvc1 タグで iOS/QuickTime 再生互換。preset slow で圧縮効率を確保。
-        VideoCodec::Hevc => {
-            command.args(&["-c:v", "libx265", "-preset", "slow", "-crf", &crf, "-tag:v", "hvc1"]);
+        let partial = dir.join(format!("segment-{index:05}.partial.mp4"));
+        let mut command = ffmpeg_command(options);
+        command.args(["-ss", &format!("{offset:.3}")]);
+        if let Some(length) = length {
+            command.args(["-t", &format!("{length:.3}")]);
         }
+        command.args(&input_args).arg("-i").arg(input_path);
+        command.args(&video_args);
+        command.args(["-an", "-y"]).arg(&partial);
+        run_ffmpeg(&mut command, options)?;
+        fs::rename(&partial, &done)?;
     }
 
-    // 幅広い再生互換のため 8bit 4:2:0 に固定
-    command.args(&["-pix_fmt", "yuv420p"]);
-    command.args(&["-c:a", "aac", "-b:a", "128k"]);
+    let list = dir.join("segments.txt");
+    fs::write(&list, segment_list(segments.len()))?;
+    // つなぐ途中で止まっても出力が圧縮済みに見えないよう、出力の隣の一時ファイルに書いてから移す
+    // （作業ディレクトリが別のファイルシステムにあっても移せるように）
+    let joined = temp_path(output_path);
+    let mut command = ffmpeg_command(options);
+    command.args(["-f", "concat", "-i"]).arg(&list).arg("-i").arg(input_path);
+    command.args(["-map", "0:v", "-map", "1:a:0?", "-c:v", "copy", "-c:a", "aac", "-b:a", "128k"]);
+    command.args(audio_filter_args(input_path, options)?);
+    command.args(["-movflags", "+faststart", "-y"]).arg(&joined);
+    if let Err(e) = run_ffmpeg(&mut command, options) {
+        let _ = fs::remove_file(&joined);
+        return Err(e);
+    }
+    fs::rename(&joined, output_path)?;
+    let _ = fs::remove_dir_all(&dir);
+
+    stats(original_size, output_path, start)
+}
+
+/// 区間を置く作業ディレクトリ。再開できるよう、実行ごとではなく出力ごとに決まった場所にする。
+/// `temp_dir` の下に置く場合は、別のディレクトリの同名の出力と混ざらないよう出力の絶対パスのハッシュを付ける。
+fn segments_dir(output_path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let name = output_path.file_name().unwrap_or_default().to_string_lossy();
+    match temp_dir {
+        Some(temp_dir) => {
+            let mut hasher = DefaultHasher::new();
+            std::path::absolute(output_path).unwrap_or_else(|_| output_path.to_path_buf()).hash(&mut hasher);
+            temp_dir.join(format!(".{name}-{:016x}.segments", hasher.finish()))
+        }
+        None => output_path.with_file_name(format!(".{name}.segments")),
+    }
+}
+
+/// 再開してよいかを判断するための、入力とエンコード設定の記録
+fn segments_fingerprint(input_path: &Path, seconds: u32, encode_args: &[OsString]) -> Result<String, CompressError> {
+    let metadata = fs::metadata(input_path)?;
+    let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    let args: Vec<String> = encode_args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    Ok(format!("{} {} {seconds} {}\n", metadata.len(), modified.as_secs(), args.join(" ")))
+}
+
+/// 作業ディレクトリを用意する。前回と入力や設定が違う場合は、書き終えた区間を使わずに作り直す
+fn prepare_segments_dir(dir: &Path, fingerprint: &str) -> Result<(), CompressError> {
+    let settings = dir.join("settings.txt");
+    if fs::read_to_string(&settings).is_ok_and(|previous| previous == fingerprint) {
+        return Ok(());
+    }
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+    fs::write(settings, fingerprint)?;
+    Ok(())
+}
+
+/// 区間ごとの開始位置と長さ（秒）。最後の区間は長さを指定せず、終わりまでエンコードする
+/// （ffprobe の長さがわずかに短くても末尾を落とさないため）。
+fn segment_ranges(duration: f64, seconds: u32) -> Vec<(f64, Option<f64>)> {
+    let count = (duration / seconds as f64).ceil().max(1.0) as usize;
+    (0..count)
+        .map(|index| {
+            let length = (index + 1 < count).then_some(seconds as f64);
+            ((index as u64 * seconds as u64) as f64, length)
+        })
+        .collect()
+}
+
+/// 書き終えた区間のファイル名
+fn segment_name(index: usize) -> String {
+    format!("segment-{index:05}.mp4")
+}
+
+/// 区間を順につなぐ concat デマルチプレクサの一覧
+fn segment_list(count: usize) -> String {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for index in 0..count {
+        list.push_str(&format!("file '{}'\n", segment_name(index)));
+    }
+    list
+}
+
+/// アニメーション画像のフレームを mp4 に変換する。
+///
+/// ffmpeg はアニメーション WebP をデコードできないため、デコード済みのフレームを PNG に書き出し、
+/// 表示時間を concat デマルチプレクサの `duration` で渡す。`input_path` は元のファイルサイズを求めるのに使う。
+pub fn compress_frames(
+    input_path: &Path,
+    frames: &[Frame],
+    output_path: &Path,
+    options: &VideoOptions,
+) -> Result<CompressionStats, CompressError> {
+    if !is_ffmpeg_available() {
+        return Err(CompressError::ToolMissing("ffmpeg"));
+    }
+
+    let work_dir = work_dir(options.temp_dir.as_deref(), "frames");
+    fs::create_dir_all(&work_dir)?;
+    let result = write_frames(frames, &work_dir).and_then(|list| {
+        let resize = frames.first().is_some_and(|frame| {
+            let (width, height) = frame.buffer().dimensions();
+            needs_resize(width, height)
+        });
+        // 表示時間がフレームごとに違うため、平均のフレームレートで秒数をフレーム数にする
+        let seconds: f64 = frames.iter().map(frame_seconds).sum();
+        let keyframes = keyframe_frames(input_path, options, (seconds > 0.0).then(|| frames.len() as f64 / seconds));
+        let input: Vec<OsString> = vec!["-f".into(), "concat".into(), "-i".into(), list.into()];
+        encode(input_path, &input, resize, keyframes, output_path, options)
+    });
+    let _ = fs::remove_dir_all(&work_dir);
+    result
+}
 
-    // リサイズフィルターを追加（必要な場合）
-    if !resize_filter.is_empty() {
-        let filter_parts: Vec<&str> = resize_filter.split_whitespace().collect();
-        command.args(filter_parts);
+/// フレームを PNG として `dir` に書き出し、concat デマルチプレクサに渡す一覧のパスを返す
+fn write_frames(frames: &[Frame], dir: &Path) -> Result<PathBuf, CompressError> {
+    for (index, frame) in frames.iter().enumerate() {
+        frame.buffer().save(dir.join(frame_name(index)))?;
     }
+    let list = dir.join("frames.txt");
+    fs::write(&list, concat_list(frames))?;
+    Ok(list)
+}
+
+/// 連番のフレームのファイル名
+fn frame_name(index: usize) -> String {
+    format!("frame-{index:05}.png")
+}
+
+/// concat デマルチプレクサの一覧。ファイル名は一覧のあるディレクトリからの相対パスになる。
+fn concat_list(frames: &[Frame]) -> String {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for (index, frame) in frames.iter().enumerate() {
+        list.push_str(&format!("file '{}'\nduration {:.3}\n", frame_name(index), frame_seconds(frame)));
+    }
+    // 最後のフレームの duration を反映させるには、同じファイルをもう一度並べる必要がある
+    if let Some(last) = frames.len().checked_sub(1) {
+        list.push_str(&format!("file '{}'\n", frame_name(last)));
+    }
+    list
+}
+
+/// フレームの表示時間（秒）。ブラウザと同じく、10ms 以下の表示時間は 100ms として扱う
+fn frame_seconds(frame: &Frame) -> f64 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    let millis = numer as f64 / denom as f64;
+    if millis <= 10.0 { 0.1 } else { millis / 1000.0 }
+}
+
+/// 16:9 かつ幅がフル HD（1920）を超える場合は縮小する。
+/// 16:9 とみなす誤差の内で幅が 1920 以下の映像（1916x1088 など）は、高さが 1080 を超えても幅 1920 へ拡大してしまうため縮小しない
+fn needs_resize(width: u32, height: u32) -> bool {
+    is_16_9(width, height) && width > 1920
+}
+
+/// `input` を入力とする ffmpeg で mp4 にエンコードする
+fn encode(
+    input_path: &Path,
+    input: &[OsString],
+    resize: bool,
+    keyframes: Option<u32>,
+    output_path: &Path,
+    options: &VideoOptions,
+) -> Result<CompressionStats, CompressError> {
+    // 開始時間を記録
+    let start = Instant::now();
+
+    // 出力ディレクトリの存在チェックと作成
+    ensure_parent_dir(output_path)?;
+
+    // 元のファイルサイズを取得
+    let metadata = fs::metadata(input_path)?;
+    let original_size = metadata.len();
+
+    // FFmpegコマンドの実行
+    let mut command = ffmpeg_command(options);
+    command.args(input);
+    command.args(video_args(resize, keyframes, options));
+    command.args(["-c:a", "aac", "-b:a", "128k"]);
+    command.args(audio_filter_args(input_path, options)?);
 
-    let status = command
-        .args(&["-movflags", "+faststart"]) // ストリーミング向けに moov を先頭へ
+    command
+        .args(["-movflags", "+faststart"]) // ストリーミング向けに moov を先頭へ
         .arg("-y") // 確認なしで上書き
-        .arg(output_file_path)
-        .status()
-        .map_err(|e| CompressError::Ffmpeg(format!("FFmpegの実行に失敗: {e}")))?;
+        .arg(output_path);
+
+    run_ffmpeg(&mut command, options)?;
+
+    stats(original_size, output_path, start)
+}
+
+/// ログの詳しさだけを指定した ffmpeg コマンド
+fn ffmpeg_command(options: &VideoOptions) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-loglevel", options.loglevel.name()]);
+    command
+}
 
-    if !status.success() {
-        return Err(CompressError::Ffmpeg(format!("FFmpegがエラーコードで終了: {status}")));
+/// 映像のフィルタとエンコーダの引数（入力の後ろ、音声と出力の前に置く）。`keyframes` はキーフレームの間隔のフレーム数
+fn video_args(resize: bool, keyframes: Option<u32>, options: &VideoOptions) -> Vec<OsString> {
+    let crf = options.crf().to_string();
+    let preset = options.preset.value(options.codec);
+    // リサイズと透かしのフィルターを追加（必要な場合）
+    let mut args = filter_args(resize, options.sharpen, options.scale_align, options.watermark.as_ref());
+    let encoder: Vec<&str> = match options.codec {
+        // AV1: 圧縮率最優先。preset は数値（小さいほど高効率）。
+        VideoCodec::Av1 => vec!["-c:v", "libsvtav1", "-preset", &preset, "-crf", &crf],
+        // HEVC: hvc1 タグで iOS/QuickTime 再生互換。
+        VideoCodec::Hevc => vec!["-c:v", "libx265", "-preset", &preset, "-crf", &crf, "-tag:v", "hvc1"],
+    };
+    args.extend(encoder.into_iter().map(OsString::from));
+    // 配信の区間の境目をそろえられるよう、最小の間隔も同じにして一定の間隔にする（SVT-AV1 は -keyint_min を使わない）
+    if let Some(frames) = keyframes {
+        let frames = frames.to_string();
+        args.extend(["-g", &frames, "-keyint_min", &frames].map(OsString::from));
     }
 
+    // 幅広い再生互換のため 8bit 4:2:0 に固定
+    args.extend(["-pix_fmt", "yuv420p"].map(OsString::from));
+    // 既定の固定フレームレートではフレームが間引かれたり複製されたりして、表示時間がずれる
+    if options.animation {
+        args.extend(["-fps_mode", "vfr"].map(OsString::from));
+    }
+    args
+}
+
+/// 音声にかけるフィルタの引数（`-af`）。2パスの正規化では先に音量を測る
+fn audio_filter_args(input_path: &Path, options: &VideoOptions) -> Result<Vec<OsString>, CompressError> {
+    let one_pass = format!("loudnorm={LOUDNORM_TARGET}");
+    let filter = match options.normalize_audio {
+        None => return Ok(Vec::new()),
+        Some(NormalizeAudio::OnePass) => one_pass,
+        Some(NormalizeAudio::TwoPass) => match measure_loudness(input_path, options)? {
+            Some(measured) => format!("{one_pass}:{measured}:linear=true"),
+            // 音声がない・無音などで測れない場合は1パスと同じにする
+            None => one_pass,
+        },
+    };
+    Ok(vec!["-af".into(), filter.into()])
+}
+
+/// loudnorm の1パス目で音量を測り、2パス目に渡す `measured_*` の引数にする。測れない場合は `None`。
+fn measure_loudness(input_path: &Path, options: &VideoOptions) -> Result<Option<String>, CompressError> {
+    let mut command = Command::new("ffmpeg");
+    // 測った値は info のログに出るため、ログの詳しさの指定によらず info で実行する
+    command.args(["-hide_banner", "-nostats", "-loglevel", "info", "-i"]).arg(input_path);
+    command.args(["-vn", "-af", &format!("loudnorm={LOUDNORM_TARGET}:print_format=json"), "-f", "null", "-"]);
+    if options.print_command {
+        println!("{}", format_command(&command));
+    }
+
+    let output = command.output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_loudness(&String::from_utf8_lossy(&output.stderr)))
+}
+
+/// loudnorm が最後に出力する JSON から、2パス目の引数を組み立てる
+fn parse_loudness(log: &str) -> Option<String> {
+    let json = &log[log.rfind('{')?..];
+    let value = |key: &str| -> Option<f64> {
+        let rest = &json[json.find(&format!("\"{key}\""))? + key.len() + 2..];
+        let rest = &rest[rest.find('"')? + 1..];
+        rest[..rest.find('"')?].trim().parse().ok().filter(|value: &f64| value.is_finite())
+    };
+    Some(format!(
+        "measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}",
+        value("input_i")?,
+        value("input_tp")?,
+        value("input_lra")?,
+        value("input_thresh")?,
+        value("target_offset")?,
+    ))
+}
+
+/// ffmpeg を実行する。指定があれば実行する前にコマンドを表示する
+fn run_ffmpeg(command: &mut Command, options: &VideoOptions) -> Result<(), CompressError> {
+    if options.print_command {
+        println!("{}", format_command(command));
+    }
+    run_tool("ffmpeg", command, options.loglevel.is_quiet())
+}
+
+/// 元のサイズと書き出した出力から圧縮の統計情報を求める
+fn stats(original_size: u64, output_path: &Path, start: Instant) -> Result<CompressionStats, CompressError> {
     // 圧縮後のファイルサイズを取得
     let compressed_metadata = fs::metadata(output_path)?;
     let compressed_size = compressed_metadata.len();
@@ -218,6 +986,127 @@ pub fn compress_video(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use image::{Delay, RgbaImage};
+
+    /// フレームごとの表示時間を保ち、最後のフレームの表示時間も反映させること
+    #[test]
+    fn lists_frames_with_durations() {
+        let frame = |millis: u32| Frame::from_parts(RgbaImage::new(2, 2), 0, 0, Delay::from_numer_denom_ms(millis, 1));
+        let list = concat_list(&[frame(40), frame(250), frame(0)]);
+        assert_eq!(
+            list,
+            "ffconcat version 1.0\n\
+             file 'frame-00000.png'\nduration 0.040\n\
+             file 'frame-00001.png'\nduration 0.250\n\
+             file 'frame-00002.png'\nduration 0.100\n\
+             file 'frame-00002.png'\n"
+        );
+    }
+
+    /// loudnorm の JSON から測った値を読み、無音（-inf）は測れなかったとみなすこと
+    #[test]
+    fn parses_loudnorm_measurement() {
+        let log = "[Parsed_loudnorm_0 @ 0x1] \n{\n\t\"input_i\" : \"-27.61\",\n\t\"input_tp\" : \"-4.47\",\n\t\"input_lra\" : \"18.06\",\n\t\"input_thresh\" : \"-39.20\",\n\t\"output_i\" : \"-16.58\",\n\t\"target_offset\" : \"0.58\"\n}\n";
+        assert_eq!(
+            parse_loudness(log).as_deref(),
+            Some("measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.2:offset=0.58")
+        );
+        assert_eq!(parse_loudness(&log.replace("-27.61", "-inf")), None);
+        assert_eq!(parse_loudness("no audio"), None);
+    }
+
+    /// 等分した区間の中央の時刻を取ること
+    #[test]
+    fn spaces_frames_evenly() {
+        assert_eq!(frame_times(10.0, 4), vec![1.25, 3.75, 6.25, 8.75]);
+        assert_eq!(frame_times(3.0, 1), vec![1.5]);
+    }
+
+    /// 最後の区間だけ長さを指定せず、区間の一覧を順に並べること
+    #[test]
+    fn splits_into_segments() {
+        assert_eq!(segment_ranges(250.5, 100), vec![(0.0, Some(100.0)), (100.0, Some(100.0)), (200.0, None)]);
+        assert_eq!(segment_ranges(100.0, 100), vec![(0.0, None)]);
+        assert_eq!(
+            segment_list(2),
+            "ffconcat version 1.0\nfile 'segment-00000.mp4'\nfile 'segment-00001.mp4'\n"
+        );
+        assert_eq!(segments_dir(Path::new("out/clip.mp4"), None), PathBuf::from("out/.clip.mp4.segments"));
+        let scratch = segments_dir(Path::new("out/clip.mp4"), Some(Path::new("/scratch")));
+        assert!(scratch.to_string_lossy().starts_with("/scratch/.clip.mp4-"), "{scratch:?}");
+        assert_ne!(scratch, segments_dir(Path::new("other/clip.mp4"), Some(Path::new("/scratch"))));
+    }
+
+    /// 設定が同じなら書き終えた区間を残し、違えば作り直すこと
+    #[test]
+    fn resumes_only_with_same_settings() {
+        let dir = std::env::temp_dir().join("compressor_segments_test");
+        let _ = fs::remove_dir_all(&dir);
+        prepare_segments_dir(&dir, "a\n").unwrap();
+        fs::write(dir.join(segment_name(0)), b"done").unwrap();
+
+        prepare_segments_dir(&dir, "a\n").unwrap();
+        assert!(dir.join(segment_name(0)).exists());
+
+        prepare_segments_dir(&dir, "b\n").unwrap();
+        assert!(!dir.join(segment_name(0)).exists());
+        assert_eq!(fs::read_to_string(dir.join("settings.txt")).unwrap(), "b\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// フレーム数と秒数の間隔を読み、秒数はフレームレートからフレーム数にして -g と -keyint_min に渡すこと
+    #[test]
+    fn parses_keyframe_interval() {
+        assert_eq!(parse_keyframe_interval("48"), Ok(KeyframeInterval::Frames(48)));
+        assert_eq!(parse_keyframe_interval("2s"), Ok(KeyframeInterval::Seconds(2.0)));
+        assert!(parse_keyframe_interval("0").is_err());
+        assert!(parse_keyframe_interval("0s").is_err());
+        assert!(parse_keyframe_interval("1.5").is_err());
+
+        assert_eq!(parse_frame_rate("30000/1001\n").map(|rate| (rate * 100.0).round()), Some(2997.0));
+        assert_eq!(parse_frame_rate("25"), Some(25.0));
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(KeyframeInterval::Seconds(2.0).frames(parse_frame_rate("30000/1001")), Some(60));
+        assert_eq!(KeyframeInterval::Seconds(2.0).frames(None), None);
+        assert_eq!(KeyframeInterval::Frames(48).frames(None), Some(48));
+
+        let args: Vec<String> = video_args(false, Some(60), &VideoOptions::default()).iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert!(args.windows(4).any(|w| w == ["-g", "60", "-keyint_min", "60"]), "{args:?}");
+        assert!(!video_args(false, None, &VideoOptions::default()).contains(&OsString::from("-g")));
+    }
+
+    /// display matrix（反時計回り）とタグ（時計回り）のどちらからも時計回りの角度を読み、display matrix を優先すること
+    #[test]
+    fn parses_rotation() {
+        assert_eq!(parse_rotation("rotation=-90\n"), 90);
+        assert_eq!(parse_rotation("TAG:rotate=270\n"), 270);
+        assert_eq!(parse_rotation("TAG:rotate=90\nrotation=90\n"), 270);
+        assert_eq!(parse_rotation("rotation=180.000000\n"), 180);
+        assert_eq!(parse_rotation("rotation=-89.5\n"), 90);
+        assert_eq!(parse_rotation(""), 0);
+        assert_eq!(parse_rotation("rotation=nan\n"), 0);
+    }
+
+    /// ストリームの種類を読み、映像のない入力（音声だけ、読めないファイル）を見分けること
+    #[test]
+    fn parses_streams() {
+        assert_eq!(parse_streams("video\naudio\n"), Streams { video: true, audio: true });
+        assert_eq!(parse_streams("audio\n"), Streams { video: false, audio: true });
+        assert_eq!(parse_streams("video,\n"), Streams { video: true, audio: false });
+        assert_eq!(parse_streams(""), Streams::default());
+    }
+
+    /// ffprobe が失敗したかエラーを出した場合と、求める種類のストリームがない場合を読めないとすること
+    #[test]
+    fn finds_probe_problems() {
+        assert_eq!(find_problem(true, "video\naudio\n", "", true), None);
+        assert_eq!(find_problem(true, "audio\n", "", false), None);
+        assert_eq!(find_problem(false, "", "\nclip.mp4: Invalid data found when processing input\n", true).as_deref(), Some("clip.mp4: Invalid data found when processing input"));
+        assert_eq!(find_problem(true, "video\n", "[h264] error while decoding\n", true).as_deref(), Some("[h264] error while decoding"));
+        assert_eq!(find_problem(false, "", "", true).as_deref(), Some("ffprobe で読めません"));
+        assert_eq!(find_problem(true, "audio\n", "", true).as_deref(), Some("映像のストリームがありません"));
+        assert_eq!(find_problem(true, "video\n", "", false).as_deref(), Some("音声のストリームがありません"));
+    }
 
     /// CRFスケールはコーデック間で異なるため既定値を取り違えないこと
     #[test]
@@ -226,10 +1115,108 @@ mod tests {
         assert_eq!(VideoCodec::Hevc.default_crf(), 28);
     }
 
+    /// 既定の preset は従来どおり（AV1=5, HEVC=slow）で、遅いほど SVT-AV1 の数値が小さくなること
+    #[test]
+    fn maps_presets_per_codec() {
+        assert_eq!(VideoPreset::default().value(VideoCodec::Av1), "5");
+        assert_eq!(VideoPreset::default().value(VideoCodec::Hevc), "slow");
+        assert_eq!(VideoPreset::Veryfast.value(VideoCodec::Hevc), "veryfast");
+
+        let levels: Vec<u8> = VideoPreset::value_variants().iter().map(|p| p.svtav1_level()).collect();
+        assert!(levels.windows(2).all(|pair| pair[0] > pair[1]), "{levels:?}");
+    }
+
+    /// 縮小の後ろにだけ unsharp を足すこと（空白を含めると引数の分割が崩れる）
+    #[test]
+    fn appends_unsharp_after_scale() {
+        assert_eq!(resize_filter_chain(None, ScaleAlign::default()), "scale=1920:-2");
+        assert_eq!(resize_filter_chain(Some(0.8), ScaleAlign::default()), "scale=1920:-2,unsharp=5:5:0.8");
+        assert_eq!(resize_filter_chain(None, ScaleAlign::Sixteen), "scale=1920:-16");
+    }
+
+    /// フル HD を超える 16:9 だけを縮小し、幅が 1920 以下の映像は高さが 1080 を超えても拡大しないこと
+    #[test]
+    fn resizes_only_down() {
+        assert!(needs_resize(3840, 2160));
+        assert!(needs_resize(1930, 1086));
+        assert!(!needs_resize(1920, 1080));
+        assert!(!needs_resize(1280, 720));
+        assert!(!needs_resize(1916, 1088), "幅 1920 への拡大になる");
+        assert!(!needs_resize(3840, 1600), "16:9 ではない");
+    }
+
+    /// すべての入力を一度ずつ調べ、同時に動かす数は jobs までで、調べられなかった入力は含めないこと
+    #[test]
+    fn probes_all_concurrently() {
+        let paths: Vec<PathBuf> = (0..12).map(|i| PathBuf::from(format!("clip{i}.mp4"))).collect();
+        let (running, peak, calls) = (AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0));
+        let probed = probe_all_with(&paths, 3, |path| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            match path.to_str() {
+                Some("clip0.mp4") => Err(CompressError::ToolMissing("ffprobe")),
+                Some("clip1.mp4") => Ok(Streams { video: false, audio: true }),
+                _ => Ok(Streams { video: true, audio: true }),
+            }
+        });
+
+        assert_eq!(calls.load(Ordering::SeqCst), 12);
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        assert_eq!(probed.len(), 11);
+        assert!(!probed.contains_key(Path::new("clip0.mp4")));
+        assert_eq!(probed[Path::new("clip1.mp4")], Streams { video: false, audio: true });
+        assert!(probe_all_with(&[], 4, probe_streams).is_empty());
+    }
+
+    /// テキストは -vf の drawtext に、ロゴは2つ目の入力と overlay にすること
+    #[test]
+    fn builds_watermark_filters() {
+        let args = |resize, source: &str| -> Vec<String> {
+            let watermark = Watermark {
+                source: source.into(),
+                position: crate::watermark::Position::BottomRight,
+                opacity: 0.5,
+                margin: 16,
+            };
+            filter_args(resize, None, ScaleAlign::default(), Some(&watermark)).iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+        };
+
+        assert_eq!(filter_args(false, None, ScaleAlign::default(), None), Vec::<OsString>::new());
+        assert_eq!(
+            args(true, "logo.png"),
+            [
+                "-i",
+                "logo.png",
+                "-filter_complex",
+                "[0:v]scale=1920:-2[base];[1:v]format=rgba,colorchannelmixer=aa=0.5[mark];[base][mark]overlay=W-w-16:H-h-16[v]",
+                "-map",
+                "[v]",
+                "-map",
+                "0:a:0?",
+            ]
+        );
+        assert_eq!(
+            args(false, "notes.txt"),
+            [
+                "-vf",
+                "drawtext=textfile=notes.txt:fontsize=h/30:fontcolor=white@0.5:shadowcolor=black@0.5:shadowx=2:shadowy=2:x=w-tw-16:y=h-th-16",
+            ]
+        );
+    }
+
+    /// パスの記号がフィルタの区切りとして解釈されないようエスケープすること
+    #[test]
+    fn escapes_filter_value() {
+        assert_eq!(escape_filter_value("C:/a b,c.txt"), "C\\\\:/a b\\,c.txt");
+        assert_eq!(escape_filter_value("it's"), "it\\\\\\'s");
+    }
+
     /// 存在しないファイルは対象外として扱うこと
     #[test]
     fn missing_file_is_not_matched() {
-        assert!(!is_match_extension("/nonexistent/clip.mp4"));
+        assert!(!is_match_extension(Path::new("/nonexistent/clip.mp4")));
     }
 
     /// 圧縮失敗時にpanicせずErrを返すこと（バッチ処理を中断させないため）
@@ -241,12 +1228,7 @@ mod tests {
         fs::write(&broken, b"not a real video").unwrap();
         let output = dir.join("out.mp4");
 
-        let result = path2compress(
-            broken.to_str().unwrap(),
-            output.to_str().unwrap(),
-            VideoCodec::Av1,
-            None,
-        );
+        let result = path2compress(&broken, &output, &VideoOptions::default());
 
         assert!(result.is_err(), "壊れた動画でErrにならなかった");
         let _ = fs::remove_dir_all(&dir);
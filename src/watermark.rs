@@ -0,0 +1,367 @@
+//! 見える透かし（`--watermark`）。
+//!
+//! 画像ファイル（png/jpg/webp）を渡すとロゴとして、それ以外はテキストファイルとして中身の文字列を重ねる。
+//! 静止画では組み込みの 5x7 ビットマップフォントで描くため ASCII だけを描ける（それ以外の文字は `?`）。
+//! 動画では ffmpeg の `drawtext`（テキスト）と `overlay`（ロゴ）に置き換える。
+
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use crate::error::CompressError;
+
+/// 透かしを置く位置
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    /// 右下（既定）
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// 1 つの軸での寄せ方
+#[derive(Clone, Copy)]
+enum Align {
+    Start,
+    End,
+    Center,
+}
+
+impl Position {
+    /// 横と縦の寄せ方
+    fn align(self) -> (Align, Align) {
+        match self {
+            Position::TopLeft => (Align::Start, Align::Start),
+            Position::TopRight => (Align::End, Align::Start),
+            Position::BottomLeft => (Align::Start, Align::End),
+            Position::BottomRight => (Align::End, Align::End),
+            Position::Center => (Align::Center, Align::Center),
+        }
+    }
+}
+
+/// 透かしの指定
+#[derive(Clone, PartialEq, Debug)]
+pub struct Watermark {
+    /// ロゴの画像ファイル、または重ねる文字列を書いたテキストファイル
+    pub source: PathBuf,
+    /// 置く位置
+    pub position: Position,
+    /// 不透明度（0 より大きく 1 以下）
+    pub opacity: f32,
+    /// 画像の端からの余白（ピクセル）。中央に置く場合は使わない
+    pub margin: u32,
+}
+
+/// 読み込んだ透かしの中身
+pub enum Mark {
+    /// 重ねる文字列（改行で複数行）
+    Text(String),
+    /// ロゴ
+    Image(RgbaImage),
+}
+
+impl Mark {
+    /// 拡張子が画像ならロゴとして、それ以外はテキストとして読む
+    pub fn load(path: &Path) -> Result<Mark, CompressError> {
+        if is_image(path) {
+            return Ok(Mark::Image(image::open(path)?.to_rgba8()));
+        }
+
+        let text = std::fs::read_to_string(path)?;
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(CompressError::InvalidInput(format!("透かしのテキストファイルが空です: {:?}", path)));
+        }
+        Ok(Mark::Text(text.to_string()))
+    }
+}
+
+/// ロゴとして扱う拡張子か
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "webp"))
+        .unwrap_or(false)
+}
+
+impl Watermark {
+    /// ロゴの画像か（テキストファイルでないか）
+    pub fn is_logo(&self) -> bool {
+        is_image(&self.source)
+    }
+
+    /// 画像に透かしを重ねる
+    pub fn apply(&self, mut img: DynamicImage) -> Result<DynamicImage, CompressError> {
+        let mut mark = match Mark::load(&self.source)? {
+            Mark::Text(text) => render_text(&text, text_scale(img.height())),
+            Mark::Image(logo) => fit(logo, img.width(), img.height(), self.margin),
+        };
+        for pixel in mark.pixels_mut() {
+            pixel.0[3] = (pixel.0[3] as f32 * self.opacity).round() as u8;
+        }
+
+        let (x, y) = self.offset(img.width(), img.height(), mark.width(), mark.height());
+        imageops::overlay(&mut img, &mark, x as i64, y as i64);
+        Ok(img)
+    }
+
+    /// 透かしの左上の位置。透かしが余白に収まらない場合は画像の端に寄せる
+    fn offset(&self, width: u32, height: u32, mark_width: u32, mark_height: u32) -> (u32, u32) {
+        let place = |align, outer: u32, inner: u32| match align {
+            Align::Start => self.margin,
+            Align::End => outer.saturating_sub(inner).saturating_sub(self.margin),
+            Align::Center => outer.saturating_sub(inner) / 2,
+        };
+        let (x, y) = self.position.align();
+        (place(x, width, mark_width), place(y, height, mark_height))
+    }
+
+    /// ffmpeg の式で書いた透かしの左上の位置。`outer`/`inner` は画面と透かしの (幅, 高さ) を表す変数名
+    pub fn ffmpeg_offset(&self, outer: (&str, &str), inner: (&str, &str)) -> (String, String) {
+        let place = |align, outer: &str, inner: &str| match align {
+            Align::Start => self.margin.to_string(),
+            Align::End => format!("{outer}-{inner}-{}", self.margin),
+            Align::Center => format!("({outer}-{inner})/2"),
+        };
+        let (x, y) = self.position.align();
+        (place(x, outer.0, inner.0), place(y, outer.1, inner.1))
+    }
+}
+
+/// 文字の高さを画像の高さのおよそ 1/30 にする拡大率
+fn text_scale(height: u32) -> u32 {
+    (height / (30 * CELL_HEIGHT)).max(1)
+}
+
+/// ロゴが余白の内側に収まらない場合だけ縮小する（縦横比は保つ）
+fn fit(logo: RgbaImage, width: u32, height: u32, margin: u32) -> RgbaImage {
+    let max_width = width.saturating_sub(margin * 2).max(1);
+    let max_height = height.saturating_sub(margin * 2).max(1);
+    if logo.width() <= max_width && logo.height() <= max_height {
+        return logo;
+    }
+
+    DynamicImage::ImageRgba8(logo).resize(max_width, max_height, imageops::FilterType::Lanczos3).to_rgba8()
+}
+
+/// 1文字の枠（字形 5x7 と字間・行間 1）
+const CELL_WIDTH: u32 = 6;
+const CELL_HEIGHT: u32 = 8;
+
+/// 白い文字に黒い影を付けて描く。影があるので明るい背景でも読める
+fn render_text(text: &str, scale: u32) -> RgbaImage {
+    let lines: Vec<&str> = text.lines().collect();
+    let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as u32;
+    // 影の分だけ 1 ドット広げる
+    let mut mark = RgbaImage::new((columns * CELL_WIDTH + 1) * scale, (lines.len() as u32 * CELL_HEIGHT + 1) * scale);
+
+    for (shift, color) in [(1, Rgba([0, 0, 0, 255])), (0, Rgba([255, 255, 255, 255]))] {
+        for (row, line) in lines.iter().enumerate() {
+            for (column, c) in line.chars().enumerate() {
+                let x0 = column as u32 * CELL_WIDTH + shift;
+                let y0 = row as u32 * CELL_HEIGHT + shift;
+                for (dx, bits) in glyph(c).iter().enumerate() {
+                    for dy in 0..7 {
+                        if bits >> dy & 1 == 0 {
+                            continue;
+                        }
+                        for sx in 0..scale {
+                            for sy in 0..scale {
+                                mark.put_pixel((x0 + dx as u32) * scale + sx, (y0 + dy) * scale + sy, color);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    mark
+}
+
+/// 文字の字形（5 列。各列の下位ビットが上の行）。ASCII 以外は `?` で描く
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        ' '..='~' => FONT[c as usize - ' ' as usize],
+        _ => FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// ASCII の 0x20-0x7E の 5x7 フォント
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x01, 0x01], // F
+    [0x3E, 0x41, 0x41, 0x51, 0x32], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x04, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x7F, 0x20, 0x18, 0x20, 0x7F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x08, 0x14, 0x54, 0x54, 0x3C], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x00, 0x7F, 0x10, 0x28, 0x44], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
+
+/// --watermark-opacity の値を読む。0 より大きく 1 以下。
+#[allow(dead_code)]
+pub fn parse_opacity(value: &str) -> Result<f32, String> {
+    let opacity: f32 = value.parse().map_err(|_| format!("数値で指定してください: {value}"))?;
+    if !(opacity > 0.0 && opacity <= 1.0) {
+        return Err(format!("0 より大きく 1 以下で指定してください: {value}"));
+    }
+
+    Ok(opacity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watermark(position: Position) -> Watermark {
+        Watermark { source: PathBuf::from("logo.png"), position, opacity: 0.5, margin: 10 }
+    }
+
+    /// 余白を空けて指定の位置に置き、収まらない場合は端に寄せること
+    #[test]
+    fn places_mark_by_position() {
+        assert_eq!(watermark(Position::TopLeft).offset(200, 100, 50, 20), (10, 10));
+        assert_eq!(watermark(Position::BottomRight).offset(200, 100, 50, 20), (140, 70));
+        assert_eq!(watermark(Position::Center).offset(200, 100, 50, 20), (75, 40));
+        assert_eq!(watermark(Position::BottomLeft).offset(200, 100, 195, 20), (10, 70));
+        assert_eq!(watermark(Position::TopRight).offset(200, 100, 195, 20), (0, 10));
+    }
+
+    /// ffmpeg の式も同じ位置を表すこと
+    #[test]
+    fn builds_ffmpeg_offset() {
+        let (x, y) = watermark(Position::BottomRight).ffmpeg_offset(("W", "H"), ("w", "h"));
+        assert_eq!((x.as_str(), y.as_str()), ("W-w-10", "H-h-10"));
+        let (x, y) = watermark(Position::Center).ffmpeg_offset(("w", "h"), ("tw", "th"));
+        assert_eq!((x.as_str(), y.as_str()), ("(w-tw)/2", "(h-th)/2"));
+    }
+
+    /// 文字は白、影は黒で描き、それ以外は透明のままにすること
+    #[test]
+    fn renders_text_with_shadow() {
+        let mark = render_text("I\n-", 2);
+        assert_eq!(mark.dimensions(), (14, 34));
+        // 'I' の縦棒（3 列目）と、その右下の影
+        assert_eq!(mark.get_pixel(2 * 2, 3 * 2).0, [255, 255, 255, 255]);
+        assert_eq!(mark.get_pixel(3 * 2, 4 * 2).0, [0, 0, 0, 255]);
+        assert_eq!(mark.get_pixel(0, 0).0[3], 0);
+        assert_eq!(glyph('あ'), glyph('?'));
+    }
+
+    /// ロゴを不透明度に応じて混ぜ、範囲外の画素には触れないこと
+    #[test]
+    fn blends_logo_with_opacity() {
+        let dir = std::env::temp_dir().join("compressor_watermark_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let logo = dir.join("logo.png");
+        RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255])).save(&logo).unwrap();
+
+        let watermark = Watermark { source: logo, position: Position::TopLeft, opacity: 0.5, margin: 2 };
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(10, 10));
+        let marked = watermark.apply(img).unwrap().to_rgb8();
+        assert!((127..=128).contains(&marked.get_pixel(3, 3).0[0]), "{:?}", marked.get_pixel(3, 3));
+        assert_eq!(marked.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(marked.get_pixel(6, 6).0, [0, 0, 0]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// 0 以下や 1 を超える不透明度は拒否すること
+    #[test]
+    fn parses_opacity() {
+        assert_eq!(parse_opacity("0.3"), Ok(0.3));
+        assert_eq!(parse_opacity("1"), Ok(1.0));
+        assert!(parse_opacity("0").is_err());
+        assert!(parse_opacity("1.5").is_err());
+    }
+}
@@ -1,15 +1,62 @@
-use image::{DynamicImage, ImageDecoder};
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageDecoder};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
 use std::path::Path;
-use webp::Encoder;
+use image::RgbaImage;
+use webp::{Encoder, WebPConfig};
 use crate::error::CompressError;
+use crate::file::ensure_parent_dir;
+use crate::utilities::{write_output, Encoded, ImageOptions};
+
+/// 既存の WebP ファイルの種類
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WebpKind {
+    /// 可逆圧縮（VP8L）か
+    pub lossless: bool,
+    /// アルファチャンネル（ALPH チャンクまたは VP8L）を持つか
+    pub alpha: bool,
+    /// アニメーション（ANIM チャンク）か
+    pub animated: bool,
+}
+
+/// RIFF のチャンクをたどって WebP の種類を判定する。WebP でないデータは `None`。
+pub fn inspect(data: &[u8]) -> Option<WebpKind> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut kind = WebpKind { lossless: false, alpha: false, animated: false };
+    let mut i = 12;
+    // チャンクは 種類(4), 長さ(4, リトルエンディアン), データ（偶数長に詰める）
+    while i + 8 <= data.len() {
+        let fourcc = &data[i..i + 4];
+        let length = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        match fourcc {
+            b"VP8L" => {
+                kind.lossless = true;
+                kind.alpha = true;
+            }
+            b"ALPH" => kind.alpha = true,
+            b"ANIM" => kind.animated = true,
+            _ => {}
+        }
+        i = (i + 8).checked_add(length)?.checked_add(length % 2)?;
+    }
+    Some(kind)
+}
+
+/// アニメーション WebP の全フレームを取り出す。各フレームはキャンバス全体に合成した画像と表示時間を持つ。
+pub fn frames(path: &Path) -> Result<Vec<Frame>, CompressError> {
+    let decoder = WebPDecoder::new(BufReader::new(File::open(path)?))?;
+    Ok(decoder.into_frames().collect_frames()?)
+}
 
 /// 画像を読み込み、Exif の Orientation をピクセルに反映して返す。
 ///
 /// WebP には Exif をそのまま引き継げないため、向きの情報をピクセル側に焼き込む。
 /// これをしないと、縦向きに撮影した写真が横倒しで出力される。
-fn open_with_orientation(path: &Path) -> Result<DynamicImage, CompressError> {
+pub(crate) fn open_with_orientation(path: &Path) -> Result<DynamicImage, CompressError> {
     let mut decoder = image::ImageReader::open(path)?
         .with_guessed_format()?
         .into_decoder()?;
@@ -20,32 +67,134 @@ fn open_with_orientation(path: &Path) -> Result<DynamicImage, CompressError> {
     Ok(img)
 }
 
-/// jpg/jpeg 向け: 非可逆 WebP に圧縮する（quality は 0-100）。
-pub fn path2compress_lossy(path: &Path, output_path: &Path, quality: f32) -> Result<(), CompressError> {
-    let img = open_with_orientation(path)?;
+/// 向きを反映した画像を読み込み、指定があれば切り抜いて透かしを重ねる（位置は見た目の向きで指定される）
+fn open_for_encode(path: &Path, options: &ImageOptions) -> Result<DynamicImage, CompressError> {
+    options.edit(open_with_orientation(path)?)
+}
+
+/// jpg/jpeg 向け: 非可逆 WebP に圧縮する（`options.quality` は 0-100）。
+pub fn path2compress_lossy(path: &Path, output_path: &Path, options: &ImageOptions) -> Result<(), CompressError> {
+    let img = open_for_encode(path, options)?;
     let rgb = img.to_rgb8();
 
     let encoder = Encoder::from_rgb(rgb.as_raw(), rgb.width(), rgb.height());
-    let data = encoder.encode(quality);
+    let data = encoder.encode(options.quality);
 
     write_file(output_path, &data)
 }
 
 /// png 向け: 可逆 WebP に圧縮する（アルファ保持）。
-pub fn path2compress_lossless(path: &Path, output_path: &Path) -> Result<(), CompressError> {
-    let img = open_with_orientation(path)?;
-    let rgba = img.to_rgba8();
-
-    let encoder = Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height());
-    let data = encoder.encode_lossless();
+pub fn path2compress_lossless(path: &Path, output_path: &Path, options: &ImageOptions) -> Result<(), CompressError> {
+    let img = open_for_encode(path, options)?;
+    let data = encode_lossless(&img.to_rgba8())?;
 
     write_file(output_path, &data)
 }
 
+/// 可逆 WebP にする。libwebp は既定で透明な画素の色を圧縮しやすい値に置き換えるが、
+/// `exact` を指定してそれも残し、デコードし直すと RGBA のすべての値が元と一致するようにする。
+pub fn encode_lossless(rgba: &RgbaImage) -> Result<Vec<u8>, CompressError> {
+    let mut config = WebPConfig::new().map_err(|_| CompressError::InvalidInput("libwebp の設定を初期化できません".to_string()))?;
+    config.lossless = 1;
+    config.exact = 1;
+    // 可逆では品質は圧縮の手間（大きいほど小さく・遅く）を表す。encode_lossless と同じ値にする
+    config.quality = 75.0;
+    let data = Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+        .encode_advanced(&config)
+        .map_err(|e| CompressError::InvalidInput(format!("可逆 WebP にエンコードできません: {e:?}")))?;
+    Ok(data.to_vec())
+}
+
+/// webp 向け: 元の WebP と同じ方式（可逆/非可逆）で再圧縮する。元より大きくなる場合は元のまま出力する。
+pub fn path2compress_webp(
+    path: &Path,
+    output_path: &Path,
+    kind: WebpKind,
+    options: &ImageOptions,
+) -> Result<(), CompressError> {
+    write_output(output_path, &path2webp(path, kind, options)?.into_output())
+}
+
+/// `path2compress_webp` と同じように圧縮し、ファイルに書かずに返す。
+pub fn path2webp(path: &Path, kind: WebpKind, options: &ImageOptions) -> Result<Encoded, CompressError> {
+    let original = std::fs::read(path)?;
+    let img = open_for_encode(path, options)?;
+
+    let data = if kind.lossless {
+        encode_lossless(&img.to_rgba8())?
+    } else if kind.alpha {
+        let rgba = img.to_rgba8();
+        Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height()).encode(options.quality).to_vec()
+    } else {
+        let rgb = img.to_rgb8();
+        Encoder::from_rgb(rgb.as_raw(), rgb.width(), rgb.height()).encode(options.quality).to_vec()
+    };
+
+    // 切り抜きや透かしを施した場合は元と内容が違うため、元のほうが小さくても書き戻さない
+    if options.is_edited() {
+        return Ok(Encoded::new(data));
+    }
+    Ok(Encoded::with_original(data, original))
+}
+
 fn write_file(output_path: &Path, data: &[u8]) -> Result<(), CompressError> {
+    ensure_parent_dir(output_path)?;
     let file = File::create(output_path)?;
     let mut writer = BufWriter::new(file);
     std::io::copy(&mut &data[..], &mut writer)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 可逆・アルファ付き非可逆・アニメーションを区別すること
+    #[test]
+    fn inspects_webp_kind() {
+        let rgba = [0u8; 4 * 4 * 4];
+        let lossless = Encoder::from_rgba(&rgba, 4, 4).encode_lossless();
+        assert_eq!(inspect(&lossless), Some(WebpKind { lossless: true, alpha: true, animated: false }));
+
+        let rgb = [0u8; 4 * 4 * 3];
+        let lossy = Encoder::from_rgb(&rgb, 4, 4).encode(70.0);
+        assert_eq!(inspect(&lossy), Some(WebpKind { lossless: false, alpha: false, animated: false }));
+
+        let mut animated = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        animated.extend_from_slice(&10u32.to_le_bytes());
+        animated.extend_from_slice(&[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        animated.extend_from_slice(b"ANIM");
+        animated.extend_from_slice(&6u32.to_le_bytes());
+        animated.extend_from_slice(&[0; 6]);
+        assert!(inspect(&animated).unwrap().animated);
+    }
+
+    /// 透明な画素の色も含めて、デコードし直した RGBA が元と完全に一致すること
+    #[test]
+    fn lossless_round_trips_exactly() {
+        let rgba = RgbaImage::from_fn(16, 16, |x, y| {
+            let alpha = [0, 1, 128, 255][(x % 4) as usize];
+            image::Rgba([(x * 16) as u8, (y * 16) as u8, (x * y) as u8, alpha])
+        });
+        let data = encode_lossless(&rgba).unwrap();
+        assert_eq!(inspect(&data).map(|kind| kind.lossless), Some(true));
+        assert_eq!(image::load_from_memory(&data).unwrap().to_rgba8(), rgba);
+
+        // 切り抜きなどがなければ png → webp の経路でも一致する
+        let dir = std::env::temp_dir().join("compressor_webp_lossless_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        rgba.save(dir.join("ui.png")).unwrap();
+        path2compress_lossless(&dir.join("ui.png"), &dir.join("ui.webp"), &ImageOptions::default()).unwrap();
+        assert_eq!(image::open(dir.join("ui.webp")).unwrap().to_rgba8(), rgba);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// WebP でないデータや壊れた長さではパニックしないこと
+    #[test]
+    fn rejects_non_webp() {
+        assert_eq!(inspect(b"not a webp"), None);
+        assert_eq!(inspect(b"RIFF\0\0\0\0WEBPVP8 \xFF\xFF\xFF\xFF"), Some(WebpKind { lossless: false, alpha: false, animated: false }));
+    }
+}
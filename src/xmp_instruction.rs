@@ -0,0 +1,143 @@
+//! 画像の XMP に書かれた処理の指示（`--xmp-instruction`）。
+//!
+//! 素材管理のシステムが書き出した画像では、ファイルごとの品質や形式を XMP の項目で指示できる。
+//! 値は `.compressorrc` と同じ `key = value` を `;` で区切ったもの（`quality = 60; webp = true`）か、
+//! `--xmp-preset` で名前を付けた設定の名前（`web-optimize`）。指示のないファイルは既定の設定で処理する。
+
+use std::path::Path;
+use crate::config::DirConfig;
+
+/// XMP を探す形式。XMP は jpg の APP1、png の iTXt、webp の XMP チャンク、tiff のタグにそのまま入っている
+const EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "tif", "tiff"];
+
+/// `--xmp-preset` の値（`NAME=SETTINGS`）を読む
+pub fn parse_preset(value: &str) -> Result<(String, DirConfig), String> {
+    let Some((name, settings)) = value.split_once('=') else {
+        return Err(format!("NAME=SETTINGS の形式ではありません: {value}"));
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("名前がありません: {value}"));
+    }
+    Ok((name.to_string(), parse_settings(settings)?))
+}
+
+/// `path` の XMP に書かれた `key` の指示を設定にする。指示がなければ `None`
+pub fn instruction(path: &Path, ext: &str, key: &str, presets: &[(String, DirConfig)]) -> Result<Option<DirConfig>, String> {
+    let value = read(path, ext, key).map_err(|e| e.to_string())?;
+    value.map(|value| resolve(&value, presets).map_err(|e| format!("{key}=\"{value}\": {e}"))).transpose()
+}
+
+/// `path` の XMP から `key`（`dam:compress` のように名前空間の接頭辞を付けた名前）の値を読む。
+/// XMP を探す形式でない、XMP がない、項目がない場合は `None`。
+fn read(path: &Path, ext: &str, key: &str) -> std::io::Result<Option<String>> {
+    if !EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()) {
+        return Ok(None);
+    }
+    let data = std::fs::read(path)?;
+    Ok(packet(&data).and_then(|xmp| property(xmp, key)))
+}
+
+/// 指示の値を設定にする。`key = value` を含まない値は `presets` の名前として引く
+fn resolve(value: &str, presets: &[(String, DirConfig)]) -> Result<DirConfig, String> {
+    if value.contains('=') {
+        return parse_settings(value);
+    }
+    presets
+        .iter()
+        .find(|(name, _)| name == value.trim())
+        .map(|(_, config)| config.clone())
+        .ok_or_else(|| format!("--xmp-preset にない名前です: {}", value.trim()))
+}
+
+fn parse_settings(settings: &str) -> Result<DirConfig, String> {
+    DirConfig::parse(&settings.replace(';', "\n")).map_err(|e| e.replacen(" 行目", " 番目の項目", 1))
+}
+
+/// ファイルの中の XMP のパケット（`<x:xmpmeta` から `</x:xmpmeta>` まで）
+fn packet(data: &[u8]) -> Option<&[u8]> {
+    let start = find(data, b"<x:xmpmeta")?;
+    let end = start + find(&data[start..], b"</x:xmpmeta>")?;
+    Some(&data[start..end])
+}
+
+/// `key="value"` の属性か、`<key>value</key>` の要素の値。要素が `rdf:Alt` などで包んでいれば最初の `rdf:li` の値
+fn property(xmp: &[u8], key: &str) -> Option<String> {
+    let xmp = String::from_utf8_lossy(xmp);
+    let value = attribute(&xmp, key).or_else(|| element(&xmp, key))?;
+    Some(unescape(value.trim()))
+}
+
+fn attribute<'a>(xmp: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = xmp;
+    while let Some(at) = rest.find(key) {
+        let before = rest[..at].chars().next_back();
+        let after = rest[at + key.len()..].trim_start();
+        rest = &rest[at + key.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(after) = after.strip_prefix('=') else {
+            continue;
+        };
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        return after[1..].split(quote).next();
+    }
+    None
+}
+
+fn element<'a>(xmp: &'a str, key: &str) -> Option<&'a str> {
+    let open = format!("<{key}>");
+    let start = xmp.find(&open)? + open.len();
+    let end = start + xmp[start..].find(&format!("</{key}>"))?;
+    let content = &xmp[start..end];
+    match content.find("<rdf:li") {
+        Some(li) => {
+            let text = &content[li + content[li..].find('>')? + 1..];
+            Some(&text[..text.find("</rdf:li>")?])
+        }
+        None => Some(content),
+    }
+}
+
+fn unescape(value: &str) -> String {
+    value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 属性と要素のどちらに書いた指示も読み、設定そのものか名前付きの設定として解釈すること
+    #[test]
+    fn reads_instruction_from_xmp() {
+        let attribute = b"\xff\xd8\xff\xe1..http://ns.adobe.com/xap/1.0/\0<x:xmpmeta><rdf:Description dam:id=\"1\" dam:compress=\"quality = 55; webp = true\"/></x:xmpmeta>";
+        assert_eq!(property(packet(attribute).unwrap(), "dam:compress").as_deref(), Some("quality = 55; webp = true"));
+        assert_eq!(property(packet(attribute).unwrap(), "dam:other"), None);
+        let element = b"<x:xmpmeta><dam:compress><rdf:Alt><rdf:li xml:lang=\"x-default\">web-optimize</rdf:li></rdf:Alt></dam:compress></x:xmpmeta>";
+        assert_eq!(property(packet(element).unwrap(), "dam:compress").as_deref(), Some("web-optimize"));
+        assert_eq!(packet(b"no xmp here"), None);
+
+        let config = resolve("quality = 55; webp = true", &[]).unwrap();
+        assert_eq!((config.quality, config.webp), (Some(55.0), Some(true)));
+        let presets = [parse_preset("web-optimize=quality = \"low\"; min_reduction = 5").unwrap()];
+        assert_eq!(resolve("web-optimize", &presets).unwrap().min_reduction, Some(5.0));
+        assert!(resolve("print", &presets).unwrap_err().contains("print"));
+        assert!(resolve("quality = 55; size = 3", &[]).unwrap_err().contains("2 番目の項目"));
+        assert!(parse_preset("web-optimize").is_err());
+
+        let dir = std::env::temp_dir().join("compressor_xmp_instruction_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.jpg"), attribute).unwrap();
+        std::fs::write(dir.join("a.mp4"), attribute).unwrap();
+        assert_eq!(read(&dir.join("a.jpg"), "JPG", "dam:compress").unwrap().as_deref(), Some("quality = 55; webp = true"));
+        assert_eq!(read(&dir.join("a.mp4"), "mp4", "dam:compress").unwrap(), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}